@@ -4,7 +4,7 @@
 # License, v. 2.0. If a copy of the MPL was not distributed with this
 # file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-# This script links config files from a provided profile
+# This script generates the config files from a provided profile, see config/genconfig.py
 
 set -e
 
@@ -14,12 +14,10 @@ if [ $# -eq 0 ]
   then
     echo "No profile supplied!"
     echo "Profile list:"
-    ls $PROFILE_DIR
+    ls $PROFILE_DIR | sed "s/\.json$//"
     exit
 fi
 
-PROFILE=$PROFILE_DIR/$1
-
 echo "Creating directories"
 
 mkdir -p build/
@@ -31,9 +29,6 @@ echo "Configuring..."
 
 set -x
 
-#ln $PROFILE/kentry-aarch64.S   kernel/kentry/aarch64/config.S
-ln $PROFILE/limfeats-config-x86_64.asm  kernel/obj/x86_64/limfeats/config.asm
-ln $PROFILE/rkernel.rs         kernel/src/config.rs
-ln $PROFILE/Makeconfig.mk      config.mk
+python3 config/genconfig.py $1
 
 echo "Configured!"
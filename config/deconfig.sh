@@ -4,7 +4,7 @@
 # License, v. 2.0. If a copy of the MPL was not distributed with this
 # file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-# This script cleans configuration links from the whole project
+# This script cleans generated configuration files from the whole project
 
 set -e
 
@@ -12,9 +12,9 @@ echo "Deconfiguring..."
 
 set -x
 
-#rm -f kernel/kentry/aarch64/config.S
-rm -f kernel/obj/x86_64/limfeats/config.asm
 rm -f kernel/src/config.rs
+rm -f kernel/obj/x86_64/config.asm
+rm -f kernel/obj/aarch64/config.S
 rm -f config.mk
 
 echo "Deconfigured!"
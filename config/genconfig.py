@@ -0,0 +1,173 @@
+#!/usr/bin/python3
+
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+# Generates the configuration files from config/schema.json and a profile in config/profiles/.
+# usage: genconfig.py PROFILE [TARGET OUTPUT]
+# Without TARGET every file listed in config/list.json is generated, otherwise only OUTPUT.
+# Must be run from the project root.
+
+import sys, json, os
+
+CONFIG_DIR = os.path.dirname(os.path.abspath(__file__))
+SCHEMA = os.path.join(CONFIG_DIR, "schema.json")
+PROFILE_DIR = os.path.join(CONFIG_DIR, "profiles")
+LIST = os.path.join(CONFIG_DIR, "list.json")
+
+INTEGER_TYPES = {
+    "u8": (0, 2**8 - 1),
+    "u16": (0, 2**16 - 1),
+    "u32": (0, 2**32 - 1),
+    "u64": (0, 2**64 - 1),
+    "usize": (0, 2**64 - 1),
+}
+TYPES = set(INTEGER_TYPES) | {"bool", "str"}
+TARGETS = {"rust", "nasm", "gas", "make"}
+
+class ConfigError(Exception):
+    pass
+
+def load_json(path: str):
+    try:
+        with open(path) as f:
+            return json.load(f)
+    except FileNotFoundError:
+        raise ConfigError(f"{path} does not exist")
+    except json.JSONDecodeError as e:
+        raise ConfigError(f"{path}: {e}")
+
+def check_schema(schema: dict):
+    for name, option in schema.items():
+        if option.get("type") not in TYPES:
+            raise ConfigError(f"{name}: unknown type {option.get('type')!r}")
+        if not option.get("doc"):
+            raise ConfigError(f"{name}: missing doc")
+        targets = option.get("targets", [])
+        if not targets or not set(targets) <= TARGETS:
+            raise ConfigError(f"{name}: targets must be a non-empty subset of {sorted(TARGETS)}")
+        if option["type"] == "str" and set(targets) & {"nasm", "gas"}:
+            raise ConfigError(f"{name}: strings are not supported in assembly")
+        option["default"] = validate(name, option, option.get("default"))
+
+def validate(name: str, option: dict, value):
+    """Checks the type and range of a value, returns it normalized"""
+    typ = option["type"]
+    if typ == "bool":
+        if not isinstance(value, bool):
+            raise ConfigError(f"{name}: expected a bool, got {value!r}")
+        return value
+    if typ == "str":
+        if not isinstance(value, str):
+            raise ConfigError(f"{name}: expected a string, got {value!r}")
+        return value
+    # integers can also be written as strings, e.g. "0xFFFF"
+    if isinstance(value, str):
+        try:
+            value = int(value, 0)
+        except ValueError:
+            raise ConfigError(f"{name}: expected an integer, got {value!r}")
+    if isinstance(value, bool) or not isinstance(value, int):
+        raise ConfigError(f"{name}: expected an integer, got {value!r}")
+    low, high = INTEGER_TYPES[typ]
+    low = max(low, option.get("min", low))
+    high = min(high, option.get("max", high))
+    if not low <= value <= high:
+        raise ConfigError(f"{name}: {value} is out of range {low}..={high}")
+    return value
+
+def resolve(schema: dict, profile: str, seen=()):
+    """Returns the values of a profile, after applying the profiles it inherits from"""
+    if profile in seen:
+        raise ConfigError(f"profile {profile} inherits from itself")
+    data = load_json(os.path.join(PROFILE_DIR, f"{profile}.json"))
+    if "inherits" in data:
+        values = resolve(schema, data["inherits"], seen + (profile,))
+    else:
+        values = {name: option["default"] for name, option in schema.items()}
+    for name, value in data.get("values", {}).items():
+        if name not in schema:
+            raise ConfigError(f"profile {profile}: unknown option {name}")
+        values[name] = validate(name, schema[name], value)
+    return values
+
+def options_for(schema: dict, values: dict, target: str):
+    return [(name, schema[name], values[name]) for name in schema if target in schema[name]["targets"]]
+
+def rust_value(option: dict, value):
+    if option["type"] == "bool":
+        return "true" if value else "false"
+    if option["type"] == "str":
+        return json.dumps(value, ensure_ascii=False)
+    return str(value)
+
+def rust_type(option: dict):
+    return "&str" if option["type"] == "str" else option["type"]
+
+def asm_value(value):
+    return str(int(value))
+
+def generate_rust(opts, header):
+    lines = [f"// {header}", ""]
+    for name, option, value in opts:
+        lines += [f"/// {line}".rstrip() for line in option["doc"].split("\n")]
+        lines.append(f"pub const {name}: {rust_type(option)} = {rust_value(option, value)};")
+        lines.append("")
+    return "\n".join(lines)
+
+def generate_nasm(opts, header):
+    lines = [f"; {header}", ""]
+    for name, option, value in opts:
+        lines += [f"; {line}".rstrip() for line in option["doc"].split("\n")]
+        lines.append(f"{name} equ {asm_value(value)}")
+    return "\n".join(lines) + "\n"
+
+def generate_gas(opts, header):
+    lines = [f"// {header}", ""]
+    for name, option, value in opts:
+        lines += [f"// {line}".rstrip() for line in option["doc"].split("\n")]
+        lines.append(f".equ {name}, {asm_value(value)}")
+    return "\n".join(lines) + "\n"
+
+def generate_make(opts, header):
+    lines = [f"# {header}", "# All of these options can be changed at runtime using the following syntax:",
+             "# make [TARGETS] [OPTION1]=[VALUE1] [OPTION2]=[VALUE2] ...", ""]
+    for name, option, value in opts:
+        lines += [f"# {line}".rstrip() for line in option["doc"].split("\n")]
+        if option["type"] == "bool":
+            # make conditionals treat an empty variable as false
+            value = "yes" if value else ""
+        lines.append(f"{name} ?= {value}".rstrip())
+        lines.append("")
+    return "\n".join(lines)
+
+GENERATORS = {
+    "rust": generate_rust,
+    "nasm": generate_nasm,
+    "gas": generate_gas,
+    "make": generate_make,
+}
+
+def generate(profile: str, outputs: dict):
+    schema = load_json(SCHEMA)
+    check_schema(schema)
+    values = resolve(schema, profile)
+    header = f"Generated by config/genconfig.py from profile '{profile}', do not edit"
+    for target, path in outputs.items():
+        if target not in GENERATORS:
+            raise ConfigError(f"unknown target {target}")
+        os.makedirs(os.path.dirname(path) or ".", exist_ok=True)
+        with open(path, "w") as f:
+            f.write(GENERATORS[target](options_for(schema, values, target), header))
+
+if __name__ == "__main__":
+    if len(sys.argv) not in (2, 4):
+        print("usage: genconfig.py PROFILE [TARGET OUTPUT]")
+        sys.exit(1)
+    outputs = load_json(LIST) if len(sys.argv) == 2 else {sys.argv[2]: sys.argv[3]}
+    try:
+        generate(sys.argv[1], outputs)
+    except ConfigError as e:
+        print(f"config error: {e}")
+        sys.exit(1)
@@ -4,7 +4,7 @@
 # License, v. 2.0. If a copy of the MPL was not distributed with this
 # file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-# This script links config files from a provided profile
+# This script regenerates the config files from a provided profile
 
 set -xe
 
@@ -0,0 +1,25 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Generates the `config` module from the default profile, the same way `config/config.sh` does
+//! for the kernel.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    let config = Path::new(&env::var("OUT_DIR").unwrap()).join("config.rs");
+    let status = Command::new("python3")
+        .args(["../config/genconfig.py", "default", "rust"])
+        .arg(&config)
+        .status()
+        .expect("failed to run python3");
+    assert!(status.success(), "config/genconfig.py failed");
+    println!("cargo:rerun-if-changed=../config/genconfig.py");
+    println!("cargo:rerun-if-changed=../config/schema.json");
+    println!("cargo:rerun-if-changed=../config/profiles/default.json");
+}
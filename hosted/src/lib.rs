@@ -0,0 +1,70 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Hosted build of the platform independent kernel modules.
+//!
+//! The kernel itself only compiles for the bare-metal targets, so this crate includes the pure
+//! logic modules by path and provides std replacements for the kernel facilities they use.
+//! Run the tests with `cargo test` from this directory.
+
+extern crate alloc;
+
+/// Same as the kernel `log!()`, but prints to stdout
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => (std::print!($($arg)*));
+}
+
+// The shared sources follow the lint settings of the kernel crate, where they are linted.
+// Some items are only used by kernel modules that are not part of this build.
+
+/// Stands in for the page tables of the kernel, host memory handed to `MapArea`s is always mapped
+pub mod arch {
+    pub const PAGE_SIZE: usize = 0x1000;
+
+    pub trait Paging {
+        fn translate(virt: usize) -> Option<usize>;
+    }
+
+    pub struct Current;
+
+    impl Paging for Current {
+        fn translate(virt: usize) -> Option<usize> {
+            Some(virt)
+        }
+    }
+}
+
+/// The default kernel configuration profile, generated by `build.rs`
+pub mod config {
+    include!(concat!(env!("OUT_DIR"), "/config.rs"));
+}
+
+#[path = "../../kernel/src/acpi.rs"]
+pub mod acpi;
+
+#[path = "../../kernel/src/cmdline.rs"]
+pub mod cmdline;
+
+#[path = "../../kernel/src/fdt.rs"]
+pub mod fdt;
+
+/// Only the portable drivers
+#[path = "../../kernel/src/driver"]
+pub mod driver {
+    pub mod keyboard;
+    pub mod pci {
+        pub mod bus;
+    }
+}
+
+#[path = "../../kernel/src/tools.rs"]
+#[allow(unused_attributes, clippy::all)]
+pub mod tools;
+
+/// `mall.rs` is left out, the host keeps its own global allocator
+#[allow(unused_imports, dead_code, clippy::all)]
+pub mod memman;
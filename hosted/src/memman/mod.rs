@@ -0,0 +1,10 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+#[path = "../../../kernel/src/memman/map.rs"]
+pub mod map;
+#[path = "../../../kernel/src/memman/staticalloc.rs"]
+pub mod staticalloc;
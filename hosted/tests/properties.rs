@@ -0,0 +1,257 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Property tests for the kernel modules built by `kernel-hosted`
+
+use core::alloc::{GlobalAlloc, Layout};
+use kernel_hosted::memman::map::{
+    MapArea, MemoryMapper, MemoryMapperError, TableMemoryMapper, TreeMemoryMapper,
+};
+use kernel_hosted::memman::staticalloc::StaticAllocator;
+use kernel_hosted::tools::{bin_extract, bin_insert};
+use proptest::prelude::*;
+
+const MAP_END: usize = 10_000;
+
+/// (start, length) pairs, some of them reaching out of the map
+fn regions() -> impl Strategy<Value = Vec<(usize, usize)>> {
+    prop::collection::vec((0..MAP_END, 1..1_500usize), 0..50)
+        .prop_map(|v| v.into_iter().map(|(s, l)| (s, s + l)).collect())
+}
+
+fn overlaps(a: (usize, usize), b: (usize, usize)) -> bool {
+    a.0 < b.1 && b.0 < a.1
+}
+
+/// bit range as (higher, lower)
+fn bit_range() -> impl Strategy<Value = (usize, usize)> {
+    (0..64usize).prop_flat_map(|lower| (lower..64usize, Just(lower)))
+}
+
+fn mask(higher: usize, lower: usize) -> u64 {
+    let width = higher + 1 - lower;
+    if width == 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
+
+fn check_claims<M>(mapper: M, requests: Vec<(usize, usize)>) -> Result<(), TestCaseError>
+where
+    M: MemoryMapper,
+{
+    let mut model: Vec<(usize, usize)> = Vec::new();
+    let mut areas = Vec::new();
+
+    for region in requests {
+        let expect_ok = region.1 <= MAP_END && !model.iter().any(|c| overlaps(*c, region));
+        match mapper.claim(region) {
+            Ok(area) => {
+                prop_assert!(expect_ok, "{:?} claimed over {:?}", region, model);
+                model.push(region);
+                areas.push(area);
+            }
+            Err(MemoryMapperError::OutOfBound(dim)) => {
+                prop_assert!(region.1 > MAP_END);
+                prop_assert_eq!(dim, (0, MAP_END));
+            }
+            Err(MemoryMapperError::AlreadyOccupiedBy(occupant)) => {
+                prop_assert!(!expect_ok);
+                prop_assert!(model.contains(&occupant));
+                prop_assert!(overlaps(occupant, region));
+            }
+            Err(MemoryMapperError::Empty) => prop_assert!(false, "{:?} is not empty", region),
+        }
+    }
+
+    model.sort_unstable();
+    prop_assert_eq!(mapper.iter().collect::<Vec<_>>(), model);
+    Ok(())
+}
+
+fn check_gaps<M>(mapper: M, requests: Vec<(usize, usize)>) -> Result<(), TestCaseError>
+where
+    M: MemoryMapper,
+{
+    let areas: Vec<_> = requests
+        .into_iter()
+        .filter_map(|r| mapper.claim(r).ok())
+        .collect();
+
+    // claimed regions and gaps together must tile the whole map without holes
+    let mut tiles: Vec<_> = mapper.iter().chain(mapper.gaps()).collect();
+    tiles.sort_unstable();
+    let mut cursor = 0;
+    for (start, end) in tiles {
+        prop_assert_eq!(start, cursor);
+        prop_assert!(end > start);
+        cursor = end;
+    }
+    prop_assert_eq!(cursor, MAP_END);
+
+    // gaps are never empty or adjacent to each other
+    let gaps: Vec<_> = mapper.gaps().collect();
+    for pair in gaps.windows(2) {
+        prop_assert!(pair[0].1 < pair[1].0);
+    }
+    for area in areas {
+        mapper.free(area);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Claim(usize, usize),
+    /// index into the held areas, modulo their count
+    Free(usize),
+    /// index and split offset
+    Split(usize, usize),
+    /// indices of the two areas
+    Merge(usize, usize),
+}
+
+fn operations() -> impl Strategy<Value = Vec<Op>> {
+    let op = prop_oneof![
+        (0..MAP_END, 1..1_500usize).prop_map(|(s, l)| Op::Claim(s, s + l)),
+        any::<usize>().prop_map(Op::Free),
+        (any::<usize>(), 0..1_500usize).prop_map(|(i, at)| Op::Split(i, at)),
+        (any::<usize>(), any::<usize>()).prop_map(|(i, j)| Op::Merge(i, j)),
+    ];
+    prop::collection::vec(op, 0..80)
+}
+
+/// Runs random operations and compares the map with the regions of the held areas
+fn check_operations<M>(mapper: M, ops: Vec<Op>) -> Result<(), TestCaseError>
+where
+    M: MemoryMapper,
+{
+    let mut held: Vec<(MapArea, (usize, usize))> = Vec::new();
+    for op in ops {
+        match op {
+            Op::Claim(start, end) => {
+                if let Ok(area) = mapper.claim((start, end)) {
+                    held.push((area, (start, end)));
+                }
+            }
+            Op::Free(i) if !held.is_empty() => {
+                let (area, _) = held.swap_remove(i % held.len());
+                mapper.free(area);
+            }
+            Op::Split(i, offset) if !held.is_empty() => {
+                let (area, (start, end)) = held.swap_remove(i % held.len());
+                let at = start + offset;
+                match mapper.split(area, at) {
+                    Ok((low, high)) => {
+                        prop_assert!(start < at && at < end);
+                        held.push((low, (start, at)));
+                        held.push((high, (at, end)));
+                    }
+                    Err(area) => {
+                        prop_assert!(at <= start || at >= end);
+                        held.push((area, (start, end)));
+                    }
+                }
+            }
+            Op::Merge(i, j) if held.len() >= 2 => {
+                let i = i % held.len();
+                let j = j % held.len();
+                if i == j {
+                    continue;
+                }
+                let (first, second) = (i.max(j), i.min(j));
+                let (a, ra) = held.swap_remove(first);
+                let (b, rb) = held.swap_remove(second);
+                match mapper.merge(a, b) {
+                    Ok(area) => {
+                        prop_assert!(ra.1 == rb.0 || rb.1 == ra.0);
+                        held.push((area, (ra.0.min(rb.0), ra.1.max(rb.1))));
+                    }
+                    Err((a, b)) => {
+                        prop_assert!(ra.1 != rb.0 && rb.1 != ra.0);
+                        held.push((a, ra));
+                        held.push((b, rb));
+                    }
+                }
+            }
+            _ => {}
+        }
+        let mut model: Vec<_> = held.iter().map(|(_, r)| *r).collect();
+        model.sort_unstable();
+        prop_assert_eq!(mapper.iter().collect::<Vec<_>>(), model);
+    }
+    for (area, _) in held {
+        mapper.free(area);
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn table_claims_never_overlap(requests in regions()) {
+        check_claims(unsafe { TableMemoryMapper::manage((0, MAP_END)) }, requests)?;
+    }
+
+    #[test]
+    fn tree_claims_never_overlap(requests in regions()) {
+        check_claims(unsafe { TreeMemoryMapper::manage((0, MAP_END)) }, requests)?;
+    }
+
+    #[test]
+    fn table_gaps_complement_claims(requests in regions()) {
+        check_gaps(unsafe { TableMemoryMapper::manage((0, MAP_END)) }, requests)?;
+    }
+
+    #[test]
+    fn tree_gaps_complement_claims(requests in regions()) {
+        check_gaps(unsafe { TreeMemoryMapper::manage((0, MAP_END)) }, requests)?;
+    }
+
+    #[test]
+    fn table_operations_match_model(ops in operations()) {
+        check_operations(unsafe { TableMemoryMapper::manage((0, MAP_END)) }, ops)?;
+    }
+
+    #[test]
+    fn tree_operations_match_model(ops in operations()) {
+        check_operations(unsafe { TreeMemoryMapper::manage((0, MAP_END)) }, ops)?;
+    }
+
+    #[test]
+    fn allocations_aligned_and_disjoint(
+        layouts in prop::collection::vec((1..256usize, 0..7u32), 1..40)
+    ) {
+        let allocator = StaticAllocator::<4096>::new();
+        let mut live: Vec<(usize, usize)> = Vec::new();
+
+        for (size, align_pow) in layouts {
+            let layout = Layout::from_size_align(size, 1 << align_pow).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) } as usize;
+            if ptr == 0 {
+                continue;
+            }
+            prop_assert_eq!(ptr % layout.align(), 0);
+            let region = (ptr, ptr + size);
+            prop_assert!(!live.iter().any(|l| overlaps(*l, region)));
+            live.push(region);
+        }
+    }
+
+    #[test]
+    fn insert_then_extract(target: u64, payload: u64, (higher, lower) in bit_range()) {
+        let result = bin_insert(target, payload, higher, lower);
+        prop_assert_eq!(bin_extract(result, higher, lower), payload & mask(higher, lower));
+        // bits outside of the range are preserved
+        let outside = !(mask(higher, lower) << lower);
+        prop_assert_eq!(result & outside, target & outside);
+    }
+
+    #[test]
+    fn extract_matches_shift(target: u64, (higher, lower) in bit_range()) {
+        prop_assert_eq!(bin_extract(target, higher, lower), (target >> lower) & mask(higher, lower));
+    }
+}
@@ -2,10 +2,27 @@
 ; License, v. 2.0. If a copy of the MPL was not distributed with this
 ; file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+%include "config.asm"   ; generated by config/genconfig.py, see config/README.md
+
+section .bss
+
+global boot_stack_bottom
+global boot_stack_top
+
+; range of the stack handed over by limine, read by `limine::boot_stack()`
+alignb 8
+boot_stack_bottom: resq 1
+boot_stack_top: resq 1
+
 section .text
 
 extern kmain
 global _start
 
 _start:
+mov [rel boot_stack_top], rsp
+mov rax, rsp
+sub rax, BOOT_STACK_SIZE    ; the requested stack size, see `limine::boot_stack()`
+mov [rel boot_stack_bottom], rax
+xor rbp, rbp    ; null frame pointer terminates backtraces
 jmp kmain   ; main function linked from kernel src/main.rs
@@ -0,0 +1,284 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Lookup of ACPI tables through the RSDP, as passed by `limine::rsdp()`.
+//!
+//! Only the table headers are parsed here, the users of a table parse its contents. The tables
+//! are reached through the HHDM, their memory is held by `memman::reclaim` until
+//! `copy_tables()` moved the ones still in use to the heap.
+//! main source: ACPI Specification 6.5, chapter 5.2 "ACPI System Description Tables"
+
+use crate::memman::map;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Size of the ACPI 1.0 RSDP, covered by the first checksum
+const RSDP_V1_SIZE: usize = 20;
+/// Size of the ACPI 2.0+ RSDP, covered by the extended checksum
+const RSDP_V2_SIZE: usize = 36;
+/// Size of the header every system description table starts with
+pub const HEADER_SIZE: usize = 36;
+/// Tables that are parsed after boot, kept by `copy_tables()`
+const KEPT_SIGNATURES: [&[u8; 4]; 1] = [b"MCFG"];
+
+/// Heap copies of the `KEPT_SIGNATURES` tables
+static COPIES: Mutex<Vec<Sdt<'static>>> = Mutex::new(Vec::new());
+/// Set by `copy_tables()`, the firmware tables may be gone afterwards
+static COPIED: AtomicBool = AtomicBool::new(false);
+
+/// Error returned by the table parsers & `find_table()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    BadSignature,
+    BadChecksum,
+    /// The length in a header does not fit into the given bytes
+    Truncated,
+    /// The root table has no table with this signature
+    NotFound([u8; 4]),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadSignature => write!(f, "ACPI table has a wrong signature"),
+            Self::BadChecksum => write!(f, "ACPI table has a wrong checksum"),
+            Self::Truncated => write!(f, "ACPI table is truncated"),
+            Self::NotFound(signature) => write!(
+                f,
+                "no ACPI table {}",
+                core::str::from_utf8(signature).unwrap_or("????")
+            ),
+        }
+    }
+}
+
+/// ACPI checksums make the sum of all bytes 0
+fn checksum(bytes: &[u8]) -> Result<(), AcpiError> {
+    match bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) {
+        0 => Ok(()),
+        _ => Err(AcpiError::BadChecksum),
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Root System Description Pointer, points to the RSDT and (since ACPI 2.0) the XSDT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rsdp {
+    pub revision: u8,
+    /// Physical address of the RSDT
+    pub rsdt: u32,
+    /// Physical address of the XSDT, preferred over the RSDT
+    pub xsdt: Option<u64>,
+}
+
+impl Rsdp {
+    /// Validates an RSDP, `bytes` must hold at least the ACPI 1.0 part
+    pub fn parse(bytes: &[u8]) -> Result<Self, AcpiError> {
+        let v1 = bytes.get(..RSDP_V1_SIZE).ok_or(AcpiError::Truncated)?;
+        if &v1[..8] != RSDP_SIGNATURE {
+            return Err(AcpiError::BadSignature);
+        }
+        checksum(v1)?;
+        let revision = v1[15];
+        let rsdt = read_u32(v1, 16);
+        if revision < 2 {
+            return Ok(Self {
+                revision,
+                rsdt,
+                xsdt: None,
+            });
+        }
+        let v2 = bytes.get(..RSDP_V2_SIZE).ok_or(AcpiError::Truncated)?;
+        checksum(v2)?;
+        Ok(Self {
+            revision,
+            rsdt,
+            xsdt: Some(read_u64(v2, 24)),
+        })
+    }
+}
+
+/// A system description table with a valid header & checksum
+#[derive(Debug, Clone, Copy)]
+pub struct Sdt<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Sdt<'a> {
+    /// Validates the table at the start of `bytes`, which may continue past its end
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, AcpiError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(AcpiError::Truncated);
+        }
+        let length = read_u32(bytes, 4) as usize;
+        let bytes = bytes
+            .get(..length)
+            .filter(|_| length >= HEADER_SIZE)
+            .ok_or(AcpiError::Truncated)?;
+        checksum(bytes)?;
+        Ok(Self { bytes })
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        self.bytes[..4].try_into().unwrap()
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    /// The whole table, including the header
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    /// The table after the header
+    pub fn data(&self) -> &'a [u8] {
+        &self.bytes[HEADER_SIZE..]
+    }
+
+    /// Physical addresses of the tables listed by an RSDT (`wide` false) or an XSDT (`wide` true)
+    pub fn entries(&self, wide: bool) -> impl Iterator<Item = u64> + 'a {
+        let size = if wide { 8 } else { 4 };
+        self.data().chunks_exact(size).map(move |entry| match wide {
+            true => read_u64(entry, 0),
+            false => read_u32(entry, 0) as u64,
+        })
+    }
+}
+
+/// Validates the table at the physical `address`, reached through the HHDM
+///
+/// # Safety
+/// A table must be mapped at `address` for as long as the result is used
+unsafe fn table_at(address: u64) -> Result<Sdt<'static>, AcpiError> {
+    let virt = address as usize + map::physical_offset();
+    let header = core::slice::from_raw_parts(virt as *const u8, HEADER_SIZE);
+    let length = read_u32(header, 4) as usize;
+    Sdt::parse(core::slice::from_raw_parts(virt as *const u8, length))
+}
+
+/// Finds the table with `signature` in the RSDT or XSDT of the RSDP at the virtual address
+/// `rsdp`. Tables with a wrong checksum are skipped. After `copy_tables()`, only the copies are
+/// searched.
+///
+/// # Safety
+/// `rsdp` must point to a mapped RSDP, like the one from `limine::rsdp()`
+pub unsafe fn find_table(rsdp: usize, signature: &[u8; 4]) -> Result<Sdt<'static>, AcpiError> {
+    if COPIED.load(Ordering::Acquire) {
+        return COPIES
+            .lock()
+            .iter()
+            .find(|table| &table.signature() == signature)
+            .copied()
+            .ok_or(AcpiError::NotFound(*signature));
+    }
+    let bytes = |size| core::slice::from_raw_parts(rsdp as *const u8, size);
+    let rsdp = match Rsdp::parse(bytes(RSDP_V1_SIZE))? {
+        v1 if v1.revision < 2 => v1,
+        _ => Rsdp::parse(bytes(RSDP_V2_SIZE))?,
+    };
+    let (root, wide) = match rsdp.xsdt {
+        Some(xsdt) => (table_at(xsdt)?, true),
+        None => (table_at(rsdp.rsdt as u64)?, false),
+    };
+    root.entries(wide)
+        .filter_map(|address| table_at(address).ok())
+        .find(|table| &table.signature() == signature)
+        .ok_or(AcpiError::NotFound(*signature))
+}
+
+/// Copies the tables that are still parsed after boot to the heap, `find_table()` only returns
+/// these copies afterwards.
+///
+/// # Safety
+/// Same as `find_table()`, the ACPI-reclaimable memory may only be released afterwards
+pub unsafe fn copy_tables(rsdp: usize) {
+    let mut copies = COPIES.lock();
+    for signature in KEPT_SIGNATURES {
+        if let Ok(table) = find_table(rsdp, signature) {
+            let bytes = table.bytes().to_vec().leak();
+            copies.push(Sdt { bytes });
+        }
+    }
+    COPIED.store(true, Ordering::Release);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+    use alloc::vec::Vec;
+
+    /// Fixes the checksum byte at `at` so the sum of `bytes` is 0
+    fn fix_checksum(bytes: &mut [u8], at: usize) {
+        bytes[at] = 0;
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes[at] = 0u8.wrapping_sub(sum);
+    }
+
+    fn table(signature: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(signature);
+        bytes.extend_from_slice(&((HEADER_SIZE + data.len()) as u32).to_le_bytes());
+        bytes.resize(HEADER_SIZE, 0);
+        bytes.extend_from_slice(data);
+        fix_checksum(&mut bytes, 9);
+        bytes
+    }
+
+    unit_test! {
+        fn rsdp_revisions() {
+            let mut rsdp = [0u8; RSDP_V2_SIZE];
+            rsdp[..8].copy_from_slice(RSDP_SIGNATURE);
+            rsdp[16..20].copy_from_slice(&0x1234u32.to_le_bytes());
+            fix_checksum(&mut rsdp[..RSDP_V1_SIZE], 8);
+            assert_eq!(
+                Rsdp::parse(&rsdp[..RSDP_V1_SIZE]),
+                Ok(Rsdp { revision: 0, rsdt: 0x1234, xsdt: None })
+            );
+
+            rsdp[15] = 2;
+            rsdp[24..32].copy_from_slice(&0x5678u64.to_le_bytes());
+            fix_checksum(&mut rsdp[..RSDP_V1_SIZE], 8);
+            fix_checksum(&mut rsdp, 32);
+            assert_eq!(Rsdp::parse(&rsdp).unwrap().xsdt, Some(0x5678));
+            assert_eq!(Rsdp::parse(&rsdp[..RSDP_V1_SIZE]), Err(AcpiError::Truncated));
+            rsdp[0] = b'X';
+            assert_eq!(Rsdp::parse(&rsdp), Err(AcpiError::BadSignature));
+        }
+
+        fn sdt_checksum_and_length() {
+            let mut bytes = table(b"MCFG", &[1, 2, 3]);
+            let sdt = Sdt::parse(&bytes).unwrap();
+            assert_eq!(&sdt.signature(), b"MCFG");
+            assert_eq!(sdt.data(), &[1, 2, 3]);
+            assert_eq!(Sdt::parse(&bytes[..HEADER_SIZE + 1]).err(), Some(AcpiError::Truncated));
+            bytes[HEADER_SIZE] = 5;
+            assert_eq!(Sdt::parse(&bytes).err(), Some(AcpiError::BadChecksum));
+        }
+
+        fn root_table_entries() {
+            let mut data = Vec::new();
+            data.extend_from_slice(&0x1000u64.to_le_bytes());
+            data.extend_from_slice(&0x2000u64.to_le_bytes());
+            let bytes = table(b"XSDT", &data);
+            let xsdt = Sdt::parse(&bytes).unwrap();
+            assert!(xsdt.entries(true).eq([0x1000, 0x2000]));
+            assert!(xsdt.entries(false).eq([0x1000, 0, 0x2000, 0]));
+        }
+    }
+}
@@ -0,0 +1,135 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// local APIC registers, in xAPIC (MMIO) & x2APIC (MSR) mode
+// main source: Intel SDM Vol. 3A, chapter 11 "Advanced Programmable Interrupt Controller"
+
+use super::Amd64;
+use crate::arch::{Paging, PagingError};
+use crate::bitfield;
+use crate::memman::map::{self, MapArea, MemoryMapperError};
+use core::fmt;
+use spin::{Mutex, Once};
+use x86::msr::{rdmsr, wrmsr, IA32_APIC_BASE, IA32_X2APIC_ICR};
+
+/// Offset of the low half of the interrupt command register in the xAPIC MMIO page
+const XAPIC_ICR_LOW: usize = 0x300;
+/// Offset of the high half, which holds the destination
+const XAPIC_ICR_HIGH: usize = 0x310;
+/// Size of the xAPIC MMIO page
+const XAPIC_SIZE: usize = 0x1000;
+
+/// Error returned by `init()`
+#[derive(Debug)]
+pub enum ApicError {
+    /// The xAPIC page could not be claimed
+    Registers(MemoryMapperError),
+    Mapping(PagingError),
+}
+
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Registers(e) => write!(f, "local APIC registers can not be claimed, {:?}", e),
+            Self::Mapping(e) => write!(f, "local APIC registers can not be mapped, {}", e),
+        }
+    }
+}
+
+/// The xAPIC MMIO page of the boot CPU, claimed & mapped by `init()`
+static XAPIC: Once<Mutex<MapArea>> = Once::new();
+
+/// Claims & maps the xAPIC MMIO page, so `InterruptCommand::send()` can reach it. Nothing to do
+/// in x2APIC mode, where the registers are MSRs.
+pub fn init() -> Result<(), ApicError> {
+    let base = unsafe { ApicBase::read() };
+    if base.x2apic() || XAPIC.is_completed() {
+        return Ok(());
+    }
+    let region = (base.address(), base.address() + XAPIC_SIZE);
+    let area = map::claim_global(region).map_err(ApicError::Registers)?;
+    if let Err(e) = Amd64::map_device(region) {
+        map::free_global(area);
+        return Err(ApicError::Mapping(e));
+    }
+    XAPIC.call_once(|| Mutex::new(area));
+    Ok(())
+}
+
+bitfield! {
+    /// The IA32_APIC_BASE MSR
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct ApicBase(u64) {
+        /// Set on the bootstrap processor
+        pub bool, bsp, _: 8;
+        pub bool, x2apic, set_x2apic: 10;
+        pub bool, enabled, set_enabled: 11;
+        /// Frame number of the xAPIC MMIO page
+        pub u64, frame, set_frame: 51, 12;
+    }
+}
+
+impl ApicBase {
+    /// Reads the IA32_APIC_BASE MSR
+    ///
+    /// # Safety
+    /// Must run in ring 0 on a CPU with a local APIC (CPUID.01h:EDX bit 9), otherwise it faults
+    pub unsafe fn read() -> Self {
+        Self::from_raw(rdmsr(IA32_APIC_BASE))
+    }
+
+    /// Physical address of the xAPIC MMIO page
+    pub const fn address(&self) -> usize {
+        (self.frame() << 12) as usize
+    }
+}
+
+/// `InterruptCommand::delivery_mode()` of a non-maskable interrupt
+pub const DELIVERY_NMI: u8 = 0b100;
+/// `InterruptCommand::shorthand()` selecting every CPU except the sending one
+pub const SHORTHAND_ALL_EXCLUDING_SELF: u8 = 0b11;
+
+bitfield! {
+    /// The interrupt command register, which sends inter-processor interrupts
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct InterruptCommand(u64) {
+        pub u8, vector, set_vector: 7, 0;
+        pub u8, delivery_mode, set_delivery_mode: 10, 8;
+        /// Logical instead of physical destination
+        pub bool, logical, set_logical: 11;
+        /// Set while the interrupt has not been accepted yet, xAPIC only
+        pub bool, pending, _: 12;
+        pub bool, assert, set_assert: 14;
+        pub bool, level_triggered, set_level_triggered: 15;
+        pub u8, shorthand, set_shorthand: 19, 18;
+        /// APIC ID of the target, ignored when a shorthand is used
+        pub u32, destination, set_destination: 63, 32;
+    }
+}
+
+impl InterruptCommand {
+    /// Sends the interrupt, through the MSR in x2APIC mode or the MMIO page in xAPIC mode.
+    /// Returns false when the xAPIC page was not mapped by `init()` or is in use.
+    ///
+    /// # Safety
+    /// Must run in ring 0, the interrupt must not break the target CPUs
+    pub unsafe fn send(self) -> bool {
+        let base = ApicBase::read();
+        if base.x2apic() {
+            wrmsr(IA32_X2APIC_ICR, self.raw());
+            return true;
+        }
+        let Some(mut xapic) = XAPIC.get().and_then(Mutex::try_lock) else {
+            return false;
+        };
+        // writing the low half sends the interrupt, so the destination (bits 56-63 in xAPIC
+        // mode) goes first
+        let high = self.destination() << 24;
+        let low = self.raw() as u32;
+        xapic.write::<u32>(XAPIC_ICR_HIGH, high).is_ok()
+            && xapic.write::<u32>(XAPIC_ICR_LOW, low).is_ok()
+    }
+}
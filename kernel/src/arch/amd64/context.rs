@@ -0,0 +1,71 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// switches between threads of execution by swapping stacks, the callee-saved registers of the
+// System V ABI are pushed on the stack that is left
+
+use crate::arch;
+use core::arch::global_asm;
+
+/// rbp, rbx, r12, r13, r14 & r15
+const SAVED_REGISTERS: usize = 6;
+
+/// A saved thread of execution, see `arch::Context`
+#[derive(Debug, Default)]
+pub struct Context {
+    /// rsp after pushing the callee-saved registers
+    rsp: usize,
+}
+
+// amd64_switch_context(from: *mut usize (rdi), to: usize (rsi))
+global_asm!(
+    r#"
+.section .text.amd64_switch_context, "ax"
+.global amd64_switch_context
+amd64_switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#
+);
+
+extern "C" {
+    fn amd64_switch_context(from: *mut usize, to: usize);
+}
+
+impl arch::Context for Context {
+    unsafe fn new(entry: extern "C" fn() -> !, stack_top: usize) -> Self {
+        // popped by amd64_switch_context, then `ret` jumps to `entry` as if it was called, which
+        // leaves rsp 8 bytes below a 16 byte boundary
+        let frame = (stack_top as *mut usize).sub(SAVED_REGISTERS + 2);
+        for i in 0..SAVED_REGISTERS {
+            // a null rbp terminates backtraces
+            frame.add(i).write(0);
+        }
+        frame.add(SAVED_REGISTERS).write(entry as usize);
+        // return address of `entry`, which never returns
+        frame.add(SAVED_REGISTERS + 1).write(0);
+        Self {
+            rsp: frame as usize,
+        }
+    }
+
+    unsafe fn switch(from: &mut Self, to: &Self) {
+        amd64_switch_context(&mut from.rsp, to.rsp);
+    }
+}
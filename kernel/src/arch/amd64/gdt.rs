@@ -7,10 +7,9 @@
 // loads the Global Descriptor Table
 // main source: https://wiki.osdev.org/Global_Descriptor_Table
 
-use x86::dtables::{lgdt, sgdt, DescriptorTablePointer};
-#[macro_use]
-use crate::tools::{bin_extract, bin_insert};
 use crate::bitfield;
+use crate::tools::bin_extract;
+use x86::dtables::{lgdt, sgdt, DescriptorTablePointer};
 
 const PRIVILEGE_KERNEL: u8 = 0;
 const PRIVILEGE_USER: u8 = 3;
@@ -27,62 +26,34 @@ static GDT: &[SegmentDescriptor] = &[
     // after this anything can be loaded
 ];
 
-/* const_bitfield implementation of SegmentDescriptor
-
-use const_bitfield::bitfield;
 bitfield! {
-    #[derive(Debug)]
-    struct SegmentDescriptor(u64);
-    u16, limit0, set_limit0: 15, 0;
-    u16, base0,  set_base0: 31, 16;
-    u8,  base1,  set_base1: 39, 32;
-
-    // access byte 40-47
-    bool, access_A, set_access_a: 40; // Accessed
-    bool, access_RW, set_access_rw: 41; // Readable/Writable
-    bool, access_DC, set_access_dc: 42; // Director/Conforming
-    bool, access_E, set_access_e: 43;  // executable
-    bool, access_S, set_access_s: 44;   // type
-    u8, access_DPL, set_access_dpl: 46, 45; // descriptor prviliege level
-    bool, access_P, set_access_p: 47; // present
-
-    u8, limit1, set_limit1: 51, 48;
-
-    // flags 52-55
-    bool, reserved, _: 52;
-    bool, flag_L, set_flag_l: 53; // Long-mode
-    bool, flag_DB, set_flag_db: 54; // size
-    bool, flag_G, set_flag_g: 55; // granularity
-
-    u8, base2, set_base2: 63, 56;
+    struct SegmentDescriptor(u64) {
+        u16, limit0, set_limit0: 15, 0;
+        u16, base0, set_base0: 31, 16;
+        u8, base1, set_base1: 39, 32;
+
+        // access byte 40-47
+        bool, access_a, set_access_a: 40; // Accessed
+        bool, access_rw, set_access_rw: 41; // Readable/Writable
+        bool, access_dc, set_access_dc: 42; // Direction/Conforming
+        bool, access_e, set_access_e: 43; // executable
+        bool, access_s, set_access_s: 44; // type
+        u8, access_dpl, set_access_dpl: 46, 45; // descriptor privilege level
+        bool, access_p, set_access_p: 47; // present
+
+        u8, limit1, set_limit1: 51, 48;
+
+        // flags 52-55
+        bool, reserved, _: 52;
+        bool, flag_l, set_flag_l: 53; // Long-mode
+        bool, flag_db, set_flag_db: 54; // size
+        bool, flag_g, set_flag_g: 55; // granularity
+
+        u8, base2, set_base2: 63, 56;
+    }
 }
-*/
-
-struct SegmentDescriptor(u64);
 
 impl SegmentDescriptor {
-    // bit write & readers
-    bitfield!(set_limit0, u16, 15, 0);
-    bitfield!(set_base0, u16, 31, 16);
-    bitfield!(set_base1, u8, 39, 32);
-
-    bitfield!(set_access_a, bool, 40);
-    bitfield!(set_access_rw, bool, 41);
-    bitfield!(set_access_dc, bool, 42);
-    bitfield!(set_access_e, bool, 43);
-    bitfield!(set_access_s, bool, 44);
-    bitfield!(set_access_dpl, u8, 46, 45);
-    bitfield!(set_access_p, bool, 47);
-
-    bitfield!(set_limit1, u8, 51, 48);
-
-    bitfield!(set_reserved, bool, 52); // dont use this
-    bitfield!(set_flag_l, bool, 53);
-    bitfield!(set_flag_db, bool, 54);
-    bitfield!(set_flag_g, bool, 55);
-
-    bitfield!(set_base2, u8, 63, 56);
-
     const fn null() -> Self {
         Self(0_u64)
     }
@@ -0,0 +1,209 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// loads the Interrupt Descriptor Table & dispatches exceptions and legacy PIC interrupts
+// main source: https://wiki.osdev.org/Interrupt_Descriptor_Table
+
+use super::pic;
+use crate::{bitfield, trace};
+use core::arch::global_asm;
+use core::sync::atomic::Ordering;
+use spin::Once;
+use x86::dtables::{lidt, DescriptorTablePointer};
+
+/// Vectors 0-31 are CPU exceptions, followed by the 16 IRQs of the PICs
+pub const IRQ_BASE: u8 = 32;
+const VECTORS: usize = IRQ_BASE as usize + pic::IRQS;
+/// Index of rbp in `InterruptFrame::registers`
+const RBP: usize = 8;
+/// Distance between the entry stubs, see `amd64_interrupt_stubs`
+const STUB_SIZE: usize = 16;
+/// `GateDescriptor::gate_type()` of an interrupt gate, which masks interrupts on entry
+const GATE_INTERRUPT: u8 = 0xE;
+
+/// Registers saved on interrupt entry, laid out as pushed by `amd64_interrupt_common`
+#[repr(C)]
+#[derive(Debug)]
+pub struct InterruptFrame {
+    /// r15 - r8, rbp, rdi, rsi, rdx, rcx, rbx, rax
+    pub registers: [u64; 15],
+    pub vector: u64,
+    /// pushed by the CPU for some exceptions, 0 for the others
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+bitfield! {
+    #[derive(Clone, Copy)]
+    struct GateDescriptor(u128) {
+        u16, offset0, set_offset0: 15, 0;
+        u16, selector, set_selector: 31, 16;
+        /// Interrupt stack table entry, 0 keeps the current stack
+        u8, ist, set_ist: 34, 32;
+        u8, gate_type, set_gate_type: 43, 40;
+        u8, dpl, set_dpl: 46, 45;
+        bool, present, set_present: 47;
+        u16, offset1, set_offset1: 63, 48;
+        u32, offset2, set_offset2: 95, 64;
+    }
+}
+
+impl GateDescriptor {
+    /// A kernel interrupt gate to `handler`, in the code segment `selector`
+    const fn new(handler: u64, selector: u16) -> Self {
+        let mut gate = Self::from_raw(0);
+        gate.set_offset0(handler as u16);
+        gate.set_offset1((handler >> 16) as u16);
+        gate.set_offset2((handler >> 32) as u32);
+        gate.set_selector(selector);
+        gate.set_gate_type(GATE_INTERRUPT);
+        gate.set_present(true);
+        gate
+    }
+}
+
+// Every vector has an entry stub of `STUB_SIZE` bytes. It pushes a 0 for vectors without an error
+// code & the vector number, then jumps to the common handler.
+global_asm!(
+    r#"
+.macro interrupt_stub vector
+    .balign {stub_size}
+    .if (\vector == 8) || (\vector >= 10 && \vector <= 14) || (\vector == 17) || (\vector == 21) || (\vector == 29) || (\vector == 30)
+    .else
+    push 0
+    .endif
+    push \vector
+    jmp amd64_interrupt_common
+.endm
+
+.section .text.interrupt_stubs, "ax"
+.balign {stub_size}
+.global amd64_interrupt_stubs
+amd64_interrupt_stubs:
+    .irp vector, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47
+    interrupt_stub \vector
+    .endr
+
+amd64_interrupt_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    // the CPU aligned the stack before pushing 5 values, with the error code, the vector & 15
+    // registers it is aligned again
+    mov rdi, rsp
+    cld
+    call {handler}
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    // vector & error code
+    add rsp, 16
+    iretq
+"#,
+    stub_size = const STUB_SIZE,
+    handler = sym interrupt_handler,
+);
+
+extern "C" {
+    static amd64_interrupt_stubs: u8;
+}
+
+static IDT: Once<[GateDescriptor; VECTORS]> = Once::new();
+
+/// Names the CPU exceptions
+fn exception_name(vector: u64) -> &'static str {
+    match vector {
+        0 => "division error",
+        1 => "debug",
+        2 => "non-maskable interrupt",
+        3 => "breakpoint",
+        4 => "overflow",
+        5 => "bound range exceeded",
+        6 => "invalid opcode",
+        7 => "device not available",
+        8 => "double fault",
+        10 => "invalid TSS",
+        11 => "segment not present",
+        12 => "stack-segment fault",
+        13 => "general protection fault",
+        14 => "page fault",
+        16 => "x87 floating-point exception",
+        17 => "alignment check",
+        18 => "machine check",
+        19 => "SIMD floating-point exception",
+        20 => "virtualization exception",
+        21 => "control protection exception",
+        _ => "reserved",
+    }
+}
+
+extern "C" fn interrupt_handler(frame: &mut InterruptFrame) {
+    let vector = frame.vector;
+    if vector >= IRQ_BASE as u64 {
+        return pic::handle_irq((vector - IRQ_BASE as u64) as u8);
+    }
+    // sent by `Cpu::stop_others()` while another CPU panics, any other NMI is reported below
+    if vector == 2 && crate::PANICKING.load(Ordering::SeqCst) {
+        loop {
+            unsafe { x86::halt() };
+        }
+    }
+    trace::set_exception(frame.rip as usize, frame.registers[RBP] as usize);
+    match vector {
+        14 => panic!(
+            "Exception: page fault (error 0x{:X}) at 0x{:X}, address 0x{:X}",
+            frame.error_code,
+            frame.rip,
+            unsafe { x86::controlregs::cr2() }
+        ),
+        _ => panic!(
+            "Exception: {} (error 0x{:X}) at 0x{:X}",
+            exception_name(vector),
+            frame.error_code,
+            frame.rip
+        ),
+    }
+}
+
+/// Loads the IDT, interrupts stay disabled
+pub fn init() {
+    let stubs = unsafe { &amd64_interrupt_stubs as *const u8 as u64 };
+    let selector = x86::segmentation::cs().bits();
+    let idt = IDT.call_once(|| {
+        core::array::from_fn(|vector| {
+            GateDescriptor::new(stubs + (vector * STUB_SIZE) as u64, selector)
+        })
+    });
+    unsafe { lidt(&DescriptorTablePointer::new_from_slice(idt)) };
+}
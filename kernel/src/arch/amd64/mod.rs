@@ -4,20 +4,48 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use super::ArchType;
+use super::{Arch, ArchType, Cpu, Mmio};
+use crate::bitfield;
+use crate::log::LogLevel;
+use crate::log_at;
+use apic::InterruptCommand;
+use core::arch::asm;
 use x86;
 use x86_64;
 
+pub mod apic;
+pub mod context;
 pub mod gdt;
+pub mod idt;
+pub mod paging;
+pub mod pic;
 
-#[inline]
-pub const fn get_arch() -> ArchType {
-    ArchType::X86_64
-}
+/// The amd64/x86_64 port
+pub struct Amd64;
+
+impl Arch for Amd64 {
+    const TYPE: ArchType = ArchType::X86_64;
+    type Context = context::Context;
+
+    fn init() {
+        // load our GDT
+        gdt::init();
+        idt::init();
+    }
 
-pub fn init() {
-    // load our GDT
-    gdt::init();
+    /// Only the legacy PICs for now, drivers register their IRQs with `pic::register()`. The local
+    /// APIC is only used to stop the other CPUs on a panic.
+    fn init_devices() {
+        pic::init();
+        if let Err(e) = apic::init() {
+            log_at!(
+                LogLevel::Warn,
+                "[WARN] {}, other CPUs are not stopped on a panic\n",
+                e
+            );
+        }
+        Self::enable_interrupts();
+    }
 }
 
 pub mod portio {
@@ -46,14 +74,80 @@ pub mod portio {
     }
 }
 
-pub mod cpu {
+bitfield! {
+    /// The RFLAGS register, only the flags used by the kernel
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    struct Rflags(u64) {
+        /// Set when maskable interrupts are enabled
+        bool, interrupt, _: 9;
+    }
+}
+
+impl Cpu for Amd64 {
     // WARNING: Will cause a general protection fault if used outside of ring 0.
-    pub unsafe fn halt() {
+    unsafe fn halt() {
         x86::halt();
     }
 
-    // WARNING: May fail with #UD if rdpid is not supported (check CPUID).
-    pub unsafe fn read_id() -> u64 {
-        x86::rdpid()
+    fn disable_interrupts() {
+        unsafe { x86::irq::disable() };
+    }
+
+    fn enable_interrupts() {
+        unsafe { x86::irq::enable() };
+    }
+
+    fn interrupts_enabled() -> bool {
+        let rflags: u64;
+        unsafe { asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags)) };
+        Rflags::from_raw(rflags).interrupt()
+    }
+
+    /// Sends an NMI to every CPU except the current one, does nothing in xAPIC mode before
+    /// `apic::init()` mapped the registers.
+    /// WARNING: Will cause a general protection fault if used outside of ring 0.
+    unsafe fn stop_others() {
+        let mut command = InterruptCommand::from_raw(0);
+        command.set_delivery_mode(apic::DELIVERY_NMI);
+        command.set_assert(true);
+        command.set_shorthand(apic::SHORTHAND_ALL_EXCLUDING_SELF);
+        command.send();
+    }
+
+    /// The initial local APIC ID, from CPUID leaf 1
+    fn id() -> u64 {
+        let leaf = unsafe { core::arch::x86_64::__cpuid(1) };
+        (leaf.ebx >> 24) as u64
+    }
+
+    /// Reads the stack pointer (rsp)
+    #[inline(always)]
+    fn read_stack_pointer() -> usize {
+        let sp: usize;
+        unsafe { asm!("mov {}, rsp", out(reg) sp, options(nomem, nostack)) };
+        sp
+    }
+
+    /// Reads the frame pointer (rbp) of the calling function
+    #[inline(always)]
+    fn read_frame_pointer() -> usize {
+        let fp: usize;
+        unsafe { asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack)) };
+        fp
+    }
+}
+
+impl Mmio for Amd64 {
+    fn read_barrier() {
+        unsafe { asm!("lfence", options(nostack, preserves_flags)) };
+    }
+
+    /// Only needed for write-combining memory, other stores are not reordered
+    fn write_barrier() {
+        unsafe { asm!("sfence", options(nostack, preserves_flags)) };
+    }
+
+    fn barrier() {
+        unsafe { asm!("mfence", options(nostack, preserves_flags)) };
     }
 }
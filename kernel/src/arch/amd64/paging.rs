@@ -0,0 +1,174 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// inspects & extends the active page tables
+// main source: https://wiki.osdev.org/Paging
+
+use super::Amd64;
+use crate::arch::{Paging, PagingError};
+use crate::bitfield;
+use crate::memman::{frame, map};
+use core::arch::asm;
+use x86::controlregs::{cr3, cr3_write, cr4, Cr4};
+
+bitfield! {
+    /// An entry of a page table at any level
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    struct Entry(u64) {
+        bool, present, set_present: 0;
+        bool, writable, set_writable: 1;
+        bool, user, set_user: 2;
+        /// Page-level write-through
+        bool, write_through, set_write_through: 3;
+        /// Page-level cache disable, with write-through selects the uncached PAT entry
+        bool, cache_disable, set_cache_disable: 4;
+        bool, accessed, _: 5;
+        bool, dirty, _: 6;
+        /// Marks 1 GiB (level 3) and 2 MiB (level 2) pages instead of a pointer to a table
+        bool, huge, set_huge: 7;
+        bool, global, set_global: 8;
+        /// Frame number of the next table or page
+        u64, frame, set_frame: 51, 12;
+        bool, no_execute, set_no_execute: 63;
+    }
+}
+
+impl Entry {
+    /// Physical address of the next table or page
+    const fn address(&self) -> usize {
+        (self.frame() << 12) as usize
+    }
+
+    /// Points the entry to a page-aligned physical address
+    const fn set_address(&mut self, address: usize) {
+        self.set_frame(address as u64 >> 12);
+    }
+
+    /// Whether the entry points to a page instead of a table, a level 1 entry always does
+    const fn is_page(&self, level: usize) -> bool {
+        level == 1 || (level <= 3 && self.huge())
+    }
+
+    unsafe fn read(entry: *const u64) -> Self {
+        Self::from_raw(entry.read_volatile())
+    }
+
+    unsafe fn write(self, entry: *mut u64) {
+        entry.write_volatile(self.raw());
+    }
+}
+
+/// Physical address of the root table
+fn root() -> usize {
+    Entry::from_raw(unsafe { cr3() }).address()
+}
+
+/// Number of paging levels in use, 4 or 5
+fn levels() -> usize {
+    if unsafe { cr4() }.contains(Cr4::CR4_ENABLE_LA57) {
+        5
+    } else {
+        4
+    }
+}
+
+fn walk(table: usize, level: usize, physical_offset: usize, f: &mut dyn FnMut(usize)) {
+    f(table);
+    // level 1 entries point to pages, not tables
+    if level == 1 {
+        return;
+    }
+    let entries = (table + physical_offset) as *const u64;
+    for i in 0..Amd64::PAGE_SIZE / 8 {
+        let entry = unsafe { Entry::read(entries.add(i)) };
+        if entry.present() && !entry.is_page(level) {
+            walk(entry.address(), level - 1, physical_offset, f);
+        }
+    }
+}
+
+/// Walks the tables towards the level 1 entry of `virt`, creating missing tables if `create`.
+/// Returns the last entry reached & its level: the level 1 entry, a huge page or an entry that
+/// is not present.
+unsafe fn find_entry(virt: usize, create: bool) -> Result<(*mut u64, usize), PagingError> {
+    let offset = map::physical_offset();
+    let mut table = root();
+    let mut level = levels();
+    loop {
+        let index = (virt >> (12 + 9 * (level - 1))) & 0x1FF;
+        let entry = (table + offset + index * 8) as *mut u64;
+        let current = Entry::read(entry);
+        if current.is_page(level) {
+            return Ok((entry, level));
+        }
+        if !current.present() {
+            if !create {
+                return Ok((entry, level));
+            }
+            let frame = frame::claim_zeroed_frames(1).ok_or(PagingError::OutOfMemory)?;
+            let new_table = frame.region().0;
+            // page tables are never freed
+            core::mem::forget(frame);
+            let mut pointer = Entry::from_raw(0);
+            pointer.set_address(new_table);
+            pointer.set_present(true);
+            pointer.set_writable(true);
+            pointer.write(entry);
+        }
+        table = Entry::read(entry).address();
+        level -= 1;
+    }
+}
+
+impl Paging for Amd64 {
+    const PAGE_SIZE: usize = 4096;
+
+    fn for_each_table_frame(physical_offset: usize, f: &mut dyn FnMut(usize)) {
+        walk(root(), levels(), physical_offset, f);
+    }
+
+    fn translate(virt: usize) -> Option<usize> {
+        let (entry, level) = unsafe { find_entry(virt, false) }.ok()?;
+        let entry = unsafe { Entry::read(entry) };
+        if !entry.present() {
+            return None;
+        }
+        // a level n page covers 4 KiB << 9 * (n - 1)
+        let page_size = Self::PAGE_SIZE << (9 * (level - 1));
+        let base = entry.address() & !(page_size - 1);
+        Some(base + virt % page_size)
+    }
+
+    fn map_device(region: (usize, usize)) -> Result<(), PagingError> {
+        let offset = map::physical_offset();
+        let start = region.0 - region.0 % Self::PAGE_SIZE;
+        for page in (start..region.1).step_by(Self::PAGE_SIZE) {
+            unsafe {
+                let (entry, _) = find_entry(page + offset, true)?;
+                if !Entry::read(entry).present() {
+                    let mut device = Entry::from_raw(0);
+                    device.set_address(page);
+                    device.set_present(true);
+                    device.set_writable(true);
+                    device.set_write_through(true);
+                    device.set_cache_disable(true);
+                    device.write(entry);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Only flushes the current CPU, the others are never started
+    fn flush(virt: usize) {
+        unsafe { asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags)) };
+    }
+
+    /// Only flushes the current CPU, the others are never started
+    fn flush_all() {
+        unsafe { cr3_write(cr3()) };
+    }
+}
@@ -0,0 +1,125 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// drives the two legacy 8259 programmable interrupt controllers
+// main source: https://wiki.osdev.org/8259_PIC
+
+use super::idt::IRQ_BASE;
+use super::portio;
+use crate::log::LogLevel;
+use crate::log_at;
+use core::fmt;
+use spin::{Mutex, Once};
+
+pub const IRQS: usize = 16;
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xA0;
+const SLAVE_DATA: u16 = 0xA1;
+
+/// ICW1: initialization, ICW4 follows
+const ICW1_INIT: u8 = 0x11;
+/// ICW4: 8086 mode
+const ICW4_8086: u8 = 0x01;
+/// OCW2: non-specific end of interrupt
+const OCW2_EOI: u8 = 0x20;
+/// OCW3: the next read of the command port returns the in-service register
+const OCW3_READ_ISR: u8 = 0x0B;
+/// The slave is connected to IRQ 2 of the master
+const CASCADE_IRQ: u8 = 2;
+
+/// Error returned by `register()`
+#[derive(Debug)]
+pub enum PicError {
+    InvalidIrq(u8),
+    AlreadyRegistered(u8),
+}
+
+impl fmt::Display for PicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidIrq(irq) => write!(f, "invalid IRQ {}", irq),
+            Self::AlreadyRegistered(irq) => write!(f, "IRQ {} already has a handler", irq),
+        }
+    }
+}
+
+static HANDLERS: [Once<fn()>; IRQS] = [const { Once::new() }; IRQS];
+/// Bit n masks IRQ n, the slave is in the high byte
+static MASK: Mutex<u16> = Mutex::new(0xFFFF);
+
+unsafe fn write_mask(mask: u16) {
+    portio::output_byte(MASTER_DATA, mask as u8);
+    portio::output_byte(SLAVE_DATA, (mask >> 8) as u8);
+}
+
+/// Remaps the IRQs to the vectors after `IRQ_BASE` & masks all of them, except for the cascade
+pub fn init() {
+    unsafe {
+        portio::output_byte(MASTER_COMMAND, ICW1_INIT);
+        portio::output_byte(SLAVE_COMMAND, ICW1_INIT);
+        // ICW2: vector offsets
+        portio::output_byte(MASTER_DATA, IRQ_BASE);
+        portio::output_byte(SLAVE_DATA, IRQ_BASE + 8);
+        // ICW3: the master gets a bit mask of its slaves, the slave its cascade identity
+        portio::output_byte(MASTER_DATA, 1 << CASCADE_IRQ);
+        portio::output_byte(SLAVE_DATA, CASCADE_IRQ);
+        portio::output_byte(MASTER_DATA, ICW4_8086);
+        portio::output_byte(SLAVE_DATA, ICW4_8086);
+        let mut mask = MASK.lock();
+        *mask = !(1 << CASCADE_IRQ);
+        write_mask(*mask);
+    }
+}
+
+/// Sets the handler of `irq` and unmasks it
+pub fn register(irq: u8, handler: fn()) -> Result<(), PicError> {
+    let slot = HANDLERS
+        .get(irq as usize)
+        .ok_or(PicError::InvalidIrq(irq))?;
+    if slot.is_completed() {
+        return Err(PicError::AlreadyRegistered(irq));
+    }
+    slot.call_once(|| handler);
+    let mut mask = MASK.lock();
+    *mask &= !(1 << irq);
+    unsafe { write_mask(*mask) };
+    Ok(())
+}
+
+/// Whether `irq` is actually being serviced, IRQ 7 & 15 are also raised for spurious interrupts
+fn in_service(irq: u8) -> bool {
+    let (port, bit) = match irq {
+        0..=7 => (MASTER_COMMAND, irq),
+        _ => (SLAVE_COMMAND, irq - 8),
+    };
+    unsafe {
+        portio::output_byte(port, OCW3_READ_ISR);
+        portio::input_byte(port) & (1 << bit) != 0
+    }
+}
+
+/// Runs the handler of `irq` and signals its end. Called by the IDT entries after `IRQ_BASE`.
+pub fn handle_irq(irq: u8) {
+    if (irq == 7 || irq == 15) && !in_service(irq) {
+        // the master still saw the cascade interrupt of a spurious slave IRQ
+        if irq == 15 {
+            unsafe { portio::output_byte(MASTER_COMMAND, OCW2_EOI) };
+        }
+        return;
+    }
+    match HANDLERS.get(irq as usize).and_then(|h| h.get()) {
+        Some(handler) => handler(),
+        None => log_at!(LogLevel::Warn, "[WARN] unhandled IRQ {}\n", irq),
+    }
+    unsafe {
+        if irq >= 8 {
+            portio::output_byte(SLAVE_COMMAND, OCW2_EOI);
+        }
+        portio::output_byte(MASTER_COMMAND, OCW2_EOI);
+    }
+}
@@ -0,0 +1,69 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// switches between threads of execution by swapping stacks, the callee-saved registers of the
+// AAPCS64 are stored on the stack that is left
+
+use crate::arch;
+use core::arch::global_asm;
+
+/// x19 - x30, x29 is the frame pointer & x30 the return address
+const SAVED_REGISTERS: usize = 12;
+
+/// A saved thread of execution, see `arch::Context`
+#[derive(Debug, Default)]
+pub struct Context {
+    /// sp after storing the callee-saved registers
+    sp: usize,
+}
+
+// arm64_switch_context(from: *mut usize (x0), to: usize (x1))
+global_asm!(
+    r#"
+.section .text.arm64_switch_context, "ax"
+.global arm64_switch_context
+arm64_switch_context:
+    sub sp, sp, #96
+    stp x19, x20, [sp]
+    stp x21, x22, [sp, #16]
+    stp x23, x24, [sp, #32]
+    stp x25, x26, [sp, #48]
+    stp x27, x28, [sp, #64]
+    stp x29, x30, [sp, #80]
+    mov x9, sp
+    str x9, [x0]
+    mov sp, x1
+    ldp x19, x20, [sp]
+    ldp x21, x22, [sp, #16]
+    ldp x23, x24, [sp, #32]
+    ldp x25, x26, [sp, #48]
+    ldp x27, x28, [sp, #64]
+    ldp x29, x30, [sp, #80]
+    add sp, sp, #96
+    ret
+"#
+);
+
+extern "C" {
+    fn arm64_switch_context(from: *mut usize, to: usize);
+}
+
+impl arch::Context for Context {
+    unsafe fn new(entry: extern "C" fn() -> !, stack_top: usize) -> Self {
+        // restored by arm64_switch_context, whose `ret` jumps to x30
+        let frame = (stack_top as *mut usize).sub(SAVED_REGISTERS);
+        for i in 0..SAVED_REGISTERS {
+            // a null x29 terminates backtraces
+            frame.add(i).write(0);
+        }
+        frame.add(SAVED_REGISTERS - 1).write(entry as usize);
+        Self { sp: frame as usize }
+    }
+
+    unsafe fn switch(from: &mut Self, to: &Self) {
+        arm64_switch_context(&mut from.sp, to.sp);
+    }
+}
@@ -0,0 +1,76 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// exception levels, the kernel runs at EL1
+// main source: ARM Architecture Reference Manual, D1.2 "Exception levels"
+
+use core::arch::asm;
+
+/// HCR_EL2.RW, EL1 executes in AArch64
+const HCR_RW: u64 = 1 << 31;
+/// HCR_EL2.E2H, the host runs at EL2 (VHE)
+const HCR_E2H: u64 = 1 << 34;
+/// CNTHCTL_EL2, lets EL1 use the physical counter & timer in both E2H layouts
+const CNTHCTL_EL1_ACCESS: u64 = 0b11 | (0b11 << 10);
+/// SPSR_EL2 for returning to EL1h with debug, SError, IRQ and FIQ masked
+const SPSR_EL1H_MASKED: u64 = (0b1111 << 6) | 0b0101;
+
+/// The exception level the CPU currently runs at
+pub fn current() -> u8 {
+    let el: u64;
+    unsafe { asm!("mrs {}, CurrentEL", out(reg) el, options(nomem, nostack)) };
+    ((el >> 2) & 0b11) as u8
+}
+
+/// Drops to EL1 if the kernel was entered at EL2, does nothing at EL1.
+///
+/// The limine protocol enters AArch64 kernels at EL1, this covers loaders that leave the kernel
+/// at EL2. The higher half can only be mapped at EL2 with VHE, whose translation regime has the
+/// same layout as the EL1 one, so the active tables & their configuration are copied to the EL1
+/// registers (through their `_EL12` aliases) and execution continues on the same stack.
+///
+/// WARNING: must be called before any EL1 system register is configured
+pub unsafe fn drop_to_el1() {
+    if current() != 2 {
+        return;
+    }
+    let hcr: u64;
+    asm!("mrs {}, hcr_el2", out(reg) hcr, options(nomem, nostack));
+    if hcr & HCR_E2H == 0 {
+        panic!("Entered at EL2 without VHE, the higher half kernel can not be mapped");
+    }
+    // with E2H set, the *_el1 names access the EL2 registers
+    asm!(
+        "mrs {tmp}, sctlr_el1",
+        "msr s3_5_c1_c0_0, {tmp}", // SCTLR_EL12
+        "mrs {tmp}, tcr_el1",
+        "msr s3_5_c2_c0_2, {tmp}", // TCR_EL12
+        "mrs {tmp}, mair_el1",
+        "msr s3_5_c10_c2_0, {tmp}", // MAIR_EL12
+        "mrs {tmp}, ttbr0_el1",
+        "msr s3_5_c2_c0_0, {tmp}", // TTBR0_EL12
+        "mrs {tmp}, ttbr1_el1",
+        "msr s3_5_c2_c0_1, {tmp}", // TTBR1_EL12
+        "mrs {tmp}, cnthctl_el2",
+        "orr {tmp}, {tmp}, {cnthctl}",
+        "msr cnthctl_el2, {tmp}",
+        "msr cntvoff_el2, xzr",
+        "msr hcr_el2, {hcr}",
+        "msr spsr_el2, {spsr}",
+        "adr {tmp}, 2f",
+        "msr elr_el2, {tmp}",
+        "mov {tmp}, sp",
+        "msr sp_el1, {tmp}",
+        "isb",
+        "eret",
+        "2:",
+        tmp = out(reg) _,
+        cnthctl = in(reg) CNTHCTL_EL1_ACCESS,
+        // keep E2H, so the EL1 registers written above stay where they are
+        hcr = in(reg) HCR_RW | HCR_E2H,
+        spsr = in(reg) SPSR_EL1H_MASKED,
+    );
+}
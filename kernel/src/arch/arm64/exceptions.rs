@@ -0,0 +1,179 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// exception vector table, loaded into VBAR_EL1
+// main source: ARM Architecture Reference Manual, D1.10 "Exception entry"
+
+use super::gic;
+use crate::trace;
+use core::arch::global_asm;
+
+/// Registers saved on exception entry, laid out as pushed by `arm64_exception_common`
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    /// x0 - x30
+    pub x: [u64; 31],
+    pub elr: u64,
+    pub spsr: u64,
+    pub esr: u64,
+    pub far: u64,
+    /// keeps sp 16 byte aligned
+    _padding: u64,
+}
+
+/// What caused the exception, the low 2 bits of the vector index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Synchronous,
+    Irq,
+    Fiq,
+    SError,
+}
+
+/// Where the exception came from, the high 2 bits of the vector index
+const SOURCE_NAMES: [&str; 4] = [
+    "current EL with SP0",
+    "current EL with SPx",
+    "lower EL (AArch64)",
+    "lower EL (AArch32)",
+];
+
+// The table holds 16 entries of 0x80 bytes: (sync, irq, fiq, serror) for each source.
+// Each entry reserves a `TrapFrame` on the stack, saves x0 & x1 and jumps to the common handler
+// with the vector index in x1.
+global_asm!(
+    r#"
+.macro vector_entry index
+    .balign 0x80
+    sub sp, sp, #{frame_size}
+    stp x0, x1, [sp]
+    mov x1, #\index
+    b arm64_exception_common
+.endm
+
+.section .text.exception_vectors, "ax"
+.balign 0x800
+.global arm64_exception_vectors
+arm64_exception_vectors:
+    .irp index, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+    vector_entry \index
+    .endr
+
+arm64_exception_common:
+    stp x2, x3, [sp, #16]
+    stp x4, x5, [sp, #32]
+    stp x6, x7, [sp, #48]
+    stp x8, x9, [sp, #64]
+    stp x10, x11, [sp, #80]
+    stp x12, x13, [sp, #96]
+    stp x14, x15, [sp, #112]
+    stp x16, x17, [sp, #128]
+    stp x18, x19, [sp, #144]
+    stp x20, x21, [sp, #160]
+    stp x22, x23, [sp, #176]
+    stp x24, x25, [sp, #192]
+    stp x26, x27, [sp, #208]
+    stp x28, x29, [sp, #224]
+    mrs x2, elr_el1
+    stp x30, x2, [sp, #240]
+    mrs x2, spsr_el1
+    mrs x3, esr_el1
+    stp x2, x3, [sp, #256]
+    mrs x2, far_el1
+    str x2, [sp, #272]
+
+    mov x0, sp
+    bl {handler}
+
+    // the handler may have changed the return address or state
+    ldp x30, x2, [sp, #240]
+    ldr x3, [sp, #256]
+    msr elr_el1, x2
+    msr spsr_el1, x3
+    ldp x0, x1, [sp]
+    ldp x2, x3, [sp, #16]
+    ldp x4, x5, [sp, #32]
+    ldp x6, x7, [sp, #48]
+    ldp x8, x9, [sp, #64]
+    ldp x10, x11, [sp, #80]
+    ldp x12, x13, [sp, #96]
+    ldp x14, x15, [sp, #112]
+    ldp x16, x17, [sp, #128]
+    ldp x18, x19, [sp, #144]
+    ldp x20, x21, [sp, #160]
+    ldp x22, x23, [sp, #176]
+    ldp x24, x25, [sp, #192]
+    ldp x26, x27, [sp, #208]
+    ldp x28, x29, [sp, #224]
+    add sp, sp, #{frame_size}
+    eret
+"#,
+    frame_size = const core::mem::size_of::<TrapFrame>(),
+    handler = sym exception_handler,
+);
+
+extern "C" {
+    static arm64_exception_vectors: u8;
+}
+
+/// Names the exception class of ESR_EL1 (bits 31:26)
+fn class_name(class: u64) -> &'static str {
+    match class {
+        0x00 => "unknown reason",
+        0x0E => "illegal execution state",
+        0x15 => "SVC instruction",
+        0x18 => "trapped system register access",
+        0x20 => "instruction abort from a lower EL",
+        0x21 => "instruction abort",
+        0x22 => "PC alignment fault",
+        0x24 => "data abort from a lower EL",
+        0x25 => "data abort",
+        0x26 => "SP alignment fault",
+        0x3C => "BRK instruction",
+        _ => "other",
+    }
+}
+
+extern "C" fn exception_handler(frame: &mut TrapFrame, index: u64) {
+    let kind = match index % 4 {
+        0 => Kind::Synchronous,
+        1 => Kind::Irq,
+        2 => Kind::Fiq,
+        _ => Kind::SError,
+    };
+    let source = SOURCE_NAMES[(index / 4) as usize % 4];
+    match kind {
+        Kind::Irq => gic::handle_irq(),
+        Kind::Synchronous => {
+            let class = (frame.esr >> 26) & 0x3F;
+            trace::set_exception(frame.elr as usize, frame.x[29] as usize);
+            panic!(
+                "Synchronous exception from {}: {} (ESR 0x{:X}) at 0x{:X}, FAR 0x{:X}",
+                source,
+                class_name(class),
+                frame.esr,
+                frame.elr,
+                frame.far
+            );
+        }
+        Kind::Fiq | Kind::SError => {
+            trace::set_exception(frame.elr as usize, frame.x[29] as usize);
+            panic!(
+                "Unexpected {:?} exception from {} at 0x{:X}, ESR 0x{:X}",
+                kind, source, frame.elr, frame.esr
+            )
+        }
+    }
+}
+
+/// Points VBAR_EL1 to the exception vector table
+pub fn init() {
+    unsafe {
+        let vectors = &arm64_exception_vectors as *const u8 as u64;
+        core::arch::asm!("msr vbar_el1, {}", "isb", in(reg) vectors, options(nostack));
+    }
+}
@@ -0,0 +1,320 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// GICv2 & GICv3 interrupt controller driver, only routes interrupts to the boot CPU
+// main source: ARM Generic Interrupt Controller Architecture Specification, versions 2.0 & 3/4
+
+use super::Arm64;
+use crate::arch::{Cpu, Paging, PagingError};
+use crate::fdt::Fdt;
+use crate::log_at;
+use crate::memman::map::{self, MapArea, MemoryMapperError};
+use core::arch::asm;
+use core::fmt;
+use spin::{Mutex, Once};
+
+/// Interrupt IDs 0-15 are SGIs, 16-31 PPIs and 32-1019 SPIs
+const MAX_INTERRUPTS: usize = 1020;
+/// Returned by an acknowledge when no interrupt is pending
+const INTID_SPURIOUS: u32 = 1023;
+/// Priority given to every enabled interrupt, lower is more urgent
+const DEFAULT_PRIORITY: u8 = 0xA0;
+
+const GICV2_COMPATIBLE: &[&str] = &["arm,gic-400", "arm,cortex-a15-gic", "arm,cortex-a9-gic"];
+const GICV3_COMPATIBLE: &[&str] = &["arm,gic-v3"];
+
+// distributor registers, shared by both versions
+const GICD_CTLR: usize = 0x000;
+const GICD_IGROUPR: usize = 0x080;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_IROUTER: usize = 0x6000;
+/// GICD_CTLR.RWP, set while a write to GICD_CTLR is in progress (GICv3)
+const GICD_CTLR_RWP: u32 = 1 << 31;
+/// GICD_CTLR.ARE_NS | GICD_CTLR.EnableGrp1NS (GICv3)
+const GICD_CTLR_V3_ENABLE: u32 = (1 << 4) | (1 << 1);
+
+// GICv2 CPU interface registers
+const GICC_CTLR: usize = 0x000;
+const GICC_PMR: usize = 0x004;
+const GICC_IAR: usize = 0x00C;
+const GICC_EOIR: usize = 0x010;
+
+// GICv3 redistributor registers, each CPU has an RD frame followed by an SGI frame
+const GICR_FRAME_SIZE: usize = 0x2_0000;
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+const GICR_SGI_BASE: usize = 0x1_0000;
+/// GICR_TYPER.Last, set in the last frame of the region
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// Error returned by `init()` & `register()`
+#[derive(Debug)]
+pub enum GicError {
+    /// The device tree has no supported interrupt controller
+    NotFound,
+    /// The registers are not in the device tree or could not be claimed
+    Registers(Option<MemoryMapperError>),
+    Mapping(PagingError),
+    /// No redistributor belongs to the current CPU
+    NoRedistributor,
+    NotInitialized,
+    InvalidInterrupt(u32),
+    AlreadyRegistered(u32),
+}
+
+impl fmt::Display for GicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "no supported interrupt controller in the device tree"),
+            Self::Registers(None) => write!(f, "interrupt controller registers are missing"),
+            Self::Registers(Some(e)) => {
+                write!(
+                    f,
+                    "interrupt controller registers can not be claimed, {:?}",
+                    e
+                )
+            }
+            Self::Mapping(e) => {
+                write!(f, "interrupt controller registers can not be mapped, {}", e)
+            }
+            Self::NoRedistributor => write!(f, "no redistributor for the current CPU"),
+            Self::NotInitialized => write!(f, "interrupt controller is not initialized"),
+            Self::InvalidInterrupt(i) => write!(f, "invalid interrupt ID {}", i),
+            Self::AlreadyRegistered(i) => write!(f, "interrupt {} already has a handler", i),
+        }
+    }
+}
+
+enum Gic {
+    V2 {
+        distributor: Mutex<MapArea>,
+        cpu_interface: Mutex<MapArea>,
+    },
+    V3 {
+        distributor: Mutex<MapArea>,
+        redistributors: Mutex<MapArea>,
+        /// offset of the RD & SGI frames of the boot CPU in `redistributors`
+        frame: usize,
+        /// affinity of the boot CPU, SPIs are routed to it
+        affinity: u64,
+    },
+}
+
+static GIC: Once<Gic> = Once::new();
+
+static HANDLERS: [Once<fn()>; MAX_INTERRUPTS] = [const { Once::new() }; MAX_INTERRUPTS];
+
+/// Claims & maps the next register region of a `reg` property
+fn claim_registers(reg: &mut impl Iterator<Item = (u64, u64)>) -> Result<MapArea, GicError> {
+    let (start, size) = reg.next().ok_or(GicError::Registers(None))?;
+    let region = (start as usize, (start + size) as usize);
+    let area = map::claim_global(region).map_err(|e| GicError::Registers(Some(e)))?;
+    if let Err(e) = Arm64::map_device(region) {
+        map::free_global(area);
+        return Err(GicError::Mapping(e));
+    }
+    Ok(area)
+}
+
+fn wait_while(area: &MapArea, offset: usize, mask: u32) {
+    while area.read::<u32>(offset).unwrap() & mask != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Finds the redistributor frame of the CPU with `affinity`, returns its offset in `area`
+fn find_redistributor(area: &MapArea, affinity: u64) -> Option<usize> {
+    (0..area.len())
+        .step_by(GICR_FRAME_SIZE)
+        .map(|offset| (offset, area.read::<u64>(offset + GICR_TYPER).unwrap()))
+        .scan(false, |last, (offset, typer)| {
+            // stop after the frame marked as the last one
+            if *last {
+                return None;
+            }
+            *last = typer & GICR_TYPER_LAST != 0;
+            Some((offset, typer))
+        })
+        .find(|(_, typer)| typer >> 32 == affinity)
+        .map(|(offset, _)| offset)
+}
+
+/// Affinity of the current CPU in the format of GICR_TYPER & GICD_IROUTER (Aff3.Aff2.Aff1.Aff0)
+fn current_affinity() -> u64 {
+    let mpidr = Arm64::id();
+    (mpidr & 0xFF_FFFF) | (((mpidr >> 32) & 0xFF) << 24)
+}
+
+/// Sets up the first supported interrupt controller in the device tree for the current CPU.
+/// Interrupts stay masked in DAIF.
+pub fn init(fdt: &Fdt) -> Result<(), GicError> {
+    if let Some(node) = fdt.find_compatible(GICV3_COMPATIBLE) {
+        let mut reg = node.reg();
+        let mut distributor = claim_registers(&mut reg)?;
+        let mut redistributors = claim_registers(&mut reg)?;
+        let affinity = current_affinity();
+        let frame =
+            find_redistributor(&redistributors, affinity).ok_or(GicError::NoRedistributor)?;
+        unsafe { init_v3(&mut distributor, &mut redistributors, frame) };
+        GIC.call_once(|| Gic::V3 {
+            distributor: Mutex::new(distributor),
+            redistributors: Mutex::new(redistributors),
+            frame,
+            affinity,
+        });
+        return Ok(());
+    }
+    if let Some(node) = fdt.find_compatible(GICV2_COMPATIBLE) {
+        let mut reg = node.reg();
+        let mut distributor = claim_registers(&mut reg)?;
+        let mut cpu_interface = claim_registers(&mut reg)?;
+        init_v2(&mut distributor, &mut cpu_interface);
+        GIC.call_once(|| Gic::V2 {
+            distributor: Mutex::new(distributor),
+            cpu_interface: Mutex::new(cpu_interface),
+        });
+        return Ok(());
+    }
+    Err(GicError::NotFound)
+}
+
+fn init_v2(distributor: &mut MapArea, cpu_interface: &mut MapArea) {
+    distributor.write::<u32>(GICD_CTLR, 1).unwrap();
+    // accept every priority
+    cpu_interface.write::<u32>(GICC_PMR, 0xFF).unwrap();
+    cpu_interface.write::<u32>(GICC_CTLR, 1).unwrap();
+}
+
+unsafe fn init_v3(distributor: &mut MapArea, redistributors: &mut MapArea, frame: usize) {
+    distributor
+        .write::<u32>(GICD_CTLR, GICD_CTLR_V3_ENABLE)
+        .unwrap();
+    wait_while(distributor, GICD_CTLR, GICD_CTLR_RWP);
+    // wake up the redistributor
+    let waker = redistributors.read::<u32>(frame + GICR_WAKER).unwrap();
+    redistributors
+        .write::<u32>(frame + GICR_WAKER, waker & !GICR_WAKER_PROCESSOR_SLEEP)
+        .unwrap();
+    wait_while(
+        redistributors,
+        frame + GICR_WAKER,
+        GICR_WAKER_CHILDREN_ASLEEP,
+    );
+    // SGIs & PPIs belong to group 1
+    redistributors
+        .write::<u32>(frame + GICR_SGI_BASE + GICD_IGROUPR, u32::MAX)
+        .unwrap();
+    // the CPU interface is accessed through system registers
+    asm!(
+        "mrs {tmp}, s3_0_c12_c12_5", // ICC_SRE_EL1
+        "orr {tmp}, {tmp}, #1",
+        "msr s3_0_c12_c12_5, {tmp}",
+        "isb",
+        "msr s3_0_c4_c6_0, {pmr}", // ICC_PMR_EL1, accept every priority
+        "msr s3_0_c12_c12_7, {one}", // ICC_IGRPEN1_EL1
+        "isb",
+        tmp = out(reg) _,
+        pmr = in(reg) 0xFFu64,
+        one = in(reg) 1u64,
+        options(nostack)
+    );
+}
+
+/// Sets the handler of interrupt `intid` and enables it, SPIs are routed to the current CPU
+pub fn register(intid: u32, handler: fn()) -> Result<(), GicError> {
+    let gic = GIC.get().ok_or(GicError::NotInitialized)?;
+    let slot = HANDLERS
+        .get(intid as usize)
+        .ok_or(GicError::InvalidInterrupt(intid))?;
+    if slot.is_completed() {
+        return Err(GicError::AlreadyRegistered(intid));
+    }
+    slot.call_once(|| handler);
+
+    let n = intid as usize;
+    let (word, bit) = (n / 32 * 4, 1u32 << (n % 32));
+    match gic {
+        Gic::V2 { distributor, .. } => {
+            let mut gicd = distributor.lock();
+            gicd.write::<u8>(GICD_IPRIORITYR + n, DEFAULT_PRIORITY)
+                .unwrap();
+            // SGIs & PPIs always target the current CPU
+            if n >= 32 {
+                gicd.write::<u8>(GICD_ITARGETSR + n, 1).unwrap();
+            }
+            gicd.write::<u32>(GICD_ISENABLER + word, bit).unwrap();
+        }
+        // SGIs & PPIs are configured in the redistributor, SPIs in the distributor
+        Gic::V3 {
+            redistributors,
+            frame,
+            ..
+        } if n < 32 => {
+            let sgi_base = frame + GICR_SGI_BASE;
+            let mut gicr = redistributors.lock();
+            gicr.write::<u8>(sgi_base + GICD_IPRIORITYR + n, DEFAULT_PRIORITY)
+                .unwrap();
+            gicr.write::<u32>(sgi_base + GICD_ISENABLER, bit).unwrap();
+        }
+        Gic::V3 {
+            distributor,
+            affinity,
+            ..
+        } => {
+            let mut gicd = distributor.lock();
+            let group = gicd.read::<u32>(GICD_IGROUPR + word).unwrap();
+            gicd.write::<u32>(GICD_IGROUPR + word, group | bit).unwrap();
+            gicd.write::<u8>(GICD_IPRIORITYR + n, DEFAULT_PRIORITY)
+                .unwrap();
+            gicd.write::<u64>(GICD_IROUTER + n * 8, *affinity).unwrap();
+            gicd.write::<u32>(GICD_ISENABLER + word, bit).unwrap();
+        }
+    }
+    Ok(())
+}
+
+/// Acknowledges the pending interrupt, runs its handler and signals its end.
+/// Called by the IRQ exception vector.
+pub fn handle_irq() {
+    let Some(gic) = GIC.get() else {
+        panic!("IRQ without an interrupt controller");
+    };
+    let (iar, intid) = match gic {
+        Gic::V2 { cpu_interface, .. } => {
+            let iar = cpu_interface.lock().read::<u32>(GICC_IAR).unwrap();
+            // bits 12:10 hold the CPU that sent an SGI
+            (iar, iar & 0x3FF)
+        }
+        Gic::V3 { .. } => {
+            let iar: u64;
+            // ICC_IAR1_EL1
+            unsafe { asm!("mrs {}, s3_0_c12_c12_0", out(reg) iar, options(nomem, nostack)) };
+            (iar as u32, iar as u32 & 0xFF_FFFF)
+        }
+    };
+    if intid == INTID_SPURIOUS {
+        return;
+    }
+    match HANDLERS.get(intid as usize).and_then(|h| h.get()) {
+        Some(handler) => handler(),
+        None => log_at!(
+            crate::log::LogLevel::Warn,
+            "[WARN] unhandled interrupt {}\n",
+            intid
+        ),
+    }
+    match gic {
+        Gic::V2 { cpu_interface, .. } => cpu_interface.lock().write::<u32>(GICC_EOIR, iar).unwrap(),
+        // ICC_EOIR1_EL1
+        Gic::V3 { .. } => unsafe {
+            asm!("msr s3_0_c12_c12_1, {}", in(reg) iar as u64, options(nomem, nostack))
+        },
+    }
+}
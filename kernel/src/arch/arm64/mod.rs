@@ -4,11 +4,137 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use super::ArchType;
+use super::{Arch, ArchType, Cpu, Mmio};
+use crate::driver::serial;
+use crate::fdt::Fdt;
+use crate::limine;
+use crate::log::LogLevel;
+use crate::{bitfield, log, log_at};
+use core::arch::asm;
 
-#[inline]
-pub const fn get_arch() -> ArchType {
-    ArchType::AArch64
+pub mod context;
+pub mod el;
+pub mod exceptions;
+pub mod gic;
+pub mod paging;
+pub mod psci;
+pub mod timer;
+
+/// The arm64/AArch64 port
+pub struct Arm64;
+
+impl Arch for Arm64 {
+    const TYPE: ArchType = ArchType::AArch64;
+    type Context = context::Context;
+
+    fn init() {
+        unsafe { el::drop_to_el1() };
+        exceptions::init();
+        paging::init();
+    }
+
+    /// The UART, PSCI, the interrupt controller & the timer are found in the device tree
+    fn init_devices() {
+        init_devices();
+    }
+}
+
+fn init_devices() {
+    let fdt = match limine::device_tree().map(Fdt::new) {
+        Ok(Ok(fdt)) => fdt,
+        Ok(Err(e)) => return log_at!(LogLevel::Warn, "[WARN] {}\n", e),
+        Err(e) => return log_at!(LogLevel::Warn, "[WARN] no device tree, {}\n", e),
+    };
+    if let Err(e) = serial::attach(&fdt) {
+        log_at!(LogLevel::Warn, "[WARN] {}\n", e);
+    }
+    match psci::init(&fdt).and_then(|_| psci::version()) {
+        Ok((major, minor)) => log!("PSCI: {}.{}\n", major, minor),
+        Err(e) => log_at!(LogLevel::Warn, "[WARN] {}\n", e),
+    }
+    if let Err(e) = gic::init(&fdt) {
+        return log_at!(LogLevel::Warn, "[WARN] {}, interrupts stay masked\n", e);
+    }
+    match timer::init(&fdt) {
+        Ok(()) => log!(
+            "timer: {} Hz counter, ticking at {} Hz\n",
+            timer::frequency(),
+            timer::TICK_HZ
+        ),
+        Err(e) => log_at!(LogLevel::Warn, "[WARN] timer, {}\n", e),
+    }
+    Arm64::enable_interrupts();
+}
+
+bitfield! {
+    /// DAIF, the exception masks of the current CPU
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    struct ExceptionMask(u64) {
+        bool, fiq, _: 6;
+        bool, irq, _: 7;
+        bool, serror, _: 8;
+        bool, debug, _: 9;
+    }
+}
+
+impl Cpu for Arm64 {
+    unsafe fn halt() {
+        asm!("wfi", options(nomem, nostack));
+    }
+
+    /// Masks debug, SError, IRQ and FIQ exceptions
+    fn disable_interrupts() {
+        unsafe { asm!("msr daifset, #0xf", options(nomem, nostack)) };
+    }
+
+    /// Unmasks SError & IRQ exceptions
+    fn enable_interrupts() {
+        unsafe { asm!("msr daifclr, #0x6", options(nomem, nostack)) };
+    }
+
+    fn interrupts_enabled() -> bool {
+        let daif: u64;
+        unsafe { asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack)) };
+        !ExceptionMask::from_raw(daif).irq()
+    }
+
+    /// Does nothing for now, secondary cores are never started (see `psci::cpu_on()`).
+    unsafe fn stop_others() {}
+
+    /// Reads MPIDR_EL1, which identifies the current CPU by its affinity levels
+    fn id() -> u64 {
+        let mpidr: u64;
+        unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack)) };
+        mpidr
+    }
+
+    /// Reads the stack pointer (sp)
+    #[inline(always)]
+    fn read_stack_pointer() -> usize {
+        let sp: usize;
+        unsafe { asm!("mov {}, sp", out(reg) sp, options(nomem, nostack)) };
+        sp
+    }
+
+    /// Reads the frame pointer (x29) of the calling function
+    #[inline(always)]
+    fn read_frame_pointer() -> usize {
+        let fp: usize;
+        unsafe { asm!("mov {}, x29", out(reg) fp, options(nomem, nostack)) };
+        fp
+    }
 }
 
-pub fn init() {}
+impl Mmio for Arm64 {
+    fn read_barrier() {
+        unsafe { asm!("dsb ld", options(nostack)) };
+    }
+
+    fn write_barrier() {
+        unsafe { asm!("dsb st", options(nostack)) };
+    }
+
+    fn barrier() {
+        unsafe { asm!("dsb sy", options(nostack)) };
+    }
+}
@@ -0,0 +1,248 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// inspects & extends the active translation tables, assumes the 4 KiB granule with 4 levels
+// main source: ARM Architecture Reference Manual, D8 "The AArch64 Virtual Memory System Architecture"
+
+use super::Arm64;
+use crate::arch::{Paging, PagingError};
+use crate::bitfield;
+use crate::memman::{frame, map};
+use core::arch::asm;
+
+/// Bits of TTBR0_EL1 & TTBR1_EL1 holding the physical address of the level 0 table
+const TTBR_ADDRESS_MASK: u64 = 0x0000_FFFF_FFFF_F000;
+/// MAIR attribute encoding of Device-nGnRnE memory
+const MAIR_DEVICE_NGNRNE: u64 = 0x00;
+
+bitfield! {
+    /// A descriptor of a translation table at any level
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    struct Descriptor(u64) {
+        bool, valid, set_valid: 0;
+        /// Set for a table (levels 0-2) or a page (level 3), unset for a block (levels 1-2)
+        bool, table_or_page, set_table_or_page: 1;
+        /// Index of the memory attribute in MAIR_EL1
+        u8, attribute, set_attribute: 4, 2;
+        /// Access flag, unset pages fault on first access
+        bool, accessed, set_accessed: 10;
+        /// Frame number of the next table, the block or the page
+        u64, frame, set_frame: 47, 12;
+        /// Privileged execute never
+        bool, privileged_no_execute, set_privileged_no_execute: 53;
+        /// Unprivileged execute never
+        bool, user_no_execute, set_user_no_execute: 54;
+    }
+}
+
+impl Descriptor {
+    /// Physical address of the next table, the block or the page
+    const fn address(&self) -> usize {
+        (self.frame() << 12) as usize
+    }
+
+    /// Points the descriptor to a page-aligned physical address
+    const fn set_address(&mut self, address: usize) {
+        self.set_frame(address as u64 >> 12);
+    }
+
+    /// Whether the descriptor points to the next level table, level 3 descriptors never do
+    const fn is_table(&self, level: usize) -> bool {
+        level < 3 && self.valid() && self.table_or_page()
+    }
+
+    unsafe fn read(descriptor: *const u64) -> Self {
+        Self::from_raw(descriptor.read_volatile())
+    }
+
+    unsafe fn write(self, descriptor: *mut u64) {
+        descriptor.write_volatile(self.raw());
+    }
+}
+
+bitfield! {
+    /// TCR_EL1, controls the translation of both halves of the address space
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    struct TranslationControl(u64) {
+        /// Size offset of the lower half (TTBR0), which covers 2^(64 - T0SZ) bytes
+        u8, t0sz, _: 5, 0;
+        /// Disables walks of TTBR0
+        bool, epd0, set_epd0: 7;
+        /// Size offset of the higher half (TTBR1)
+        u8, t1sz, _: 21, 16;
+        /// Granule of the higher half
+        u8, tg1, _: 31, 30;
+    }
+}
+
+/// `TranslationControl::tg1()` of the 4 KiB granule
+const TCR_TG1_4K: u8 = 0b10;
+/// `TranslationControl::t1sz()` with 48 bit addresses, the only size using exactly 4 levels
+const TCR_T1SZ_48BIT: u8 = 16;
+
+/// Checks that the translation regime set up by limine is the one handled here and stops
+/// translating the lower half (TTBR0). The kernel, the HHDM and the stack all live in the higher
+/// half, the lower half is left for user space.
+///
+/// The kernel does not build its own tables, it keeps using the TTBR1 tables of limine and only
+/// extends them (see `map_device()`), which is why `reclaim` keeps their frames.
+pub fn init() {
+    let raw: u64;
+    unsafe { asm!("mrs {}, tcr_el1", out(reg) raw, options(nomem, nostack)) };
+    let mut tcr = TranslationControl::from_raw(raw);
+    if tcr.tg1() != TCR_TG1_4K || tcr.t1sz() != TCR_T1SZ_48BIT {
+        panic!(
+            "Unsupported translation regime, TCR_EL1 = {:?} (expected a 4 KiB granule with 48 bit addresses)",
+            tcr
+        );
+    }
+    tcr.set_epd0(true);
+    unsafe {
+        asm!(
+            "msr tcr_el1, {}",
+            "isb",
+            "tlbi vmalle1",
+            "dsb ish",
+            "isb",
+            in(reg) tcr.raw(),
+            options(nostack)
+        )
+    };
+}
+
+/// Index of the Device-nGnRnE attribute in MAIR_EL1. Limine does not use every one of the 8
+/// attributes and unused ones are zero, which is this exact encoding.
+fn device_attribute() -> Option<u8> {
+    let mair: u64;
+    unsafe { asm!("mrs {}, mair_el1", out(reg) mair, options(nomem, nostack)) };
+    (0..8).find(|i| (mair >> (i * 8)) & 0xFF == MAIR_DEVICE_NGNRNE)
+}
+
+/// Walks the TTBR1 tables towards the level 3 descriptor of `virt`, creating missing tables if
+/// `create`. Returns the last descriptor reached & its level: the level 3 descriptor, a block or
+/// an invalid descriptor.
+unsafe fn find_descriptor(virt: usize, create: bool) -> Result<(*mut u64, usize), PagingError> {
+    let ttbr1: u64;
+    asm!("mrs {}, ttbr1_el1", out(reg) ttbr1, options(nomem, nostack));
+    let offset = map::physical_offset();
+    let mut table = (ttbr1 & TTBR_ADDRESS_MASK) as usize;
+    for level in 0..3 {
+        let index = (virt >> (39 - 9 * level)) & 0x1FF;
+        let descriptor = (table + offset + index * 8) as *mut u64;
+        let current = Descriptor::read(descriptor);
+        match (current.valid(), current.table_or_page()) {
+            (true, true) => {}
+            // a block
+            (true, false) => return Ok((descriptor, level)),
+            _ if !create => return Ok((descriptor, level)),
+            _ => {
+                let frame = frame::claim_zeroed_frames(1).ok_or(PagingError::OutOfMemory)?;
+                let new_table = frame.region().0;
+                // translation tables are never freed
+                core::mem::forget(frame);
+                // the zeroes must be visible to the walker before the table is
+                asm!("dsb ishst", options(nostack));
+                let mut next = Descriptor::from_raw(0);
+                next.set_valid(true);
+                next.set_table_or_page(true);
+                next.set_address(new_table);
+                next.write(descriptor);
+            }
+        }
+        table = Descriptor::read(descriptor).address();
+    }
+    let index = (virt >> 12) & 0x1FF;
+    Ok(((table + offset + index * 8) as *mut u64, 3))
+}
+
+impl Paging for Arm64 {
+    const PAGE_SIZE: usize = 4096;
+
+    /// Walks both TTBR0 & TTBR1
+    fn for_each_table_frame(physical_offset: usize, f: &mut dyn FnMut(usize)) {
+        let (ttbr0, ttbr1): (u64, u64);
+        unsafe {
+            asm!("mrs {}, ttbr0_el1", out(reg) ttbr0, options(nomem, nostack));
+            asm!("mrs {}, ttbr1_el1", out(reg) ttbr1, options(nomem, nostack));
+        }
+        for root in [ttbr0, ttbr1] {
+            walk((root & TTBR_ADDRESS_MASK) as usize, 0, physical_offset, f);
+        }
+    }
+
+    /// Only translates higher half addresses, the lower half is not used by the kernel
+    fn translate(virt: usize) -> Option<usize> {
+        let (descriptor, level) = unsafe { find_descriptor(virt, false) }.ok()?;
+        let descriptor = unsafe { Descriptor::read(descriptor) };
+        if !descriptor.valid() {
+            return None;
+        }
+        // a level n block covers 4 KiB << 9 * (3 - n)
+        let size = Self::PAGE_SIZE << (9 * (3 - level));
+        let base = descriptor.address() & !(size - 1);
+        Some(base + virt % size)
+    }
+
+    fn map_device(region: (usize, usize)) -> Result<(), PagingError> {
+        let attribute = device_attribute().ok_or(PagingError::NoDeviceAttribute)?;
+        let offset = map::physical_offset();
+        let start = region.0 - region.0 % Self::PAGE_SIZE;
+        for page in (start..region.1).step_by(Self::PAGE_SIZE) {
+            unsafe {
+                let (descriptor, _) = find_descriptor(page + offset, true)?;
+                if !Descriptor::read(descriptor).valid() {
+                    let mut device = Descriptor::from_raw(0);
+                    device.set_valid(true);
+                    device.set_table_or_page(true);
+                    device.set_attribute(attribute);
+                    device.set_accessed(true);
+                    device.set_privileged_no_execute(true);
+                    device.set_user_no_execute(true);
+                    device.set_address(page);
+                    device.write(descriptor);
+                }
+            }
+        }
+        // make the new descriptors visible to the table walker
+        unsafe { asm!("dsb ishst", "isb", options(nostack)) };
+        Ok(())
+    }
+
+    fn flush(virt: usize) {
+        let page = (virt >> 12) as u64 & 0xFFF_FFFF_FFFF;
+        unsafe {
+            asm!("dsb ishst", "tlbi vaae1is, {}", "dsb ish", "isb", in(reg) page, options(nostack))
+        };
+    }
+
+    fn flush_all() {
+        unsafe {
+            asm!(
+                "dsb ishst",
+                "tlbi vmalle1is",
+                "dsb ish",
+                "isb",
+                options(nostack)
+            )
+        };
+    }
+}
+
+fn walk(table: usize, level: usize, physical_offset: usize, f: &mut dyn FnMut(usize)) {
+    f(table);
+    // level 3 descriptors point to pages, not tables
+    if level == 3 {
+        return;
+    }
+    let descriptors = (table + physical_offset) as *const u64;
+    for i in 0..Arm64::PAGE_SIZE / 8 {
+        let descriptor = unsafe { Descriptor::read(descriptors.add(i)) };
+        if !descriptor.is_table(level) {
+            continue;
+        }
+        walk(descriptor.address(), level + 1, physical_offset, f);
+    }
+}
@@ -0,0 +1,143 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// power control through the firmware, using SMC calling convention function IDs
+// main source: ARM Power State Coordination Interface (DEN 0022)
+
+use crate::fdt::Fdt;
+use core::arch::asm;
+use core::fmt;
+use spin::Once;
+
+const PSCI_VERSION: u32 = 0x8400_0000;
+const CPU_OFF: u32 = 0x8400_0002;
+const CPU_ON: u32 = 0xC400_0003;
+const SYSTEM_OFF: u32 = 0x8400_0008;
+const SYSTEM_RESET: u32 = 0x8400_0009;
+
+const COMPATIBLE: &[&str] = &["arm,psci-1.0", "arm,psci-0.2"];
+
+/// Instruction used to call the firmware, from the `method` property
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Conduit {
+    Smc,
+    Hvc,
+}
+
+static CONDUIT: Once<Conduit> = Once::new();
+
+/// Error returned by PSCI calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciError {
+    /// `init()` found no PSCI node in the device tree
+    Unavailable,
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    Unknown(i64),
+}
+
+impl From<i64> for PsciError {
+    fn from(code: i64) -> Self {
+        match code {
+            -1 => Self::NotSupported,
+            -2 => Self::InvalidParameters,
+            -3 => Self::Denied,
+            -4 => Self::AlreadyOn,
+            -5 => Self::OnPending,
+            -6 => Self::InternalFailure,
+            -7 => Self::NotPresent,
+            -8 => Self::Disabled,
+            -9 => Self::InvalidAddress,
+            _ => Self::Unknown(code),
+        }
+    }
+}
+
+impl fmt::Display for PsciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable => write!(f, "PSCI is not available"),
+            Self::Unknown(code) => write!(f, "PSCI error {}", code),
+            e => write!(f, "PSCI error {:?}", e),
+        }
+    }
+}
+
+/// Calls the firmware, returns x0
+unsafe fn call(function: u32, arg1: u64, arg2: u64, arg3: u64) -> Result<i64, PsciError> {
+    let mut x0 = function as u64;
+    match CONDUIT.get().ok_or(PsciError::Unavailable)? {
+        // `smc #0`, which the assembler only accepts with the el3 target feature
+        Conduit::Smc => asm!(
+            ".inst 0xd4000003",
+            inout("x0") x0, inout("x1") arg1 => _, inout("x2") arg2 => _, inout("x3") arg3 => _,
+            options(nostack)
+        ),
+        Conduit::Hvc => asm!(
+            "hvc #0",
+            inout("x0") x0, inout("x1") arg1 => _, inout("x2") arg2 => _, inout("x3") arg3 => _,
+            options(nostack)
+        ),
+    }
+    match x0 as i64 {
+        code if code < 0 => Err(PsciError::from(code)),
+        value => Ok(value),
+    }
+}
+
+/// Reads the conduit from the device tree, PSCI 0.2 or newer is required
+pub fn init(fdt: &Fdt) -> Result<(), PsciError> {
+    let node = fdt
+        .find_compatible(COMPATIBLE)
+        .ok_or(PsciError::Unavailable)?;
+    let conduit = match node.property_str("method") {
+        Some("smc") => Conduit::Smc,
+        Some("hvc") => Conduit::Hvc,
+        _ => return Err(PsciError::Unavailable),
+    };
+    CONDUIT.call_once(|| conduit);
+    Ok(())
+}
+
+/// PSCI version as (major, minor)
+pub fn version() -> Result<(u16, u16), PsciError> {
+    let version = unsafe { call(PSCI_VERSION, 0, 0, 0)? };
+    Ok(((version >> 16) as u16, version as u16))
+}
+
+/// Starts the CPU with MPIDR `target` at the physical address `entry`, with `context` in x0.
+/// The CPU starts at EL1 with the MMU off.
+pub unsafe fn cpu_on(target: u64, entry: usize, context: u64) -> Result<(), PsciError> {
+    call(CPU_ON, target, entry as u64, context).map(|_| ())
+}
+
+/// Powers down the current CPU, only returns on failure
+pub unsafe fn cpu_off() -> PsciError {
+    call(CPU_OFF, 0, 0, 0)
+        .err()
+        .unwrap_or(PsciError::Unknown(0))
+}
+
+/// Powers off the machine, only returns on failure
+pub fn system_off() -> PsciError {
+    unsafe { call(SYSTEM_OFF, 0, 0, 0) }
+        .err()
+        .unwrap_or(PsciError::Unknown(0))
+}
+
+/// Resets the machine, only returns on failure
+pub fn system_reset() -> PsciError {
+    unsafe { call(SYSTEM_RESET, 0, 0, 0) }
+        .err()
+        .unwrap_or(PsciError::Unknown(0))
+}
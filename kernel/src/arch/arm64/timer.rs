@@ -0,0 +1,70 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// periodic tick from the EL1 virtual timer of the generic timer
+// main source: ARM Architecture Reference Manual, D11 "The Generic Timer in AArch64 state"
+
+use super::gic::{self, GicError};
+use crate::fdt::Fdt;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Ticks per second
+pub const TICK_HZ: u64 = 100;
+/// PPI of the virtual timer when the device tree does not list it, same on every ARM platform
+const VIRTUAL_TIMER_INTID: u32 = 27;
+/// CNTV_CTL_EL0.ENABLE, IMASK stays clear
+const CNTV_CTL_ENABLE: u64 = 1;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Frequency of the system counter in Hz
+pub fn frequency() -> u64 {
+    let frequency: u64;
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) frequency, options(nomem, nostack)) };
+    frequency
+}
+
+/// Current value of the virtual counter
+pub fn counter() -> u64 {
+    let counter: u64;
+    unsafe { asm!("isb", "mrs {}, cntvct_el0", out(reg) counter, options(nomem, nostack)) };
+    counter
+}
+
+/// Ticks since `init()`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Fires the timer again in one tick
+fn arm() {
+    let interval = frequency() / TICK_HZ;
+    unsafe { asm!("msr cntv_tval_el0, {}", in(reg) interval, options(nomem, nostack)) };
+}
+
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    arm();
+}
+
+/// The virtual timer interrupt, the third one of the `arm,armv8-timer` node
+fn interrupt(fdt: &Fdt) -> u32 {
+    fdt.find_compatible(&["arm,armv8-timer"])
+        .and_then(|node| node.property("interrupts"))
+        // each interrupt is (type, number, flags), PPI numbers start at interrupt ID 16
+        .and_then(|interrupts| interrupts.get(2 * 12 + 4..2 * 12 + 8))
+        .map(|number| u32::from_be_bytes(number.try_into().unwrap()) + 16)
+        .unwrap_or(VIRTUAL_TIMER_INTID)
+}
+
+/// Starts ticking at `TICK_HZ`, the interrupt controller must be initialized
+pub fn init(fdt: &Fdt) -> Result<(), GicError> {
+    gic::register(interrupt(fdt), tick)?;
+    arm();
+    unsafe { asm!("msr cntv_ctl_el0, {}", "isb", in(reg) CNTV_CTL_ENABLE, options(nostack)) };
+    Ok(())
+}
@@ -4,17 +4,202 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+//! Every port implements the traits below on a marker type, which is exported as `Current`.
+//! Portable code only goes through the traits, so both ports have to provide the same
+//! operations with the same signatures. Code that only makes sense on one architecture, like
+//! drivers using x86 I/O ports, uses the port module (`arch::amd64` or `arch::arm64`) directly.
+
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ArchType {
     X86_64,
     AArch64,
 }
 
+/// An architecture port
+pub trait Arch: Cpu + Paging + Mmio {
+    const TYPE: ArchType;
+    /// Saved state of a thread of execution, see `Context`
+    type Context: Context;
+
+    /// Sets up the current CPU: descriptor tables, exception vectors & such.
+    /// Called early in `kmain`, before any memory is claimed.
+    fn init();
+
+    /// Sets up the interrupt controller & the timer, then enables interrupts.
+    /// Needs the global memory map, as their registers are claimed.
+    fn init_devices();
+}
+
+/// Control over the current CPU
+pub trait Cpu {
+    /// Waits for the next interrupt
+    ///
+    /// # Safety
+    /// Never returns if interrupts are disabled
+    unsafe fn halt();
+
+    /// Masks maskable interrupts on the current CPU
+    fn disable_interrupts();
+
+    /// Unmasks maskable interrupts on the current CPU
+    fn enable_interrupts();
+
+    fn interrupts_enabled() -> bool;
+
+    /// Stops every CPU except the current one, used on panic
+    ///
+    /// # Safety
+    /// The other CPUs are stopped wherever they are, e.g. while holding a lock. Only the current
+    /// CPU may run afterwards.
+    unsafe fn stop_others();
+
+    /// Identifies the current CPU: the local APIC ID on amd64, MPIDR_EL1 on arm64
+    fn id() -> u64;
+
+    /// Reads the stack pointer, must be inlined into the caller
+    fn read_stack_pointer() -> usize;
+
+    /// Reads the frame pointer of the calling function, must be inlined into the caller
+    fn read_frame_pointer() -> usize;
+}
+
+/// Error returned by `Paging::map_device()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// No frame was left for a new page table
+    OutOfMemory,
+    /// The page tables can not express device memory (arm64: no Device-nGnRnE attribute in MAIR)
+    NoDeviceAttribute,
+}
+
+impl fmt::Display for PagingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfMemory => write!(f, "out of memory for page tables"),
+            Self::NoDeviceAttribute => write!(f, "no memory attribute for device memory"),
+        }
+    }
+}
+
+/// Operations on the active page tables
+pub trait Paging {
+    /// Size of the smallest page and of every page table
+    const PAGE_SIZE: usize;
+
+    /// Calls `f` with the physical address of every page table in use, including the root ones.
+    ///
+    /// `physical_offset` is the virtual address at which physical memory is mapped (the HHDM).
+    fn for_each_table_frame(physical_offset: usize, f: &mut dyn FnMut(usize));
+
+    /// Physical address `virt` is mapped to, if it is mapped
+    fn translate(virt: usize) -> Option<usize>;
+
+    /// Maps the physical `region` (start, end) into the HHDM as uncached device memory, so MMIO
+    /// registers can be reached through `MapArea`s. Pages that are already mapped are left as
+    /// they are.
+    fn map_device(region: (usize, usize)) -> Result<(), PagingError>;
+
+    /// Removes the translation of `virt` from the TLB of every CPU
+    fn flush(virt: usize);
+
+    /// Removes every non-global translation from the TLB of every CPU
+    fn flush_all();
+}
+
+/// Ordering of memory accesses, e.g. between filling a DMA buffer & ringing a doorbell register
+pub trait Mmio {
+    /// Earlier reads complete before later reads
+    fn read_barrier();
+
+    /// Earlier writes complete before later writes
+    fn write_barrier();
+
+    /// Earlier reads & writes complete before later ones
+    fn barrier();
+}
+
+/// Callee-saved state of a thread of execution, switched by `switch()`.
+/// The default context holds nothing, it is only a place to save the current state into.
+pub trait Context: Sized + Default {
+    /// A context that starts executing `entry` on the stack ending at `stack_top`, which must be
+    /// aligned to 16 bytes
+    ///
+    /// # Safety
+    /// The stack must be valid & unused for as long as the context runs
+    unsafe fn new(entry: extern "C" fn() -> !, stack_top: usize) -> Self;
+
+    /// Saves the current state into `from` and resumes `to`. Returns once `from` is resumed.
+    ///
+    /// # Safety
+    /// `to` must have been created by `new()` or saved by `switch()`, and not have been resumed
+    /// since
+    unsafe fn switch(from: &mut Self, to: &Self);
+}
+
 #[cfg(target_arch = "x86_64")]
-mod amd64;
+pub mod amd64;
+/// The architecture the kernel is built for
 #[cfg(target_arch = "x86_64")]
-pub use amd64::*;
+pub type Current = amd64::Amd64;
 
 #[cfg(target_arch = "aarch64")]
-mod arm64;
+pub mod arm64;
+/// The architecture the kernel is built for
 #[cfg(target_arch = "aarch64")]
-pub use arm64::*;
+pub type Current = arm64::Arm64;
+
+/// `Paging::PAGE_SIZE` of the current architecture
+pub const PAGE_SIZE: usize = <Current as Paging>::PAGE_SIZE;
+
+/// Runs `f` with interrupts disabled on the current CPU, e.g. to take a lock that an interrupt
+/// handler takes as well. Restores the previous state afterwards.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = Current::interrupts_enabled();
+    Current::disable_interrupts();
+    let result = f();
+    if enabled {
+        Current::enable_interrupts();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+    use alloc::vec;
+    use core::ptr;
+    use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+    type TestContext = <Current as Arch>::Context;
+
+    static MAIN: AtomicPtr<TestContext> = AtomicPtr::new(ptr::null_mut());
+    static THREAD: AtomicPtr<TestContext> = AtomicPtr::new(ptr::null_mut());
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn thread() -> ! {
+        RAN.store(true, Ordering::SeqCst);
+        unsafe {
+            Context::switch(
+                &mut *THREAD.load(Ordering::SeqCst),
+                &*MAIN.load(Ordering::SeqCst),
+            )
+        };
+        unreachable!("the test thread was resumed");
+    }
+
+    unit_test! {
+        fn switch_context() {
+            let stack = vec![0u128; 1024];
+            let stack_top = stack.as_ptr_range().end as usize;
+            let mut main = TestContext::default();
+            let mut other = unsafe { TestContext::new(thread, stack_top) };
+            MAIN.store(&mut main, Ordering::SeqCst);
+            THREAD.store(&mut other, Ordering::SeqCst);
+            unsafe { Context::switch(&mut *MAIN.load(Ordering::SeqCst), &*THREAD.load(Ordering::SeqCst)) };
+            assert!(RAN.load(Ordering::SeqCst));
+        }
+    }
+}
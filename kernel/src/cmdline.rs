@@ -0,0 +1,256 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Kernel command line parsing.
+//!
+//! The command line is a list of options separated by whitespace, in the form `name=value`.
+//! Values containing whitespace can be quoted: `name="some value"`. A bare `name` is the same as
+//! `name=true`.
+//!
+//! Subsystems declare their options as `Param` statics and list them in a `PARAMS` slice, which
+//! `kmain` passes to `apply()`. Options nobody declared are returned as rejected.
+
+use alloc::vec::Vec;
+use core::fmt;
+use spin::once::Once;
+
+/// Implemented by every type a `Param` can hold
+pub trait FromParam: Sized {
+    /// Parses a value from the command line, the error describes the expected format
+    fn from_param(value: &'static str) -> Result<Self, &'static str>;
+}
+
+/// A typed command line option, declared by the subsystem that uses it
+pub struct Param<T: 'static> {
+    name: &'static str,
+    help: &'static str,
+    default: T,
+    value: Once<T>,
+}
+
+impl<T: FromParam + Copy> Param<T> {
+    pub const fn new(name: &'static str, help: &'static str, default: T) -> Self {
+        Self {
+            name,
+            help,
+            default,
+            value: Once::new(),
+        }
+    }
+
+    /// Returns the value from the command line, or the default if it was not given
+    pub fn get(&self) -> T {
+        *self.value.get().unwrap_or(&self.default)
+    }
+}
+
+/// Type erased `Param`, so options of different types can be registered together
+pub trait AnyParam: Sync {
+    fn name(&self) -> &'static str;
+    fn help(&self) -> &'static str;
+    /// Parses and stores the value, fails if it is invalid or was already set
+    fn set(&self, value: &'static str) -> Result<(), &'static str>;
+}
+
+impl<T: FromParam + Copy + Send + Sync> AnyParam for Param<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn set(&self, value: &'static str) -> Result<(), &'static str> {
+        if self.value.is_completed() {
+            return Err("given more than once");
+        }
+        let value = T::from_param(value)?;
+        self.value.call_once(|| value);
+        Ok(())
+    }
+}
+
+/// Iterates over the `(name, value)` pairs of a command line
+pub struct Options {
+    rest: &'static str,
+}
+
+/// Splits a command line into options
+pub fn options(cmdline: &'static str) -> Options {
+    Options { rest: cmdline }
+}
+
+impl Iterator for Options {
+    type Item = (&'static str, &'static str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.trim_start();
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+        let name_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        let rest = &rest[name_end..];
+        let Some(rest) = rest.strip_prefix('=') else {
+            self.rest = rest;
+            return Some((name, "true"));
+        };
+        let (value, rest) = match rest.strip_prefix('"') {
+            // an unterminated quote takes the rest of the line
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => {
+                let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            }
+        };
+        self.rest = rest;
+        Some((name, value))
+    }
+}
+
+/// An option that `apply()` could not set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rejected {
+    pub name: &'static str,
+    pub value: &'static str,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}: {}", self.name, self.value, self.reason)
+    }
+}
+
+/// Sets the registered `params` from the command line. Returns the unknown, invalid or repeated
+/// options, which are left unapplied.
+pub fn apply(cmdline: &'static str, params: &[&[&dyn AnyParam]]) -> Vec<Rejected> {
+    let mut rejected = Vec::new();
+    for (name, value) in options(cmdline) {
+        let param = params
+            .iter()
+            .flat_map(|p| p.iter())
+            .find(|p| p.name() == name);
+        let result = match param {
+            Some(param) => param.set(value),
+            None => Err("unknown option"),
+        };
+        if let Err(reason) = result {
+            rejected.push(Rejected {
+                name,
+                value,
+                reason,
+            });
+        }
+    }
+    rejected
+}
+
+/// Lists the registered `params` with their description
+pub struct Help<'a>(pub &'a [&'a [&'a dyn AnyParam]]);
+
+impl fmt::Display for Help<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for param in self.0.iter().flat_map(|p| p.iter()) {
+            writeln!(f, "{}: {}", param.name(), param.help())?;
+        }
+        Ok(())
+    }
+}
+
+// Value types
+
+impl FromParam for bool {
+    fn from_param(value: &'static str) -> Result<Self, &'static str> {
+        match value {
+            "true" | "yes" | "on" | "1" => Ok(true),
+            "false" | "no" | "off" | "0" => Ok(false),
+            _ => Err("expected true or false"),
+        }
+    }
+}
+
+impl FromParam for &'static str {
+    fn from_param(value: &'static str) -> Result<Self, &'static str> {
+        Ok(value)
+    }
+}
+
+/// Implements `FromParam` for integers, in decimal or hexadecimal with a `0x` prefix
+macro_rules! integer_param {
+    ($($typ:ty),*) => {
+        $(
+            impl FromParam for $typ {
+                fn from_param(value: &'static str) -> Result<Self, &'static str> {
+                    let parsed = match value.strip_prefix("0x") {
+                        Some(hex) => <$typ>::from_str_radix(hex, 16),
+                        None => value.parse(),
+                    };
+                    parsed.map_err(|_| concat!("expected an integer (", stringify!($typ), ")"))
+                }
+            }
+        )*
+    };
+}
+
+integer_param!(u8, u16, u32, u64, usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    unit_test! {
+        fn split_options() {
+            let opts: Vec<_> = options("  a=1 flag  b=\"x y\" c= d=\"open").collect();
+            assert_eq!(
+                opts,
+                [("a", "1"), ("flag", "true"), ("b", "x y"), ("c", ""), ("d", "open")]
+            );
+        }
+
+        fn typed_values() {
+            assert_eq!(u16::from_param("0x3F8"), Ok(0x3F8));
+            assert_eq!(u32::from_param("115200"), Ok(115200));
+            assert!(u8::from_param("256").is_err());
+            assert_eq!(bool::from_param("off"), Ok(false));
+            assert!(bool::from_param("maybe").is_err());
+        }
+
+        fn apply_params() {
+            static NUMBER: Param<u32> = Param::new("test.number", "a number", 7);
+            static FLAG: Param<bool> = Param::new("test.flag", "a flag", false);
+            static NAME: Param<&str> = Param::new("test.name", "a name", "none");
+            static PARAMS: &[&dyn AnyParam] = &[&NUMBER, &FLAG, &NAME];
+
+            assert_eq!(NUMBER.get(), 7);
+            // unknown, invalid & repeated options are rejected
+            let rejected = apply(
+                "test.flag test.number=x test.number=12 test.number=13 other=1",
+                &[PARAMS],
+            );
+            let reasons: Vec<_> = rejected.iter().map(|r| (r.name, r.reason)).collect();
+            assert_eq!(
+                reasons,
+                [
+                    ("test.number", "expected an integer (u32)"),
+                    ("test.number", "given more than once"),
+                    ("other", "unknown option"),
+                ]
+            );
+            assert_eq!(NUMBER.get(), 12);
+            assert!(FLAG.get());
+            assert_eq!(NAME.get(), "none");
+        }
+    }
+}
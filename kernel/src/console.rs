@@ -0,0 +1,108 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! The kernel console. Input drivers push key events from their interrupt handlers into a
+//! bounded queue, which is read with `read_key()` & `read_char()`. Serial input is polled by
+//! `read_char()` as well. Output goes to serial & the framebuffer console, but not to the log.
+
+use crate::arch;
+use crate::cmdline::{AnyParam, Param};
+use crate::driver::keyboard::{keymap, KeyEvent, Keymap};
+use crate::driver::{fbcon, serial};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+pub static KEYMAP: Param<&Keymap> = Param::new(
+    "console.keymap",
+    "keyboard layout of the console: us, de, cz",
+    &keymap::US,
+);
+
+/// Command line options of the console
+pub static PARAMS: &[&dyn AnyParam] = &[&KEYMAP];
+
+/// Key events that were not read yet, further events are dropped
+const QUEUE_SIZE: usize = 64;
+
+struct Queue {
+    events: [Option<KeyEvent>; QUEUE_SIZE],
+    /// index of the oldest event
+    head: usize,
+    len: usize,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    events: [None; QUEUE_SIZE],
+    head: 0,
+    len: 0,
+});
+
+/// Events dropped because the queue was full
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Queues a key event, called by input drivers from their interrupt handlers
+pub fn push_key(event: KeyEvent) {
+    arch::without_interrupts(|| {
+        let mut queue = QUEUE.lock();
+        if queue.len == QUEUE_SIZE {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let tail = (queue.head + queue.len) % QUEUE_SIZE;
+        queue.events[tail] = Some(event);
+        queue.len += 1;
+    })
+}
+
+/// Takes the oldest queued key event
+pub fn read_key() -> Option<KeyEvent> {
+    arch::without_interrupts(|| {
+        let mut queue = QUEUE.lock();
+        if queue.len == 0 {
+            return None;
+        }
+        let head = queue.head;
+        queue.head = (head + 1) % QUEUE_SIZE;
+        queue.len -= 1;
+        queue.events[head].take()
+    })
+}
+
+/// Takes a character typed on serial or, with the `console.keymap` layout, on a keyboard.
+/// Serial sends carriage returns for enter & DEL for backspace, they are turned into '\n' & '\x08'.
+pub fn read_char() -> Option<char> {
+    if let Some(byte) = serial::read() {
+        return Some(match byte {
+            b'\r' => '\n',
+            0x7F => '\x08',
+            _ => byte as char,
+        });
+    }
+    let keymap = KEYMAP.get();
+    core::iter::from_fn(read_key).find_map(|event| event.char(keymap))
+}
+
+/// Writes to serial & the framebuffer console
+pub fn write(text: &str) {
+    serial::write(text);
+    fbcon::write(text);
+}
+
+/// `fmt::Write` for the console outputs, see `write()`
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write(s);
+        Ok(())
+    }
+}
+
+/// Number of key events dropped because nobody read them
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
@@ -0,0 +1,556 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! AHCI SATA controllers: every port with a disk behind it is added as a `BlockDevice` named
+//! sda, sdb, ... ATAPI drives & port multipliers are not supported.
+//!
+//! Every port has a command list of up to 32 slots, each pointing to a command table that holds
+//! the command FIS and the physical region of the data. Transfers use the 48 bit LBA DMA
+//! commands and bounce the data through a `DmaBuffer`. No interrupts are used, finished commands
+//! are polled in the command issue register.
+//!
+//! main sources: https://wiki.osdev.org/AHCI, Serial ATA AHCI 1.3.1 Specification
+
+use crate::arch::{Current, Mmio, PAGE_SIZE};
+use crate::driver::block::{self, BlockDevice, BlockError, BlockFuture, DmaBuffer};
+use crate::driver::pci::bus::{COMMAND_BUS_MASTER, COMMAND_MEMORY};
+use crate::driver::pci::{self, Function, Match, PciDriver, PciError};
+use crate::driver::{DeviceId, DriverError};
+use crate::log::LogLevel;
+use crate::memman::frame;
+use crate::memman::map::{self, MapArea};
+use crate::memman::stats::Size;
+use crate::{log, log_at};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// BAR with the registers (ABAR)
+const REGISTERS_BAR: usize = 5;
+
+// generic host control
+const HOST_CAPABILITIES: usize = 0x00;
+const GLOBAL_CONTROL: usize = 0x04;
+const PORTS_IMPLEMENTED: usize = 0x0C;
+/// The controller reaches 64 bit addresses
+const CAPABILITY_64BIT: u32 = 1 << 31;
+const GLOBAL_AHCI_ENABLE: u32 = 1 << 31;
+
+const PORTS: usize = 32;
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+// port registers, relative to the port
+const PORT_COMMAND_LIST: usize = 0x00;
+const PORT_FIS_BASE: usize = 0x08;
+const PORT_INTERRUPT_STATUS: usize = 0x10;
+const PORT_INTERRUPT_ENABLE: usize = 0x14;
+const PORT_COMMAND: usize = 0x18;
+const PORT_TASK_FILE: usize = 0x20;
+const PORT_SIGNATURE: usize = 0x24;
+const PORT_SATA_STATUS: usize = 0x28;
+const PORT_SATA_ERROR: usize = 0x30;
+const PORT_COMMAND_ISSUE: usize = 0x38;
+
+const COMMAND_START: u32 = 1 << 0;
+const COMMAND_FIS_RECEIVE: u32 = 1 << 4;
+const COMMAND_FIS_RUNNING: u32 = 1 << 14;
+const COMMAND_LIST_RUNNING: u32 = 1 << 15;
+/// A device is present and the link is up, in the low bits of the SATA status
+const DETECTION_PRESENT: u32 = 3;
+const SIGNATURE_DISK: u32 = 0x0000_0101;
+const TASK_FILE_DRQ: u32 = 1 << 3;
+const TASK_FILE_BUSY: u32 = 1 << 7;
+const INTERRUPT_TASK_FILE_ERROR: u32 = 1 << 30;
+
+// memory of a port: the command list, the received FISes & a command table for every slot
+const SLOTS: usize = 32;
+const HEADER_SIZE: usize = 32;
+const FIS_OFFSET: usize = SLOTS * HEADER_SIZE;
+const FIS_SIZE: usize = 256;
+/// Command tables are aligned to 128 bytes, ours hold the command FIS & one region
+const TABLES_OFFSET: usize = FIS_OFFSET + FIS_SIZE;
+const TABLE_SIZE: usize = 256;
+const PORT_MEMORY: usize = TABLES_OFFSET + SLOTS * TABLE_SIZE;
+/// Offset of the physical region descriptor table in a command table
+const REGIONS_OFFSET: usize = 0x80;
+/// Length of a host to device FIS in double words, the low bits of a command header
+const FIS_LENGTH: u32 = 5;
+/// The device is written to, in a command header
+const HEADER_WRITE: u32 = 1 << 6;
+
+const FIS_HOST_TO_DEVICE: u8 = 0x27;
+/// The FIS holds a command, not a device control update
+const FIS_COMMAND: u8 = 0x80;
+/// LBA addressing, in the device register
+const DEVICE_LBA: u8 = 1 << 6;
+
+const ATA_IDENTIFY: u8 = 0xEC;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+/// Size of the IDENTIFY DEVICE data
+const IDENTIFY_SIZE: usize = 512;
+
+/// Most bytes moved by one command, longer transfers are split
+const MAX_TRANSFER: usize = 64 * 1024;
+/// Register polls before giving up on a port
+const TIMEOUT: usize = 1_000_000;
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "ahci",
+    matches: &[Match::class(0x01, 0x06, Some(0x01))],
+    attach,
+};
+
+/// Number of disks added so far, names them
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Error of the controller or a port
+#[derive(Debug)]
+pub enum AhciError {
+    Registers(PciError),
+    /// A port did not start or stop in time
+    Timeout,
+    /// No memory for the command lists was left, or none the controller can reach
+    OutOfMemory,
+    /// The disk only supports 28 bit LBA
+    NoLba48,
+    Identify(BlockError),
+}
+
+impl fmt::Display for AhciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Registers(e) => write!(f, "AHCI registers, {}", e),
+            Self::Timeout => write!(f, "AHCI port timed out"),
+            Self::OutOfMemory => write!(f, "out of memory for an AHCI command list"),
+            Self::NoLba48 => write!(f, "SATA disk does not support 48 bit LBA"),
+            Self::Identify(e) => write!(f, "SATA disk can not be identified, {}", e),
+        }
+    }
+}
+
+/// Geometry of a disk, from IDENTIFY DEVICE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    pub sectors: u64,
+    pub sector_size: usize,
+    pub lba48: bool,
+}
+
+impl Identity {
+    /// Reads the 256 words of IDENTIFY DEVICE
+    pub fn parse(words: &[u16; 256]) -> Self {
+        let lba48 = words[83] & (1 << 10) != 0;
+        let sectors = match lba48 {
+            true => words[100..104]
+                .iter()
+                .rev()
+                .fold(0, |sectors, word| (sectors << 16) | *word as u64),
+            false => ((words[61] as u64) << 16) | words[60] as u64,
+        };
+        // word 106 is valid with bit 14 set & bit 15 clear, bit 12 means long logical sectors
+        let long = words[106] & 0xC000 == 0x4000 && words[106] & (1 << 12) != 0;
+        let sector_size = match long {
+            true => (((words[118] as usize) << 16) | words[117] as usize) * 2,
+            false => 512,
+        };
+        Self {
+            sectors,
+            sector_size,
+            lba48,
+        }
+    }
+}
+
+/// A host to device register FIS with `command` for `count` sectors at `lba`
+pub fn command_fis(command: u8, lba: u64, count: u16) -> [u8; 20] {
+    let lba = lba.to_le_bytes();
+    let count = count.to_le_bytes();
+    let mut fis = [0; 20];
+    fis[..12].copy_from_slice(&[
+        FIS_HOST_TO_DEVICE,
+        FIS_COMMAND,
+        command,
+        0,
+        lba[0],
+        lba[1],
+        lba[2],
+        DEVICE_LBA,
+        lba[3],
+        lba[4],
+        lba[5],
+        0,
+    ]);
+    fis[12..14].copy_from_slice(&count);
+    fis
+}
+
+/// Registers of a controller, shared by its ports
+struct Controller {
+    registers: Mutex<MapArea>,
+    /// Reaches 64 bit addresses
+    wide: bool,
+    /// Slots of every command list
+    slots: usize,
+}
+
+impl Controller {
+    fn read(&self, offset: usize) -> u32 {
+        self.registers.lock().read(offset).unwrap()
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        self.registers.lock().write(offset, value).unwrap();
+    }
+
+    /// Whether `address` is reachable by the controller for `len` bytes
+    fn reaches(&self, address: u64, len: usize) -> bool {
+        self.wide || address + len as u64 <= 1 << 32
+    }
+}
+
+fn attach(_: DeviceId, function: &Function) -> Result<(), DriverError> {
+    let config = pci::config().ok_or(DriverError::NoDevice)?;
+    let registers = pci::map_bar(function.bars[REGISTERS_BAR])
+        .map_err(|e| DriverError::failed(AhciError::Registers(e)))?;
+    function.enable(config, COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    let capabilities: u32 = registers.read(HOST_CAPABILITIES).unwrap();
+    let controller = Arc::new(Controller {
+        registers: Mutex::new(registers),
+        wide: capabilities & CAPABILITY_64BIT != 0,
+        slots: ((capabilities >> 8) & 0x1F) as usize + 1,
+    });
+    let control = controller.read(GLOBAL_CONTROL);
+    controller.write(GLOBAL_CONTROL, control | GLOBAL_AHCI_ENABLE);
+
+    let implemented = controller.read(PORTS_IMPLEMENTED);
+    for port in (0..PORTS).filter(|port| implemented & (1 << port) != 0) {
+        let base = PORT_BASE + port * PORT_SIZE;
+        if controller.read(base + PORT_SATA_STATUS) & 0xF != DETECTION_PRESENT
+            || controller.read(base + PORT_SIGNATURE) != SIGNATURE_DISK
+        {
+            continue;
+        }
+        if let Err(e) = add_disk(&controller, base) {
+            log_at!(LogLevel::Warn, "[WARN] AHCI port {}: {}\n", port, e);
+        }
+    }
+    Ok(())
+}
+
+/// Starts the port at `base`, identifies its disk and adds it
+fn add_disk(controller: &Arc<Controller>, base: usize) -> Result<(), AhciError> {
+    let memory = frame::claim_zeroed_frames(PORT_MEMORY.div_ceil(PAGE_SIZE))
+        .ok_or(AhciError::OutOfMemory)?;
+    if !controller.reaches(memory.region().0 as u64, PORT_MEMORY) {
+        map::free_global(memory);
+        return Err(AhciError::OutOfMemory);
+    }
+    let mut disk = AhciDisk {
+        controller: controller.clone(),
+        base,
+        memory: memory.region().0 as u64,
+        state: Mutex::new(PortState {
+            memory,
+            free: match controller.slots {
+                SLOTS => !0,
+                slots => (1 << slots) - 1,
+            },
+            failed: 0,
+        }),
+        sectors: 0,
+        sector_size: 512,
+    };
+    let identity = match disk.start().and_then(|()| disk.identify()) {
+        Ok(identity) => identity,
+        Err(e) => {
+            disk.release();
+            return Err(e);
+        }
+    };
+    disk.sectors = identity.sectors;
+    disk.sector_size = identity.sector_size;
+
+    let name = block::letter_name("sd", DISKS.fetch_add(1, Ordering::SeqCst));
+    let size = Size(identity.sectors as usize * identity.sector_size);
+    if block::add(&name, Arc::new(disk)).is_ok() {
+        log!("{}: {} sectors ({})\n", name, identity.sectors, size);
+    }
+    Ok(())
+}
+
+struct PortState {
+    /// Command list, received FISes & command tables
+    memory: MapArea,
+    /// Slots without a command, one bit per slot
+    free: u32,
+    /// Slots whose command failed, they were pending when the port was restarted
+    failed: u32,
+}
+
+/// A SATA disk behind a port
+pub struct AhciDisk {
+    controller: Arc<Controller>,
+    /// Offset of the port registers
+    base: usize,
+    /// Physical address of the memory in `PortState`
+    memory: u64,
+    state: Mutex<PortState>,
+    sectors: u64,
+    sector_size: usize,
+}
+
+impl AhciDisk {
+    fn read_port(&self, offset: usize) -> u32 {
+        self.controller.read(self.base + offset)
+    }
+
+    fn write_port(&self, offset: usize, value: u32) {
+        self.controller.write(self.base + offset, value);
+    }
+
+    /// Polls until the port register at `offset` has none of `bits` set
+    fn wait_clear(&self, offset: usize, bits: u32) -> Result<(), AhciError> {
+        for _ in 0..TIMEOUT {
+            if self.read_port(offset) & bits == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(AhciError::Timeout)
+    }
+
+    /// Stops processing the command list & receiving FISes
+    fn stop(&self) -> Result<(), AhciError> {
+        let command = self.read_port(PORT_COMMAND);
+        self.write_port(PORT_COMMAND, command & !COMMAND_START);
+        self.wait_clear(PORT_COMMAND, COMMAND_LIST_RUNNING)?;
+        let command = self.read_port(PORT_COMMAND);
+        self.write_port(PORT_COMMAND, command & !COMMAND_FIS_RECEIVE);
+        self.wait_clear(PORT_COMMAND, COMMAND_FIS_RUNNING)
+    }
+
+    /// (Re)starts the port on its memory, with the errors cleared
+    fn start(&self) -> Result<(), AhciError> {
+        self.stop()?;
+        let memory = self.memory;
+        self.write_port(PORT_COMMAND_LIST, memory as u32);
+        self.write_port(PORT_COMMAND_LIST + 4, (memory >> 32) as u32);
+        let fis = memory + FIS_OFFSET as u64;
+        self.write_port(PORT_FIS_BASE, fis as u32);
+        self.write_port(PORT_FIS_BASE + 4, (fis >> 32) as u32);
+        // both are write-one-to-clear
+        self.write_port(PORT_SATA_ERROR, !0);
+        self.write_port(PORT_INTERRUPT_STATUS, !0);
+        self.write_port(PORT_INTERRUPT_ENABLE, 0);
+        self.wait_clear(PORT_TASK_FILE, TASK_FILE_BUSY | TASK_FILE_DRQ)?;
+        let command = self.read_port(PORT_COMMAND);
+        self.write_port(PORT_COMMAND, command | COMMAND_FIS_RECEIVE);
+        self.write_port(PORT_COMMAND, command | COMMAND_FIS_RECEIVE | COMMAND_START);
+        Ok(())
+    }
+
+    /// Stops the port and gives back its memory
+    fn release(self) {
+        let _ = self.stop();
+        map::free_global(self.state.into_inner().memory);
+    }
+
+    /// Reads the geometry of the disk, which must support 48 bit LBA
+    fn identify(&self) -> Result<Identity, AhciError> {
+        let mut buffer = DmaBuffer::new(IDENTIFY_SIZE).map_err(AhciError::Identify)?;
+        let identify = self.issue(ATA_IDENTIFY, 0, 0, Some((&mut buffer, IDENTIFY_SIZE)));
+        block::block_on(identify).map_err(AhciError::Identify)?;
+        let mut bytes = [0; IDENTIFY_SIZE];
+        buffer.copy_to(0, &mut bytes);
+        let mut words = [0; 256];
+        for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+        let identity = Identity::parse(&words);
+        match identity.lba48 {
+            true => Ok(identity),
+            false => Err(AhciError::NoLba48),
+        }
+    }
+
+    /// Runs the ATA `command` for `count` sectors at `lba`, with the first `len` bytes of the
+    /// buffer of `data` as its data
+    async fn issue(
+        &self,
+        command: u8,
+        lba: u64,
+        count: u16,
+        data: Option<(&mut DmaBuffer, usize)>,
+    ) -> Result<(), BlockError> {
+        if let Some((buffer, len)) = &data {
+            if !self.controller.reaches(buffer.address(), *len) {
+                return Err(BlockError::OutOfMemory);
+            }
+        }
+        let slot = block::poll_until(|| {
+            let mut state = self.state.lock();
+            let slot = state.free.trailing_zeros() as usize;
+            if slot >= SLOTS {
+                return None;
+            }
+            state.free &= !(1 << slot);
+            Some(slot)
+        })
+        .await;
+
+        let mut buffer = None;
+        {
+            let mut state = self.state.lock();
+            let memory = &mut state.memory;
+            let table = TABLES_OFFSET + slot * TABLE_SIZE;
+            memory
+                .write(table, command_fis(command, lba, count))
+                .unwrap();
+            let mut header = FIS_LENGTH;
+            if let Some((data, len)) = data {
+                let region = table + REGIONS_OFFSET;
+                memory.write(region, data.address()).unwrap();
+                memory.write(region + 8, 0u32).unwrap();
+                // the byte count is stored minus one
+                memory.write(region + 12, len as u32 - 1).unwrap();
+                header |= 1 << 16;
+                buffer = Some(data);
+            }
+            if command == ATA_WRITE_DMA_EXT {
+                header |= HEADER_WRITE;
+            }
+            let at = slot * HEADER_SIZE;
+            memory.write(at, header).unwrap();
+            // bytes transferred, updated by the controller
+            memory.write(at + 4, 0u32).unwrap();
+            let table_address = memory.region().0 as u64 + table as u64;
+            memory.write(at + 8, table_address).unwrap();
+            if let Some(buffer) = &mut buffer {
+                buffer.submitted();
+            }
+            // the command table is complete before the controller fetches it
+            Current::barrier();
+            self.write_port(PORT_COMMAND_ISSUE, 1 << slot);
+        }
+
+        let failed = block::poll_until(|| {
+            let mut state = self.state.lock();
+            if self.read_port(PORT_INTERRUPT_STATUS) & INTERRUPT_TASK_FILE_ERROR != 0 {
+                // the port stops on errors, every pending command fails
+                state.failed |= self.read_port(PORT_COMMAND_ISSUE);
+                if let Err(e) = self.start() {
+                    log_at!(LogLevel::Warn, "[WARN] AHCI port restart, {}\n", e);
+                }
+            }
+            if self.read_port(PORT_COMMAND_ISSUE) & (1 << slot) != 0 {
+                return None;
+            }
+            let failed = state.failed & (1 << slot) != 0;
+            state.failed &= !(1 << slot);
+            state.free |= 1 << slot;
+            Some(failed)
+        })
+        .await;
+        if let Some(buffer) = buffer {
+            buffer.completed();
+        }
+        match failed {
+            true => Err(BlockError::Io),
+            false => Ok(()),
+        }
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check(self, sector, buffer.len(), false)?;
+            let mut sector = sector;
+            let chunk_size = MAX_TRANSFER.max(self.sector_size);
+            for chunk in buffer.chunks_mut(chunk_size) {
+                let count = chunk.len() / self.sector_size;
+                let mut memory = DmaBuffer::new(chunk.len())?;
+                let data = Some((&mut memory, chunk.len()));
+                self.issue(ATA_READ_DMA_EXT, sector, count as u16, data)
+                    .await?;
+                memory.copy_to(0, chunk);
+                sector += count as u64;
+            }
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check(self, sector, buffer.len(), true)?;
+            let mut sector = sector;
+            let chunk_size = MAX_TRANSFER.max(self.sector_size);
+            for chunk in buffer.chunks(chunk_size) {
+                let count = chunk.len() / self.sector_size;
+                let mut memory = DmaBuffer::new(chunk.len())?;
+                memory.copy_from(0, chunk);
+                let data = Some((&mut memory, chunk.len()));
+                self.issue(ATA_WRITE_DMA_EXT, sector, count as u16, data)
+                    .await?;
+                sector += count as u64;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_> {
+        Box::pin(self.issue(ATA_FLUSH_CACHE_EXT, 0, 0, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    unit_test! {
+        fn identify_geometry() {
+            let mut words = [0; 256];
+            words[60] = 0xFFFF;
+            words[61] = 0x0FFF;
+            assert_eq!(
+                Identity::parse(&words),
+                Identity { sectors: 0x0FFF_FFFF, sector_size: 512, lba48: false }
+            );
+            words[83] = 1 << 10;
+            words[100] = 0x5678;
+            words[101] = 0x1234;
+            words[102] = 0x1;
+            words[106] = 0x4000 | (1 << 12);
+            words[117] = 2048;
+            assert_eq!(
+                Identity::parse(&words),
+                Identity { sectors: 0x1_1234_5678, sector_size: 4096, lba48: true }
+            );
+        }
+
+        fn read_command_fis() {
+            let fis = command_fis(ATA_READ_DMA_EXT, 0x0605_0403_0201, 0x0180);
+            assert_eq!(
+                fis[..14],
+                [0x27, 0x80, 0x25, 0, 1, 2, 3, 0x40, 4, 5, 6, 0, 0x80, 0x01]
+            );
+            assert!(fis[14..].iter().all(|b| *b == 0));
+        }
+    }
+}
@@ -0,0 +1,401 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Block devices: storage that is read & written in whole sectors. Drivers implement
+//! `BlockDevice` and pass their disks to `add()`, users look them up by name with `get()`.
+//!
+//! Transfers are asynchronous, `read()` & `write()` return futures that are ready once the
+//! device is done. There is no executor yet, `block_on()` polls a future until it is ready.
+//! Drivers without interrupts wait for their devices with `poll_until()`, and move data by DMA
+//! through a `DmaBuffer`.
+
+use crate::arch::PAGE_SIZE;
+use crate::memman::frame;
+use crate::memman::map::{self, MapArea};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// Error of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The transfer reaches past the last sector
+    OutOfRange,
+    /// The buffer is not a whole number of sectors
+    Unaligned,
+    ReadOnly,
+    /// The device reported an error
+    Io,
+    /// The device does not support the operation
+    Unsupported,
+    /// No memory was left for the transfer
+    OutOfMemory,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfRange => write!(f, "transfer past the end of the device"),
+            Self::Unaligned => write!(f, "transfer is not a whole number of sectors"),
+            Self::ReadOnly => write!(f, "device is read-only"),
+            Self::Io => write!(f, "device I/O error"),
+            Self::Unsupported => write!(f, "operation is not supported by the device"),
+            Self::OutOfMemory => write!(f, "out of memory for the transfer"),
+        }
+    }
+}
+
+/// A pending transfer, see `BlockDevice`
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), BlockError>> + Send + 'a>>;
+
+/// Storage addressed in sectors of `sector_size()` bytes
+pub trait BlockDevice: Send + Sync {
+    /// Size of a sector in bytes, a power of two
+    fn sector_size(&self) -> usize;
+
+    /// Number of sectors
+    fn sectors(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads `buffer.len() / sector_size()` sectors starting at `sector` into `buffer`
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a>;
+
+    /// Writes `buffer.len() / sector_size()` sectors starting at `sector` from `buffer`
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a>;
+
+    /// Waits until everything written before is stored persistently
+    fn flush(&self) -> BlockFuture<'_> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Checks that a transfer of `len` bytes at `sector` covers whole sectors inside of `device`,
+/// and that `device` is writable if `write` is set. Implementations of `BlockDevice` call this
+/// before starting a transfer.
+pub fn check(
+    device: &(impl BlockDevice + ?Sized),
+    sector: u64,
+    len: usize,
+    write: bool,
+) -> Result<(), BlockError> {
+    if len % device.sector_size() != 0 {
+        return Err(BlockError::Unaligned);
+    }
+    if write && device.read_only() {
+        return Err(BlockError::ReadOnly);
+    }
+    let count = (len / device.sector_size()) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sectors() => Ok(()),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Polls `future` until it is ready. Wakeups are not waited for, the future is polled again
+/// right away, which suits the drivers that poll their devices for completion.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = core::pin::pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Ready once `f` returns a value. Without interrupts the waker asks to be polled again right
+/// away, so `f` is called until the device is done.
+pub async fn poll_until<T>(mut f: impl FnMut() -> Option<T>) -> T {
+    core::future::poll_fn(|context| match f() {
+        Some(value) => Poll::Ready(value),
+        None => {
+            context.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+/// Frames the device moves the data of a transfer from or into. Heap buffers have no known
+/// physical address, so drivers bounce the data through a `DmaBuffer`.
+pub struct DmaBuffer {
+    area: Option<MapArea>,
+    /// The device may still use the memory
+    pending: bool,
+}
+
+impl DmaBuffer {
+    /// Claims frames for at least `len` bytes, the content is undefined
+    pub fn new(len: usize) -> Result<Self, BlockError> {
+        let area =
+            frame::claim_frames(len.div_ceil(PAGE_SIZE).max(1)).ok_or(BlockError::OutOfMemory)?;
+        Ok(Self {
+            area: Some(area),
+            pending: false,
+        })
+    }
+
+    /// Physical address of the first byte, aligned to a page
+    pub fn address(&self) -> u64 {
+        self.area().region().0 as u64
+    }
+
+    pub fn area(&self) -> &MapArea {
+        self.area.as_ref().unwrap()
+    }
+
+    pub fn area_mut(&mut self) -> &mut MapArea {
+        self.area.as_mut().unwrap()
+    }
+
+    /// Copies `data` into the buffer at `offset`
+    pub fn copy_from(&mut self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= self.area().len());
+        let address = self.area().virtual_address() + offset;
+        // SAFETY: the frames are claimed by the buffer and cover the range
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), address as *mut u8, data.len()) };
+    }
+
+    /// Copies the bytes at `offset` out of the buffer into `data`
+    pub fn copy_to(&self, offset: usize, data: &mut [u8]) {
+        assert!(offset + data.len() <= self.area().len());
+        let address = self.area().virtual_address() + offset;
+        // SAFETY: same as in `copy_from()`
+        unsafe {
+            core::ptr::copy_nonoverlapping(address as *const u8, data.as_mut_ptr(), data.len())
+        };
+    }
+
+    /// Marks the buffer as handed to the device. Dropped before `completed()`, e.g. with the
+    /// future of the transfer, the frames are leaked instead of freed, as the device may still
+    /// write into them.
+    pub fn submitted(&mut self) {
+        self.pending = true;
+    }
+
+    /// Marks the buffer as given back by the device
+    pub fn completed(&mut self) {
+        self.pending = false;
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let Some(area) = self.area.take() else {
+            return;
+        };
+        match self.pending {
+            true => core::mem::forget(area),
+            false => map::free_global(area),
+        }
+    }
+}
+
+/// A block device in memory, contents are lost when it is dropped
+pub struct RamDisk {
+    sector_size: usize,
+    data: Mutex<RamData>,
+}
+
+/// Contents of a `RamDisk`, always whole sectors
+enum RamData {
+    Owned(Vec<u8>),
+    /// A static image, sectors are copied onto the heap when they are first written
+    CopyOnWrite {
+        image: &'static [u8],
+        written: BTreeMap<usize, Box<[u8]>>,
+    },
+}
+
+impl RamDisk {
+    /// A zeroed disk of `sectors` sectors
+    pub fn new(sector_size: usize, sectors: usize) -> Self {
+        Self::from_bytes(sector_size, alloc::vec![0; sector_size * sectors])
+    }
+
+    /// A disk holding `data`, cut to whole sectors
+    pub fn from_bytes(sector_size: usize, mut data: Vec<u8>) -> Self {
+        data.truncate(data.len() / sector_size * sector_size);
+        Self {
+            sector_size,
+            data: Mutex::new(RamData::Owned(data)),
+        }
+    }
+
+    /// A disk starting out as `image`, cut to whole sectors. Only the written sectors take up
+    /// heap space, the image itself is never changed.
+    pub fn from_static(sector_size: usize, image: &'static [u8]) -> Self {
+        Self {
+            sector_size,
+            data: Mutex::new(RamData::CopyOnWrite {
+                image: &image[..image.len() / sector_size * sector_size],
+                written: BTreeMap::new(),
+            }),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sectors(&self) -> u64 {
+        let len = match &*self.data.lock() {
+            RamData::Owned(data) => data.len(),
+            RamData::CopyOnWrite { image, .. } => image.len(),
+        };
+        (len / self.sector_size) as u64
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            check(self, sector, buffer.len(), false)?;
+            let size = self.sector_size;
+            let first = sector as usize;
+            match &*self.data.lock() {
+                RamData::Owned(data) => {
+                    buffer.copy_from_slice(&data[first * size..first * size + buffer.len()])
+                }
+                RamData::CopyOnWrite { image, written } => {
+                    for (index, chunk) in (first..).zip(buffer.chunks_mut(size)) {
+                        match written.get(&index) {
+                            Some(copy) => chunk.copy_from_slice(copy),
+                            None => chunk.copy_from_slice(&image[index * size..][..size]),
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            check(self, sector, buffer.len(), true)?;
+            let size = self.sector_size;
+            let first = sector as usize;
+            match &mut *self.data.lock() {
+                RamData::Owned(data) => {
+                    data[first * size..first * size + buffer.len()].copy_from_slice(buffer)
+                }
+                RamData::CopyOnWrite { written, .. } => {
+                    for (index, chunk) in (first..).zip(buffer.chunks(size)) {
+                        written.insert(index, chunk.into());
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// A block device known under a name
+#[derive(Clone)]
+pub struct Disk {
+    pub name: String,
+    pub device: Arc<dyn BlockDevice>,
+}
+
+static DISKS: Mutex<Vec<Disk>> = Mutex::new(Vec::new());
+
+/// Makes `device` available under `name`, which must not be taken yet
+pub fn add(name: &str, device: Arc<dyn BlockDevice>) -> Result<(), Arc<dyn BlockDevice>> {
+    let mut disks = DISKS.lock();
+    if disks.iter().any(|disk| disk.name == name) {
+        return Err(device);
+    }
+    disks.push(Disk {
+        name: name.into(),
+        device,
+    });
+    Ok(())
+}
+
+/// The block device called `name`
+pub fn get(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DISKS
+        .lock()
+        .iter()
+        .find(|disk| disk.name == name)
+        .map(|disk| disk.device.clone())
+}
+
+/// Calls `f` with every block device, in the order they were added
+pub fn with_disks<R>(f: impl FnOnce(&[Disk]) -> R) -> R {
+    f(&DISKS.lock())
+}
+
+/// Names disks like `prefix` followed by a, b, ..., z, aa, ab, ... for `index` 0, 1, ...
+pub fn letter_name(prefix: &str, index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut rest = index + 1;
+    while rest > 0 {
+        rest -= 1;
+        letters.push(b'a' + (rest % 26) as u8);
+        rest /= 26;
+    }
+    let mut name = String::from(prefix);
+    name.extend(letters.iter().rev().map(|letter| *letter as char));
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    unit_test! {
+        fn ram_disk_read_write() {
+            let disk = RamDisk::new(512, 4);
+            let data = [0xAB; 1024];
+            block_on(disk.write(2, &data)).unwrap();
+            let mut buffer = [0; 1536];
+            block_on(disk.read(1, &mut buffer)).unwrap();
+            assert!(buffer[..512].iter().all(|b| *b == 0));
+            assert!(buffer[512..].iter().all(|b| *b == 0xAB));
+        }
+
+        fn ram_disk_copy_on_write() {
+            static IMAGE: [u8; 1300] = [0x11; 1300];
+            let disk = RamDisk::from_static(512, &IMAGE);
+            assert_eq!(disk.sectors(), 2);
+            block_on(disk.write(1, &[0xAB; 512])).unwrap();
+            let mut buffer = [0; 1024];
+            block_on(disk.read(0, &mut buffer)).unwrap();
+            assert!(buffer[..512].iter().all(|b| *b == 0x11));
+            assert!(buffer[512..].iter().all(|b| *b == 0xAB));
+            assert!(IMAGE.iter().all(|b| *b == 0x11));
+        }
+
+        fn transfers_are_checked() {
+            let disk = RamDisk::new(512, 4);
+            let mut buffer = [0; 1024];
+            assert_eq!(block_on(disk.read(3, &mut buffer)), Err(BlockError::OutOfRange));
+            assert_eq!(block_on(disk.read(u64::MAX, &mut buffer)), Err(BlockError::OutOfRange));
+            assert_eq!(block_on(disk.read(0, &mut buffer[..100])), Err(BlockError::Unaligned));
+            assert_eq!(block_on(disk.read(2, &mut buffer)), Ok(()));
+        }
+
+        fn disk_names() {
+            assert_eq!(letter_name("vd", 0), "vda");
+            assert_eq!(letter_name("vd", 25), "vdz");
+            assert_eq!(letter_name("vd", 26), "vdaa");
+            assert_eq!(letter_name("sd", 27), "sdab");
+        }
+    }
+}
@@ -0,0 +1,140 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Text console on the first framebuffer, drawn with a fixed-size font. Only 32 bit
+//! framebuffers are supported, like in `limine::draw_pixel()`.
+
+use crate::limine::{self, Framebuffer, LColor};
+use embedded_graphics::mono_font::ascii::FONT_8X13;
+use embedded_graphics::mono_font::{MonoFont, MonoTextStyle, MonoTextStyleBuilder};
+use embedded_graphics::prelude::*;
+use embedded_graphics::text::{Baseline, Text};
+use spin::Mutex;
+
+const FONT: MonoFont = FONT_8X13;
+const FOREGROUND: LColor = LColor {
+    r: 0xCC,
+    g: 0xCC,
+    b: 0xCC,
+};
+const BACKGROUND: LColor = LColor { r: 0, g: 0, b: 0 };
+
+struct FbCon {
+    fb: Framebuffer,
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+}
+
+static FBCON: Mutex<Option<FbCon>> = Mutex::new(None);
+
+impl FbCon {
+    fn style() -> MonoTextStyle<'static, LColor> {
+        MonoTextStyleBuilder::new()
+            .font(&FONT)
+            .text_color(FOREGROUND)
+            .background_color(BACKGROUND)
+            .build()
+    }
+
+    fn draw(&mut self, c: char) {
+        let mut buffer = [0; 4];
+        let position = Point::new(
+            (self.column * FONT.character_size.width as usize) as i32,
+            (self.row * FONT.character_size.height as usize) as i32,
+        );
+        // characters outside of the font are drawn as '?' by embedded-graphics
+        let _ = Text::with_baseline(
+            c.encode_utf8(&mut buffer),
+            position,
+            Self::style(),
+            Baseline::Top,
+        )
+        .draw(&mut self.fb);
+    }
+
+    /// Clears `count` pixel rows starting at `y`
+    fn clear_lines(&mut self, y: usize, count: usize) {
+        let start = self.fb.address.wrapping_add(y * self.fb.pitch as usize);
+        unsafe { core::ptr::write_bytes(start, 0, count * self.fb.pitch as usize) };
+    }
+
+    /// Moves every text row up by one and clears the last one
+    fn scroll(&mut self) {
+        let line = FONT.character_size.height as usize * self.fb.pitch as usize;
+        let text_size = self.rows * line;
+        unsafe {
+            core::ptr::copy(
+                self.fb.address.wrapping_add(line),
+                self.fb.address,
+                text_size - line,
+            )
+        };
+        let height = FONT.character_size.height as usize;
+        self.clear_lines((self.rows - 1) * height, height);
+    }
+
+    fn newline(&mut self) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll();
+        }
+    }
+
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.column = 0,
+            // erases the previous character on the same row
+            '\x08' => {
+                if self.column > 0 {
+                    self.column -= 1;
+                    self.draw(' ');
+                }
+            }
+            _ => {
+                if self.column == self.columns {
+                    self.newline();
+                }
+                self.draw(c);
+                self.column += 1;
+            }
+        }
+    }
+}
+
+/// Takes over the first framebuffer and clears it, does nothing without a 32 bit framebuffer
+pub fn init() {
+    let Some(fb) = limine::framebuffers()
+        .ok()
+        .and_then(|fbs| fbs.first())
+        .filter(|fb| fb.bpp == 32)
+    else {
+        return;
+    };
+    let mut console = FbCon {
+        fb: fb.clone(),
+        columns: fb.width as usize / FONT.character_size.width as usize,
+        rows: fb.height as usize / FONT.character_size.height as usize,
+        column: 0,
+        row: 0,
+    };
+    if console.columns == 0 || console.rows == 0 {
+        return;
+    }
+    console.clear_lines(0, fb.height as usize);
+    *FBCON.lock() = Some(console);
+}
+
+/// Draws `text` at the cursor, wrapping long lines & scrolling at the bottom
+pub fn write(text: &str) {
+    if let Some(console) = FBCON.lock().as_mut() {
+        text.chars().for_each(|c| console.write_char(c));
+    }
+}
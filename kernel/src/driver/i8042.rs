@@ -0,0 +1,204 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! i8042 PS/2 controller with a keyboard on its first port. Received scancodes are decoded on
+//! IRQ 1 and queued in the console.
+//!
+//! main source: https://wiki.osdev.org/I8042_PS/2_Controller
+
+use super::keyboard::{Decoder, ScancodeSet};
+use super::{Device, DeviceId, Driver, DriverError, Resource};
+use crate::arch::amd64::{pic, portio};
+use crate::{console, log};
+use core::fmt;
+use spin::Mutex;
+
+const DATA: u16 = 0x60;
+/// Status when read, command when written
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_PORT2: u8 = 0xA7;
+const COMMAND_TEST_CONTROLLER: u8 = 0xAA;
+const COMMAND_TEST_PORT1: u8 = 0xAB;
+const COMMAND_DISABLE_PORT1: u8 = 0xAD;
+const COMMAND_ENABLE_PORT1: u8 = 0xAE;
+
+const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+/// The controller translates set 2 scancodes of the first port to set 1
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const TEST_CONTROLLER_PASSED: u8 = 0x55;
+const TEST_PORT_PASSED: u8 = 0x00;
+
+const KEYBOARD_RESET: u8 = 0xFF;
+const KEYBOARD_ENABLE_SCANNING: u8 = 0xF4;
+const KEYBOARD_ACK: u8 = 0xFA;
+const KEYBOARD_SELF_TEST_PASSED: u8 = 0xAA;
+
+const KEYBOARD_IRQ: u8 = 1;
+/// Status polls before giving up on the controller
+const TIMEOUT: usize = 100_000;
+
+/// Error returned by `init()`
+#[derive(Debug)]
+pub enum I8042Error {
+    /// The controller did not respond in time, there may be none
+    Timeout,
+    ControllerTest(u8),
+    PortTest(u8),
+    /// The keyboard answered a command with something else than expected
+    Keyboard {
+        command: u8,
+        response: u8,
+    },
+    Irq(pic::PicError),
+}
+
+impl fmt::Display for I8042Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "PS/2 controller timed out"),
+            Self::ControllerTest(r) => write!(f, "PS/2 controller self test failed, 0x{:X}", r),
+            Self::PortTest(r) => write!(f, "PS/2 port test failed, 0x{:X}", r),
+            Self::Keyboard { command, response } => write!(
+                f,
+                "PS/2 keyboard answered command 0x{:X} with 0x{:X}",
+                command, response
+            ),
+            Self::Irq(e) => write!(f, "PS/2 keyboard IRQ, {}", e),
+        }
+    }
+}
+
+/// Set by `init()` once the scancode set is known
+static DECODER: Mutex<Option<Decoder>> = Mutex::new(None);
+
+fn wait_for(mask: u8, set: bool) -> Result<(), I8042Error> {
+    for _ in 0..TIMEOUT {
+        if (unsafe { portio::input_byte(STATUS) } & mask != 0) == set {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(I8042Error::Timeout)
+}
+
+fn read() -> Result<u8, I8042Error> {
+    wait_for(STATUS_OUTPUT_FULL, true)?;
+    Ok(unsafe { portio::input_byte(DATA) })
+}
+
+fn write(port: u16, value: u8) -> Result<(), I8042Error> {
+    wait_for(STATUS_INPUT_FULL, false)?;
+    unsafe { portio::output_byte(port, value) };
+    Ok(())
+}
+
+/// Sends a command to the keyboard and checks its acknowledgement
+fn keyboard_command(command: u8) -> Result<(), I8042Error> {
+    write(DATA, command)?;
+    match read()? {
+        KEYBOARD_ACK => Ok(()),
+        response => Err(I8042Error::Keyboard { command, response }),
+    }
+}
+
+/// Tests the controller, resets the keyboard and starts receiving its scancodes on IRQ 1.
+/// The second port (usually a mouse) stays disabled.
+pub fn init() -> Result<ScancodeSet, I8042Error> {
+    write(COMMAND, COMMAND_DISABLE_PORT1)?;
+    write(COMMAND, COMMAND_DISABLE_PORT2)?;
+    // drop whatever arrived before, without a controller the status reads as 0xFF forever
+    let mut flushed = false;
+    for _ in 0..TIMEOUT {
+        if unsafe { portio::input_byte(STATUS) } & STATUS_OUTPUT_FULL == 0 {
+            flushed = true;
+            break;
+        }
+        unsafe { portio::input_byte(DATA) };
+    }
+    if !flushed {
+        return Err(I8042Error::Timeout);
+    }
+
+    write(COMMAND, COMMAND_READ_CONFIG)?;
+    let config = read()? & !(CONFIG_PORT1_IRQ | CONFIG_PORT2_IRQ);
+    write(COMMAND, COMMAND_WRITE_CONFIG)?;
+    write(DATA, config)?;
+
+    // the self test may reset the configuration
+    write(COMMAND, COMMAND_TEST_CONTROLLER)?;
+    match read()? {
+        TEST_CONTROLLER_PASSED => {}
+        r => return Err(I8042Error::ControllerTest(r)),
+    }
+    write(COMMAND, COMMAND_WRITE_CONFIG)?;
+    write(DATA, config)?;
+    write(COMMAND, COMMAND_TEST_PORT1)?;
+    match read()? {
+        TEST_PORT_PASSED => {}
+        r => return Err(I8042Error::PortTest(r)),
+    }
+
+    write(COMMAND, COMMAND_ENABLE_PORT1)?;
+    keyboard_command(KEYBOARD_RESET)?;
+    match read()? {
+        KEYBOARD_SELF_TEST_PASSED => {}
+        response => {
+            return Err(I8042Error::Keyboard {
+                command: KEYBOARD_RESET,
+                response,
+            })
+        }
+    }
+    keyboard_command(KEYBOARD_ENABLE_SCANNING)?;
+
+    let set = match config & CONFIG_TRANSLATION {
+        0 => ScancodeSet::Two,
+        _ => ScancodeSet::One,
+    };
+    *DECODER.lock() = Some(Decoder::new(set));
+    pic::register(KEYBOARD_IRQ, handle_irq).map_err(I8042Error::Irq)?;
+    write(COMMAND, COMMAND_WRITE_CONFIG)?;
+    write(DATA, config | CONFIG_PORT1_IRQ)?;
+    Ok(set)
+}
+
+/// Attaches to the platform device `i8042`
+pub struct I8042Driver;
+
+pub static DRIVER: I8042Driver = I8042Driver;
+
+impl Driver for I8042Driver {
+    fn name(&self) -> &'static str {
+        "i8042"
+    }
+
+    fn probe(&self, device: &Device) -> bool {
+        matches!(device.resource, Resource::Platform) && device.name.as_str() == "i8042"
+    }
+
+    fn attach(&self, _: DeviceId, _: &Device) -> Result<(), DriverError> {
+        let set = init().map_err(DriverError::failed)?;
+        log!("PS/2 keyboard: scancode set {:?}\n", set);
+        Ok(())
+    }
+}
+
+fn handle_irq() {
+    let byte = unsafe { portio::input_byte(DATA) };
+    // only taken here & in `init()` before the IRQ is enabled
+    if let Some(event) = DECODER.lock().as_mut().and_then(|d| d.feed(byte)) {
+        console::push_key(event);
+    }
+}
@@ -0,0 +1,260 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Keyboard layouts, mapping keys & modifiers to characters.
+//!
+//! A layout lists the characters of the keys that differ between layouts. Letters missing from
+//! the list type themselves as on a US keyboard, keys like space or the keypad are the same
+//! everywhere. Dead keys (e.g. `´` on DE) type their accent as a plain character.
+
+use super::{Key, Modifiers};
+use crate::cmdline::FromParam;
+
+/// Characters of a key: without modifiers, with shift & with AltGr
+type Entry = (Key, char, char, Option<char>);
+
+#[derive(Debug)]
+pub struct Keymap {
+    /// Selects the layout on the command line
+    pub name: &'static str,
+    entries: &'static [Entry],
+}
+
+impl Keymap {
+    pub const fn new(name: &'static str, entries: &'static [Entry]) -> Self {
+        Self { name, entries }
+    }
+
+    /// The character typed by `key` with `modifiers` held down, if any.
+    ///
+    /// Caps lock works like shift for keys that type a letter without modifiers.
+    pub fn lookup(&self, key: Key, modifiers: Modifiers) -> Option<char> {
+        if let Some(c) = common(key, modifiers) {
+            return Some(c);
+        }
+        let (_, normal, shifted, altgr) = self
+            .entries
+            .iter()
+            .find(|entry| entry.0 == key)
+            .copied()
+            .or_else(|| letter(key).map(|c| (key, c, c.to_ascii_uppercase(), None)))?;
+        if modifiers.altgr() {
+            return altgr;
+        }
+        if modifiers.caps_lock() && normal.is_alphabetic() {
+            // e.g. ě on CZ, where shift types a digit
+            let mut upper = normal.to_uppercase();
+            return match (modifiers.shift(), upper.next(), upper.next()) {
+                (true, _, _) => Some(normal),
+                (false, Some(c), None) => Some(c),
+                // no single uppercase character, like ß
+                (false, _, _) => Some(normal),
+            };
+        }
+        Some(if modifiers.shift() { shifted } else { normal })
+    }
+}
+
+impl FromParam for &'static Keymap {
+    fn from_param(value: &'static str) -> Result<Self, &'static str> {
+        KEYMAPS
+            .iter()
+            .copied()
+            .find(|keymap| keymap.name == value)
+            .ok_or("expected us, de or cz")
+    }
+}
+
+/// Keys that type the same characters on every layout
+fn common(key: Key, modifiers: Modifiers) -> Option<char> {
+    let num_lock = modifiers.num_lock();
+    Some(match key {
+        Key::Space => ' ',
+        Key::Enter | Key::KeypadEnter => '\n',
+        Key::Tab => '\t',
+        Key::Backspace => '\x08',
+        Key::Escape => '\x1B',
+        Key::KeypadPlus => '+',
+        Key::KeypadMinus => '-',
+        Key::KeypadMultiply => '*',
+        Key::KeypadDivide => '/',
+        // without num lock the keypad works as the cursor keys
+        Key::Keypad0 if num_lock => '0',
+        Key::Keypad1 if num_lock => '1',
+        Key::Keypad2 if num_lock => '2',
+        Key::Keypad3 if num_lock => '3',
+        Key::Keypad4 if num_lock => '4',
+        Key::Keypad5 if num_lock => '5',
+        Key::Keypad6 if num_lock => '6',
+        Key::Keypad7 if num_lock => '7',
+        Key::Keypad8 if num_lock => '8',
+        Key::Keypad9 if num_lock => '9',
+        Key::KeypadPeriod if num_lock => '.',
+        _ => return None,
+    })
+}
+
+/// The letter printed on `key` on a US keyboard
+fn letter(key: Key) -> Option<char> {
+    use Key::*;
+    const LETTERS: [Key; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    let index = LETTERS.iter().position(|k| *k == key)?;
+    Some((b'a' + index as u8) as char)
+}
+
+/// United States (QWERTY)
+pub static US: Keymap = Keymap::new(
+    "us",
+    &[
+        (Key::Grave, '`', '~', None),
+        (Key::Digit1, '1', '!', None),
+        (Key::Digit2, '2', '@', None),
+        (Key::Digit3, '3', '#', None),
+        (Key::Digit4, '4', '$', None),
+        (Key::Digit5, '5', '%', None),
+        (Key::Digit6, '6', '^', None),
+        (Key::Digit7, '7', '&', None),
+        (Key::Digit8, '8', '*', None),
+        (Key::Digit9, '9', '(', None),
+        (Key::Digit0, '0', ')', None),
+        (Key::Minus, '-', '_', None),
+        (Key::Equal, '=', '+', None),
+        (Key::LeftBracket, '[', '{', None),
+        (Key::RightBracket, ']', '}', None),
+        (Key::Backslash, '\\', '|', None),
+        (Key::Semicolon, ';', ':', None),
+        (Key::Quote, '\'', '"', None),
+        (Key::Comma, ',', '<', None),
+        (Key::Period, '.', '>', None),
+        (Key::Slash, '/', '?', None),
+        (Key::NonUsBackslash, '\\', '|', None),
+    ],
+);
+
+/// German (QWERTZ)
+pub static DE: Keymap = Keymap::new(
+    "de",
+    &[
+        (Key::Grave, '^', '°', None),
+        (Key::Digit1, '1', '!', None),
+        (Key::Digit2, '2', '"', Some('²')),
+        (Key::Digit3, '3', '§', Some('³')),
+        (Key::Digit4, '4', '$', None),
+        (Key::Digit5, '5', '%', None),
+        (Key::Digit6, '6', '&', None),
+        (Key::Digit7, '7', '/', Some('{')),
+        (Key::Digit8, '8', '(', Some('[')),
+        (Key::Digit9, '9', ')', Some(']')),
+        (Key::Digit0, '0', '=', Some('}')),
+        (Key::Minus, 'ß', '?', Some('\\')),
+        (Key::Equal, '´', '`', None),
+        (Key::Q, 'q', 'Q', Some('@')),
+        (Key::E, 'e', 'E', Some('€')),
+        (Key::Y, 'z', 'Z', None),
+        (Key::Z, 'y', 'Y', None),
+        (Key::M, 'm', 'M', Some('µ')),
+        (Key::LeftBracket, 'ü', 'Ü', None),
+        (Key::RightBracket, '+', '*', Some('~')),
+        (Key::Backslash, '#', '\'', None),
+        (Key::Semicolon, 'ö', 'Ö', None),
+        (Key::Quote, 'ä', 'Ä', None),
+        (Key::Comma, ',', ';', None),
+        (Key::Period, '.', ':', None),
+        (Key::Slash, '-', '_', None),
+        (Key::NonUsBackslash, '<', '>', Some('|')),
+    ],
+);
+
+/// Czech (QWERTZ)
+pub static CZ: Keymap = Keymap::new(
+    "cz",
+    &[
+        (Key::Grave, ';', '°', None),
+        (Key::Digit1, '+', '1', Some('!')),
+        (Key::Digit2, 'ě', '2', Some('@')),
+        (Key::Digit3, 'š', '3', Some('#')),
+        (Key::Digit4, 'č', '4', Some('$')),
+        (Key::Digit5, 'ř', '5', Some('%')),
+        (Key::Digit6, 'ž', '6', Some('^')),
+        (Key::Digit7, 'ý', '7', Some('&')),
+        (Key::Digit8, 'á', '8', Some('*')),
+        (Key::Digit9, 'í', '9', Some('(')),
+        (Key::Digit0, 'é', '0', Some(')')),
+        (Key::Minus, '=', '%', None),
+        (Key::Equal, '´', 'ˇ', None),
+        (Key::Q, 'q', 'Q', Some('\\')),
+        (Key::W, 'w', 'W', Some('|')),
+        (Key::E, 'e', 'E', Some('€')),
+        (Key::Y, 'z', 'Z', None),
+        (Key::Z, 'y', 'Y', None),
+        (Key::F, 'f', 'F', Some('[')),
+        (Key::G, 'g', 'G', Some(']')),
+        (Key::V, 'v', 'V', Some('@')),
+        (Key::B, 'b', 'B', Some('{')),
+        (Key::N, 'n', 'N', Some('}')),
+        (Key::LeftBracket, 'ú', '/', Some('[')),
+        (Key::RightBracket, ')', '(', Some(']')),
+        (Key::Backslash, '¨', '\'', None),
+        (Key::Semicolon, 'ů', '"', Some('$')),
+        (Key::Quote, '§', '!', Some('\'')),
+        (Key::Comma, ',', '?', Some('<')),
+        (Key::Period, '.', ':', Some('>')),
+        (Key::Slash, '-', '_', Some('*')),
+        (Key::NonUsBackslash, '\\', '|', None),
+    ],
+);
+
+/// Every built-in layout
+pub static KEYMAPS: &[&Keymap] = &[&US, &DE, &CZ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    fn shifted() -> Modifiers {
+        let mut modifiers = Modifiers::from_raw(0);
+        modifiers.set_left_shift(true);
+        modifiers
+    }
+
+    unit_test! {
+        fn us_letters_and_symbols() {
+            let none = Modifiers::from_raw(0);
+            assert_eq!(US.lookup(Key::A, none), Some('a'));
+            assert_eq!(US.lookup(Key::A, shifted()), Some('A'));
+            assert_eq!(US.lookup(Key::Digit2, shifted()), Some('@'));
+            assert_eq!(US.lookup(Key::F1, none), None);
+        }
+
+        fn de_swaps_y_and_z() {
+            let none = Modifiers::from_raw(0);
+            assert_eq!(DE.lookup(Key::Z, none), Some('y'));
+            assert_eq!(DE.lookup(Key::Y, shifted()), Some('Z'));
+            let mut altgr = none;
+            altgr.set_altgr(true);
+            assert_eq!(DE.lookup(Key::Q, altgr), Some('@'));
+            assert_eq!(DE.lookup(Key::A, altgr), None);
+        }
+
+        fn caps_lock_uppercases_letters() {
+            let mut caps = Modifiers::from_raw(0);
+            caps.set_caps_lock(true);
+            assert_eq!(CZ.lookup(Key::Digit2, caps), Some('Ě'));
+            assert_eq!(CZ.lookup(Key::Comma, caps), Some(','));
+            assert_eq!(DE.lookup(Key::Minus, caps), Some('ß'));
+            caps.set_right_shift(true);
+            assert_eq!(US.lookup(Key::B, caps), Some('b'));
+        }
+
+        fn keymap_from_param() {
+            assert_eq!(<&Keymap>::from_param("cz").map(|k| k.name), Ok("cz"));
+            assert!(<&Keymap>::from_param("fr").is_err());
+        }
+    }
+}
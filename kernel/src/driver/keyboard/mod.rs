@@ -0,0 +1,604 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Keyboard input independent of the controller: physical keys, modifier state and decoding of
+//! PS/2 scancode sets 1 & 2 into key events. `keymap` turns key events into characters.
+//!
+//! main source: https://wiki.osdev.org/PS/2_Keyboard
+
+use crate::bitfield;
+
+pub mod keymap;
+
+pub use keymap::Keymap;
+
+/// A physical key, named after its label on a US keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+    H,
+    I,
+    J,
+    K,
+    L,
+    M,
+    N,
+    O,
+    P,
+    Q,
+    R,
+    S,
+    T,
+    U,
+    V,
+    W,
+    X,
+    Y,
+    Z,
+    Digit0,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Grave,
+    Minus,
+    Equal,
+    LeftBracket,
+    RightBracket,
+    Backslash,
+    Semicolon,
+    Quote,
+    Comma,
+    Period,
+    Slash,
+    /// The extra key next to left shift on ISO keyboards
+    NonUsBackslash,
+    Space,
+    Enter,
+    Backspace,
+    Tab,
+    Escape,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    /// AltGr on most non-US layouts
+    RightAlt,
+    LeftSuper,
+    RightSuper,
+    Menu,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    PrintScreen,
+    Pause,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+    KeypadPeriod,
+    KeypadPlus,
+    KeypadMinus,
+    KeypadMultiply,
+    KeypadDivide,
+    KeypadEnter,
+}
+
+bitfield! {
+    /// Modifier keys held down & lock keys toggled on when an event happened
+    #[derive(Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Modifiers(u16) {
+        pub bool, left_shift, set_left_shift: 0;
+        pub bool, right_shift, set_right_shift: 1;
+        pub bool, left_ctrl, set_left_ctrl: 2;
+        pub bool, right_ctrl, set_right_ctrl: 3;
+        pub bool, alt, set_alt: 4;
+        pub bool, altgr, set_altgr: 5;
+        pub bool, left_super, set_left_super: 6;
+        pub bool, right_super, set_right_super: 7;
+        pub bool, caps_lock, set_caps_lock: 8;
+        pub bool, num_lock, set_num_lock: 9;
+        pub bool, scroll_lock, set_scroll_lock: 10;
+    }
+}
+
+impl Modifiers {
+    pub const fn shift(&self) -> bool {
+        self.left_shift() || self.right_shift()
+    }
+
+    pub const fn ctrl(&self) -> bool {
+        self.left_ctrl() || self.right_ctrl()
+    }
+
+    /// Updates the state after `key` was pressed or released
+    fn update(&mut self, key: Key, pressed: bool) {
+        match key {
+            Key::LeftShift => self.set_left_shift(pressed),
+            Key::RightShift => self.set_right_shift(pressed),
+            Key::LeftCtrl => self.set_left_ctrl(pressed),
+            Key::RightCtrl => self.set_right_ctrl(pressed),
+            Key::LeftAlt => self.set_alt(pressed),
+            Key::RightAlt => self.set_altgr(pressed),
+            Key::LeftSuper => self.set_left_super(pressed),
+            Key::RightSuper => self.set_right_super(pressed),
+            // locks toggle on press, typematic repeats are ignored by the caller
+            Key::CapsLock if pressed => self.set_caps_lock(!self.caps_lock()),
+            Key::NumLock if pressed => self.set_num_lock(!self.num_lock()),
+            Key::ScrollLock if pressed => self.set_scroll_lock(!self.scroll_lock()),
+            _ => {}
+        }
+    }
+}
+
+/// A key was pressed (or repeated while held down) or released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub pressed: bool,
+    /// State after the event, a shift press already has shift set
+    pub modifiers: Modifiers,
+}
+
+impl KeyEvent {
+    /// The character typed by this event with `keymap`, only presses type characters
+    pub fn char(&self, keymap: &Keymap) -> Option<char> {
+        if !self.pressed {
+            return None;
+        }
+        keymap.lookup(self.key, self.modifiers)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// Sent by XT keyboards and by i8042 controllers translating set 2
+    One,
+    /// The default set of AT & PS/2 keyboards
+    Two,
+}
+
+/// Prefix bytes received before the scancode of a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prefix {
+    None,
+    /// 0xE0, the key is in the extended table
+    Extended,
+    /// 0xE1 starts the pause sequence, the number of bytes left to skip
+    Pause(u8),
+}
+
+/// Turns scancode bytes into key events, keeping track of the modifier state
+#[derive(Debug)]
+pub struct Decoder {
+    set: ScancodeSet,
+    prefix: Prefix,
+    /// Set 2 sends 0xF0 before the scancode of a released key
+    release: bool,
+    modifiers: Modifiers,
+    /// Caps, num & scroll lock held down, their typematic repeats must not toggle them again
+    held_locks: [bool; 3],
+}
+
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            prefix: Prefix::None,
+            release: false,
+            modifiers: Modifiers::from_raw(0),
+            held_locks: [false; 3],
+        }
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Feeds the next byte from the keyboard, returns an event once a key sequence is complete
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        match (self.prefix, byte) {
+            (Prefix::Pause(left), _) => {
+                self.prefix = match left {
+                    1 => Prefix::None,
+                    _ => Prefix::Pause(left - 1),
+                };
+                // the pause key has no release code, the whole sequence is a press
+                return (left == 1).then(|| self.event(Key::Pause, true));
+            }
+            (_, 0xE0) => {
+                self.prefix = Prefix::Extended;
+                return None;
+            }
+            (_, 0xE1) => {
+                // set 1: 1D 45 E1 9D C5, set 2: 14 77 E1 F0 14 F0 77
+                self.prefix = Prefix::Pause(match self.set {
+                    ScancodeSet::One => 5,
+                    ScancodeSet::Two => 7,
+                });
+                return None;
+            }
+            (_, 0xF0) if self.set == ScancodeSet::Two => {
+                self.release = true;
+                return None;
+            }
+            _ => {}
+        }
+        let extended = self.prefix == Prefix::Extended;
+        self.prefix = Prefix::None;
+        let (code, pressed) = match self.set {
+            ScancodeSet::One => (byte & 0x7F, byte & 0x80 == 0),
+            ScancodeSet::Two => (byte, !core::mem::take(&mut self.release)),
+        };
+        let key = match (self.set, extended) {
+            (ScancodeSet::One, false) => set1(code),
+            (ScancodeSet::One, true) => set1_extended(code),
+            (ScancodeSet::Two, false) => set2(code),
+            (ScancodeSet::Two, true) => set2_extended(code),
+        }?;
+        Some(self.event(key, pressed))
+    }
+
+    fn event(&mut self, key: Key, pressed: bool) -> KeyEvent {
+        let lock = match key {
+            Key::CapsLock => Some(0),
+            Key::NumLock => Some(1),
+            Key::ScrollLock => Some(2),
+            _ => None,
+        };
+        // a held down lock key repeats its press, which must not toggle it again
+        let repeated = match lock {
+            Some(i) => core::mem::replace(&mut self.held_locks[i], pressed) && pressed,
+            None => false,
+        };
+        if !repeated {
+            self.modifiers.update(key, pressed);
+        }
+        KeyEvent {
+            key,
+            pressed,
+            modifiers: self.modifiers,
+        }
+    }
+}
+
+/// Make codes of scancode set 1, break codes additionally have bit 7 set
+fn set1(code: u8) -> Option<Key> {
+    use Key::*;
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Digit1,
+        0x03 => Digit2,
+        0x04 => Digit3,
+        0x05 => Digit4,
+        0x06 => Digit5,
+        0x07 => Digit6,
+        0x08 => Digit7,
+        0x09 => Digit8,
+        0x0A => Digit9,
+        0x0B => Digit0,
+        0x0C => Minus,
+        0x0D => Equal,
+        0x0E => Backspace,
+        0x0F => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1A => LeftBracket,
+        0x1B => RightBracket,
+        0x1C => Enter,
+        0x1D => LeftCtrl,
+        0x1E => A,
+        0x1F => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Quote,
+        0x29 => Grave,
+        0x2A => LeftShift,
+        0x2B => Backslash,
+        0x2C => Z,
+        0x2D => X,
+        0x2E => C,
+        0x2F => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => KeypadMultiply,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3A => CapsLock,
+        0x3B => F1,
+        0x3C => F2,
+        0x3D => F3,
+        0x3E => F4,
+        0x3F => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Keypad7,
+        0x48 => Keypad8,
+        0x49 => Keypad9,
+        0x4A => KeypadMinus,
+        0x4B => Keypad4,
+        0x4C => Keypad5,
+        0x4D => Keypad6,
+        0x4E => KeypadPlus,
+        0x4F => Keypad1,
+        0x50 => Keypad2,
+        0x51 => Keypad3,
+        0x52 => Keypad0,
+        0x53 => KeypadPeriod,
+        0x56 => NonUsBackslash,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+/// Set 1 make codes after 0xE0. The fake shifts around some extended keys (0x2A, 0x36) are
+/// ignored.
+fn set1_extended(code: u8) -> Option<Key> {
+    use Key::*;
+    Some(match code {
+        0x1C => KeypadEnter,
+        0x1D => RightCtrl,
+        0x35 => KeypadDivide,
+        0x37 => PrintScreen,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => Up,
+        0x49 => PageUp,
+        0x4B => Left,
+        0x4D => Right,
+        0x4F => End,
+        0x50 => Down,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5B => LeftSuper,
+        0x5C => RightSuper,
+        0x5D => Menu,
+        _ => return None,
+    })
+}
+
+/// Make codes of scancode set 2, break codes are the same after 0xF0
+fn set2(code: u8) -> Option<Key> {
+    use Key::*;
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0A => F8,
+        0x0B => F6,
+        0x0C => F4,
+        0x0D => Tab,
+        0x0E => Grave,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftCtrl,
+        0x15 => Q,
+        0x16 => Digit1,
+        0x1A => Z,
+        0x1B => S,
+        0x1C => A,
+        0x1D => W,
+        0x1E => Digit2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Digit4,
+        0x26 => Digit3,
+        0x29 => Space,
+        0x2A => V,
+        0x2B => F,
+        0x2C => T,
+        0x2D => R,
+        0x2E => Digit5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Digit6,
+        0x3A => M,
+        0x3B => J,
+        0x3C => U,
+        0x3D => Digit7,
+        0x3E => Digit8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Digit0,
+        0x46 => Digit9,
+        0x49 => Period,
+        0x4A => Slash,
+        0x4B => L,
+        0x4C => Semicolon,
+        0x4D => P,
+        0x4E => Minus,
+        0x52 => Quote,
+        0x54 => LeftBracket,
+        0x55 => Equal,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5A => Enter,
+        0x5B => RightBracket,
+        0x5D => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Keypad1,
+        0x6B => Keypad4,
+        0x6C => Keypad7,
+        0x70 => Keypad0,
+        0x71 => KeypadPeriod,
+        0x72 => Keypad2,
+        0x73 => Keypad5,
+        0x74 => Keypad6,
+        0x75 => Keypad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => KeypadPlus,
+        0x7A => Keypad3,
+        0x7B => KeypadMinus,
+        0x7C => KeypadMultiply,
+        0x7D => Keypad9,
+        0x7E => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
+
+/// Set 2 make codes after 0xE0. The fake shifts around some extended keys (0x12, 0x59) are
+/// ignored.
+fn set2_extended(code: u8) -> Option<Key> {
+    use Key::*;
+    Some(match code {
+        0x11 => RightAlt,
+        0x14 => RightCtrl,
+        0x1F => LeftSuper,
+        0x27 => RightSuper,
+        0x2F => Menu,
+        0x4A => KeypadDivide,
+        0x5A => KeypadEnter,
+        0x69 => End,
+        0x6B => Left,
+        0x6C => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => Down,
+        0x74 => Right,
+        0x75 => Up,
+        0x7A => PageDown,
+        0x7C => PrintScreen,
+        0x7D => PageUp,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    fn feed_all(decoder: &mut Decoder, bytes: &[u8]) -> Option<KeyEvent> {
+        bytes.iter().filter_map(|b| decoder.feed(*b)).last()
+    }
+
+    unit_test! {
+        fn set1_press_and_release() {
+            let mut decoder = Decoder::new(ScancodeSet::One);
+            let press = decoder.feed(0x1E).unwrap();
+            assert_eq!((press.key, press.pressed), (Key::A, true));
+            let release = decoder.feed(0x9E).unwrap();
+            assert_eq!((release.key, release.pressed), (Key::A, false));
+        }
+
+        fn set2_extended_release() {
+            let mut decoder = Decoder::new(ScancodeSet::Two);
+            let press = feed_all(&mut decoder, &[0xE0, 0x75]).unwrap();
+            assert_eq!((press.key, press.pressed), (Key::Up, true));
+            let release = feed_all(&mut decoder, &[0xE0, 0xF0, 0x75]).unwrap();
+            assert_eq!((release.key, release.pressed), (Key::Up, false));
+        }
+
+        fn modifiers_follow_presses() {
+            let mut decoder = Decoder::new(ScancodeSet::Two);
+            assert!(decoder.feed(0x12).unwrap().modifiers.shift());
+            let event = decoder.feed(0x1C).unwrap();
+            assert!(event.modifiers.shift());
+            feed_all(&mut decoder, &[0xF0, 0x12]);
+            assert!(!decoder.modifiers().shift());
+        }
+
+        fn lock_ignores_repeats() {
+            let mut decoder = Decoder::new(ScancodeSet::One);
+            // pressed, repeated & released
+            feed_all(&mut decoder, &[0x3A, 0x3A, 0xBA]);
+            assert!(decoder.modifiers().caps_lock());
+            feed_all(&mut decoder, &[0x3A, 0xBA]);
+            assert!(!decoder.modifiers().caps_lock());
+        }
+
+        fn pause_sequence() {
+            let mut decoder = Decoder::new(ScancodeSet::Two);
+            let events = [0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77, 0x1C]
+                .iter()
+                .filter_map(|b| decoder.feed(*b))
+                .map(|e| e.key)
+                .collect::<alloc::vec::Vec<_>>();
+            assert_eq!(events, [Key::Pause, Key::A]);
+        }
+    }
+}
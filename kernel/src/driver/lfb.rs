@@ -4,9 +4,17 @@ use embedded_graphics::{
 use tinybmp::Bmp;
 
 pub fn init() {
-    let fb = crate::limine::framebuffer0();
+    let Some(fb) = crate::limine::framebuffers()
+        .ok()
+        .and_then(|fbs| fbs.first())
+    else {
+        return;
+    };
+    let mut fb = fb.clone();
 
     let bmp_data = include_bytes!("../../../cat.bmp");
     let bmp = Bmp::from_slice(bmp_data).unwrap();
-    Image::new(&bmp, Point::new(200, 200)).draw(fb).unwrap();
+    Image::new(&bmp, Point::new(200, 200))
+        .draw(&mut fb)
+        .unwrap();
 }
@@ -1,2 +1,474 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Device driver model: a tree of the devices that were found and the drivers bound to them.
+//!
+//! `discover()` adds the devices described by the firmware (ACPI, the device tree) and the
+//! platform defaults, bus drivers add the devices behind them while attaching (e.g. PCI
+//! functions). A driver implements `Driver` and is passed to `register()`. `init()` runs the
+//! drivers in the order of their dependencies, each one attaches to the unbound devices it
+//! probes successfully. Devices added later are offered to every driver, in the same order.
+//!
+//! The serial port & the framebuffer console are set up before the heap exists, they stay
+//! outside of the model.
+
+use crate::fdt::{Fdt, Node};
+use crate::limine;
+use crate::log::LogLevel;
+use crate::{log, log_at};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+pub mod ahci;
+pub mod block;
+pub mod fbcon;
+#[cfg(target_arch = "x86_64")]
+pub mod i8042;
+pub mod keyboard;
 pub mod lfb;
+pub mod nvme;
+pub mod partition;
+pub mod pci;
 pub mod serial;
+pub mod virtio;
+
+/// Most drivers that can be registered
+const MAX_DRIVERS: usize = 32;
+/// Longest device name, longer names are cut
+pub const NAME_CAPACITY: usize = 32;
+
+/// Identifies a device, devices are never removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId(usize);
+
+/// What describes a device, depends on where it was found
+#[derive(Clone)]
+pub enum Resource {
+    /// Part of every machine of the architecture, or set up by the boot loader
+    Platform,
+    /// Described by the ACPI table with this signature
+    Acpi([u8; 4]),
+    DeviceTree(Node<'static>),
+    Pci(pci::Function),
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Platform => write!(f, "platform"),
+            Self::Acpi(signature) => write!(
+                f,
+                "ACPI {}",
+                core::str::from_utf8(signature).unwrap_or("????")
+            ),
+            Self::DeviceTree(node) => match node.compatible().next() {
+                Some(compatible) => write!(f, "device tree {}", compatible),
+                None => write!(f, "device tree"),
+            },
+            Self::Pci(function) => write!(f, "PCI 0x{:X}:0x{:X}", function.vendor, function.device),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Device {
+    pub name: ArrayString<NAME_CAPACITY>,
+    pub parent: Option<DeviceId>,
+    pub resource: Resource,
+    /// Name of the bound driver
+    pub driver: Option<&'static str>,
+}
+
+/// Error returned by the drivers & the model
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriverError {
+    /// The driver can not be detached
+    Unsupported,
+    NoDevice,
+    NotBound,
+    TooManyDrivers,
+    MissingDependency {
+        driver: &'static str,
+        dependency: &'static str,
+    },
+    /// The driver depends on itself through its dependencies, or on a driver that does
+    DependencyCycle(&'static str),
+    /// The device did not work as expected, the message comes from the driver
+    Failed(String),
+}
+
+impl DriverError {
+    /// `Failed` with the message of a driver specific error
+    pub fn failed(error: impl fmt::Display) -> Self {
+        Self::Failed(format!("{}", error))
+    }
+
+    /// The driver a dependency error is about
+    fn driver(&self) -> Option<&'static str> {
+        match self {
+            Self::MissingDependency { driver, .. } | Self::DependencyCycle(driver) => Some(driver),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported => write!(f, "the driver can not be detached"),
+            Self::NoDevice => write!(f, "no such device"),
+            Self::NotBound => write!(f, "the device has no driver"),
+            Self::TooManyDrivers => write!(f, "too many drivers"),
+            Self::MissingDependency { driver, dependency } => {
+                write!(
+                    f,
+                    "driver {} depends on missing driver {}",
+                    driver, dependency
+                )
+            }
+            Self::DependencyCycle(driver) => {
+                write!(f, "driver {} has cyclic dependencies", driver)
+            }
+            Self::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// A device driver
+pub trait Driver: Sync {
+    /// Unique name, used by `dependencies()` of other drivers
+    fn name(&self) -> &'static str;
+
+    /// Drivers that attach before this one, e.g. the bus the devices are on
+    fn dependencies(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Whether the driver supports `device`. Only looks at the description, not at the hardware.
+    ///
+    /// WARNING: runs with the device list locked, using the model here deadlocks
+    fn probe(&self, device: &Device) -> bool;
+
+    /// Takes over the device, may add the devices behind it with `add_device()`
+    fn attach(&self, id: DeviceId, device: &Device) -> Result<(), DriverError>;
+
+    /// Releases the device, which is bound to this driver. Drivers can not be detached by default.
+    fn detach(&self, _id: DeviceId, _device: &Device) -> Result<(), DriverError> {
+        Err(DriverError::Unsupported)
+    }
+}
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+/// In the order they were registered
+static DRIVERS: Mutex<ArrayVec<&'static dyn Driver, MAX_DRIVERS>> =
+    Mutex::new(ArrayVec::new_const());
+/// Set by `init()`, drivers registered afterwards are attached right away
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Adds a device below `parent`, it gets a driver on the next `init()` or `register()`, or
+/// right away when it is added by an attaching driver
+pub fn add_device(name: &str, parent: Option<DeviceId>, resource: Resource) -> DeviceId {
+    let mut short = ArrayString::new();
+    for c in name.chars() {
+        if short.try_push(c).is_err() {
+            break;
+        }
+    }
+    let mut devices = DEVICES.lock();
+    devices.push(Device {
+        name: short,
+        parent,
+        resource,
+        driver: None,
+    });
+    DeviceId(devices.len() - 1)
+}
+
+/// Adds a driver. After `init()` it is attached to the unbound devices right away.
+pub fn register(driver: &'static dyn Driver) -> Result<(), DriverError> {
+    DRIVERS
+        .lock()
+        .try_push(driver)
+        .map_err(|_| DriverError::TooManyDrivers)?;
+    if !STARTED.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let (ordered, errors) = order(&DRIVERS.lock());
+    if let Some(e) = errors
+        .into_iter()
+        .find(|e| e.driver() == Some(driver.name()))
+    {
+        return Err(e);
+    }
+    if ordered.iter().any(|d| d.name() == driver.name()) {
+        let count = DEVICES.lock().len();
+        (0..count).for_each(|index| try_attach(index, driver));
+        bind(count, &ordered);
+    }
+    Ok(())
+}
+
+/// Sorts `drivers` so every driver comes after its dependencies. Drivers with missing or cyclic
+/// dependencies are left out and returned as errors.
+fn order(drivers: &[&'static dyn Driver]) -> (Vec<&'static dyn Driver>, Vec<DriverError>) {
+    let mut ordered: Vec<&'static dyn Driver> = Vec::new();
+    let mut errors = Vec::new();
+    let mut left: Vec<&'static dyn Driver> = Vec::new();
+    for driver in drivers {
+        let missing = driver
+            .dependencies()
+            .iter()
+            .find(|dependency| !drivers.iter().any(|d| d.name() == **dependency));
+        match missing {
+            Some(dependency) => errors.push(DriverError::MissingDependency {
+                driver: driver.name(),
+                dependency,
+            }),
+            None => left.push(*driver),
+        }
+    }
+    // drivers depending on a left out driver are left out as well
+    loop {
+        let ready = |driver: &&'static dyn Driver| {
+            driver
+                .dependencies()
+                .iter()
+                .all(|dependency| ordered.iter().any(|d| d.name() == *dependency))
+        };
+        let Some(index) = left.iter().position(ready) else {
+            break;
+        };
+        ordered.push(left.remove(index));
+    }
+    errors.extend(
+        left.iter()
+            .map(|driver| DriverError::DependencyCycle(driver.name())),
+    );
+    (ordered, errors)
+}
+
+/// Attaches `driver` to the device at `index` if it is unbound and the driver probes it
+fn try_attach(index: usize, driver: &'static dyn Driver) {
+    let device = {
+        let devices = DEVICES.lock();
+        let device = &devices[index];
+        if device.driver.is_some() || !driver.probe(device) {
+            return;
+        }
+        device.clone()
+    };
+    // unlocked, the driver may add devices
+    match driver.attach(DeviceId(index), &device) {
+        Ok(()) => {
+            log!("{}: bound to {}\n", device.name, driver.name());
+            DEVICES.lock()[index].driver = Some(driver.name());
+        }
+        Err(e) => log_at!(
+            LogLevel::Warn,
+            "[WARN] {}: {} failed to attach, {}\n",
+            device.name,
+            driver.name(),
+            e
+        ),
+    }
+}
+
+/// Offers the devices from index `start` on to the `ordered` drivers, until attaching adds no
+/// more devices
+fn bind(mut start: usize, ordered: &[&'static dyn Driver]) {
+    loop {
+        let end = DEVICES.lock().len();
+        if start == end {
+            return;
+        }
+        for driver in ordered {
+            (start..end).for_each(|index| try_attach(index, *driver));
+        }
+        start = end;
+    }
+}
+
+/// Runs the registered drivers in the order of their dependencies on every device found so far
+pub fn init() {
+    let (ordered, errors) = order(&DRIVERS.lock());
+    for e in errors {
+        log_at!(LogLevel::Warn, "[WARN] {}\n", e);
+    }
+    STARTED.store(true, Ordering::SeqCst);
+    bind(0, &ordered);
+}
+
+/// Detaches the driver of a device, the device stays unbound until a driver is registered
+pub fn detach(id: DeviceId) -> Result<(), DriverError> {
+    let device = DEVICES
+        .lock()
+        .get(id.0)
+        .cloned()
+        .ok_or(DriverError::NoDevice)?;
+    let name = device.driver.ok_or(DriverError::NotBound)?;
+    let driver = *DRIVERS
+        .lock()
+        .iter()
+        .find(|d| d.name() == name)
+        .ok_or(DriverError::NotBound)?;
+    driver.detach(id, &device)?;
+    DEVICES.lock()[id.0].driver = None;
+    Ok(())
+}
+
+/// Finds a device by its name
+pub fn find(name: &str) -> Option<DeviceId> {
+    DEVICES
+        .lock()
+        .iter()
+        .position(|device| device.name.as_str() == name)
+        .map(DeviceId)
+}
+
+/// Calls `f` with every device, indexed by `DeviceId`. Parents come before their children.
+pub fn with_devices<R>(f: impl FnOnce(&[Device]) -> R) -> R {
+    f(&DEVICES.lock())
+}
+
+impl DeviceId {
+    /// Position in the slice passed by `with_devices()`
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// Calls `f` with every registered driver
+pub fn with_drivers<R>(f: impl FnOnce(&[&'static dyn Driver]) -> R) -> R {
+    f(&DRIVERS.lock())
+}
+
+/// Whether a device tree node is in use, nodes without a status are
+fn enabled(node: &Node) -> bool {
+    matches!(node.property_str("status"), None | Some("okay" | "ok"))
+}
+
+/// Adds every enabled device tree node that has a compatible string, below the nearest ancestor
+/// that was added as well
+fn discover_device_tree(fdt: &Fdt<'static>) {
+    // (depth, device) of the ancestors that were added
+    let mut ancestors: Vec<(usize, DeviceId)> = Vec::new();
+    for node in fdt.nodes() {
+        while ancestors
+            .last()
+            .is_some_and(|(depth, _)| *depth >= node.depth)
+        {
+            ancestors.pop();
+        }
+        if node.depth == 0 || node.compatible().next().is_none() || !enabled(&node) {
+            continue;
+        }
+        let parent = ancestors.last().map(|(_, id)| *id);
+        let id = add_device(node.name, parent, Resource::DeviceTree(node));
+        ancestors.push((node.depth, id));
+    }
+}
+
+/// Adds the devices described by the firmware & the platform defaults
+pub fn discover() {
+    if let Ok(rsdp) = limine::rsdp() {
+        // the rest of the ACPI namespace needs an AML interpreter
+        if unsafe { crate::acpi::find_table(rsdp, pci::ecam::MCFG_SIGNATURE) }.is_ok() {
+            add_device("pci", None, Resource::Acpi(*pci::ecam::MCFG_SIGNATURE));
+        }
+    }
+    if let Ok(Ok(fdt)) = limine::device_tree().map(Fdt::new) {
+        discover_device_tree(&fdt);
+    }
+    #[cfg(target_arch = "x86_64")]
+    {
+        add_device("i8042", None, Resource::Platform);
+        // configuration through I/O ports, if there is no ECAM
+        if find("pci").is_none() {
+            add_device("pci", None, Resource::Platform);
+        }
+    }
+}
+
+/// Registers the drivers built into the kernel
+pub fn register_builtin() {
+    let mut builtin: ArrayVec<&'static dyn Driver, MAX_DRIVERS> = ArrayVec::new();
+    builtin.push(&pci::HOST_DRIVER);
+    builtin.push(&virtio::blk::DRIVER);
+    builtin.push(&ahci::DRIVER);
+    builtin.push(&nvme::DRIVER);
+    #[cfg(target_arch = "x86_64")]
+    builtin.push(&i8042::DRIVER);
+    for driver in builtin {
+        if let Err(e) = register(driver) {
+            log_at!(LogLevel::Warn, "[WARN] {}\n", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    struct TestDriver(&'static str, &'static [&'static str]);
+
+    impl Driver for TestDriver {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn dependencies(&self) -> &'static [&'static str] {
+            self.1
+        }
+
+        fn probe(&self, _: &Device) -> bool {
+            false
+        }
+
+        fn attach(&self, _: DeviceId, _: &Device) -> Result<(), DriverError> {
+            Ok(())
+        }
+    }
+
+    static BLOCK: TestDriver = TestDriver("block", &["pci", "timer"]);
+    static PCI: TestDriver = TestDriver("pci", &[]);
+    static TIMER: TestDriver = TestDriver("timer", &["pci"]);
+    static LOOP_A: TestDriver = TestDriver("a", &["b"]);
+    static LOOP_B: TestDriver = TestDriver("b", &["a"]);
+    static NEEDS_A: TestDriver = TestDriver("c", &["a"]);
+    static MISSING: TestDriver = TestDriver("usb", &["xhci"]);
+
+    fn names(drivers: &[&'static dyn Driver]) -> Vec<&'static str> {
+        drivers.iter().map(|d| d.name()).collect()
+    }
+
+    unit_test! {
+        fn dependencies_first() {
+            let (ordered, errors) = order(&[&BLOCK, &PCI, &TIMER]);
+            assert_eq!(names(&ordered), ["pci", "timer", "block"]);
+            assert!(errors.is_empty());
+        }
+
+        fn broken_dependencies_are_left_out() {
+            let (ordered, errors) = order(&[&LOOP_A, &PCI, &NEEDS_A, &LOOP_B, &MISSING]);
+            assert_eq!(names(&ordered), ["pci"]);
+            assert_eq!(
+                errors,
+                [
+                    DriverError::MissingDependency { driver: "usb", dependency: "xhci" },
+                    DriverError::DependencyCycle("a"),
+                    DriverError::DependencyCycle("c"),
+                    DriverError::DependencyCycle("b"),
+                ]
+            );
+        }
+    }
+}
@@ -0,0 +1,630 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! NVM Express controllers: every active namespace is added as a `BlockDevice` named like
+//! nvme0n1, for namespace 1 of the first controller.
+//!
+//! Commands go on a submission queue, their results come back on the paired completion queue.
+//! The admin queue pair sets up a single I/O queue pair shared by the namespaces. Data is
+//! bounced through a `DmaBuffer`, described to the controller by PRP entries. No interrupts are
+//! used, new completions are found by their phase bit.
+//!
+//! main sources: NVM Express Base Specification 2.0, NVM Command Set Specification 1.0
+
+use crate::arch::{Current, Mmio};
+use crate::driver::block::{self, BlockDevice, BlockError, BlockFuture, DmaBuffer};
+use crate::driver::pci::bus::{COMMAND_BUS_MASTER, COMMAND_INTX_DISABLE, COMMAND_MEMORY};
+use crate::driver::pci::{self, Function, Match, PciDriver, PciError};
+use crate::driver::{DeviceId, DriverError};
+use crate::log::LogLevel;
+use crate::memman::frame;
+use crate::memman::map::{self, MapArea};
+use crate::memman::stats::Size;
+use crate::{log, log_at};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use arrayvec::ArrayVec;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+const REGISTERS_BAR: usize = 0;
+const CAPABILITIES: usize = 0x00;
+const CONFIGURATION: usize = 0x14;
+const STATUS: usize = 0x1C;
+const ADMIN_QUEUE_ATTRIBUTES: usize = 0x24;
+const ADMIN_SUBMISSION_QUEUE: usize = 0x28;
+const ADMIN_COMPLETION_QUEUE: usize = 0x30;
+const DOORBELLS: usize = 0x1000;
+
+/// The NVM command set is supported, in the capabilities
+const CAPABILITY_NVM: u64 = 1 << 37;
+const CONFIGURATION_ENABLE: u32 = 1 << 0;
+/// 64 byte submission & 16 byte completion entries, as powers of two
+const CONFIGURATION_ENTRY_SIZES: u32 = (6 << 16) | (4 << 20);
+const STATUS_READY: u32 = 1 << 0;
+const STATUS_FATAL: u32 = 1 << 1;
+
+/// Memory page size set in the configuration, the unit of PRP entries
+const MEMORY_PAGE: usize = 4096;
+const SUBMISSION_SIZE: usize = 64;
+const COMPLETION_SIZE: usize = 16;
+const ADMIN_ENTRIES: u16 = 32;
+const IO_ENTRIES: u16 = 64;
+const IO_QUEUE: u16 = 1;
+
+const ADMIN_CREATE_SUBMISSION_QUEUE: u8 = 0x01;
+const ADMIN_CREATE_COMPLETION_QUEUE: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const IDENTIFY_NAMESPACE: u32 = 0x00;
+const IDENTIFY_CONTROLLER: u32 = 0x01;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 0x02;
+const IDENTIFY_SIZE: usize = 4096;
+/// Maximum data transfer size in the controller identity, a power of two of memory pages
+const IDENTIFY_MAX_TRANSFER: usize = 77;
+/// The queue is physically contiguous, when it is created
+const QUEUE_CONTIGUOUS: u32 = 1 << 0;
+
+const IO_FLUSH: u8 = 0x00;
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+/// Most bytes moved by one command, longer transfers are split
+const MAX_TRANSFER: usize = 64 * 1024;
+/// Pages of the largest transfer, the PRP list holds all but the first
+const MAX_PAGES: usize = MAX_TRANSFER / MEMORY_PAGE;
+/// Register polls before giving up on the controller
+const TIMEOUT: usize = 10_000_000;
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "nvme",
+    matches: &[Match::class(0x01, 0x08, Some(0x02))],
+    attach,
+};
+
+/// Number of controllers found so far, names their namespaces
+static CONTROLLERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Error of a controller
+#[derive(Debug)]
+pub enum NvmeError {
+    Registers(PciError),
+    /// The controller did not get ready or disabled in time
+    Timeout,
+    /// The controller reported a fatal status
+    Fatal,
+    /// The controller lacks the NVM command set or 4 KiB memory pages
+    Unsupported,
+    OutOfMemory,
+    /// An admin command failed with this status
+    Command {
+        opcode: u8,
+        status: u16,
+    },
+}
+
+impl fmt::Display for NvmeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Registers(e) => write!(f, "NVMe registers, {}", e),
+            Self::Timeout => write!(f, "NVMe controller timed out"),
+            Self::Fatal => write!(f, "NVMe controller reported a fatal status"),
+            Self::Unsupported => write!(f, "NVMe controller is not supported"),
+            Self::OutOfMemory => write!(f, "out of memory for NVMe queues"),
+            Self::Command { opcode, status } => write!(
+                f,
+                "NVMe admin command 0x{:X} failed with status 0x{:X}",
+                opcode, status
+            ),
+        }
+    }
+}
+
+/// A submission queue entry without the command ID
+#[derive(Debug, Clone, Copy, Default)]
+struct Command {
+    opcode: u8,
+    namespace: u32,
+    /// The two PRP entries of the data
+    prp: (u64, u64),
+    /// Command specific double words 10 to 15
+    dwords: [u32; 6],
+}
+
+/// A submission queue & its completion queue
+struct QueuePair {
+    id: u16,
+    size: u16,
+    submissions: MapArea,
+    completions: MapArea,
+    /// Next submission entry to fill
+    tail: u16,
+    /// Next completion entry to read
+    head: u16,
+    /// Phase bit of new completion entries, flips on every pass through the queue
+    phase: bool,
+    /// Command IDs without a pending command, one less than the entries so the queue never
+    /// looks empty when it is full
+    free: Vec<u16>,
+    /// Status & result of finished commands, by command ID
+    done: Vec<Option<(u16, u32)>>,
+}
+
+impl QueuePair {
+    /// Claims zeroed memory for both queues, `None` if no memory is left
+    fn new(id: u16, size: u16) -> Option<Self> {
+        let pages = |entry: usize| (size as usize * entry).div_ceil(MEMORY_PAGE);
+        let submissions = frame::claim_zeroed_frames(pages(SUBMISSION_SIZE))?;
+        let Some(completions) = frame::claim_zeroed_frames(pages(COMPLETION_SIZE)) else {
+            map::free_global(submissions);
+            return None;
+        };
+        Some(Self {
+            id,
+            size,
+            submissions,
+            completions,
+            tail: 0,
+            head: 0,
+            phase: true,
+            free: (0..size - 1).rev().collect(),
+            done: alloc::vec![None; size as usize],
+        })
+    }
+
+    /// Puts `command` on the submission queue and returns its ID, `None` if the queue is full.
+    /// The new tail is rung afterwards.
+    fn submit(&mut self, command: &Command) -> Option<u16> {
+        let id = self.free.pop()?;
+        let at = self.tail as usize * SUBMISSION_SIZE;
+        let area = &mut self.submissions;
+        area.write(at, command.opcode as u32 | ((id as u32) << 16))
+            .unwrap();
+        area.write(at + 4, command.namespace).unwrap();
+        area.write(at + 8, 0u64).unwrap();
+        area.write(at + 16, 0u64).unwrap();
+        area.write(at + 24, command.prp.0).unwrap();
+        area.write(at + 32, command.prp.1).unwrap();
+        area.write(at + 40, command.dwords).unwrap();
+        self.tail = (self.tail + 1) % self.size;
+        Some(id)
+    }
+
+    /// Reads the new completion entries, returns whether there were any. The new head is rung
+    /// afterwards.
+    fn collect(&mut self) -> bool {
+        let mut found = false;
+        loop {
+            let at = self.head as usize * COMPLETION_SIZE;
+            let last: u32 = self.completions.read(at + 12).unwrap();
+            if (last & (1 << 16) != 0) != self.phase {
+                return found;
+            }
+            found = true;
+            // the entry is read after the phase bit that covers it
+            Current::read_barrier();
+            let result: u32 = self.completions.read(at).unwrap();
+            let id = last as u16;
+            if let Some(done) = self.done.get_mut(id as usize) {
+                *done = Some(((last >> 17) as u16, result));
+            }
+            self.head = (self.head + 1) % self.size;
+            if self.head == 0 {
+                self.phase = !self.phase;
+            }
+        }
+    }
+
+    /// Status & result of the command `id` once it is done, the ID is free again afterwards
+    fn take(&mut self, id: u16) -> Option<(u16, u32)> {
+        let done = self.done.get_mut(id as usize)?.take()?;
+        self.free.push(id);
+        Some(done)
+    }
+
+    fn addresses(&self) -> (u64, u64) {
+        (
+            self.submissions.region().0 as u64,
+            self.completions.region().0 as u64,
+        )
+    }
+
+    /// Returns the memory of the queues, the controller must not use them anymore
+    fn free(self) {
+        map::free_global(self.submissions);
+        map::free_global(self.completions);
+    }
+}
+
+/// The addresses of the pages after the first one, for `len` bytes at the page aligned
+/// `address`
+fn following_pages(address: u64, len: usize) -> ArrayVec<u64, MAX_PAGES> {
+    (1..len.div_ceil(MEMORY_PAGE))
+        .map(|page| address + (page * MEMORY_PAGE) as u64)
+        .collect()
+}
+
+/// Size in sectors & sector size of a namespace, from its identity. `None` for an inactive
+/// namespace.
+fn namespace_geometry(identity: &[u8]) -> Option<(u64, usize)> {
+    let sectors = u64::from_le_bytes(identity[0..8].try_into().unwrap());
+    // the low bits of the formatted LBA size pick one of the formats from offset 128
+    let format = 128 + (identity[26] & 0xF) as usize * 4;
+    let shift = identity[format + 2];
+    match sectors {
+        0 => None,
+        _ if !(9..=16).contains(&shift) => None,
+        _ => Some((sectors, 1 << shift)),
+    }
+}
+
+/// A controller, set up by `start()`
+struct Controller {
+    registers: Mutex<MapArea>,
+    /// Distance between the doorbells
+    stride: usize,
+    admin: Mutex<QueuePair>,
+    io: Mutex<QueuePair>,
+    /// Most bytes in one command
+    max_transfer: usize,
+}
+
+impl Controller {
+    fn read(&self, offset: usize) -> u32 {
+        self.registers.lock().read(offset).unwrap()
+    }
+
+    fn write<T: map::Plain>(&self, offset: usize, value: T) {
+        self.registers.lock().write(offset, value).unwrap();
+    }
+
+    /// Polls until the status ready bit is `ready`
+    fn wait_ready(&self, ready: bool) -> Result<(), NvmeError> {
+        for _ in 0..TIMEOUT {
+            let status = self.read(STATUS);
+            if status & STATUS_FATAL != 0 && ready {
+                return Err(NvmeError::Fatal);
+            }
+            if (status & STATUS_READY != 0) == ready {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(NvmeError::Timeout)
+    }
+
+    /// Resets the controller, starts it on the admin queue and creates the I/O queue
+    fn start(&mut self) -> Result<(), NvmeError> {
+        self.write(CONFIGURATION, 0u32);
+        self.wait_ready(false)?;
+        let (size, (submissions, completions)) = {
+            let admin = self.admin.get_mut();
+            (admin.size as u32 - 1, admin.addresses())
+        };
+        self.write(ADMIN_QUEUE_ATTRIBUTES, (size << 16) | size);
+        self.write(ADMIN_SUBMISSION_QUEUE, submissions);
+        self.write(ADMIN_COMPLETION_QUEUE, completions);
+        self.write(
+            CONFIGURATION,
+            CONFIGURATION_ENTRY_SIZES | CONFIGURATION_ENABLE,
+        );
+        self.wait_ready(true)?;
+
+        let mut identity = DmaBuffer::new(IDENTIFY_SIZE).map_err(|_| NvmeError::OutOfMemory)?;
+        self.identify(IDENTIFY_CONTROLLER, 0, &mut identity)?;
+        let exponent = identity.area().read::<u8>(IDENTIFY_MAX_TRANSFER).unwrap();
+        if exponent != 0 {
+            let limit = MEMORY_PAGE.saturating_mul(1 << exponent.min(32));
+            self.max_transfer = self.max_transfer.min(limit);
+        }
+
+        let (size, (submissions, completions)) = {
+            let io = self.io.get_mut();
+            (((io.size as u32 - 1) << 16) | io.id as u32, io.addresses())
+        };
+        let create = |opcode, queue, flags| Command {
+            opcode,
+            prp: (queue, 0),
+            dwords: [size, flags, 0, 0, 0, 0],
+            ..Default::default()
+        };
+        // the completion queue comes first, the submission queue refers to it
+        let completion = create(ADMIN_CREATE_COMPLETION_QUEUE, completions, QUEUE_CONTIGUOUS);
+        self.run_admin(completion)?;
+        let submission = create(
+            ADMIN_CREATE_SUBMISSION_QUEUE,
+            submissions,
+            ((IO_QUEUE as u32) << 16) | QUEUE_CONTIGUOUS,
+        );
+        self.run_admin(submission)?;
+        Ok(())
+    }
+
+    /// Disables the controller and gives back its memory
+    fn release(self) {
+        self.write(CONFIGURATION, 0u32);
+        let _ = self.wait_ready(false);
+        self.admin.into_inner().free();
+        self.io.into_inner().free();
+        map::free_global(self.registers.into_inner());
+    }
+
+    /// Runs an admin command and waits for it
+    fn run_admin(&self, command: Command) -> Result<u32, NvmeError> {
+        block::block_on(self.run(&self.admin, command)).map_err(|status| NvmeError::Command {
+            opcode: command.opcode,
+            status,
+        })
+    }
+
+    /// Reads the identity `kind` of `namespace` into `buffer`
+    fn identify(&self, kind: u32, namespace: u32, buffer: &mut DmaBuffer) -> Result<(), NvmeError> {
+        buffer.submitted();
+        let result = self.run_admin(Command {
+            opcode: ADMIN_IDENTIFY,
+            namespace,
+            prp: (buffer.address(), 0),
+            dwords: [kind, 0, 0, 0, 0, 0],
+        });
+        buffer.completed();
+        result.map(|_| ())
+    }
+
+    /// Rings the submission tail or the completion head doorbell of `queue`
+    fn ring(&self, queue: u16, completion: bool, value: u16) {
+        let index = 2 * queue as usize + completion as usize;
+        self.write(DOORBELLS + index * self.stride, value as u32);
+    }
+
+    /// Runs `command` on `queue`, returns its result or its status if it failed
+    async fn run(&self, queue: &Mutex<QueuePair>, command: Command) -> Result<u32, u16> {
+        let id = block::poll_until(|| {
+            let mut queue = queue.lock();
+            let id = queue.submit(&command)?;
+            // the entry is complete before the controller fetches it
+            Current::barrier();
+            self.ring(queue.id, false, queue.tail);
+            Some(id)
+        })
+        .await;
+        let (status, result) = block::poll_until(|| {
+            let mut queue = queue.lock();
+            if queue.collect() {
+                self.ring(queue.id, true, queue.head);
+            }
+            queue.take(id)
+        })
+        .await;
+        match status {
+            0 => Ok(result),
+            status => Err(status),
+        }
+    }
+}
+
+fn attach(_: DeviceId, function: &Function) -> Result<(), DriverError> {
+    let config = pci::config().ok_or(DriverError::NoDevice)?;
+    let registers = pci::map_bar(function.bars[REGISTERS_BAR])
+        .map_err(|e| DriverError::failed(NvmeError::Registers(e)))?;
+    // completions are polled
+    function.enable(
+        config,
+        COMMAND_MEMORY | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE,
+    );
+    let capabilities: u64 = registers.read(CAPABILITIES).unwrap();
+    let minimum_page = 1 << (12 + ((capabilities >> 48) & 0xF));
+    if capabilities & CAPABILITY_NVM == 0 || minimum_page > MEMORY_PAGE {
+        map::free_global(registers);
+        return Err(DriverError::failed(NvmeError::Unsupported));
+    }
+    // the queues may hold at most this many entries
+    let entries = (capabilities & 0xFFFF) as u16 + 1;
+    let admin = QueuePair::new(0, ADMIN_ENTRIES.min(entries));
+    let io = QueuePair::new(IO_QUEUE, IO_ENTRIES.min(entries));
+    let (admin, io) = match (admin, io) {
+        (Some(admin), Some(io)) => (admin, io),
+        (admin, io) => {
+            admin.into_iter().chain(io).for_each(QueuePair::free);
+            map::free_global(registers);
+            return Err(DriverError::failed(NvmeError::OutOfMemory));
+        }
+    };
+    let mut controller = Controller {
+        registers: Mutex::new(registers),
+        stride: 4 << ((capabilities >> 32) & 0xF),
+        admin: Mutex::new(admin),
+        io: Mutex::new(io),
+        max_transfer: MAX_TRANSFER,
+    };
+    let namespaces = match controller.start().and_then(|()| namespaces(&controller)) {
+        Ok(namespaces) => namespaces,
+        Err(e) => {
+            controller.release();
+            return Err(DriverError::failed(e));
+        }
+    };
+
+    let index = CONTROLLERS.fetch_add(1, Ordering::SeqCst);
+    let controller = Arc::new(controller);
+    for (id, sectors, sector_size) in namespaces {
+        let name = format!("nvme{}n{}", index, id);
+        let namespace = NvmeNamespace {
+            controller: controller.clone(),
+            id,
+            sectors,
+            sector_size,
+        };
+        match block::add(&name, Arc::new(namespace)) {
+            Ok(()) => log!(
+                "{}: {} sectors ({})\n",
+                name,
+                sectors,
+                Size(sectors as usize * sector_size)
+            ),
+            Err(_) => log_at!(LogLevel::Warn, "[WARN] disk {} exists already\n", name),
+        }
+    }
+    Ok(())
+}
+
+/// ID, size in sectors & sector size of the active namespaces
+fn namespaces(controller: &Controller) -> Result<Vec<(u32, u64, usize)>, NvmeError> {
+    let mut buffer = DmaBuffer::new(IDENTIFY_SIZE).map_err(|_| NvmeError::OutOfMemory)?;
+    controller.identify(IDENTIFY_ACTIVE_NAMESPACES, 0, &mut buffer)?;
+    // a list of IDs, ended by a zero
+    let ids: Vec<u32> = (0..IDENTIFY_SIZE / 4)
+        .map(|index| buffer.area().read::<u32>(index * 4).unwrap())
+        .take_while(|id| *id != 0)
+        .collect();
+    let mut found = Vec::new();
+    let mut identity = [0; IDENTIFY_SIZE];
+    for id in ids {
+        controller.identify(IDENTIFY_NAMESPACE, id, &mut buffer)?;
+        buffer.copy_to(0, &mut identity);
+        if let Some((sectors, sector_size)) = namespace_geometry(&identity) {
+            found.push((id, sectors, sector_size));
+        }
+    }
+    Ok(found)
+}
+
+/// A namespace of a controller
+pub struct NvmeNamespace {
+    controller: Arc<Controller>,
+    id: u32,
+    sectors: u64,
+    sector_size: usize,
+}
+
+impl NvmeNamespace {
+    /// Runs the I/O command `opcode` at `sector` on the first `len` bytes of `buffer`, which
+    /// has room for the PRP list after them
+    async fn io(
+        &self,
+        opcode: u8,
+        sector: u64,
+        buffer: &mut DmaBuffer,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        let address = buffer.address();
+        let pages = following_pages(address, len);
+        let second = match pages.len() {
+            0 => 0,
+            1 => pages[0],
+            _ => {
+                let list = len.next_multiple_of(MEMORY_PAGE);
+                for (index, page) in pages.iter().enumerate() {
+                    buffer.area_mut().write(list + index * 8, *page).unwrap();
+                }
+                address + list as u64
+            }
+        };
+        // the sector count is stored minus one
+        let count = (len / self.sector_size) as u32 - 1;
+        let command = Command {
+            opcode,
+            namespace: self.id,
+            prp: (address, second),
+            dwords: [sector as u32, (sector >> 32) as u32, count, 0, 0, 0],
+        };
+        buffer.submitted();
+        let result = self.controller.run(&self.controller.io, command).await;
+        buffer.completed();
+        result.map(|_| ()).map_err(|_| BlockError::Io)
+    }
+
+    /// Bytes moved by one command, a whole number of sectors
+    fn chunk_size(&self) -> usize {
+        self.controller.max_transfer.max(self.sector_size) / self.sector_size * self.sector_size
+    }
+}
+
+/// Room for the data of `len` bytes and the PRP list behind it
+fn buffer_size(len: usize) -> usize {
+    len.next_multiple_of(MEMORY_PAGE) + MEMORY_PAGE
+}
+
+impl BlockDevice for NvmeNamespace {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check(self, sector, buffer.len(), false)?;
+            let mut sector = sector;
+            for chunk in buffer.chunks_mut(self.chunk_size()) {
+                let mut memory = DmaBuffer::new(buffer_size(chunk.len()))?;
+                self.io(IO_READ, sector, &mut memory, chunk.len()).await?;
+                memory.copy_to(0, chunk);
+                sector += (chunk.len() / self.sector_size) as u64;
+            }
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check(self, sector, buffer.len(), true)?;
+            let mut sector = sector;
+            for chunk in buffer.chunks(self.chunk_size()) {
+                let mut memory = DmaBuffer::new(buffer_size(chunk.len()))?;
+                memory.copy_from(0, chunk);
+                self.io(IO_WRITE, sector, &mut memory, chunk.len()).await?;
+                sector += (chunk.len() / self.sector_size) as u64;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_> {
+        Box::pin(async move {
+            let command = Command {
+                opcode: IO_FLUSH,
+                namespace: self.id,
+                ..Default::default()
+            };
+            let result = self.controller.run(&self.controller.io, command).await;
+            result.map(|_| ()).map_err(|_| BlockError::Io)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    unit_test! {
+        fn prp_pages() {
+            assert!(following_pages(0x10000, 512).is_empty());
+            assert!(following_pages(0x10000, 4096).is_empty());
+            assert_eq!(following_pages(0x10000, 4097).as_slice(), [0x11000]);
+            let all = following_pages(0x10000, MAX_TRANSFER);
+            assert_eq!(all.len(), MAX_PAGES - 1);
+            assert_eq!(all.last(), Some(&0x1F000));
+        }
+
+        fn namespace_formats() {
+            let mut identity = [0; IDENTIFY_SIZE];
+            assert_eq!(namespace_geometry(&identity), None);
+            identity[0..8].copy_from_slice(&0x20_0000u64.to_le_bytes());
+            // format 1 has 4 KiB sectors
+            identity[26] = 1;
+            identity[128 + 2] = 9;
+            identity[132 + 2] = 12;
+            assert_eq!(namespace_geometry(&identity), Some((0x20_0000, 4096)));
+            identity[26] = 0;
+            assert_eq!(namespace_geometry(&identity), Some((0x20_0000, 512)));
+        }
+    }
+}
@@ -0,0 +1,730 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Partition tables: `scan()` reads the table of every disk and adds each partition as a block
+//! device of its own, named after the disk, e.g. vda1 or nvme0n1p1.
+//!
+//! A protective MBR marks a GPT disk. The GPT header & the entry array are checked with their
+//! CRC32, if either is damaged the backup table at the end of the disk is used. Other disks are
+//! read as MBR, the logical partitions in an extended partition are numbered from 5 on.
+//!
+//! main source: UEFI Specification 2.10, chapter 5 "GUID Partition Table (GPT) Disk Layout"
+
+use crate::driver::block::{self, BlockDevice, BlockError, BlockFuture};
+use crate::log::LogLevel;
+use crate::memman::stats::Size;
+use crate::{log, log_at};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use spin::Mutex;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+/// System ID of the MBR partition covering a GPT disk
+const PROTECTIVE: u8 = 0xEE;
+/// System IDs of extended partitions, which hold a chain of extended boot records
+const EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Number of the first logical partition
+const FIRST_LOGICAL: usize = 5;
+/// Most logical partitions that are read, ends cyclic chains
+const MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// Size of the header fields that are read
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRY_SIZE: usize = 128;
+/// Largest entry array that is read, the usual one has 128 entries of 128 bytes
+const MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+/// Partition names are up to 36 UTF-16 code units
+const NAME_OFFSET: usize = 56;
+
+/// GUID as stored on disk, the first three groups are little endian
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const ZERO: Self = Self([0; 16]);
+
+    /// The GUID written as `a-b-c-d-e`, `e` has 48 bits
+    pub const fn new(a: u32, b: u16, c: u16, d: u16, e: u64) -> Self {
+        let (a, b, c) = (a.to_le_bytes(), b.to_le_bytes(), c.to_le_bytes());
+        let (d, e) = (d.to_be_bytes(), e.to_be_bytes());
+        Self([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], e[2], e[3], e[4], e[5],
+            e[6], e[7],
+        ])
+    }
+
+    fn read(bytes: &[u8], offset: usize) -> Self {
+        Self(bytes[offset..offset + 16].try_into().unwrap())
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // bytes in the order they are written
+        const ORDER: [usize; 16] = [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+        for (position, index) in ORDER.iter().enumerate() {
+            if matches!(position, 4 | 6 | 8 | 10) {
+                f.write_char('-')?;
+            }
+            // no padding in the format strings, see the warning on `log!()`
+            for digit in [self.0[*index] >> 4, self.0[*index] & 0xF] {
+                let digit = char::from_digit(digit as u32, 16).unwrap();
+                f.write_char(digit.to_ascii_uppercase())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub const EFI_SYSTEM: Guid = Guid::new(0xC12A7328, 0xF81F, 0x11D2, 0xBA4B, 0x00A0C93EC93B);
+pub const BIOS_BOOT: Guid = Guid::new(0x21686148, 0x6449, 0x6E6F, 0x744E, 0x656564454649);
+pub const BASIC_DATA: Guid = Guid::new(0xEBD0A0A2, 0xB9E5, 0x4433, 0x87C0, 0x68B6B72699C7);
+pub const LINUX_FILESYSTEM: Guid = Guid::new(0x0FC63DAF, 0x8483, 0x4772, 0x8E79, 0x3D69D8477DE4);
+pub const LINUX_SWAP: Guid = Guid::new(0x0657FD6D, 0xA4AB, 0x43C4, 0x84E5, 0x0933C84B4F4F);
+
+/// Names of the common partition types
+const GPT_TYPES: [(Guid, &str); 5] = [
+    (EFI_SYSTEM, "EFI system"),
+    (BIOS_BOOT, "BIOS boot"),
+    (BASIC_DATA, "basic data"),
+    (LINUX_FILESYSTEM, "Linux filesystem"),
+    (LINUX_SWAP, "Linux swap"),
+];
+
+const MBR_TYPES: [(u8, &str); 9] = [
+    (0x01, "FAT12"),
+    (0x04, "FAT16"),
+    (0x06, "FAT16"),
+    (0x07, "NTFS/exFAT"),
+    (0x0B, "FAT32"),
+    (0x0C, "FAT32"),
+    (0x0E, "FAT16"),
+    (0x82, "Linux swap"),
+    (0x83, "Linux"),
+];
+
+/// Error of reading a partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    Block(BlockError),
+    /// The disk has neither a GPT nor an MBR with partitions
+    NoTable,
+    /// The disk has a protective MBR, but both GPTs are damaged
+    BadGpt,
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(e: BlockError) -> Self {
+        Self::Block(e)
+    }
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Block(e) => write!(f, "partition table can not be read, {}", e),
+            Self::NoTable => write!(f, "no partition table"),
+            Self::BadGpt => write!(f, "primary & backup GPT are damaged"),
+        }
+    }
+}
+
+/// What the partition table says about a partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Gpt {
+        /// What the partition is used for, e.g. `EFI_SYSTEM`
+        type_guid: Guid,
+        /// Identifies the partition
+        guid: Guid,
+        name: String,
+    },
+    Mbr {
+        /// What the partition is used for, e.g. 0x0C for FAT32
+        system: u8,
+        bootable: bool,
+    },
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Gpt {
+                type_guid, name, ..
+            } => {
+                match GPT_TYPES.iter().find(|(guid, _)| guid == type_guid) {
+                    Some((_, type_name)) => write!(f, "{}", type_name)?,
+                    None => write!(f, "{}", type_guid)?,
+                }
+                match name.is_empty() {
+                    true => Ok(()),
+                    false => write!(f, " \"{}\"", name),
+                }
+            }
+            Self::Mbr { system, bootable } => {
+                match MBR_TYPES.iter().find(|(id, _)| id == system) {
+                    Some((_, type_name)) => write!(f, "{}", type_name)?,
+                    None => write!(f, "type 0x{:X}", system)?,
+                }
+                match bootable {
+                    true => write!(f, ", bootable"),
+                    false => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// Position in the table from 1 on, names the partition
+    pub number: usize,
+    pub first: u64,
+    pub sectors: u64,
+    pub kind: Kind,
+}
+
+/// The partitions of a disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    /// In the order of their numbers
+    pub partitions: Vec<Partition>,
+    /// The primary GPT is damaged, the partitions come from the backup
+    pub backup: bool,
+}
+
+/// Reads `count` sectors from `first` on, the error is `None` if they are past the end
+async fn read_sectors(
+    disk: &dyn BlockDevice,
+    first: u64,
+    count: usize,
+) -> Result<Option<Vec<u8>>, BlockError> {
+    let mut buffer = alloc::vec![0; count * disk.sector_size()];
+    match disk.read(first, &mut buffer).await {
+        Ok(()) => Ok(Some(buffer)),
+        Err(BlockError::OutOfRange) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// CRC32 as in zlib & Ethernet, which GPT uses
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ 0xEDB88320,
+            _ => crc >> 1,
+        })
+    })
+}
+
+struct MbrEntry {
+    bootable: bool,
+    system: u8,
+    first: u32,
+    sectors: u32,
+}
+
+/// The four entries of a master or extended boot record. `None` without the signature, or if
+/// an entry has an invalid status, e.g. in the boot sector of a filesystem.
+fn mbr_entries(sector: &[u8]) -> Option<[MbrEntry; 4]> {
+    if sector[510..512] != MBR_SIGNATURE {
+        return None;
+    }
+    let entries = core::array::from_fn(|index| {
+        let entry = &sector[MBR_ENTRIES + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        (
+            entry[0],
+            MbrEntry {
+                bootable: entry[0] == 0x80,
+                system: entry[4],
+                first: read_u32(entry, 8),
+                sectors: read_u32(entry, 12),
+            },
+        )
+    });
+    match entries.iter().all(|(status, _)| matches!(status, 0 | 0x80)) {
+        true => Some(entries.map(|(_, entry)| entry)),
+        false => None,
+    }
+}
+
+/// Reads the partition table of `disk`
+pub async fn read_table(disk: &dyn BlockDevice) -> Result<Table, PartitionError> {
+    let sector = read_sectors(disk, 0, 1).await?;
+    let entries = sector
+        .as_deref()
+        .and_then(mbr_entries)
+        .ok_or(PartitionError::NoTable)?;
+    if entries.iter().any(|entry| entry.system == PROTECTIVE) {
+        return read_gpt(disk).await;
+    }
+    let partitions = read_mbr(disk, &entries).await?;
+    match partitions.is_empty() {
+        true => Err(PartitionError::NoTable),
+        false => Ok(Table {
+            partitions,
+            backup: false,
+        }),
+    }
+}
+
+/// The primary partitions, followed by the logical ones. Partitions past the end of the disk
+/// are left out.
+async fn read_mbr(
+    disk: &dyn BlockDevice,
+    entries: &[MbrEntry; 4],
+) -> Result<Vec<Partition>, PartitionError> {
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (index, entry) in entries.iter().enumerate() {
+        if entry.system == 0 || entry.sectors == 0 {
+            continue;
+        }
+        if EXTENDED.contains(&entry.system) {
+            extended = Some(entry.first as u64);
+            continue;
+        }
+        push_mbr(disk, &mut partitions, index + 1, 0, entry);
+    }
+    let Some(extended) = extended else {
+        return Ok(partitions);
+    };
+    // every extended boot record has a logical partition relative to itself & the next record
+    // relative to the extended partition
+    let mut record = extended;
+    let mut number = FIRST_LOGICAL;
+    for _ in 0..MAX_LOGICAL {
+        let Some(entries) = read_sectors(disk, record, 1).await? else {
+            break;
+        };
+        let Some([entry, next, ..]) = mbr_entries(&entries) else {
+            break;
+        };
+        if entry.system != 0 && entry.sectors != 0 {
+            push_mbr(disk, &mut partitions, number, record, &entry);
+            number += 1;
+        }
+        if !EXTENDED.contains(&next.system) || next.first == 0 {
+            break;
+        }
+        record = extended + next.first as u64;
+    }
+    Ok(partitions)
+}
+
+/// Adds the partition of an MBR entry that is relative to `base`, if it is inside of `disk`
+fn push_mbr(
+    disk: &dyn BlockDevice,
+    partitions: &mut Vec<Partition>,
+    number: usize,
+    base: u64,
+    entry: &MbrEntry,
+) {
+    let first = base + entry.first as u64;
+    if first + entry.sectors as u64 > disk.sectors() {
+        return;
+    }
+    partitions.push(Partition {
+        number,
+        first,
+        sectors: entry.sectors as u64,
+        kind: Kind::Mbr {
+            system: entry.system,
+            bootable: entry.bootable,
+        },
+    });
+}
+
+struct GptHeader {
+    /// Sector of the other header
+    alternate: u64,
+    first_usable: u64,
+    last_usable: u64,
+    entries: u64,
+    entry_count: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// The GPT header in sector `lba`, `None` if it is missing or damaged
+async fn read_gpt_header(
+    disk: &dyn BlockDevice,
+    lba: u64,
+) -> Result<Option<GptHeader>, BlockError> {
+    let Some(mut sector) = read_sectors(disk, lba, 1).await? else {
+        return Ok(None);
+    };
+    let size = read_u32(&sector, 12) as usize;
+    if &sector[0..8] != GPT_SIGNATURE || !(GPT_HEADER_SIZE..=sector.len()).contains(&size) {
+        return Ok(None);
+    }
+    // the checksum is calculated with its own field zeroed
+    let crc = read_u32(&sector, 16);
+    sector[16..20].fill(0);
+    if crc32(&sector[..size]) != crc || read_u64(&sector, 24) != lba {
+        return Ok(None);
+    }
+    let header = GptHeader {
+        alternate: read_u64(&sector, 32),
+        first_usable: read_u64(&sector, 40),
+        last_usable: read_u64(&sector, 48),
+        entries: read_u64(&sector, 72),
+        entry_count: read_u32(&sector, 80) as usize,
+        entry_size: read_u32(&sector, 84) as usize,
+        entries_crc: read_u32(&sector, 88),
+    };
+    let valid = header.entry_size >= GPT_ENTRY_SIZE
+        && header.entry_size % 8 == 0
+        && header
+            .entry_count
+            .checked_mul(header.entry_size)
+            .is_some_and(|len| len <= MAX_ENTRIES_SIZE);
+    Ok(valid.then_some(header))
+}
+
+/// The partitions in the entry array of `header`, `None` if it is damaged. Unused entries &
+/// partitions outside of the usable sectors are left out.
+async fn read_gpt_entries(
+    disk: &dyn BlockDevice,
+    header: &GptHeader,
+) -> Result<Option<Vec<Partition>>, BlockError> {
+    let len = header.entry_count * header.entry_size;
+    let count = len.div_ceil(disk.sector_size());
+    let Some(entries) = read_sectors(disk, header.entries, count).await? else {
+        return Ok(None);
+    };
+    if crc32(&entries[..len]) != header.entries_crc {
+        return Ok(None);
+    }
+    let partitions = entries[..len]
+        .chunks(header.entry_size)
+        .enumerate()
+        .filter_map(|(index, entry)| {
+            let type_guid = Guid::read(entry, 0);
+            let first = read_u64(entry, 32);
+            let last = read_u64(entry, 40);
+            if type_guid == Guid::ZERO
+                || first > last
+                || first < header.first_usable
+                || last > header.last_usable
+            {
+                return None;
+            }
+            let units = entry[NAME_OFFSET..GPT_ENTRY_SIZE]
+                .chunks(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|unit| *unit != 0);
+            let name = char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect();
+            Some(Partition {
+                number: index + 1,
+                first,
+                sectors: last - first + 1,
+                kind: Kind::Gpt {
+                    type_guid,
+                    guid: Guid::read(entry, 16),
+                    name,
+                },
+            })
+        })
+        .collect();
+    Ok(Some(partitions))
+}
+
+/// Reads the primary GPT, or the backup if the primary header or its entries are damaged
+async fn read_gpt(disk: &dyn BlockDevice) -> Result<Table, PartitionError> {
+    let primary = read_gpt_header(disk, 1).await?;
+    if let Some(header) = &primary {
+        if let Some(partitions) = read_gpt_entries(disk, header).await? {
+            return Ok(Table {
+                partitions,
+                backup: false,
+            });
+        }
+    }
+    // without a primary header, the backup is in the last sector
+    let alternate = match &primary {
+        Some(header) => header.alternate,
+        None => disk.sectors().saturating_sub(1),
+    };
+    let Some(header) = read_gpt_header(disk, alternate).await? else {
+        return Err(PartitionError::BadGpt);
+    };
+    match read_gpt_entries(disk, &header).await? {
+        Some(partitions) => Ok(Table {
+            partitions,
+            backup: true,
+        }),
+        None => Err(PartitionError::BadGpt),
+    }
+}
+
+/// A partition as a block device of its own, its sectors are moved by the first one
+pub struct PartitionDevice {
+    disk: Arc<dyn BlockDevice>,
+    first: u64,
+    sectors: u64,
+}
+
+impl PartitionDevice {
+    pub fn new(disk: Arc<dyn BlockDevice>, partition: &Partition) -> Self {
+        Self {
+            disk,
+            first: partition.first,
+            sectors: partition.sectors,
+        }
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only()
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check(self, sector, buffer.len(), false)?;
+            self.disk.read(self.first + sector, buffer).await
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check(self, sector, buffer.len(), true)?;
+            self.disk.write(self.first + sector, buffer).await
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_> {
+        self.disk.flush()
+    }
+}
+
+/// Name of partition `number` of the disk `disk`, with a p in between if the disk name ends
+/// with a digit
+pub fn partition_name(disk: &str, number: usize) -> String {
+    match disk.ends_with(|c: char| c.is_ascii_digit()) {
+        true => format!("{}p{}", disk, number),
+        false => format!("{}{}", disk, number),
+    }
+}
+
+/// A partition that was added as a block device
+#[derive(Clone)]
+pub struct Entry {
+    pub name: String,
+    /// Name of the disk the partition is on
+    pub disk: String,
+    pub partition: Partition,
+}
+
+static PARTITIONS: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+/// Adds the partitions of every disk that was not scanned yet as block devices
+pub fn scan() {
+    let disks = block::with_disks(|disks| disks.to_vec());
+    for disk in disks {
+        let known = PARTITIONS
+            .lock()
+            .iter()
+            .any(|entry| entry.name == disk.name || entry.disk == disk.name);
+        if known {
+            continue;
+        }
+        let table = match block::block_on(read_table(&*disk.device)) {
+            Ok(table) => table,
+            Err(PartitionError::NoTable) => continue,
+            Err(e) => {
+                log_at!(LogLevel::Warn, "[WARN] {}: {}\n", disk.name, e);
+                continue;
+            }
+        };
+        if table.backup {
+            log_at!(
+                LogLevel::Warn,
+                "[WARN] {}: primary GPT is damaged, using the backup\n",
+                disk.name
+            );
+        }
+        for partition in table.partitions {
+            let name = partition_name(&disk.name, partition.number);
+            let device = PartitionDevice::new(disk.device.clone(), &partition);
+            if block::add(&name, Arc::new(device)).is_err() {
+                log_at!(LogLevel::Warn, "[WARN] disk {} exists already\n", name);
+                continue;
+            }
+            log!(
+                "{}: {} sectors ({}) from {} on, {}\n",
+                name,
+                partition.sectors,
+                Size(partition.sectors as usize * disk.device.sector_size()),
+                partition.first,
+                partition.kind
+            );
+            PARTITIONS.lock().push(Entry {
+                name,
+                disk: disk.name.clone(),
+                partition,
+            });
+        }
+    }
+}
+
+/// Calls `f` with every partition added by `scan()`, in the order they were added
+pub fn with_partitions<R>(f: impl FnOnce(&[Entry]) -> R) -> R {
+    f(&PARTITIONS.lock())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::block::{block_on, RamDisk};
+    use crate::unit_test;
+
+    const SECTOR: usize = 512;
+    /// Sectors of the test disks
+    const SECTORS: usize = 64;
+
+    /// Writes an MBR entry into `sector`
+    fn mbr_entry(sector: &mut [u8], index: usize, system: u8, first: u32, sectors: u32) {
+        let entry = &mut sector[MBR_ENTRIES + index * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        entry[4] = system;
+        entry[8..12].copy_from_slice(&first.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+        sector[510..512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    /// Writes a GPT header into sector `lba` of `image`, with 4 entries in sector `entries`
+    fn gpt_header(image: &mut [u8], lba: u64, alternate: u64, entries: u64) {
+        let entries_crc = crc32(&image[entries as usize * SECTOR..][..SECTOR]);
+        let header = &mut image[lba as usize * SECTOR..][..SECTOR];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate.to_le_bytes());
+        header[40..48].copy_from_slice(&3u64.to_le_bytes());
+        header[48..56].copy_from_slice(&(SECTORS as u64 - 3).to_le_bytes());
+        header[72..80].copy_from_slice(&entries.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32(&header[..GPT_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// A disk with a protective MBR and two partitions in the primary & the backup GPT
+    fn gpt_image() -> Vec<u8> {
+        let mut image = alloc::vec![0; SECTORS * SECTOR];
+        mbr_entry(&mut image, 0, PROTECTIVE, 1, SECTORS as u32 - 1);
+        let last = SECTORS as u64 - 1;
+        for lba in [2, last - 1] {
+            let entries = &mut image[lba as usize * SECTOR..][..SECTOR];
+            entries[0..16].copy_from_slice(&EFI_SYSTEM.0);
+            entries[32..40].copy_from_slice(&3u64.to_le_bytes());
+            entries[40..48].copy_from_slice(&10u64.to_le_bytes());
+            for (index, unit) in "boot".encode_utf16().enumerate() {
+                entries[NAME_OFFSET + index * 2..][..2].copy_from_slice(&unit.to_le_bytes());
+            }
+            // the second entry is unused
+            let entry = &mut entries[2 * GPT_ENTRY_SIZE..][..GPT_ENTRY_SIZE];
+            entry[0..16].copy_from_slice(&LINUX_FILESYSTEM.0);
+            entry[32..40].copy_from_slice(&11u64.to_le_bytes());
+            entry[40..48].copy_from_slice(&60u64.to_le_bytes());
+        }
+        gpt_header(&mut image, 1, last, 2);
+        gpt_header(&mut image, last, 1, last - 1);
+        image
+    }
+
+    unit_test! {
+        fn crc_and_guids() {
+            assert_eq!(crc32(b"123456789"), 0xCBF43926);
+            assert_eq!(
+                format!("{}", EFI_SYSTEM),
+                "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+            );
+            assert_eq!(EFI_SYSTEM.0[0..4], [0x28, 0x73, 0x2A, 0xC1]);
+        }
+
+        fn gpt_backup() {
+            let table = block_on(read_table(&RamDisk::from_bytes(SECTOR, gpt_image()))).unwrap();
+            assert!(!table.backup);
+            assert_eq!(table.partitions.len(), 2);
+            assert_eq!((table.partitions[0].first, table.partitions[0].sectors), (3, 8));
+            assert_eq!(
+                table.partitions[0].kind,
+                Kind::Gpt { type_guid: EFI_SYSTEM, guid: Guid::ZERO, name: "boot".into() }
+            );
+            assert_eq!((table.partitions[1].number, table.partitions[1].sectors), (3, 50));
+
+            // damaged primary entries, then a damaged primary header
+            let mut image = gpt_image();
+            image[2 * SECTOR + 40] = 11;
+            let backup = block_on(read_table(&RamDisk::from_bytes(SECTOR, image.clone())));
+            assert_eq!(backup.as_ref().map(|table| table.backup), Ok(true));
+            assert_eq!(backup.unwrap().partitions, table.partitions);
+            image[SECTOR] = 0;
+            let backup = block_on(read_table(&RamDisk::from_bytes(SECTOR, image.clone())));
+            assert_eq!(backup.map(|table| table.partitions), Ok(table.partitions));
+            image[(SECTORS - 1) * SECTOR + 88] ^= 1;
+            let disk = RamDisk::from_bytes(SECTOR, image);
+            assert_eq!(block_on(read_table(&disk)), Err(PartitionError::BadGpt));
+        }
+
+        fn mbr_logical() {
+            let mut image = alloc::vec![0; SECTORS * SECTOR];
+            mbr_entry(&mut image, 0, 0x0C, 1, 9);
+            mbr_entry(&mut image, 1, 0x05, 10, 50);
+            // past the end of the disk
+            mbr_entry(&mut image, 2, 0x83, 60, 10);
+            mbr_entry(&mut image[10 * SECTOR..], 0, 0x83, 1, 19);
+            mbr_entry(&mut image[10 * SECTOR..], 1, 0x05, 20, 30);
+            mbr_entry(&mut image[30 * SECTOR..], 0, 0x82, 2, 28);
+            let disk = Arc::new(RamDisk::from_bytes(SECTOR, image));
+            let table = block_on(read_table(&*disk)).unwrap();
+            let layout: Vec<_> = table
+                .partitions
+                .iter()
+                .map(|partition| (partition.number, partition.first, partition.sectors))
+                .collect();
+            assert_eq!(layout, [(1, 1, 9), (5, 11, 19), (6, 32, 28)]);
+
+            // the partitions are moved by their first sector
+            let partition = PartitionDevice::new(disk.clone(), &table.partitions[1]);
+            block_on(partition.write(1, &[0xAB; SECTOR])).unwrap();
+            let mut buffer = [0; SECTOR];
+            block_on(disk.read(12, &mut buffer)).unwrap();
+            assert!(buffer.iter().all(|byte| *byte == 0xAB));
+            assert_eq!(block_on(partition.read(19, &mut buffer)), Err(BlockError::OutOfRange));
+            assert_eq!(partition_name("vda", 5), "vda5");
+            assert_eq!(partition_name("nvme0n1", 1), "nvme0n1p1");
+        }
+    }
+}
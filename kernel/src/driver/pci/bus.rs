@@ -0,0 +1,730 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! The PCI bus independent of how its configuration space is reached: function headers, BARs,
+//! capability lists, the enumeration of buses behind bridges and the IDs drivers match on.
+//!
+//! main sources: https://wiki.osdev.org/PCI, PCI Local Bus Specification 3.0 chapter 6
+
+use alloc::vec::Vec;
+use core::fmt;
+
+// configuration space header, shared by every header type
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION: u16 = 0x08;
+pub const PROG_IF: u16 = 0x09;
+pub const SUBCLASS: u16 = 0x0A;
+pub const CLASS: u16 = 0x0B;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const BAR0: u16 = 0x10;
+/// Secondary bus number of a PCI-to-PCI bridge (header type 1)
+pub const SECONDARY_BUS: u16 = 0x19;
+pub const CAPABILITIES: u16 = 0x34;
+pub const INTERRUPT_LINE: u16 = 0x3C;
+pub const INTERRUPT_PIN: u16 = 0x3D;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+/// The function has a capability list at `CAPABILITIES`
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+pub const HEADER_GENERAL: u8 = 0x00;
+pub const HEADER_BRIDGE: u8 = 0x01;
+/// Read as the vendor ID of a missing function
+const NO_VENDOR: u16 = 0xFFFF;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+/// Vendor specific, the layout after the header is defined by the device (e.g. virtio)
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+/// A capability takes at least 4 bytes after the 64 byte header, more entries mean a loop
+const MAX_CAPABILITIES: usize = 48;
+
+pub const DEVICES: u8 = 32;
+pub const FUNCTIONS: u8 = 8;
+
+/// Location of a function in the configuration space
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Address {
+    /// PCI segment group, always 0 with the legacy mechanism
+    pub segment: u16,
+    pub bus: u8,
+    /// 0 to 31
+    pub device: u8,
+    /// 0 to 7
+    pub function: u8,
+}
+
+impl Address {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+/// Formatted as segment:bus:device.function, like lspci
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // no padding in the format strings, see the warning on `log!()`
+        let pad = |value: u16| if value < 0x10 { "0" } else { "" };
+        write!(
+            f,
+            "{:X}:{}{:X}:",
+            self.segment,
+            pad(self.bus as u16),
+            self.bus
+        )?;
+        write!(
+            f,
+            "{}{:X}.{}",
+            pad(self.device as u16),
+            self.device,
+            self.function
+        )
+    }
+}
+
+/// A way to reach the configuration space, e.g. I/O ports or ECAM.
+///
+/// Only aligned 32 bit accesses are required, the narrower ones are built on top of them.
+pub trait ConfigAccess {
+    /// Reads the 32 bits at `offset`, which is aligned to 4. Missing functions read as all ones.
+    fn read(&self, address: Address, offset: u16) -> u32;
+
+    /// Writes the 32 bits at `offset`, which is aligned to 4
+    fn write(&self, address: Address, offset: u16, value: u32);
+
+    fn read16(&self, address: Address, offset: u16) -> u16 {
+        (self.read(address, offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+
+    fn read8(&self, address: Address, offset: u16) -> u8 {
+        (self.read(address, offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// Read-modify-write of the containing 32 bits.
+    ///
+    /// WARNING: writes back the other half, which clears write-one-to-clear bits that are set.
+    /// Use `Function::set_command()` for the command register.
+    fn write16(&self, address: Address, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read(address, offset & !3) & !(0xFFFF << shift);
+        self.write(address, offset & !3, old | ((value as u32) << shift));
+    }
+}
+
+/// A base address register, 64 bit memory BARs take two slots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io {
+        port: u32,
+        size: u32,
+    },
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// The BAR takes this slot & the next one
+        wide: bool,
+    },
+}
+
+impl Bar {
+    /// Decodes a BAR from its `value` and the `mask` read back after writing all ones. The upper
+    /// 32 bits are the next BAR of a 64 bit memory BAR. `None` if the BAR is not implemented.
+    pub fn decode(value: u64, mask: u64) -> Option<Self> {
+        if value & 1 == 1 {
+            let mut mask = mask as u32 & !0x3;
+            // the upper 16 bits may be hardwired to 0 on x86
+            if mask != 0 && mask & 0xFFFF_0000 == 0 {
+                mask |= 0xFFFF_0000;
+            }
+            return match mask {
+                0 => None,
+                _ => Some(Self::Io {
+                    port: value as u32 & !0x3,
+                    size: (!mask).wrapping_add(1),
+                }),
+            };
+        }
+        let wide = value & 0b110 == 0b100;
+        let (address, mask) = match wide {
+            true => (value & !0xF, mask & !0xF),
+            false => (value & 0xFFFF_FFF0, mask & 0xFFFF_FFF0),
+        };
+        if mask == 0 {
+            return None;
+        }
+        Some(Self::Memory {
+            address,
+            size: match wide {
+                true => (!mask).wrapping_add(1),
+                false => (!(mask as u32)).wrapping_add(1) as u64,
+            },
+            prefetchable: value & (1 << 3) != 0,
+            wide,
+        })
+    }
+
+    /// Physical address or I/O port
+    pub fn address(&self) -> u64 {
+        match *self {
+            Self::Io { port, .. } => port as u64,
+            Self::Memory { address, .. } => address,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match *self {
+            Self::Io { size, .. } => size as u64,
+            Self::Memory { size, .. } => size,
+        }
+    }
+}
+
+/// An entry of the capability list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Offset of the capability in the configuration space
+    pub offset: u8,
+}
+
+/// Iterator over the capability list of a function
+pub struct Capabilities<'a, A: ConfigAccess + ?Sized> {
+    access: &'a A,
+    address: Address,
+    next: u8,
+    left: usize,
+}
+
+impl<A: ConfigAccess + ?Sized> Iterator for Capabilities<'_, A> {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the bottom two bits are reserved, pointers into the header end the list
+        let offset = self.next & !0x3;
+        if offset < 0x40 || self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        let header = self.access.read16(self.address, offset as u16);
+        self.next = (header >> 8) as u8;
+        Some(Capability {
+            id: header as u8,
+            offset,
+        })
+    }
+}
+
+/// Message Signaled Interrupts capability
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Msi {
+    /// Offset of the capability in the configuration space
+    pub offset: u8,
+    /// Vectors the function can request, a power of two up to 32
+    pub vectors: u8,
+    /// The message address can be 64 bit
+    pub wide: bool,
+    pub per_vector_masking: bool,
+}
+
+impl Msi {
+    fn parse(access: &(impl ConfigAccess + ?Sized), address: Address, offset: u8) -> Self {
+        let control = access.read16(address, offset as u16 + 2);
+        Self {
+            offset,
+            vectors: 1 << ((control >> 1) & 0b111).min(5),
+            wide: control & (1 << 7) != 0,
+            per_vector_masking: control & (1 << 8) != 0,
+        }
+    }
+}
+
+/// MSI-X capability, the table & pending bit array are in memory BARs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiX {
+    /// Offset of the capability in the configuration space
+    pub offset: u8,
+    /// Entries in the table
+    pub table_size: u16,
+    /// BAR index & offset of the table
+    pub table: (u8, u32),
+    /// BAR index & offset of the pending bit array
+    pub pending: (u8, u32),
+}
+
+impl MsiX {
+    fn parse(access: &(impl ConfigAccess + ?Sized), address: Address, offset: u8) -> Self {
+        let control = access.read16(address, offset as u16 + 2);
+        let location = |at: u16| {
+            let value = access.read(address, offset as u16 + at);
+            ((value & 0x7) as u8, value & !0x7)
+        };
+        Self {
+            offset,
+            table_size: (control & 0x7FF) + 1,
+            table: location(4),
+            pending: location(8),
+        }
+    }
+}
+
+/// A function found by `enumerate()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub address: Address,
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// Without the multi-function bit, see `HEADER_GENERAL` & `HEADER_BRIDGE`
+    pub header_type: u8,
+    /// Bridges only have the first two
+    pub bars: [Option<Bar>; 6],
+    /// Legacy interrupt pin, 1 to 4 for INTA# to INTD#, 0 for none
+    pub interrupt_pin: u8,
+    pub msi: Option<Msi>,
+    pub msix: Option<MsiX>,
+    /// Bus behind a PCI-to-PCI bridge
+    pub secondary_bus: Option<u8>,
+}
+
+impl Function {
+    /// Reads the header of the function at `address`, `None` if there is no function.
+    ///
+    /// Probing the BAR sizes turns off decoding while the BARs hold all ones, so this must not
+    /// run while a driver uses the function.
+    pub fn read(access: &(impl ConfigAccess + ?Sized), address: Address) -> Option<Self> {
+        let vendor = access.read16(address, VENDOR_ID);
+        if vendor == NO_VENDOR {
+            return None;
+        }
+        let header_type = access.read8(address, HEADER_TYPE) & !HEADER_MULTI_FUNCTION;
+        let bar_count = match header_type {
+            HEADER_GENERAL => 6,
+            HEADER_BRIDGE => 2,
+            _ => 0,
+        };
+        let mut function = Self {
+            address,
+            vendor,
+            device: access.read16(address, DEVICE_ID),
+            class: access.read8(address, CLASS),
+            subclass: access.read8(address, SUBCLASS),
+            prog_if: access.read8(address, PROG_IF),
+            revision: access.read8(address, REVISION),
+            header_type,
+            bars: read_bars(access, address, bar_count),
+            interrupt_pin: access.read8(address, INTERRUPT_PIN),
+            msi: None,
+            msix: None,
+            secondary_bus: match header_type {
+                HEADER_BRIDGE => Some(access.read8(address, SECONDARY_BUS)),
+                _ => None,
+            },
+        };
+        for capability in function.capabilities(access) {
+            match capability.id {
+                CAPABILITY_MSI => {
+                    function.msi = Some(Msi::parse(access, address, capability.offset))
+                }
+                CAPABILITY_MSIX => {
+                    function.msix = Some(MsiX::parse(access, address, capability.offset))
+                }
+                _ => {}
+            }
+        }
+        Some(function)
+    }
+
+    /// The capability list, empty if the function has none
+    pub fn capabilities<'a, A: ConfigAccess + ?Sized>(&self, access: &'a A) -> Capabilities<'a, A> {
+        let present = access.read16(self.address, STATUS) & STATUS_CAPABILITIES != 0;
+        Capabilities {
+            access,
+            address: self.address,
+            next: match present {
+                true => access.read8(self.address, CAPABILITIES),
+                false => 0,
+            },
+            left: MAX_CAPABILITIES,
+        }
+    }
+
+    /// Overwrites the command register, see the `COMMAND_*` bits
+    pub fn set_command(&self, access: &(impl ConfigAccess + ?Sized), command: u16) {
+        // the status register above is write-one-to-clear, so it gets zeros
+        access.write(self.address, COMMAND, command as u32);
+    }
+
+    /// Sets `bits` in the command register, e.g. to enable memory decoding & bus mastering
+    pub fn enable(&self, access: &(impl ConfigAccess + ?Sized), bits: u16) {
+        let command = access.read16(self.address, COMMAND);
+        self.set_command(access, command | bits);
+    }
+}
+
+/// Reads the first `count` BARs and probes their sizes
+fn read_bars(
+    access: &(impl ConfigAccess + ?Sized),
+    address: Address,
+    count: u16,
+) -> [Option<Bar>; 6] {
+    let command = access.read16(address, COMMAND);
+    // the BARs hold all ones for a moment, nothing may decode them meanwhile
+    // like in `Function::set_command()`, the status register gets zeros
+    let disabled = command & !(COMMAND_IO | COMMAND_MEMORY);
+    access.write(address, COMMAND, disabled as u32);
+    let probe = |index: u16| {
+        let offset = BAR0 + index * 4;
+        let value = access.read(address, offset);
+        access.write(address, offset, !0);
+        let mask = access.read(address, offset);
+        access.write(address, offset, value);
+        (value as u64, mask as u64)
+    };
+    let mut bars = [None; 6];
+    let mut index = 0;
+    while index < count {
+        let (mut value, mut mask) = probe(index);
+        // the upper half of a 64 bit memory BAR is the next one
+        let wide = value & 0b111 == 0b100;
+        if wide && index + 1 < count {
+            let (high, high_mask) = probe(index + 1);
+            value |= high << 32;
+            mask |= high_mask << 32;
+        }
+        bars[index as usize] = Bar::decode(value, mask);
+        index += if wide { 2 } else { 1 };
+    }
+    access.write(address, COMMAND, command as u32);
+    bars
+}
+
+/// Finds every function on `bus` and, through bridges, on the buses behind it
+fn scan_bus(
+    access: &(impl ConfigAccess + ?Sized),
+    segment: u16,
+    bus: u8,
+    scanned: &mut [bool; 256],
+    found: &mut Vec<Function>,
+) {
+    // firmware may leave bridges with bogus bus numbers, which could loop
+    if core::mem::replace(&mut scanned[bus as usize], true) {
+        return;
+    }
+    for device in 0..DEVICES {
+        let first = Address::new(segment, bus, device, 0);
+        if access.read16(first, VENDOR_ID) == NO_VENDOR {
+            continue;
+        }
+        let functions = match access.read8(first, HEADER_TYPE) & HEADER_MULTI_FUNCTION {
+            0 => 1,
+            _ => FUNCTIONS,
+        };
+        for function in 0..functions {
+            let Some(function) =
+                Function::read(access, Address::new(segment, bus, device, function))
+            else {
+                continue;
+            };
+            let secondary = function.secondary_bus;
+            found.push(function);
+            if let Some(secondary) = secondary.filter(|secondary| *secondary > bus) {
+                scan_bus(access, segment, secondary, scanned, found);
+            }
+        }
+    }
+}
+
+/// Finds every function of a segment, starting at its first bus `root`. Functions are listed in
+/// the order they were found, bridges before the functions behind them.
+///
+/// A multi-function host bridge at `root`:00.0 means there are several host bridges, the one
+/// with function number n handles bus `root` + n.
+pub fn enumerate(access: &(impl ConfigAccess + ?Sized), segment: u16, root: u8) -> Vec<Function> {
+    let mut scanned = [false; 256];
+    let mut found = Vec::new();
+    let host = Address::new(segment, root, 0, 0);
+    let host_bridges = match access.read8(host, HEADER_TYPE) & HEADER_MULTI_FUNCTION {
+        0 => 1,
+        _ => FUNCTIONS,
+    };
+    for function in 0..host_bridges {
+        let bridge = Address::new(segment, root, 0, function);
+        if function > 0 && access.read16(bridge, VENDOR_ID) == NO_VENDOR {
+            continue;
+        }
+        if let Some(bus) = root.checked_add(function) {
+            scan_bus(access, segment, bus, &mut scanned, &mut found);
+        }
+    }
+    found
+}
+
+/// IDs a driver supports, fields that are `None` match anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match {
+    pub vendor: Option<u16>,
+    pub device: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl Match {
+    /// Matches one device of one vendor
+    pub const fn id(vendor: u16, device: u16) -> Self {
+        Self {
+            vendor: Some(vendor),
+            device: Some(device),
+            class: None,
+            subclass: None,
+            prog_if: None,
+        }
+    }
+
+    /// Matches every device of a class, e.g. (0x01, 0x06, Some(0x01)) for AHCI controllers
+    pub const fn class(class: u8, subclass: u8, prog_if: Option<u8>) -> Self {
+        Self {
+            vendor: None,
+            device: None,
+            class: Some(class),
+            subclass: Some(subclass),
+            prog_if,
+        }
+    }
+
+    pub fn matches(&self, function: &Function) -> bool {
+        fn check<T: PartialEq>(expected: Option<T>, actual: T) -> bool {
+            expected.is_none_or(|expected| expected == actual)
+        }
+        check(self.vendor, function.vendor)
+            && check(self.device, function.device)
+            && check(self.class, function.class)
+            && check(self.subclass, function.subclass)
+            && check(self.prog_if, function.prog_if)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    /// Address, registers & the writable bits of each register
+    type FakeFunction = (Address, [u32; 64], [u32; 64]);
+
+    /// Configuration space of made up functions, writes only change the writable bits
+    struct FakeSpace {
+        functions: RefCell<Vec<FakeFunction>>,
+    }
+
+    impl FakeSpace {
+        fn new() -> Self {
+            Self {
+                functions: RefCell::new(Vec::new()),
+            }
+        }
+
+        /// Adds a function, its command register is writable
+        fn add(&self, address: Address, registers: &[(u16, u32)], writable: &[(u16, u32)]) {
+            let mut config = [0; 64];
+            let mut masks = [0; 64];
+            masks[COMMAND as usize / 4] = 0xFFFF;
+            for (offset, value) in registers {
+                config[*offset as usize / 4] = *value;
+            }
+            for (offset, mask) in writable {
+                masks[*offset as usize / 4] = *mask;
+            }
+            self.functions.borrow_mut().push((address, config, masks));
+        }
+    }
+
+    impl ConfigAccess for FakeSpace {
+        fn read(&self, address: Address, offset: u16) -> u32 {
+            self.functions
+                .borrow()
+                .iter()
+                .find(|f| f.0 == address)
+                .map_or(!0, |f| f.1[offset as usize / 4])
+        }
+
+        fn write(&self, address: Address, offset: u16, value: u32) {
+            let mut functions = self.functions.borrow_mut();
+            if let Some(f) = functions.iter_mut().find(|f| f.0 == address) {
+                let (register, mask) = (&mut f.1[offset as usize / 4], f.2[offset as usize / 4]);
+                *register = (*register & !mask) | (value & mask);
+            }
+        }
+    }
+
+    fn ids(vendor: u16, device: u16) -> u32 {
+        (device as u32) << 16 | vendor as u32
+    }
+
+    /// class, subclass, prog IF & revision register
+    fn class(class: u8, subclass: u8, prog_if: u8) -> u32 {
+        u32::from_be_bytes([class, subclass, prog_if, 1])
+    }
+
+    unit_test! {
+        fn decode_bars() {
+            assert_eq!(
+                Bar::decode(0xC001, 0xFFE1),
+                Some(Bar::Io { port: 0xC000, size: 0x20 })
+            );
+            assert_eq!(
+                Bar::decode(0xFEB0_0008, 0xFFFF_F008),
+                Some(Bar::Memory { address: 0xFEB0_0000, size: 0x1000, prefetchable: true, wide: false })
+            );
+            assert_eq!(
+                Bar::decode(0x8_0000_000C, 0xFFFF_FFFF_FFFF_C00C),
+                Some(Bar::Memory { address: 0x8_0000_0000, size: 0x4000, prefetchable: true, wide: true })
+            );
+            assert_eq!(Bar::decode(0, 0), None);
+            assert_eq!(Bar::decode(1, 1), None);
+        }
+
+        fn address_display() {
+            use alloc::format;
+            assert_eq!(format!("{}", Address::new(0, 0, 0x1F, 3)), "0:00:1F.3");
+            assert_eq!(format!("{}", Address::new(1, 0x3A, 2, 0)), "1:3A:02.0");
+        }
+
+        fn read_function_header() {
+            let space = FakeSpace::new();
+            let address = Address::new(0, 0, 3, 0);
+            space.add(
+                address,
+                &[
+                    (VENDOR_ID, ids(0x1AF4, 0x1042)),
+                    (COMMAND, (STATUS_CAPABILITIES as u32) << 16 | COMMAND_MEMORY as u32),
+                    (REVISION, class(0x01, 0x00, 0x00)),
+                    (BAR0, 0xC001),
+                    (BAR0 + 4, 0xFEB0_0000),
+                    (BAR0 + 16, 0x0000_0004),
+                    (BAR0 + 20, 0x8),
+                    (CAPABILITIES, 0x40),
+                    (INTERRUPT_LINE, 0x0100),
+                    (0x40, 0x0050_0000 | CAPABILITY_MSIX as u32 | 0x50 << 8),
+                    (0x44, 0x0000_3001),
+                    (0x48, 0x0000_3801),
+                    (0x50, 0x0086_0000 | CAPABILITY_MSI as u32),
+                ],
+                &[
+                    (BAR0, 0xFFFF_FFC0),
+                    (BAR0 + 4, 0xFFFF_F000),
+                    (BAR0 + 16, 0xFFFF_C000),
+                    (BAR0 + 20, 0xFFFF_FFFF),
+                ],
+            );
+            let function = Function::read(&space, address).unwrap();
+            assert_eq!((function.vendor, function.device), (0x1AF4, 0x1042));
+            assert_eq!((function.class, function.subclass, function.revision), (1, 0, 1));
+            assert_eq!(function.interrupt_pin, 1);
+            assert_eq!(function.bars[0], Some(Bar::Io { port: 0xC000, size: 0x40 }));
+            assert_eq!(function.bars[1].map(|bar| bar.size()), Some(0x1000));
+            assert_eq!(function.bars[2], None);
+            assert_eq!(
+                function.bars[4],
+                Some(Bar::Memory { address: 0x8_0000_0000, size: 0x4000, prefetchable: false, wide: true })
+            );
+            assert_eq!(function.bars[5], None);
+            assert_eq!(
+                function.msix,
+                Some(MsiX { offset: 0x40, table_size: 0x51, table: (1, 0x3000), pending: (1, 0x3800) })
+            );
+            assert_eq!(
+                function.msi,
+                Some(Msi { offset: 0x50, vectors: 8, wide: true, per_vector_masking: false })
+            );
+            // the BARs & the command register were restored after probing
+            assert_eq!(space.read(address, BAR0 + 4), 0xFEB0_0000);
+            assert_eq!(space.read16(address, COMMAND), COMMAND_MEMORY);
+        }
+
+        fn capability_loop_ends() {
+            let space = FakeSpace::new();
+            let address = Address::new(0, 0, 0, 0);
+            space.add(
+                address,
+                &[
+                    (COMMAND, (STATUS_CAPABILITIES as u32) << 16),
+                    (CAPABILITIES, 0x40),
+                    (0x40, 0x4000 | CAPABILITY_PCI_EXPRESS as u32),
+                ],
+                &[],
+            );
+            let function = Function::read(&space, address).unwrap();
+            assert_eq!(function.capabilities(&space).count(), MAX_CAPABILITIES);
+        }
+
+        fn enumerate_behind_bridges() {
+            let space = FakeSpace::new();
+            let multi = (HEADER_MULTI_FUNCTION as u32) << 16;
+            space.add(Address::new(0, 0, 0, 0), &[(VENDOR_ID, ids(0x8086, 0x29C0))], &[]);
+            space.add(
+                Address::new(0, 0, 2, 0),
+                &[(VENDOR_ID, ids(0x8086, 0x2918)), (HEADER_TYPE & !3, multi)],
+                &[],
+            );
+            space.add(Address::new(0, 0, 2, 3), &[(VENDOR_ID, ids(0x8086, 0x2930))], &[]);
+            // a bridge to bus 1 & one pointing back to bus 0
+            let bridge = (HEADER_BRIDGE as u32) << 16;
+            space.add(
+                Address::new(0, 0, 4, 0),
+                &[(VENDOR_ID, ids(0x1B36, 0x000C)), (HEADER_TYPE & !3, bridge), (0x18, 0x0001_0100)],
+                &[],
+            );
+            space.add(
+                Address::new(0, 1, 0, 0),
+                &[(VENDOR_ID, ids(0x1B36, 0x000C)), (HEADER_TYPE & !3, bridge), (0x18, 0x0000_0001)],
+                &[],
+            );
+            let found: Vec<_> = enumerate(&space, 0, 0).iter().map(|f| f.address).collect();
+            assert_eq!(
+                found,
+                vec![
+                    Address::new(0, 0, 0, 0),
+                    Address::new(0, 0, 2, 0),
+                    Address::new(0, 0, 2, 3),
+                    Address::new(0, 0, 4, 0),
+                    Address::new(0, 1, 0, 0),
+                ]
+            );
+        }
+
+        fn match_ids_and_class() {
+            let space = FakeSpace::new();
+            let address = Address::new(0, 0, 1, 0);
+            space.add(address, &[(VENDOR_ID, ids(0x8086, 0x2922)), (REVISION, class(1, 6, 1))], &[]);
+            let function = Function::read(&space, address).unwrap();
+            assert!(Match::id(0x8086, 0x2922).matches(&function));
+            assert!(!Match::id(0x8086, 0x2923).matches(&function));
+            assert!(Match::class(1, 6, Some(1)).matches(&function));
+            assert!(Match::class(1, 6, None).matches(&function));
+            assert!(!Match::class(1, 8, None).matches(&function));
+        }
+    }
+}
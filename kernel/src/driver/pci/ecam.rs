@@ -0,0 +1,149 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! PCI Express Enhanced Configuration Access Mechanism: the configuration space of every function
+//! is a 4 KiB page of MMIO. The regions come from the ACPI MCFG table or from a
+//! `pci-host-ecam-generic` device tree node.
+//!
+//! main source: PCI Firmware Specification 3.2, chapter 4.1.2 "MCFG Table Description"
+
+use super::bus::{Address, ConfigAccess};
+use super::PciError;
+use crate::acpi::Sdt;
+use crate::arch::{Current, Paging};
+use crate::fdt::Node;
+use crate::memman::map::{self, MapArea};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+/// Reserved bytes between the MCFG header and its entries
+const MCFG_RESERVED: usize = 8;
+const MCFG_ENTRY_SIZE: usize = 16;
+pub const ECAM_COMPATIBLE: &[&str] = &["pci-host-ecam-generic"];
+/// Every bus takes 32 devices * 8 functions * 4 KiB
+const BUS_SIZE: usize = 1 << 20;
+
+/// An ECAM region, covering the buses of one segment
+pub struct Ecam {
+    pub segment: u16,
+    /// First & last bus in the region
+    pub buses: (u8, u8),
+    area: Mutex<MapArea>,
+}
+
+impl Ecam {
+    /// Claims & maps the region at `base` for `buses`, `base` is the address of bus 0 even if
+    /// the region starts at another bus
+    pub fn new(base: u64, segment: u16, buses: (u8, u8)) -> Result<Self, PciError> {
+        if buses.0 > buses.1 {
+            return Err(PciError::BadEcam);
+        }
+        let start = base as usize + buses.0 as usize * BUS_SIZE;
+        let region = (start, base as usize + (buses.1 as usize + 1) * BUS_SIZE);
+        let area = map::claim_global(region).map_err(PciError::Registers)?;
+        if let Err(e) = Current::map_device(region) {
+            map::free_global(area);
+            return Err(PciError::Mapping(e));
+        }
+        Ok(Self {
+            segment,
+            buses,
+            area: Mutex::new(area),
+        })
+    }
+
+    /// Offset of a register in the area, `None` if the function is not in the region
+    fn offset(&self, address: Address, offset: u16) -> Option<usize> {
+        if address.segment != self.segment
+            || address.bus < self.buses.0
+            || address.bus > self.buses.1
+        {
+            return None;
+        }
+        Some(
+            ((address.bus - self.buses.0) as usize * BUS_SIZE)
+                | ((address.device as usize) << 15)
+                | ((address.function as usize) << 12)
+                | (offset as usize & 0xFFC),
+        )
+    }
+}
+
+impl ConfigAccess for Ecam {
+    /// Functions outside of the region read as all ones
+    fn read(&self, address: Address, offset: u16) -> u32 {
+        match self.offset(address, offset) {
+            Some(offset) => self.area.lock().read(offset).unwrap_or(!0),
+            None => !0,
+        }
+    }
+
+    /// Writes to functions outside of the region are ignored
+    fn write(&self, address: Address, offset: u16, value: u32) {
+        if let Some(offset) = self.offset(address, offset) {
+            let _ = self.area.lock().write(offset, value);
+        }
+    }
+}
+
+/// `(base, segment, first bus, last bus)` of every region in an MCFG table
+pub fn mcfg_entries<'a>(mcfg: &Sdt<'a>) -> impl Iterator<Item = (u64, u16, u8, u8)> + 'a {
+    mcfg.data()
+        .get(MCFG_RESERVED..)
+        .unwrap_or(&[])
+        .chunks_exact(MCFG_ENTRY_SIZE)
+        .map(|entry| {
+            (
+                u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+                u16::from_le_bytes(entry[8..10].try_into().unwrap()),
+                entry[10],
+                entry[11],
+            )
+        })
+}
+
+/// Claims every region of the MCFG table, found through the RSDP at the virtual address `rsdp`
+pub fn from_acpi(rsdp: usize) -> Result<Vec<Ecam>, PciError> {
+    let mcfg = unsafe { crate::acpi::find_table(rsdp, MCFG_SIGNATURE) }.map_err(PciError::Acpi)?;
+    mcfg_entries(&mcfg)
+        .map(|(base, segment, first, last)| Ecam::new(base, segment, (first, last)))
+        .collect()
+}
+
+/// Claims the region of a generic ECAM host bridge in the device tree
+pub fn from_node(node: &Node) -> Result<Ecam, PciError> {
+    let (base, size) = node.reg().next().ok_or(PciError::BadEcam)?;
+    let buses = match node.property("bus-range") {
+        Some(range) if range.len() == 8 => (range[3], range[7]),
+        _ => (0, ((size as usize / BUS_SIZE).clamp(1, 256) - 1) as u8),
+    };
+    let segment = node.property_u32("linux,pci-domain").unwrap_or(0);
+    // the reg property starts at the first bus
+    let base = base - buses.0 as u64 * BUS_SIZE as u64;
+    Ecam::new(base, segment as u16, buses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    unit_test! {
+        fn parse_mcfg() {
+            let mut bytes = alloc::vec![0u8; crate::acpi::HEADER_SIZE + MCFG_RESERVED];
+            bytes[..4].copy_from_slice(MCFG_SIGNATURE);
+            bytes.extend_from_slice(&0xB000_0000u64.to_le_bytes());
+            bytes.extend_from_slice(&[0, 0, 0, 0xFF, 0, 0, 0, 0]);
+            let length = bytes.len() as u32;
+            bytes[4..8].copy_from_slice(&length.to_le_bytes());
+            let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            bytes[9] = 0u8.wrapping_sub(sum);
+            let mcfg = Sdt::parse(&bytes).unwrap();
+            assert!(mcfg_entries(&mcfg).eq([(0xB000_0000, 0, 0, 0xFF)]));
+        }
+    }
+}
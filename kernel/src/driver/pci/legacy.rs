@@ -0,0 +1,80 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! x86 configuration mechanism #1: the address is written to one I/O port and the data goes
+//! through another. Only reaches the first 256 bytes of every function and segment 0.
+
+use super::bus::{Address, ConfigAccess};
+use crate::arch::amd64::portio;
+use spin::Mutex;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+const ADDRESS_ENABLE: u32 = 1 << 31;
+
+/// Configuration space through `CONFIG_ADDRESS` & `CONFIG_DATA`
+#[derive(Default)]
+pub struct PortIo {
+    /// Held between writing the address & accessing the data
+    lock: Mutex<()>,
+}
+
+impl PortIo {
+    pub const fn new() -> Self {
+        Self {
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Checks that the address port keeps what is written to it, there is no way to tell
+    /// otherwise whether the mechanism exists
+    pub fn detect(&self) -> bool {
+        let _lock = self.lock.lock();
+        unsafe {
+            let old = portio::input_long(CONFIG_ADDRESS);
+            portio::output_long(CONFIG_ADDRESS, ADDRESS_ENABLE);
+            let present = portio::input_long(CONFIG_ADDRESS) == ADDRESS_ENABLE;
+            portio::output_long(CONFIG_ADDRESS, old);
+            present
+        }
+    }
+
+    fn select(address: Address, offset: u16) {
+        let value = ADDRESS_ENABLE
+            | ((address.bus as u32) << 16)
+            | ((address.device as u32) << 11)
+            | ((address.function as u32) << 8)
+            | (offset as u32 & 0xFC);
+        unsafe { portio::output_long(CONFIG_ADDRESS, value) };
+    }
+}
+
+impl ConfigAccess for PortIo {
+    /// Offsets above 255 & other segments read as all ones
+    fn read(&self, address: Address, offset: u16) -> u32 {
+        if address.segment != 0 || offset > 0xFC {
+            return !0;
+        }
+        // an interrupt handler using the mechanism would deadlock
+        crate::arch::without_interrupts(|| {
+            let _lock = self.lock.lock();
+            Self::select(address, offset);
+            unsafe { portio::input_long(CONFIG_DATA) }
+        })
+    }
+
+    /// Offsets above 255 & other segments are ignored
+    fn write(&self, address: Address, offset: u16, value: u32) {
+        if address.segment != 0 || offset > 0xFC {
+            return;
+        }
+        crate::arch::without_interrupts(|| {
+            let _lock = self.lock.lock();
+            Self::select(address, offset);
+            unsafe { portio::output_long(CONFIG_DATA, value) };
+        })
+    }
+}
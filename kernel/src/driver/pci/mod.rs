@@ -0,0 +1,221 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! PCI & PCI Express. The host bridge is a device of the driver model: `HostDriver` reaches its
+//! configuration space and adds every function behind it as a device, below the bridge it is
+//! on. Function drivers are `PciDriver`s, registered with `driver::register()`.
+//!
+//! The host bridge comes from the ACPI MCFG table or the device tree (ECAM), x86 falls back to
+//! the I/O port mechanism when there is neither.
+
+use crate::arch::{Current, Paging, PagingError};
+use crate::driver::{self, Device, DeviceId, Driver, DriverError, Resource};
+use crate::limine;
+use crate::memman::map::{self, MapArea, MemoryMapperError};
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Once;
+
+pub mod bus;
+pub mod ecam;
+#[cfg(target_arch = "x86_64")]
+pub mod legacy;
+
+pub use bus::{Address, Bar, ConfigAccess, Function, Match};
+
+/// Error of the configuration space lookup
+#[derive(Debug)]
+pub enum PciError {
+    /// Neither ECAM nor the I/O port mechanism was found
+    NoConfigSpace,
+    Acpi(crate::acpi::AcpiError),
+    /// An ECAM region is empty or missing in the device tree
+    BadEcam,
+    Registers(MemoryMapperError),
+    Mapping(PagingError),
+    /// A driver expected a memory BAR, the BAR is missing or in I/O space
+    NotMemoryBar,
+}
+
+impl fmt::Display for PciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoConfigSpace => write!(f, "no PCI configuration space"),
+            Self::Acpi(e) => write!(f, "PCI, {}", e),
+            Self::BadEcam => write!(f, "invalid PCI ECAM region"),
+            Self::Registers(e) => write!(f, "PCI ECAM region or BAR can not be claimed, {:?}", e),
+            Self::Mapping(e) => write!(f, "PCI ECAM region or BAR can not be mapped, {}", e),
+            Self::NotMemoryBar => write!(f, "PCI BAR is not in memory space"),
+        }
+    }
+}
+
+/// How the configuration space is reached
+pub enum ConfigSpace {
+    /// One region per segment or bus range
+    Ecam(Vec<ecam::Ecam>),
+    #[cfg(target_arch = "x86_64")]
+    Legacy(legacy::PortIo),
+}
+
+impl ConfigAccess for ConfigSpace {
+    fn read(&self, address: Address, offset: u16) -> u32 {
+        match self {
+            Self::Ecam(regions) => regions
+                .iter()
+                .map(|region| region.read(address, offset))
+                .find(|value| *value != !0)
+                .unwrap_or(!0),
+            #[cfg(target_arch = "x86_64")]
+            Self::Legacy(ports) => ports.read(address, offset),
+        }
+    }
+
+    fn write(&self, address: Address, offset: u16, value: u32) {
+        match self {
+            // regions ignore functions they do not cover
+            Self::Ecam(regions) => regions
+                .iter()
+                .for_each(|region| region.write(address, offset, value)),
+            #[cfg(target_arch = "x86_64")]
+            Self::Legacy(ports) => ports.write(address, offset, value),
+        }
+    }
+}
+
+/// Claims & maps the memory BAR `bar`, so its registers can be reached through the area
+pub fn map_bar(bar: Option<Bar>) -> Result<MapArea, PciError> {
+    let Some(Bar::Memory { address, size, .. }) = bar else {
+        return Err(PciError::NotMemoryBar);
+    };
+    let region = (address as usize, (address + size) as usize);
+    let area = map::claim_global(region).map_err(PciError::Registers)?;
+    if let Err(e) = Current::map_device(region) {
+        map::free_global(area);
+        return Err(PciError::Mapping(e));
+    }
+    Ok(area)
+}
+
+/// Attaches to the PCI host bridge: reaches its configuration space, enumerates the functions
+/// behind it and adds them as devices. Only one host bridge is supported.
+pub struct HostDriver;
+
+pub static HOST_DRIVER: HostDriver = HostDriver;
+
+static CONFIG: Once<ConfigSpace> = Once::new();
+
+/// The configuration space, `None` before the host bridge is attached
+pub fn config() -> Option<&'static ConfigSpace> {
+    CONFIG.get()
+}
+
+/// Reaches the configuration space described by the host bridge `device`
+fn config_space(device: &Device) -> Result<ConfigSpace, DriverError> {
+    let ecam = match &device.resource {
+        Resource::Acpi(_) => {
+            let rsdp = limine::rsdp().map_err(DriverError::failed)?;
+            ecam::from_acpi(rsdp).map_err(DriverError::failed)?
+        }
+        Resource::DeviceTree(node) => {
+            alloc::vec![ecam::from_node(node).map_err(DriverError::failed)?]
+        }
+        #[cfg(target_arch = "x86_64")]
+        Resource::Platform => {
+            let ports = legacy::PortIo::new();
+            return match ports.detect() {
+                true => Ok(ConfigSpace::Legacy(ports)),
+                false => Err(DriverError::failed(PciError::NoConfigSpace)),
+            };
+        }
+        _ => return Err(DriverError::NoDevice),
+    };
+    match ecam.is_empty() {
+        true => Err(DriverError::failed(PciError::NoConfigSpace)),
+        false => Ok(ConfigSpace::Ecam(ecam)),
+    }
+}
+
+impl Driver for HostDriver {
+    fn name(&self) -> &'static str {
+        "pci"
+    }
+
+    fn probe(&self, device: &Device) -> bool {
+        match &device.resource {
+            Resource::Acpi(signature) => signature == ecam::MCFG_SIGNATURE,
+            Resource::DeviceTree(node) => {
+                ecam::ECAM_COMPATIBLE.iter().any(|c| node.is_compatible(c))
+            }
+            Resource::Platform => cfg!(target_arch = "x86_64") && device.name.as_str() == "pci",
+            Resource::Pci(_) => false,
+        }
+    }
+
+    fn attach(&self, id: DeviceId, device: &Device) -> Result<(), DriverError> {
+        if CONFIG.is_completed() {
+            return Err(DriverError::Failed(
+                "only one PCI host bridge is supported".into(),
+            ));
+        }
+        let config = config_space(device)?;
+        let config = CONFIG.call_once(|| config);
+        let roots: Vec<(u16, u8)> = match config {
+            ConfigSpace::Ecam(regions) => regions.iter().map(|r| (r.segment, r.buses.0)).collect(),
+            #[cfg(target_arch = "x86_64")]
+            ConfigSpace::Legacy(_) => alloc::vec![(0, 0)],
+        };
+        for (segment, root) in roots {
+            // the device of the bridge in front of every bus
+            let mut parents = [None; 256];
+            parents[root as usize] = Some(id);
+            for function in bus::enumerate(config, segment, root) {
+                let parent = parents[function.address.bus as usize].unwrap_or(id);
+                let secondary = function.secondary_bus;
+                let name = format!("pci {}", function.address);
+                let child = driver::add_device(&name, Some(parent), Resource::Pci(function));
+                if let Some(secondary) = secondary {
+                    parents[secondary as usize] = Some(child);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A driver for PCI functions, matched by their IDs. Attaches after the host bridge.
+pub struct PciDriver {
+    pub name: &'static str,
+    /// The functions the driver is probed for
+    pub matches: &'static [Match],
+    /// Takes over a matching function
+    pub attach: fn(DeviceId, &Function) -> Result<(), DriverError>,
+}
+
+impl Driver for PciDriver {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn dependencies(&self) -> &'static [&'static str] {
+        &["pci"]
+    }
+
+    fn probe(&self, device: &Device) -> bool {
+        match &device.resource {
+            Resource::Pci(function) => self.matches.iter().any(|m| m.matches(function)),
+            _ => false,
+        }
+    }
+
+    fn attach(&self, id: DeviceId, device: &Device) -> Result<(), DriverError> {
+        match &device.resource {
+            Resource::Pci(function) => (self.attach)(id, function),
+            _ => Err(DriverError::NoDevice),
+        }
+    }
+}
@@ -1,18 +1,61 @@
 #[cfg(target_arch = "x86_64")]
 mod main {
-    use crate::arch::portio;
+    use crate::arch::amd64::portio;
+    use crate::cmdline::{AnyParam, FromParam, Param};
+    use core::sync::atomic::{AtomicU16, Ordering};
 
     const COM1: u16 = 0x3F8;
+    /// Frequency of the UART clock divided by 16, the divisor for a baud rate is this / baud
+    const UART_BASE_BAUD: u32 = 115_200;
+
+    /// I/O port of a 16550 UART, `com1` to `com4` or a port number
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct SerialPort(pub u16);
+
+    impl FromParam for SerialPort {
+        fn from_param(value: &'static str) -> Result<Self, &'static str> {
+            match value {
+                "com1" => Ok(Self(0x3F8)),
+                "com2" => Ok(Self(0x2F8)),
+                "com3" => Ok(Self(0x3E8)),
+                "com4" => Ok(Self(0x2E8)),
+                _ => u16::from_param(value)
+                    .map(Self)
+                    .map_err(|_| "expected com1-com4 or a port number"),
+            }
+        }
+    }
+
+    pub static PORT: Param<SerialPort> = Param::new(
+        "serial.port",
+        "serial port used for logging: com1-com4 or a port number",
+        SerialPort(COM1),
+    );
+
+    pub static BAUD: Param<u32> = Param::new("serial.baud", "serial baud rate", 115_200);
+
+    /// Port currently written to, changed by `configure()`
+    static ACTIVE_PORT: AtomicU16 = AtomicU16::new(COM1);
 
     pub fn write(text: &str) {
+        let port = ACTIVE_PORT.load(Ordering::Relaxed);
         for char in text.bytes() {
-            write_com1(char);
+            unsafe {
+                portio::output_byte(port, char);
+            }
         }
     }
 
-    fn write_com1(byte: u8) {
+    /// Takes a received byte, if there is one. The UART is polled, its interrupts stay disabled.
+    pub fn read() -> Option<u8> {
+        /// line status register, bit 0 is set when a byte was received
+        const LINE_STATUS: u16 = 5;
+        let port = ACTIVE_PORT.load(Ordering::Relaxed);
         unsafe {
-            portio::output_byte(COM1, byte);
+            if portio::input_byte(port + LINE_STATUS) & 1 == 0 {
+                return None;
+            }
+            Some(portio::input_byte(port))
         }
     }
 
@@ -22,14 +65,136 @@ mod main {
         }
         write("Serial initialized!\n");
     }
+
+    /// Switches to the port & baud rate selected on the command line
+    pub fn configure() {
+        let SerialPort(port) = PORT.get();
+        let divisor = (UART_BASE_BAUD / BAUD.get().clamp(1, UART_BASE_BAUD)) as u16;
+        unsafe {
+            // disable interrupts
+            portio::output_byte(port + 1, 0x00);
+            // set the divisor with DLAB enabled
+            portio::output_byte(port + 3, 0x80);
+            portio::output_byte(port, divisor as u8);
+            portio::output_byte(port + 1, (divisor >> 8) as u8);
+            // 8 bits, no parity, one stop bit
+            portio::output_byte(port + 3, 0x03);
+            // enable & clear the FIFOs
+            portio::output_byte(port + 2, 0xC7);
+        }
+        ACTIVE_PORT.store(port, Ordering::Relaxed);
+    }
+
+    /// Command line options of the serial driver
+    pub static PARAMS: &[&dyn AnyParam] = &[&PORT, &BAUD];
 }
 #[cfg(target_arch = "x86_64")]
 pub use main::*;
 
 #[cfg(target_arch = "aarch64")]
-mod empty {
+mod pl011 {
+    // ARM PrimeCell UART (PL011), left configured by the firmware
+    // main source: ARM PrimeCell UART (PL011) Technical Reference Manual, r1p5
+
+    use crate::arch::{Current, Paging, PagingError};
+    use crate::cmdline::AnyParam;
+    use crate::fdt::Fdt;
+    use crate::memman::map::{self, MapArea, MemoryMapperError};
+    use core::fmt;
+    use spin::Once;
+
+    const COMPATIBLE: &[&str] = &["arm,pl011"];
+    /// Data register
+    const UARTDR: usize = 0x000;
+    /// Flag register
+    const UARTFR: usize = 0x018;
+    /// UARTFR.RXFE, set while the receive FIFO is empty
+    const UARTFR_RXFE: u32 = 1 << 4;
+    /// UARTFR.TXFF, set while the transmit FIFO is full
+    const UARTFR_TXFF: u32 = 1 << 5;
+
+    /// Error returned by `attach()`
+    #[derive(Debug)]
+    pub enum SerialError {
+        /// The device tree has no PL011
+        NotFound,
+        /// The registers are not in the device tree or could not be claimed
+        Registers(Option<MemoryMapperError>),
+        Mapping(PagingError),
+    }
+
+    impl fmt::Display for SerialError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Self::NotFound => write!(f, "no PL011 UART in the device tree"),
+                Self::Registers(None) => write!(f, "UART registers are missing"),
+                Self::Registers(Some(e)) => write!(f, "UART registers can not be claimed, {:?}", e),
+                Self::Mapping(e) => write!(f, "UART registers can not be mapped, {}", e),
+            }
+        }
+    }
+
+    /// Registers of the UART, claimed & mapped by `attach()`
+    static UART: Once<MapArea> = Once::new();
+
+    /// The registers are only reachable once the global memory map exists, see `attach()`
     pub fn init() {}
-    pub fn write(text: &str) {}
+
+    /// The baud rate is kept from the firmware
+    pub fn configure() {}
+
+    /// Claims & maps the first PL011 of the device tree, text written before is dropped
+    pub fn attach(fdt: &Fdt) -> Result<(), SerialError> {
+        let node = fdt
+            .find_compatible(COMPATIBLE)
+            .ok_or(SerialError::NotFound)?;
+        let (start, size) = node.reg().next().ok_or(SerialError::Registers(None))?;
+        let region = (start as usize, (start + size) as usize);
+        let area = map::claim_global(region).map_err(|e| SerialError::Registers(Some(e)))?;
+        if let Err(e) = Current::map_device(region) {
+            map::free_global(area);
+            return Err(SerialError::Mapping(e));
+        }
+        UART.call_once(|| area);
+        write("Serial initialized!\n");
+        Ok(())
+    }
+
+    /// Address of the register at `offset`. Registers are accessed without locking, like the
+    /// port I/O of the x86_64 UART, so writing keeps working inside of the panic handler.
+    fn register(offset: usize) -> Option<*mut u32> {
+        UART.get()
+            .map(|area| (area.virtual_address() + offset) as *mut u32)
+    }
+
+    pub fn write(text: &str) {
+        let (Some(data), Some(flags)) = (register(UARTDR), register(UARTFR)) else {
+            return;
+        };
+        for char in text.bytes() {
+            // the registers were mapped by `attach()`
+            unsafe {
+                while core::ptr::read_volatile(flags) & UARTFR_TXFF != 0 {
+                    core::hint::spin_loop();
+                }
+                core::ptr::write_volatile(data, char as u32);
+            }
+        }
+    }
+
+    /// Takes a received byte, if there is one. The UART is polled, its interrupts stay disabled.
+    pub fn read() -> Option<u8> {
+        let (data, flags) = (register(UARTDR)?, register(UARTFR)?);
+        unsafe {
+            if core::ptr::read_volatile(flags) & UARTFR_RXFE != 0 {
+                return None;
+            }
+            Some(core::ptr::read_volatile(data) as u8)
+        }
+    }
+
+    /// Command line options of the serial driver
+    pub static PARAMS: &[&dyn AnyParam] = &[];
 }
 #[cfg(target_arch = "aarch64")]
-pub use empty::*;
+pub use pl011::*;
@@ -0,0 +1,266 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! virtio-blk: a disk behind the VirtIO transport, added as a `BlockDevice` named vda, vdb, ...
+//!
+//! Every request is a chain of three buffers on the only queue: a header with the type & the
+//! first sector, the data and a status byte written by the device. All three are in a
+//! `DmaBuffer` claimed for the request.
+//!
+//! main source: Virtual I/O Device (VIRTIO) Version 1.2, chapter 5.2 "Block Device"
+
+use super::queue::Buffer;
+use super::{Transport, VirtQueue, VirtioError, MODERN_DEVICE, VENDOR};
+use crate::driver::block::{self, BlockDevice, BlockError, BlockFuture, DmaBuffer};
+use crate::driver::pci::{self, Function, Match, PciDriver};
+use crate::driver::{DeviceId, DriverError};
+use crate::log;
+use crate::memman::stats::Size;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// Device type of a block device
+const DEVICE_TYPE: u16 = 2;
+/// Device ID of the transitional block device, which has the modern interface as well
+const TRANSITIONAL_DEVICE: u16 = 0x1001;
+
+const FEATURE_SIZE_MAX: u64 = 1 << 1;
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+// device specific configuration
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_SIZE_MAX: usize = 8;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// Requests always count in sectors of 512 bytes, whatever the block size of the disk is
+const SECTOR_SIZE: usize = 512;
+const HEADER_SIZE: usize = 16;
+/// The status byte follows the header, the data starts at the next sector boundary
+const STATUS_OFFSET: usize = HEADER_SIZE;
+const DATA_OFFSET: usize = SECTOR_SIZE;
+/// Most bytes moved by one request, longer transfers are split
+const MAX_TRANSFER: usize = 64 * 1024;
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &[
+        Match::id(VENDOR, TRANSITIONAL_DEVICE),
+        Match::id(VENDOR, MODERN_DEVICE + DEVICE_TYPE),
+    ],
+    attach,
+};
+
+/// Number of disks added so far, names them
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+
+fn attach(_: DeviceId, function: &Function) -> Result<(), DriverError> {
+    let config = pci::config().ok_or(DriverError::NoDevice)?;
+    let transport = Transport::new(config, function).map_err(DriverError::failed)?;
+    let disk = VirtioBlock::new(transport).map_err(DriverError::failed)?;
+    let name = block::letter_name("vd", DISKS.fetch_add(1, Ordering::SeqCst));
+    let sectors = disk.sectors;
+    let read_only = match disk.read_only {
+        true => ", read-only",
+        false => "",
+    };
+    block::add(&name, Arc::new(disk))
+        .map_err(|_| DriverError::Failed(alloc::format!("disk {} exists already", name)))?;
+    log!(
+        "{}: {} sectors ({}){}\n",
+        name,
+        sectors,
+        Size(sectors as usize * SECTOR_SIZE),
+        read_only
+    );
+    Ok(())
+}
+
+/// The data of a request
+enum Data<'a> {
+    None,
+    /// Filled by the device
+    In(&'a mut [u8]),
+    /// Read by the device
+    Out(&'a [u8]),
+}
+
+impl Data<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::In(data) => data.len(),
+            Self::Out(data) => data.len(),
+        }
+    }
+}
+
+struct State {
+    transport: Transport,
+    queue: VirtQueue,
+}
+
+/// A virtio-blk disk, see the module documentation
+pub struct VirtioBlock {
+    state: Mutex<State>,
+    sectors: u64,
+    read_only: bool,
+    /// The device keeps writes in a cache until it is flushed
+    flush: bool,
+    /// Most bytes in one request, a whole number of sectors
+    max_transfer: usize,
+}
+
+impl VirtioBlock {
+    /// Negotiates the features, reads the geometry and starts the device. The transport is
+    /// failed if the device does not work.
+    pub fn new(mut transport: Transport) -> Result<Self, VirtioError> {
+        let wanted = FEATURE_SIZE_MAX | FEATURE_READ_ONLY | FEATURE_FLUSH;
+        let features = match transport.negotiate(wanted) {
+            Ok(features) => features,
+            Err(e) => {
+                transport.fail();
+                return Err(e);
+            }
+        };
+        let mut max_transfer = MAX_TRANSFER;
+        if features & FEATURE_SIZE_MAX != 0 {
+            let size_max: u32 = transport.config(CONFIG_SIZE_MAX).unwrap_or(0);
+            max_transfer = max_transfer.min(size_max as usize / SECTOR_SIZE * SECTOR_SIZE);
+        }
+        let sectors = transport.config64(CONFIG_CAPACITY).unwrap_or(0);
+        let queue = match transport.queue(0) {
+            Ok(queue) => queue,
+            Err(e) => {
+                transport.fail();
+                return Err(e);
+            }
+        };
+        transport.start();
+        Ok(Self {
+            state: Mutex::new(State { transport, queue }),
+            sectors,
+            read_only: features & FEATURE_READ_ONLY != 0,
+            flush: features & FEATURE_FLUSH != 0,
+            max_transfer: max_transfer.max(SECTOR_SIZE),
+        })
+    }
+
+    /// Runs one request of `kind` at `sector`, `data` is at most `max_transfer` bytes
+    async fn request(&self, kind: u32, sector: u64, data: Data<'_>) -> Result<(), BlockError> {
+        let len = data.len();
+        let mut memory = DmaBuffer::new(DATA_OFFSET + len)?;
+        let start = memory.address();
+        let area = memory.area_mut();
+        area.write(0, kind).unwrap();
+        area.write(4, 0u32).unwrap();
+        area.write(8, sector).unwrap();
+        area.write(STATUS_OFFSET, !0u8).unwrap();
+        if let Data::Out(data) = &data {
+            memory.copy_from(DATA_OFFSET, data);
+        }
+
+        let mut buffers: ArrayVec<Buffer, 3> = ArrayVec::new();
+        buffers.push(Buffer {
+            address: start,
+            len: HEADER_SIZE as u32,
+            writable: false,
+        });
+        if len > 0 {
+            buffers.push(Buffer {
+                address: start + DATA_OFFSET as u64,
+                len: len as u32,
+                writable: kind == REQUEST_IN,
+            });
+        }
+        buffers.push(Buffer {
+            address: start + STATUS_OFFSET as u64,
+            len: 1,
+            writable: true,
+        });
+
+        let head = block::poll_until(|| {
+            let mut state = self.state.lock();
+            let State { transport, queue } = &mut *state;
+            let head = queue.split.push(&buffers)?;
+            transport.notify(queue);
+            Some(head)
+        })
+        .await;
+        memory.submitted();
+        block::poll_until(|| self.state.lock().queue.split.take(head)).await;
+        memory.completed();
+
+        match memory.area().read::<u8>(STATUS_OFFSET).unwrap() {
+            STATUS_OK => {}
+            STATUS_UNSUPPORTED => return Err(BlockError::Unsupported),
+            _ => return Err(BlockError::Io),
+        }
+        if let Data::In(data) = data {
+            memory.copy_to(DATA_OFFSET, data);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check(self, sector, buffer.len(), false)?;
+            let mut sector = sector;
+            for chunk in buffer.chunks_mut(self.max_transfer) {
+                let count = (chunk.len() / SECTOR_SIZE) as u64;
+                self.request(REQUEST_IN, sector, Data::In(chunk)).await?;
+                sector += count;
+            }
+            Ok(())
+        })
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(async move {
+            block::check(self, sector, buffer.len(), true)?;
+            let mut sector = sector;
+            for chunk in buffer.chunks(self.max_transfer) {
+                let count = (chunk.len() / SECTOR_SIZE) as u64;
+                self.request(REQUEST_OUT, sector, Data::Out(chunk)).await?;
+                sector += count;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&self) -> BlockFuture<'_> {
+        Box::pin(async move {
+            match self.flush {
+                true => self.request(REQUEST_FLUSH, 0, Data::None).await,
+                // writes go straight to the disk
+                false => Ok(()),
+            }
+        })
+    }
+}
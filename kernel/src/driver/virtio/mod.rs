@@ -0,0 +1,439 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! VirtIO devices on PCI, through the modern transport: the registers are split into
+//! configuration structures inside of memory BARs, found through vendor specific capabilities.
+//! `Transport` runs the initialization sequence, negotiates the features and sets up the
+//! virtqueues (see `queue.rs`). Device drivers, like virtio-blk in `blk.rs`, are `PciDriver`s
+//! built on top of it.
+//!
+//! Legacy devices (only I/O port registers) are not supported. No interrupts are used, the
+//! drivers poll the used rings of their queues.
+//!
+//! main source: Virtual I/O Device (VIRTIO) Version 1.2, chapters 3.1 & 4.1
+
+use super::pci::bus::{CAPABILITY_VENDOR, COMMAND_BUS_MASTER, COMMAND_MEMORY};
+use super::pci::{Bar, ConfigAccess, Function};
+use crate::arch::{Current, Mmio, Paging, PagingError};
+use crate::memman::map::{self, MapArea, MemoryMapperError, Plain};
+use alloc::vec::Vec;
+use core::fmt;
+use queue::SplitQueue;
+
+pub mod blk;
+pub mod queue;
+
+pub const VENDOR: u16 = 0x1AF4;
+/// Device ID of a modern-only device is this plus the device type
+pub const MODERN_DEVICE: u16 = 0x1040;
+
+// `cfg_type` of the vendor capabilities
+const STRUCTURE_COMMON: u8 = 1;
+const STRUCTURE_NOTIFY: u8 = 2;
+const STRUCTURE_DEVICE: u8 = 4;
+
+// common configuration structure
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0C;
+const MSIX_CONFIG: usize = 0x10;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_MSIX_VECTOR: usize = 0x1A;
+const QUEUE_ENABLE: usize = 0x1C;
+const QUEUE_NOTIFY_OFF: usize = 0x1E;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+const COMMON_SIZE: u32 = 0x38;
+/// Written to the MSI-X vector registers, the device does not interrupt
+const NO_VECTOR: u16 = 0xFFFF;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// The device follows VIRTIO 1.0 or later, required by the modern transport
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// Error of the transport
+#[derive(Debug)]
+pub enum VirtioError {
+    /// A configuration structure is missing, the name says which one
+    MissingStructure(&'static str),
+    /// A structure is not inside of the memory BAR with this index
+    BadBar(u8),
+    Registers(MemoryMapperError),
+    Mapping(PagingError),
+    /// The device did not accept the features, or does not offer `FEATURE_VERSION_1`
+    FeaturesRejected,
+    /// The device has no queue with this index
+    NoQueue(u16),
+    OutOfMemory,
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingStructure(name) => write!(f, "virtio {} structure is missing", name),
+            Self::BadBar(bar) => write!(f, "virtio structure is outside of BAR{}", bar),
+            Self::Registers(e) => write!(f, "virtio BAR can not be claimed, {:?}", e),
+            Self::Mapping(e) => write!(f, "virtio BAR can not be mapped, {}", e),
+            Self::FeaturesRejected => write!(f, "virtio features were rejected"),
+            Self::NoQueue(index) => write!(f, "virtio queue {} does not exist", index),
+            Self::OutOfMemory => write!(f, "out of memory for a virtqueue"),
+        }
+    }
+}
+
+/// A configuration structure, at `offset` inside of the memory BAR `bar`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Structure {
+    pub bar: u8,
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// The configuration structures of a function, the first capability of every type is used
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Structures {
+    pub common: Option<Structure>,
+    /// With the multiplier of the queue notify offsets
+    pub notify: Option<(Structure, u32)>,
+    /// Device specific configuration, e.g. the capacity of a disk
+    pub device: Option<Structure>,
+}
+
+impl Structures {
+    /// Reads the vendor capabilities of `function`
+    pub fn find(access: &(impl ConfigAccess + ?Sized), function: &Function) -> Self {
+        let mut found = Self::default();
+        let vendor = function.capabilities(access);
+        for capability in vendor.filter(|c| c.id == CAPABILITY_VENDOR) {
+            let at = capability.offset as u16;
+            let kind = access.read8(function.address, at + 3);
+            let structure = Structure {
+                bar: access.read8(function.address, at + 4),
+                offset: access.read(function.address, at + 8),
+                len: access.read(function.address, at + 12),
+            };
+            // BAR numbers above 5 are reserved
+            if structure.bar > 5 {
+                continue;
+            }
+            match kind {
+                STRUCTURE_COMMON => {
+                    found.common.get_or_insert(structure);
+                }
+                STRUCTURE_NOTIFY => {
+                    let multiplier = access.read(function.address, at + 16);
+                    found.notify.get_or_insert((structure, multiplier));
+                }
+                STRUCTURE_DEVICE => {
+                    found.device.get_or_insert(structure);
+                }
+                // the ISR status is only needed with legacy interrupts
+                _ => {}
+            }
+        }
+        found
+    }
+}
+
+/// A virtqueue of a device, set up by `Transport::queue()`
+pub struct VirtQueue {
+    pub index: u16,
+    pub split: SplitQueue,
+    /// Offset of the doorbell in the notify structure
+    notify: usize,
+}
+
+/// The modern PCI transport of a function
+pub struct Transport {
+    /// Claimed memory BARs holding the structures, by BAR index
+    bars: Vec<(u8, MapArea)>,
+    common: Structure,
+    notify: Structure,
+    notify_multiplier: u32,
+    device: Option<Structure>,
+}
+
+impl Transport {
+    /// Maps the structures of `function`, enables it and resets the device. The device is
+    /// acknowledged afterwards, features are negotiated next.
+    pub fn new(
+        access: &(impl ConfigAccess + ?Sized),
+        function: &Function,
+    ) -> Result<Self, VirtioError> {
+        let structures = Structures::find(access, function);
+        let common = structures
+            .common
+            .ok_or(VirtioError::MissingStructure("common"))?;
+        let (notify, notify_multiplier) = structures
+            .notify
+            .ok_or(VirtioError::MissingStructure("notify"))?;
+        if common.len < COMMON_SIZE {
+            return Err(VirtioError::MissingStructure("common"));
+        }
+        let mut transport = Self {
+            bars: Vec::new(),
+            common,
+            notify,
+            notify_multiplier,
+            device: structures.device,
+        };
+        for structure in [Some(common), Some(notify), structures.device]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(e) = transport.claim(function, structure) {
+                transport.free();
+                return Err(e);
+            }
+        }
+        function.enable(access, COMMAND_MEMORY | COMMAND_BUS_MASTER);
+        transport.write(common, DEVICE_STATUS, 0u8);
+        // the reset is done once the status reads as 0
+        while transport.read::<u8>(common, DEVICE_STATUS) != 0 {
+            core::hint::spin_loop();
+        }
+        transport.write(common, MSIX_CONFIG, NO_VECTOR);
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(transport)
+    }
+
+    /// Claims & maps the BAR of `structure`, unless it already is
+    fn claim(&mut self, function: &Function, structure: Structure) -> Result<(), VirtioError> {
+        let bad = VirtioError::BadBar(structure.bar);
+        let Some(Bar::Memory { address, size, .. }) = function.bars[structure.bar as usize] else {
+            return Err(bad);
+        };
+        if structure.offset as u64 + structure.len as u64 > size {
+            return Err(bad);
+        }
+        if self.bars.iter().any(|(bar, _)| *bar == structure.bar) {
+            return Ok(());
+        }
+        let region = (address as usize, (address + size) as usize);
+        let area = map::claim_global(region).map_err(VirtioError::Registers)?;
+        if let Err(e) = Current::map_device(region) {
+            map::free_global(area);
+            return Err(VirtioError::Mapping(e));
+        }
+        self.bars.push((structure.bar, area));
+        Ok(())
+    }
+
+    /// Gives back the claimed BARs
+    fn free(self) {
+        for (_, area) in self.bars {
+            map::free_global(area);
+        }
+    }
+
+    /// Area of the BAR of `structure`, it was claimed by `new()`
+    fn area(&self, structure: Structure) -> &MapArea {
+        let (_, area) = self
+            .bars
+            .iter()
+            .find(|(bar, _)| *bar == structure.bar)
+            .unwrap();
+        area
+    }
+
+    fn read<T: Plain>(&self, structure: Structure, offset: usize) -> T {
+        self.area(structure)
+            .read(structure.offset as usize + offset)
+            .unwrap()
+    }
+
+    fn write<T: Plain>(&mut self, structure: Structure, offset: usize, value: T) {
+        let (_, area) = self
+            .bars
+            .iter_mut()
+            .find(|(bar, _)| *bar == structure.bar)
+            .unwrap();
+        area.write(structure.offset as usize + offset, value)
+            .unwrap();
+    }
+
+    /// Adds `bits` to the device status
+    fn set_status(&mut self, bits: u8) {
+        let status: u8 = self.read(self.common, DEVICE_STATUS);
+        self.write(self.common, DEVICE_STATUS, status | bits);
+    }
+
+    /// Accepts the features of `wanted` that the device offers, plus `FEATURE_VERSION_1`, and
+    /// returns them
+    pub fn negotiate(&mut self, wanted: u64) -> Result<u64, VirtioError> {
+        let mut offered = 0;
+        for half in 0..2u32 {
+            self.write(self.common, DEVICE_FEATURE_SELECT, half);
+            let bits: u32 = self.read(self.common, DEVICE_FEATURE);
+            offered |= (bits as u64) << (half * 32);
+        }
+        if offered & FEATURE_VERSION_1 == 0 {
+            return Err(VirtioError::FeaturesRejected);
+        }
+        let accepted = offered & (wanted | FEATURE_VERSION_1);
+        for half in 0..2u32 {
+            self.write(self.common, DRIVER_FEATURE_SELECT, half);
+            self.write(
+                self.common,
+                DRIVER_FEATURE,
+                (accepted >> (half * 32)) as u32,
+            );
+        }
+        self.set_status(STATUS_FEATURES_OK);
+        // the device clears the bit if it can not work with the features
+        if self.read::<u8>(self.common, DEVICE_STATUS) & STATUS_FEATURES_OK == 0 {
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(accepted)
+    }
+
+    /// Sets up the queue `index` with up to `queue::MAX_SIZE` entries, after `negotiate()`
+    pub fn queue(&mut self, index: u16) -> Result<VirtQueue, VirtioError> {
+        self.write(self.common, QUEUE_SELECT, index);
+        let offered: u16 = self.read(self.common, QUEUE_SIZE);
+        if offered == 0 {
+            return Err(VirtioError::NoQueue(index));
+        }
+        // split queues have a power of two size, the driver may pick a smaller one
+        let size = 1 << offered.min(queue::MAX_SIZE).ilog2();
+        let split = SplitQueue::new(size).ok_or(VirtioError::OutOfMemory)?;
+        let (descriptors, driver, device) = split.addresses();
+        self.write(self.common, QUEUE_SIZE, size);
+        self.write(self.common, QUEUE_MSIX_VECTOR, NO_VECTOR);
+        self.write(self.common, QUEUE_DESC, descriptors);
+        self.write(self.common, QUEUE_DRIVER, driver);
+        self.write(self.common, QUEUE_DEVICE, device);
+        let notify_off: u16 = self.read(self.common, QUEUE_NOTIFY_OFF);
+        self.write(self.common, QUEUE_ENABLE, 1u16);
+        Ok(VirtQueue {
+            index,
+            split,
+            notify: notify_off as usize * self.notify_multiplier as usize,
+        })
+    }
+
+    /// Tells the device to work on what is new on `queue`
+    pub fn notify(&mut self, queue: &VirtQueue) {
+        // the ring entries are visible before the doorbell
+        Current::barrier();
+        self.write(self.notify, queue.notify, queue.index);
+    }
+
+    /// Reads the device specific configuration at `offset`, `None` if the structure does not
+    /// cover it. Retried until the device did not change it while it was read.
+    pub fn config<T: Plain>(&self, offset: usize) -> Option<T> {
+        let device = self.device?;
+        if offset + core::mem::size_of::<T>() > device.len as usize {
+            return None;
+        }
+        loop {
+            let generation: u8 = self.read(self.common, CONFIG_GENERATION);
+            let value = self.read(device, offset);
+            if self.read::<u8>(self.common, CONFIG_GENERATION) == generation {
+                return Some(value);
+            }
+        }
+    }
+
+    /// Same as `config()` for 64 bit fields, which are read in 32 bit halves
+    pub fn config64(&self, offset: usize) -> Option<u64> {
+        loop {
+            let generation: u8 = self.read(self.common, CONFIG_GENERATION);
+            let low: u32 = self.config(offset)?;
+            let high: u32 = self.config(offset + 4)?;
+            if self.read::<u8>(self.common, CONFIG_GENERATION) == generation {
+                return Some(((high as u64) << 32) | low as u64);
+            }
+        }
+    }
+
+    /// Lets the device work, once the queues are set up
+    pub fn start(&mut self) {
+        self.set_status(STATUS_DRIVER_OK);
+    }
+
+    /// Gives up on the device: marks it failed and gives back the BARs. Queues that were set up
+    /// must not be freed, the device may still use them.
+    pub fn fail(mut self) {
+        self.set_status(STATUS_FAILED);
+        self.free();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::pci::bus::{Address, CAPABILITIES, HEADER_GENERAL, STATUS};
+    use crate::unit_test;
+
+    /// Configuration space of a single function, offsets are aligned to 4
+    struct FakeFunction([u32; 64]);
+
+    impl ConfigAccess for FakeFunction {
+        fn read(&self, _: Address, offset: u16) -> u32 {
+            self.0[offset as usize / 4]
+        }
+
+        fn write(&self, _: Address, _: u16, _: u32) {}
+    }
+
+    /// A vendor capability at `at` pointing to `next`
+    fn capability(space: &mut FakeFunction, at: usize, next: u8, kind: u8, bar: u8, offset: u32) {
+        space.0[at / 4] = u32::from_le_bytes([CAPABILITY_VENDOR, next, 16, kind]);
+        space.0[at / 4 + 1] = bar as u32;
+        space.0[at / 4 + 2] = offset;
+        space.0[at / 4 + 3] = 0x1000;
+    }
+
+    unit_test! {
+        fn find_structures() {
+            let mut space = FakeFunction([0; 64]);
+            space.0[STATUS as usize / 4] = 1 << 20;
+            space.0[CAPABILITIES as usize / 4] = 0x40;
+            capability(&mut space, 0x40, 0x50, STRUCTURE_COMMON, 4, 0);
+            capability(&mut space, 0x50, 0x60, STRUCTURE_NOTIFY, 4, 0x3000);
+            space.0[0x60 / 4] = 4;
+            // a second common structure & one in a reserved BAR are ignored
+            capability(&mut space, 0x64, 0x74, STRUCTURE_COMMON, 2, 0x1000);
+            capability(&mut space, 0x74, 0x84, STRUCTURE_DEVICE, 7, 0x2000);
+            capability(&mut space, 0x84, 0, STRUCTURE_DEVICE, 4, 0x2000);
+            let function = Function {
+                address: Address::new(0, 0, 4, 0),
+                vendor: VENDOR,
+                device: MODERN_DEVICE + 2,
+                class: 1,
+                subclass: 0,
+                prog_if: 0,
+                revision: 1,
+                header_type: HEADER_GENERAL,
+                bars: [None; 6],
+                interrupt_pin: 0,
+                msi: None,
+                msix: None,
+                secondary_bus: None,
+            };
+            let structure = |offset| Structure { bar: 4, offset, len: 0x1000 };
+            assert_eq!(
+                Structures::find(&space, &function),
+                Structures {
+                    common: Some(structure(0)),
+                    notify: Some((structure(0x3000), 4)),
+                    device: Some(structure(0x2000)),
+                }
+            );
+        }
+    }
+}
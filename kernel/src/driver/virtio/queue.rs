@@ -0,0 +1,231 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Split virtqueues: a table of buffer descriptors, the available ring on which the driver
+//! offers chains of descriptors to the device, and the used ring on which the device returns
+//! them. The device is not asked for interrupts, finished chains are polled with `take()`.
+//!
+//! main source: Virtual I/O Device (VIRTIO) Version 1.2, chapter 2.7 "Split Virtqueues"
+
+use crate::arch::{Current, Mmio, PAGE_SIZE};
+use crate::memman::frame;
+use crate::memman::map::{self, MapArea};
+use alloc::vec::Vec;
+
+const DESCRIPTOR_SIZE: usize = 16;
+/// The descriptor continues in `next`
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+/// The device writes into the buffer
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+/// Asks the device not to interrupt when it returns chains
+const AVAIL_NO_INTERRUPT: u16 = 1 << 0;
+/// Size of a used ring entry: the head of the chain & the number of bytes written
+const USED_ENTRY_SIZE: usize = 8;
+/// Largest queue used, all three parts of a queue of this size fit into one page
+pub const MAX_SIZE: u16 = 128;
+
+/// Offsets of the available & used rings and the total size of a queue with `size` entries
+pub fn layout(size: u16) -> (usize, usize, usize) {
+    let size = size as usize;
+    let avail = size * DESCRIPTOR_SIZE;
+    // flags, index, the ring & the used event
+    let used = (avail + 6 + 2 * size).next_multiple_of(4);
+    (avail, used, used + 6 + USED_ENTRY_SIZE * size)
+}
+
+/// A buffer of a descriptor chain, at a physical address
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: u64,
+    pub len: u32,
+    /// The device writes into the buffer instead of reading from it
+    pub writable: bool,
+}
+
+/// A split virtqueue in memory claimed from the frame allocator
+pub struct SplitQueue {
+    size: u16,
+    area: MapArea,
+    avail: usize,
+    used: usize,
+    /// Indices of the unused descriptors
+    free: Vec<u16>,
+    /// Next descriptor of every descriptor in a chain, mirrors the table
+    next: Vec<u16>,
+    /// Number of descriptors in the chain starting at every head
+    chains: Vec<u16>,
+    /// Bytes written by the device into every finished chain, by head
+    done: Vec<Option<u32>>,
+    /// Index of the next available ring entry
+    avail_index: u16,
+    /// Index of the next used ring entry to read
+    used_index: u16,
+}
+
+impl SplitQueue {
+    /// Claims zeroed memory for a queue of `size` entries, which must be a power of two up to
+    /// `MAX_SIZE`. `None` if no memory is left.
+    pub fn new(size: u16) -> Option<Self> {
+        assert!(size.is_power_of_two() && size <= MAX_SIZE);
+        let (avail, used, total) = layout(size);
+        let mut area = frame::claim_zeroed_frames(total.div_ceil(PAGE_SIZE))?;
+        area.write(avail, AVAIL_NO_INTERRUPT).unwrap();
+        Some(Self {
+            size,
+            area,
+            avail,
+            used,
+            free: (0..size).rev().collect(),
+            next: alloc::vec![0; size as usize],
+            chains: alloc::vec![0; size as usize],
+            done: alloc::vec![None; size as usize],
+            avail_index: 0,
+            used_index: 0,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Physical addresses of the descriptor table, the available ring & the used ring
+    pub fn addresses(&self) -> (u64, u64, u64) {
+        let start = self.area.region().0 as u64;
+        (start, start + self.avail as u64, start + self.used as u64)
+    }
+
+    /// Number of descriptors left for `push()`
+    pub fn free_descriptors(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Offers a chain of `buffers` to the device and returns the head of the chain, `None` if
+    /// there are not enough free descriptors. The device has to be notified afterwards.
+    pub fn push(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+        let descriptors = self.free.split_off(self.free.len() - buffers.len());
+        for (i, (buffer, index)) in buffers.iter().zip(&descriptors).enumerate() {
+            let next = descriptors.get(i + 1).copied();
+            let mut flags = 0;
+            if next.is_some() {
+                flags |= DESCRIPTOR_NEXT;
+            }
+            if buffer.writable {
+                flags |= DESCRIPTOR_WRITE;
+            }
+            let offset = *index as usize * DESCRIPTOR_SIZE;
+            self.area.write(offset, buffer.address).unwrap();
+            self.area.write(offset + 8, buffer.len).unwrap();
+            self.area.write(offset + 12, flags).unwrap();
+            self.area.write(offset + 14, next.unwrap_or(0)).unwrap();
+            self.next[*index as usize] = next.unwrap_or(0);
+        }
+        let head = descriptors[0];
+        self.chains[head as usize] = buffers.len() as u16;
+        let slot = self.avail + 4 + 2 * (self.avail_index % self.size) as usize;
+        self.area.write(slot, head).unwrap();
+        // the device may read the entry as soon as it sees the new index
+        Current::barrier();
+        self.avail_index = self.avail_index.wrapping_add(1);
+        self.area.write(self.avail + 2, self.avail_index).unwrap();
+        Some(head)
+    }
+
+    /// Reads the chains the device returned since the last call
+    fn collect(&mut self) {
+        let index: u16 = self.area.read(self.used + 2).unwrap();
+        if index == self.used_index {
+            return;
+        }
+        // the entries are read after the index that covers them
+        Current::read_barrier();
+        while self.used_index != index {
+            let slot = self.used + 4 + USED_ENTRY_SIZE * (self.used_index % self.size) as usize;
+            let head: u32 = self.area.read(slot).unwrap();
+            let len: u32 = self.area.read(slot + 4).unwrap();
+            if let Some(done) = self.done.get_mut(head as usize) {
+                *done = Some(len);
+            }
+            self.used_index = self.used_index.wrapping_add(1);
+        }
+    }
+
+    /// Returns the number of bytes written by the device once it finished the chain starting at
+    /// `head`, its descriptors are free again afterwards. `None` while the chain is pending.
+    pub fn take(&mut self, head: u16) -> Option<u32> {
+        self.collect();
+        let len = self.done.get_mut(head as usize)?.take()?;
+        let mut index = head;
+        for _ in 0..self.chains[head as usize] {
+            self.free.push(index);
+            index = self.next[index as usize];
+        }
+        self.chains[head as usize] = 0;
+        Some(len)
+    }
+
+    /// Returns the memory of the queue, the device must not use it anymore (e.g. after a reset)
+    pub fn free(self) {
+        map::free_global(self.area);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    unit_test! {
+        fn queue_layout() {
+            assert_eq!(layout(128), (2048, 2312, 3342));
+            assert!(layout(MAX_SIZE).2 <= PAGE_SIZE);
+            // the used ring is aligned to 4
+            assert_eq!(layout(2).1, 44);
+        }
+    }
+
+    /// Returns the chain at `head` on the used ring like a device
+    fn device_return(queue: &mut SplitQueue, head: u16, len: u32) {
+        let index: u16 = queue.area.read(queue.used + 2).unwrap();
+        let slot = queue.used + 4 + USED_ENTRY_SIZE * (index % queue.size) as usize;
+        queue.area.write(slot, head as u32).unwrap();
+        queue.area.write(slot + 4, len).unwrap();
+        queue
+            .area
+            .write(queue.used + 2, index.wrapping_add(1))
+            .unwrap();
+    }
+
+    unit_test! {
+        fn push_and_take_chains() {
+            let mut queue = SplitQueue::new(4).unwrap();
+            let buffer = |writable| Buffer { address: 0x1000, len: 16, writable };
+            let first = queue.push(&[buffer(false), buffer(true)]).unwrap();
+            let second = queue.push(&[buffer(false), buffer(false)]).unwrap();
+            assert!(queue.push(&[buffer(false)]).is_none());
+
+            // the available ring holds both heads, the first chain is linked
+            assert_eq!(queue.area.read::<u16>(queue.avail + 2), Ok(2));
+            assert_eq!(queue.area.read::<u16>(queue.avail + 4), Ok(first));
+            assert_eq!(queue.area.read::<u16>(queue.avail + 6), Ok(second));
+            let descriptor = first as usize * DESCRIPTOR_SIZE;
+            assert_eq!(queue.area.read::<u16>(descriptor + 12), Ok(DESCRIPTOR_NEXT));
+            let next = queue.area.read::<u16>(descriptor + 14).unwrap() as usize;
+            assert_eq!(queue.area.read::<u16>(next * DESCRIPTOR_SIZE + 12), Ok(DESCRIPTOR_WRITE));
+
+            assert_eq!(queue.take(first), None);
+            device_return(&mut queue, second, 0);
+            device_return(&mut queue, first, 16);
+            assert_eq!(queue.take(first), Some(16));
+            assert_eq!(queue.free_descriptors(), 2);
+            assert_eq!(queue.take(second), Some(0));
+            assert_eq!(queue.free_descriptors(), 4);
+            queue.free();
+        }
+    }
+}
@@ -0,0 +1,420 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Read-only parser for flattened device trees (DTB), as passed by `limine::device_tree()`.
+//!
+//! Nodes are visited in the order they appear in the blob, a malformed structure block simply
+//! ends the iteration.
+//! main source: Devicetree Specification, chapter 5 "Flattened Devicetree (DTB) Format"
+
+use core::fmt;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+/// `size_dt_struct` was added in version 17
+const MIN_VERSION: u32 = 17;
+/// Deepest node visited, deeper nodes end the iteration
+const MAX_DEPTH: usize = 16;
+
+/// Error returned by `Fdt::new()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The header points outside of the blob
+    Truncated,
+}
+
+impl fmt::Display for FdtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a device tree blob"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported device tree version {}", v),
+            Self::Truncated => write!(f, "device tree blob is truncated"),
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads a zero terminated string at `offset`
+fn read_str(bytes: &[u8], offset: usize) -> Option<&str> {
+    let bytes = bytes.get(offset..)?;
+    let end = bytes.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&bytes[..end]).ok()
+}
+
+/// A validated device tree blob
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    structs: &'a [u8],
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(blob: &'a [u8]) -> Result<Self, FdtError> {
+        let field = |i: usize| read_u32(blob, i * 4).ok_or(FdtError::Truncated);
+        if field(0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let version = field(5)?;
+        if version < MIN_VERSION {
+            return Err(FdtError::UnsupportedVersion(version));
+        }
+        let total_size = field(1)? as usize;
+        let blob = blob.get(..total_size).ok_or(FdtError::Truncated)?;
+        let block =
+            |offset: usize, size: usize| blob.get(offset..offset + size).ok_or(FdtError::Truncated);
+        Ok(Self {
+            structs: block(field(2)? as usize, field(9)? as usize)?,
+            strings: block(field(3)? as usize, field(8)? as usize)?,
+        })
+    }
+
+    /// Iterates over every node, depth first
+    pub fn nodes(&self) -> Nodes<'a> {
+        Nodes {
+            fdt: *self,
+            offset: 0,
+            // the root node uses the defaults for its children
+            cells: [(2, 1); MAX_DEPTH],
+            depth: 0,
+        }
+    }
+
+    /// Finds the first node compatible with any of `compatible`
+    pub fn find_compatible(&self, compatible: &[&str]) -> Option<Node<'a>> {
+        self.nodes()
+            .find(|node| compatible.iter().any(|c| node.is_compatible(c)))
+    }
+
+    /// Finds a node by its full path, e.g. `/chosen`. Unit addresses must be given.
+    pub fn find_path(&self, path: &str) -> Option<Node<'a>> {
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        let mut wanted = components.next();
+        let mut depth = 1;
+        for node in self.nodes() {
+            if node.depth == 0 {
+                if wanted.is_none() {
+                    return Some(node);
+                }
+                continue;
+            }
+            // left the subtree the path is in
+            if node.depth < depth {
+                return None;
+            }
+            if node.depth == depth && Some(node.name) == wanted {
+                wanted = components.next();
+                if wanted.is_none() {
+                    return Some(node);
+                }
+                depth += 1;
+            }
+        }
+        None
+    }
+}
+
+/// A node of the device tree
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    pub name: &'a str,
+    /// 0 for the root node
+    pub depth: usize,
+    /// `#address-cells` & `#size-cells` of the parent, used by `reg`
+    cells: (u32, u32),
+    fdt: Fdt<'a>,
+    /// offset of the first property in the structure block
+    properties: usize,
+}
+
+impl<'a> Node<'a> {
+    /// Iterates over the `(name, value)` pairs of the properties
+    pub fn properties(&self) -> Properties<'a> {
+        Properties {
+            fdt: self.fdt,
+            offset: self.properties,
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Reads a property holding a single u32
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        read_u32(self.property(name)?, 0)
+    }
+
+    /// Reads a property holding a string
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        read_str(self.property(name)?, 0)
+    }
+
+    /// The strings of the `compatible` property
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> {
+        self.property("compatible")
+            .unwrap_or(&[])
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|c| c == compatible)
+    }
+
+    /// The `(address, size)` pairs of the `reg` property
+    pub fn reg(&self) -> Reg<'a> {
+        Reg {
+            value: self.property("reg").unwrap_or(&[]),
+            cells: self.cells,
+        }
+    }
+}
+
+/// Iterator over the nodes of a device tree, see `Fdt::nodes()`
+pub struct Nodes<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+    /// `#address-cells` & `#size-cells` of every open node
+    cells: [(u32, u32); MAX_DEPTH],
+    /// number of open nodes
+    depth: usize,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs;
+        loop {
+            let token = read_u32(structs, self.offset)?;
+            self.offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = read_str(structs, self.offset)?;
+                    self.offset = (self.offset + name.len() + 1).next_multiple_of(4);
+                    if self.depth == MAX_DEPTH {
+                        return None;
+                    }
+                    let mut node = Node {
+                        name,
+                        depth: self.depth,
+                        cells: (2, 1),
+                        fdt: self.fdt,
+                        properties: self.offset,
+                    };
+                    if self.depth > 0 {
+                        node.cells = self.cells[self.depth - 1];
+                    }
+                    self.cells[self.depth] = (
+                        node.property_u32("#address-cells").unwrap_or(2),
+                        node.property_u32("#size-cells").unwrap_or(1),
+                    );
+                    self.depth += 1;
+                    return Some(node);
+                }
+                FDT_END_NODE => self.depth = self.depth.checked_sub(1)?,
+                FDT_PROP => {
+                    let len = read_u32(structs, self.offset)? as usize;
+                    self.offset = (self.offset + 8 + len).next_multiple_of(4);
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                // invalid token
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Iterator over the properties of a node, see `Node::properties()`
+pub struct Properties<'a> {
+    fdt: Fdt<'a>,
+    offset: usize,
+}
+
+impl<'a> Iterator for Properties<'a> {
+    type Item = (&'a str, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs;
+        loop {
+            match read_u32(structs, self.offset)? {
+                FDT_PROP => {
+                    let len = read_u32(structs, self.offset + 4)? as usize;
+                    let name = read_str(
+                        self.fdt.strings,
+                        read_u32(structs, self.offset + 8)? as usize,
+                    )?;
+                    let start = self.offset + 12;
+                    let value = structs.get(start..start + len)?;
+                    self.offset = (start + len).next_multiple_of(4);
+                    return Some((name, value));
+                }
+                FDT_NOP => self.offset += 4,
+                // properties come before child nodes
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// Iterator over the `(address, size)` pairs of a `reg` property
+pub struct Reg<'a> {
+    value: &'a [u8],
+    cells: (u32, u32),
+}
+
+impl Reg<'_> {
+    fn read_cells(&mut self, cells: u32) -> Option<u64> {
+        let mut number = 0u64;
+        for _ in 0..cells {
+            number = number.checked_shl(32).unwrap_or(0) | read_u32(self.value, 0)? as u64;
+            self.value = &self.value[4..];
+        }
+        Some(number)
+    }
+}
+
+impl Iterator for Reg<'_> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (address_cells, size_cells) = self.cells;
+        Some((
+            self.read_cells(address_cells)?,
+            self.read_cells(size_cells)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+    use alloc::vec::Vec;
+
+    /// `(name, properties)` of a node, `None` closes the last opened node
+    type Token<'a> = Option<(&'a str, &'a [(&'a str, &'a [u8])])>;
+
+    /// Builds a device tree blob
+    fn build(tokens: &[Token]) -> Vec<u8> {
+        let mut structs = Vec::new();
+        let mut strings = Vec::new();
+        let push = |bytes: &mut Vec<u8>, word: u32| bytes.extend_from_slice(&word.to_be_bytes());
+        for token in tokens {
+            match token {
+                Some((name, properties)) => {
+                    push(&mut structs, FDT_BEGIN_NODE);
+                    structs.extend_from_slice(name.as_bytes());
+                    structs.push(0);
+                    structs.resize(structs.len().next_multiple_of(4), 0);
+                    for (name, value) in properties.iter() {
+                        push(&mut structs, FDT_PROP);
+                        push(&mut structs, value.len() as u32);
+                        push(&mut structs, strings.len() as u32);
+                        strings.extend_from_slice(name.as_bytes());
+                        strings.push(0);
+                        structs.extend_from_slice(value);
+                        structs.resize(structs.len().next_multiple_of(4), 0);
+                    }
+                }
+                None => push(&mut structs, FDT_END_NODE),
+            }
+        }
+        push(&mut structs, FDT_END);
+
+        let header_size = 40;
+        let mut blob = Vec::new();
+        let header = [
+            FDT_MAGIC,
+            (header_size + structs.len() + strings.len()) as u32,
+            header_size as u32,
+            (header_size + structs.len()) as u32,
+            header_size as u32,
+            MIN_VERSION,
+            16,
+            0,
+            strings.len() as u32,
+            structs.len() as u32,
+        ];
+        for word in header {
+            push(&mut blob, word);
+        }
+        blob.extend_from_slice(&structs);
+        blob.extend_from_slice(&strings);
+        blob
+    }
+
+    fn sample() -> Vec<u8> {
+        build(&[
+            Some((
+                "",
+                &[
+                    ("#address-cells", &[0, 0, 0, 2]),
+                    ("#size-cells", &[0, 0, 0, 2]),
+                ],
+            )),
+            Some(("chosen", &[("stdout-path", b"/pl011@9000000\0")])),
+            None,
+            Some((
+                "intc@8000000",
+                &[
+                    ("compatible", b"arm,gic-400\0arm,cortex-a15-gic\0"),
+                    ("reg", &[0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]),
+                ],
+            )),
+            Some(("v2m@8020000", &[("compatible", b"arm,gic-v2m-frame\0")])),
+            None,
+            None,
+            None,
+        ])
+    }
+
+    unit_test! {
+        fn walk_nodes() {
+            let blob = sample();
+            let fdt = Fdt::new(&blob).unwrap();
+            let nodes: Vec<_> = fdt.nodes().map(|n| (n.name, n.depth)).collect();
+            assert_eq!(
+                nodes,
+                [("", 0), ("chosen", 1), ("intc@8000000", 1), ("v2m@8020000", 2)]
+            );
+            let chosen = fdt.find_path("/chosen").unwrap();
+            assert_eq!(chosen.property_str("stdout-path"), Some("/pl011@9000000"));
+            assert!(fdt.find_path("/intc@8000000/v2m@8020000").is_some());
+            assert!(fdt.find_path("/v2m@8020000").is_none());
+        }
+
+        fn compatible_reg() {
+            let blob = sample();
+            let fdt = Fdt::new(&blob).unwrap();
+            let gic = fdt.find_compatible(&["arm,gic-v3", "arm,cortex-a15-gic"]).unwrap();
+            assert_eq!(gic.name, "intc@8000000");
+            assert!(gic.is_compatible("arm,gic-400"));
+            assert!(gic.reg().eq([(0x800_0000, 0x1_0000)]));
+        }
+
+        fn reject_invalid() {
+            let mut blob = sample();
+            assert!(matches!(Fdt::new(&blob[..30]), Err(FdtError::Truncated)));
+            blob[0] = 0;
+            assert!(matches!(Fdt::new(&blob), Err(FdtError::BadMagic)));
+        }
+    }
+}
@@ -0,0 +1,1248 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! ext2, the second extended filesystem, as the native filesystem with Unix semantics.
+//!
+//! The `NodeId` of a file is its inode number, the root directory is inode `ROOT`. The free
+//! counts of the superblock & the group descriptors are kept in memory & written through on
+//! every change, file content & bitmaps are not cached. Every operation holds the lock of the
+//! volume until it is done.
+//!
+//! Volumes with read-only compatible features besides `sparse_super` & `large_file` are mounted
+//! read-only, with incompatible features besides `filetype` not at all. Hash indexes of
+//! directories are not used, a directory that gets an entry loses its index like with the Linux
+//! ext2 driver. Times are left at 0, there is no clock yet.
+//!
+//! main source: The Second Extended File System, Internal Layout (Dave Poirier)
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsType, Metadata, NodeId};
+use crate::driver::block::{self, BlockDevice};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub static TYPE: FsType = FsType { name: "ext2", open };
+
+fn open(device: Arc<dyn BlockDevice>) -> Result<Arc<dyn FileSystem>, FsError> {
+    Ok(Arc::new(Ext2::new(device)?))
+}
+
+const ROOT: NodeId = 2;
+
+/// Offset of the superblock on the volume
+const SUPERBLOCK: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+/// Offsets of the free counts in the superblock
+const FREE_BLOCKS: u64 = 12;
+const FREE_INODES: u64 = 16;
+/// Directory entries hold the file type
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Only some groups have backups of the superblock
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+/// Files may be larger than 2 GiB
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const DESCRIPTOR_SIZE: usize = 32;
+/// The part of an inode used here, larger inodes have more fields after it
+const INODE_SIZE: usize = 128;
+/// Inodes of the first revision
+const OLD_INODE_SIZE: u64 = 128;
+const OLD_FIRST_INODE: u32 = 11;
+/// Block pointers in an inode, the last 3 are the single, double & triple indirect one
+const POINTERS: usize = 15;
+const DIRECT: usize = 12;
+
+const TYPE_MASK: u16 = 0xF000;
+const TYPE_REGULAR: u16 = 0x8000;
+const TYPE_DIRECTORY: u16 = 0x4000;
+const FILE_MODE: u16 = TYPE_REGULAR | 0o644;
+const DIRECTORY_MODE: u16 = TYPE_DIRECTORY | 0o755;
+/// Inode flag of a directory with a hash index
+const INDEX_FLAG: u32 = 0x1000;
+const MAX_LINKS: u16 = 32000;
+
+/// File types in directory entries
+const ENTRY_FILE: u8 = 1;
+const ENTRY_DIRECTORY: u8 = 2;
+/// Size of a directory entry without its name
+const ENTRY_HEADER: usize = 8;
+const MAX_NAME: usize = 255;
+
+/// Where the structures are & what the volume supports, from the superblock
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Layout {
+    block_size: u64,
+    blocks: u32,
+    /// Block of the superblock, blocks before it are not in a group
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    groups: u32,
+    /// Directory entries hold the file type
+    filetype: bool,
+    large_file: bool,
+    /// Writing would not break a feature of the volume
+    writable: bool,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+impl Layout {
+    /// Reads the superblock, `NotRecognized` if it is not one
+    fn parse(superblock: &[u8]) -> Result<Self, FsError> {
+        if read_u16(superblock, 56) != MAGIC {
+            return Err(FsError::NotRecognized);
+        }
+        let log_block_size = read_u32(superblock, 24);
+        if log_block_size > 6 {
+            return Err(FsError::Corrupted);
+        }
+        let block_size = 1024 << log_block_size;
+        let blocks = read_u32(superblock, 4);
+        let first_data_block = read_u32(superblock, 20);
+        let blocks_per_group = read_u32(superblock, 32);
+        let inodes = read_u32(superblock, 0);
+        let inodes_per_group = read_u32(superblock, 40);
+        let (inode_size, first_inode, compat) = match read_u32(superblock, 76) {
+            0 => (OLD_INODE_SIZE, OLD_FIRST_INODE, (0, 0)),
+            _ => (
+                read_u16(superblock, 88) as u64,
+                read_u32(superblock, 84),
+                (read_u32(superblock, 96), read_u32(superblock, 100)),
+            ),
+        };
+        let (incompat, ro_compat) = compat;
+        if incompat & !INCOMPAT_FILETYPE != 0 {
+            return Err(FsError::Unsupported);
+        }
+        let bits = block_size as u32 * 8;
+        if blocks_per_group == 0
+            || blocks_per_group > bits
+            || inodes_per_group == 0
+            || inodes_per_group > bits
+            || first_data_block >= blocks
+            || !inode_size.is_power_of_two()
+            || !(INODE_SIZE as u64..=block_size).contains(&inode_size)
+        {
+            return Err(FsError::Corrupted);
+        }
+        let groups = (blocks - first_data_block).div_ceil(blocks_per_group);
+        if inodes as u64 > groups as u64 * inodes_per_group as u64
+            // the reserved inodes include the root directory
+            || (ROOT as u32) >= first_inode
+            || first_inode > inodes
+        {
+            return Err(FsError::Corrupted);
+        }
+        Ok(Self {
+            block_size,
+            blocks,
+            first_data_block,
+            blocks_per_group,
+            inodes,
+            inodes_per_group,
+            inode_size,
+            groups,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            large_file: ro_compat & RO_COMPAT_LARGE_FILE != 0,
+            writable: ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE) == 0,
+        })
+    }
+
+    /// Number of block numbers in an indirect block
+    fn pointers(&self) -> u64 {
+        self.block_size / 4
+    }
+
+    /// Largest file size, the block pointers of an inode cover a limited number of blocks
+    fn max_size(&self) -> u64 {
+        let pointers = self.pointers();
+        let blocks = DIRECT as u64 + pointers + pointers.pow(2) + pointers.pow(3);
+        match self.large_file {
+            true => blocks * self.block_size,
+            false => (blocks * self.block_size).min(i32::MAX as u64),
+        }
+    }
+}
+
+/// Where the logical block `index` of a file is: the inode slot, the indexes in the indirect
+/// blocks from the top down & the number of indirect blocks. `None` past the largest file.
+fn block_path(pointers: u64, index: u64) -> Option<(usize, [u64; 3], usize)> {
+    if index < DIRECT as u64 {
+        return Some((index as usize, [0; 3], 0));
+    }
+    let mut index = index - DIRECT as u64;
+    let mut span = pointers;
+    for depth in 1..=3 {
+        if index < span {
+            let mut indexes = [0; 3];
+            let mut rest = index;
+            for level in (0..depth).rev() {
+                indexes[level] = rest % pointers;
+                rest /= pointers;
+            }
+            return Some((DIRECT - 1 + depth, indexes, depth));
+        }
+        index -= span;
+        span *= pointers;
+    }
+    None
+}
+
+/// Where the bitmaps & the inode table of a block group are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+}
+
+/// The counts of a block group descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Counts {
+    free_blocks: u16,
+    free_inodes: u16,
+    directories: u16,
+}
+
+struct State {
+    free_blocks: u32,
+    free_inodes: u32,
+    groups: Vec<Counts>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bitmap {
+    Blocks,
+    Inodes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    links: u16,
+    /// 512 byte units taken by the blocks of the file, indirect blocks included
+    sectors: u32,
+    flags: u32,
+    block: [u32; POINTERS],
+    /// The inode as read, the fields not used here are written back unchanged
+    raw: [u8; INODE_SIZE],
+}
+
+impl Inode {
+    fn parse(raw: [u8; INODE_SIZE]) -> Self {
+        let mode = read_u16(&raw, 0);
+        let high = match mode & TYPE_MASK {
+            TYPE_REGULAR => read_u32(&raw, 108) as u64,
+            _ => 0,
+        };
+        Self {
+            mode,
+            size: (high << 32) | read_u32(&raw, 4) as u64,
+            links: read_u16(&raw, 26),
+            sectors: read_u32(&raw, 28),
+            flags: read_u32(&raw, 32),
+            block: core::array::from_fn(|index| read_u32(&raw, 40 + index * 4)),
+            raw,
+        }
+    }
+
+    /// An empty inode
+    fn new(mode: u16, links: u16) -> Self {
+        let mut inode = Self::parse([0; INODE_SIZE]);
+        inode.mode = mode;
+        inode.links = links;
+        inode
+    }
+
+    fn encode(&self) -> [u8; INODE_SIZE] {
+        let mut raw = self.raw;
+        write_u16(&mut raw, 0, self.mode);
+        write_u32(&mut raw, 4, self.size as u32);
+        write_u16(&mut raw, 26, self.links);
+        write_u32(&mut raw, 28, self.sectors);
+        write_u32(&mut raw, 32, self.flags);
+        for (index, block) in self.block.iter().enumerate() {
+            write_u32(&mut raw, 40 + index * 4, *block);
+        }
+        if self.mode & TYPE_MASK == TYPE_REGULAR {
+            write_u32(&mut raw, 108, (self.size >> 32) as u32);
+        }
+        raw
+    }
+
+    fn kind(&self) -> FileType {
+        match self.mode & TYPE_MASK {
+            TYPE_DIRECTORY => FileType::Directory,
+            _ => FileType::File,
+        }
+    }
+}
+
+/// A directory entry, by its position in a block
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    at: usize,
+    /// Bytes up to the next entry
+    len: usize,
+    /// 0 for an unused entry
+    inode: u32,
+    /// File type, 0 if the volume does not store it
+    kind: u8,
+    name: Vec<u8>,
+}
+
+/// Bytes a directory entry with a name of `name_len` bytes needs
+fn record_len(name_len: usize) -> usize {
+    (ENTRY_HEADER + name_len).next_multiple_of(4)
+}
+
+/// A block of a directory & its entries
+struct DirBlock {
+    block: u32,
+    data: Vec<u8>,
+    records: Vec<Record>,
+}
+
+impl DirBlock {
+    fn parse(block: u32, data: Vec<u8>) -> Result<Self, FsError> {
+        let mut records = Vec::new();
+        let mut at = 0;
+        while at < data.len() {
+            if data.len() - at < ENTRY_HEADER {
+                return Err(FsError::Corrupted);
+            }
+            let len = read_u16(&data, at + 4) as usize;
+            let name_len = data[at + 6] as usize;
+            if len % 4 != 0 || len < ENTRY_HEADER + name_len || at + len > data.len() {
+                return Err(FsError::Corrupted);
+            }
+            records.push(Record {
+                at,
+                len,
+                inode: read_u32(&data, at),
+                kind: data[at + 7],
+                name: data[at + ENTRY_HEADER..at + ENTRY_HEADER + name_len].to_vec(),
+            });
+            at += len;
+        }
+        Ok(Self {
+            block,
+            data,
+            records,
+        })
+    }
+
+    /// Writes an entry into `data`
+    fn set(&mut self, at: usize, len: usize, inode: u32, kind: u8, name: &[u8]) {
+        write_u32(&mut self.data, at, inode);
+        write_u16(&mut self.data, at + 4, len as u16);
+        self.data[at + 6] = name.len() as u8;
+        self.data[at + 7] = kind;
+        self.data[at + ENTRY_HEADER..at + ENTRY_HEADER + name.len()].copy_from_slice(name);
+    }
+}
+
+/// Whether a name can be stored in a directory
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\0'])
+}
+
+pub struct Ext2 {
+    device: Arc<dyn BlockDevice>,
+    layout: Layout,
+    groups: Vec<Group>,
+    read_only: bool,
+    state: Mutex<State>,
+}
+
+impl Ext2 {
+    /// Reads the superblock & the group descriptors, `NotRecognized` if the device holds no
+    /// ext2 volume
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        let size = device.sectors() * device.sector_size() as u64;
+        if size < SUPERBLOCK + SUPERBLOCK_SIZE as u64 {
+            return Err(FsError::NotRecognized);
+        }
+        let mut superblock = vec![0; SUPERBLOCK_SIZE];
+        super::read_bytes(&*device, SUPERBLOCK, &mut superblock)?;
+        let layout = Layout::parse(&superblock)?;
+        if layout.blocks as u64 * layout.block_size > size {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut descriptors = vec![0; layout.groups as usize * DESCRIPTOR_SIZE];
+        let at = (layout.first_data_block as u64 + 1) * layout.block_size;
+        super::read_bytes(&*device, at, &mut descriptors)?;
+        let groups: Vec<Group> = descriptors
+            .chunks(DESCRIPTOR_SIZE)
+            .map(|descriptor| Group {
+                block_bitmap: read_u32(descriptor, 0),
+                inode_bitmap: read_u32(descriptor, 4),
+                inode_table: read_u32(descriptor, 8),
+            })
+            .collect();
+        let counts = descriptors
+            .chunks(DESCRIPTOR_SIZE)
+            .map(|descriptor| Counts {
+                free_blocks: read_u16(descriptor, 12),
+                free_inodes: read_u16(descriptor, 14),
+                directories: read_u16(descriptor, 16),
+            })
+            .collect();
+        let table_blocks =
+            (layout.inodes_per_group as u64 * layout.inode_size).div_ceil(layout.block_size);
+        if groups.iter().any(|group| {
+            group.block_bitmap >= layout.blocks
+                || group.inode_bitmap >= layout.blocks
+                || group.inode_table as u64 + table_blocks > layout.blocks as u64
+        }) {
+            return Err(FsError::Corrupted);
+        }
+
+        Ok(Self {
+            read_only: device.read_only() || !layout.writable,
+            device,
+            state: Mutex::new(State {
+                free_blocks: read_u32(&superblock, FREE_BLOCKS as usize),
+                free_inodes: read_u32(&superblock, FREE_INODES as usize),
+                groups: counts,
+            }),
+            groups,
+            layout,
+        })
+    }
+
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        super::read_bytes(&*self.device, offset, buffer)
+    }
+
+    fn write_bytes(&self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        super::write_bytes(&*self.device, offset, data)
+    }
+
+    fn block_offset(&self, block: u32) -> u64 {
+        block as u64 * self.layout.block_size
+    }
+
+    /// `block` if it is on the volume, a pointer past the end is `Corrupted`
+    fn valid(&self, block: u32) -> Result<u32, FsError> {
+        match block < self.layout.blocks {
+            true => Ok(block),
+            false => Err(FsError::Corrupted),
+        }
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, FsError> {
+        let mut data = vec![0; self.layout.block_size as usize];
+        self.read_bytes(self.block_offset(self.valid(block)?), &mut data)?;
+        Ok(data)
+    }
+
+    /// Writes the free counts of `group` & of the volume
+    fn write_counts(&self, state: &State, group: usize) -> Result<(), FsError> {
+        let entry = &state.groups[group];
+        let mut counts = [0; 6];
+        write_u16(&mut counts, 0, entry.free_blocks);
+        write_u16(&mut counts, 2, entry.free_inodes);
+        write_u16(&mut counts, 4, entry.directories);
+        let descriptors = self.block_offset(self.layout.first_data_block + 1);
+        self.write_bytes(descriptors + (group * DESCRIPTOR_SIZE) as u64 + 12, &counts)?;
+        let mut counts = [0; 8];
+        write_u32(&mut counts, 0, state.free_blocks);
+        write_u32(&mut counts, 4, state.free_inodes);
+        self.write_bytes(SUPERBLOCK + FREE_BLOCKS, &counts)
+    }
+
+    /// First block or inode number in `group` & how many there are
+    fn group_range(&self, bitmap: Bitmap, group: u32) -> (u32, u32) {
+        let layout = &self.layout;
+        match bitmap {
+            Bitmap::Blocks => {
+                let first = layout.first_data_block + group * layout.blocks_per_group;
+                (first, layout.blocks_per_group.min(layout.blocks - first))
+            }
+            Bitmap::Inodes => (group * layout.inodes_per_group + 1, layout.inodes_per_group),
+        }
+    }
+
+    fn group_of_inode(&self, inode: u32) -> u32 {
+        (inode - 1) / self.layout.inodes_per_group
+    }
+
+    /// Takes a free block or inode, searching from the group `goal` on
+    fn allocate(&self, state: &mut State, bitmap: Bitmap, goal: u32) -> Result<u32, FsError> {
+        for step in 0..self.layout.groups {
+            let group = (goal + step) % self.layout.groups;
+            let (free, location) = match bitmap {
+                Bitmap::Blocks => (
+                    state.groups[group as usize].free_blocks,
+                    self.groups[group as usize].block_bitmap,
+                ),
+                Bitmap::Inodes => (
+                    state.groups[group as usize].free_inodes,
+                    self.groups[group as usize].inode_bitmap,
+                ),
+            };
+            if free == 0 {
+                continue;
+            }
+            let (first, count) = self.group_range(bitmap, group);
+            let bits = self.read_block(location)?;
+            let Some(bit) = (0..count).find(|bit| bits[*bit as usize / 8] & (1 << (bit % 8)) == 0)
+            else {
+                return Err(FsError::Corrupted);
+            };
+            let byte = bits[bit as usize / 8] | (1 << (bit % 8));
+            self.write_bytes(self.block_offset(location) + bit as u64 / 8, &[byte])?;
+            let entry = &mut state.groups[group as usize];
+            match bitmap {
+                Bitmap::Blocks => {
+                    entry.free_blocks -= 1;
+                    state.free_blocks = state.free_blocks.saturating_sub(1);
+                }
+                Bitmap::Inodes => {
+                    entry.free_inodes -= 1;
+                    state.free_inodes = state.free_inodes.saturating_sub(1);
+                }
+            }
+            self.write_counts(state, group as usize)?;
+            return Ok(first + bit);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Gives a block or inode back
+    fn release(&self, state: &mut State, bitmap: Bitmap, number: u32) -> Result<(), FsError> {
+        let layout = &self.layout;
+        let (relative, per_group) = match bitmap {
+            Bitmap::Blocks => (number - layout.first_data_block, layout.blocks_per_group),
+            Bitmap::Inodes => (number - 1, layout.inodes_per_group),
+        };
+        let group = (relative / per_group) as usize;
+        let bit = relative % per_group;
+        let location = match bitmap {
+            Bitmap::Blocks => self.groups[group].block_bitmap,
+            Bitmap::Inodes => self.groups[group].inode_bitmap,
+        };
+        let at = self.block_offset(location) + bit as u64 / 8;
+        let mut byte = [0];
+        self.read_bytes(at, &mut byte)?;
+        if byte[0] & (1 << (bit % 8)) == 0 {
+            return Err(FsError::Corrupted);
+        }
+        self.write_bytes(at, &[byte[0] & !(1 << (bit % 8))])?;
+        let entry = &mut state.groups[group];
+        match bitmap {
+            Bitmap::Blocks => {
+                entry.free_blocks += 1;
+                state.free_blocks += 1;
+            }
+            Bitmap::Inodes => {
+                entry.free_inodes += 1;
+                state.free_inodes += 1;
+            }
+        }
+        self.write_counts(state, group)
+    }
+
+    fn inode_offset(&self, number: u32) -> Result<u64, FsError> {
+        if number == 0 || number > self.layout.inodes {
+            return Err(FsError::Corrupted);
+        }
+        let group = self.group_of_inode(number) as usize;
+        let index = (number - 1) % self.layout.inodes_per_group;
+        let table = self.groups[group].inode_table;
+        Ok(self.block_offset(table) + index as u64 * self.layout.inode_size)
+    }
+
+    fn read_inode(&self, number: u32) -> Result<Inode, FsError> {
+        let mut raw = [0; INODE_SIZE];
+        self.read_bytes(self.inode_offset(number)?, &mut raw)?;
+        Ok(Inode::parse(raw))
+    }
+
+    fn write_inode(&self, number: u32, inode: &Inode) -> Result<(), FsError> {
+        self.write_bytes(self.inode_offset(number)?, &inode.encode())
+    }
+
+    /// The inode of the directory `node`
+    fn dir_inode(&self, node: NodeId) -> Result<(u32, Inode), FsError> {
+        let number = u32::try_from(node).map_err(|_| FsError::NotFound)?;
+        let inode = self.read_inode(number)?;
+        match inode.kind() {
+            FileType::Directory => Ok((number, inode)),
+            FileType::File => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// The inode of the regular file `node`, devices & symbolic links are `Unsupported`
+    fn file_inode(&self, node: NodeId) -> Result<(u32, Inode), FsError> {
+        let number = u32::try_from(node).map_err(|_| FsError::NotFound)?;
+        let inode = self.read_inode(number)?;
+        match inode.mode & TYPE_MASK {
+            TYPE_REGULAR => Ok((number, inode)),
+            TYPE_DIRECTORY => Err(FsError::IsADirectory),
+            _ => Err(FsError::Unsupported),
+        }
+    }
+
+    fn read_pointer(&self, block: u32, index: u64) -> Result<u32, FsError> {
+        let mut pointer = [0; 4];
+        self.read_bytes(
+            self.block_offset(self.valid(block)?) + index * 4,
+            &mut pointer,
+        )?;
+        Ok(u32::from_le_bytes(pointer))
+    }
+
+    /// The block holding the logical block `index` of the file, 0 in a hole
+    fn block_of(&self, inode: &Inode, index: u64) -> Result<u32, FsError> {
+        let Some((slot, indexes, depth)) = block_path(self.layout.pointers(), index) else {
+            return Ok(0);
+        };
+        let mut block = inode.block[slot];
+        for index in &indexes[..depth] {
+            if block == 0 {
+                return Ok(0);
+            }
+            block = self.read_pointer(block, *index)?;
+        }
+        match block {
+            0 => Ok(0),
+            block => self.valid(block),
+        }
+    }
+
+    /// Takes a zeroed block for the file with `inode`
+    fn new_block(&self, state: &mut State, inode: &mut Inode, goal: u32) -> Result<u32, FsError> {
+        let block = self.allocate(state, Bitmap::Blocks, goal)?;
+        self.write_bytes(
+            self.block_offset(block),
+            &vec![0; self.layout.block_size as usize],
+        )?;
+        inode.sectors += (self.layout.block_size / 512) as u32;
+        Ok(block)
+    }
+
+    fn free_block(&self, state: &mut State, inode: &mut Inode, block: u32) -> Result<(), FsError> {
+        self.release(state, Bitmap::Blocks, self.valid(block)?)?;
+        inode.sectors = inode
+            .sectors
+            .saturating_sub((self.layout.block_size / 512) as u32);
+        Ok(())
+    }
+
+    /// The block holding the logical block `index` of the file `number`, the block & the
+    /// indirect ones leading to it are added if missing
+    fn map(
+        &self,
+        state: &mut State,
+        number: u32,
+        inode: &mut Inode,
+        index: u64,
+    ) -> Result<u32, FsError> {
+        let (slot, indexes, depth) =
+            block_path(self.layout.pointers(), index).ok_or(FsError::NoSpace)?;
+        let goal = self.group_of_inode(number);
+        let mut block = inode.block[slot];
+        if block == 0 {
+            block = self.new_block(state, inode, goal)?;
+            inode.block[slot] = block;
+        }
+        for index in &indexes[..depth] {
+            let mut next = self.read_pointer(block, *index)?;
+            if next == 0 {
+                next = self.new_block(state, inode, goal)?;
+                self.write_bytes(self.block_offset(block) + index * 4, &next.to_le_bytes())?;
+            }
+            block = self.valid(next)?;
+        }
+        Ok(block)
+    }
+
+    /// Frees the blocks of the file from the logical block `keep` on
+    fn cut(&self, state: &mut State, inode: &mut Inode, keep: u64) -> Result<(), FsError> {
+        for slot in keep.min(DIRECT as u64) as usize..DIRECT {
+            if inode.block[slot] != 0 {
+                self.free_block(state, inode, inode.block[slot])?;
+                inode.block[slot] = 0;
+            }
+        }
+        let mut start = DIRECT as u64;
+        let mut span = self.layout.pointers();
+        for depth in 1..=3 {
+            let slot = DIRECT - 1 + depth;
+            let block = inode.block[slot];
+            if block != 0 && self.cut_tree(state, inode, block, depth, start, keep)? {
+                inode.block[slot] = 0;
+            }
+            start += span;
+            span *= self.layout.pointers();
+        }
+        Ok(())
+    }
+
+    /// Frees the blocks under the indirect `block` from the logical block `keep` on, `block`
+    /// leads to `depth` levels of blocks from the logical block `start` on. True if `block`
+    /// is freed as well.
+    fn cut_tree(
+        &self,
+        state: &mut State,
+        inode: &mut Inode,
+        block: u32,
+        depth: usize,
+        start: u64,
+        keep: u64,
+    ) -> Result<bool, FsError> {
+        let span = self.layout.pointers().pow(depth as u32 - 1);
+        let mut pointers = self.read_block(block)?;
+        let mut changed = false;
+        for index in 0..self.layout.pointers() as usize {
+            let child = read_u32(&pointers, index * 4);
+            let child_start = start + index as u64 * span;
+            if child == 0 || child_start + span <= keep {
+                continue;
+            }
+            let freed = match depth {
+                1 => {
+                    self.free_block(state, inode, child)?;
+                    true
+                }
+                _ => self.cut_tree(state, inode, child, depth - 1, child_start, keep)?,
+            };
+            if freed {
+                write_u32(&mut pointers, index * 4, 0);
+                changed = true;
+            }
+        }
+        if start >= keep {
+            self.free_block(state, inode, block)?;
+            return Ok(true);
+        }
+        if changed {
+            self.write_bytes(self.block_offset(block), &pointers)?;
+        }
+        Ok(false)
+    }
+
+    /// Cuts or extends the file `number` to `size` bytes & writes its inode
+    fn resize(
+        &self,
+        state: &mut State,
+        number: u32,
+        inode: &mut Inode,
+        size: u64,
+    ) -> Result<(), FsError> {
+        let block_size = self.layout.block_size;
+        if size < inode.size {
+            self.cut(state, inode, size.div_ceil(block_size))?;
+            // the rest of the last block reads as zeros when the file grows again
+            let tail = size % block_size;
+            let block = self.block_of(inode, size / block_size)?;
+            if tail != 0 && block != 0 {
+                let zeros = vec![0; (block_size - tail) as usize];
+                self.write_bytes(self.block_offset(block) + tail, &zeros)?;
+            }
+        }
+        inode.size = size;
+        self.write_inode(number, inode)
+    }
+
+    /// The blocks of the directory with `inode`
+    fn dir_blocks(&self, inode: &Inode) -> Result<Vec<DirBlock>, FsError> {
+        let mut blocks = Vec::new();
+        for index in 0..inode.size / self.layout.block_size {
+            let block = match self.block_of(inode, index)? {
+                0 => return Err(FsError::Corrupted),
+                block => block,
+            };
+            blocks.push(DirBlock::parse(block, self.read_block(block)?)?);
+        }
+        Ok(blocks)
+    }
+
+    /// The block of the directory with `inode` holding the entry called `name` & the index
+    /// of the entry in there
+    fn find(&self, inode: &Inode, name: &str) -> Result<(DirBlock, usize), FsError> {
+        for block in self.dir_blocks(inode)? {
+            let found = block
+                .records
+                .iter()
+                .position(|record| record.inode != 0 && record.name == name.as_bytes());
+            if let Some(index) = found {
+                return Ok((block, index));
+            }
+        }
+        Err(FsError::NotFound)
+    }
+
+    /// File type of a directory entry for `kind`, if the volume stores it
+    fn entry_kind(&self, kind: FileType) -> u8 {
+        match (self.layout.filetype, kind) {
+            (false, _) => 0,
+            (true, FileType::File) => ENTRY_FILE,
+            (true, FileType::Directory) => ENTRY_DIRECTORY,
+        }
+    }
+
+    /// Adds an entry for the inode `child` to the directory `number`, which grows by a block
+    /// if no entry has room for the name. Writes the inode of the directory.
+    fn link(
+        &self,
+        state: &mut State,
+        number: u32,
+        dir: &mut Inode,
+        name: &str,
+        child: u32,
+        kind: FileType,
+    ) -> Result<(), FsError> {
+        let needed = record_len(name.len());
+        let kind = self.entry_kind(kind);
+        dir.flags &= !INDEX_FLAG;
+        for mut block in self.dir_blocks(dir)? {
+            let room = block.records.iter().find_map(|record| {
+                let used = match record.inode {
+                    0 => 0,
+                    _ => record_len(record.name.len()),
+                };
+                (record.len - used >= needed).then_some((record.at, record.len, used))
+            });
+            if let Some((at, len, used)) = room {
+                if used != 0 {
+                    write_u16(&mut block.data, at + 4, used as u16);
+                }
+                block.set(at + used, len - used, child, kind, name.as_bytes());
+                self.write_bytes(self.block_offset(block.block), &block.data)?;
+                return self.write_inode(number, dir);
+            }
+        }
+        let block_size = self.layout.block_size as usize;
+        let block = self.map(state, number, dir, dir.size / block_size as u64)?;
+        let mut added = DirBlock {
+            block,
+            data: vec![0; block_size],
+            records: Vec::new(),
+        };
+        added.set(0, block_size, child, kind, name.as_bytes());
+        self.write_bytes(self.block_offset(block), &added.data)?;
+        dir.size += block_size as u64;
+        self.write_inode(number, dir)
+    }
+
+    /// Removes the entry `index` of `block`, it is merged into the entry before
+    fn unlink(&self, mut block: DirBlock, index: usize) -> Result<(), FsError> {
+        let record = &block.records[index];
+        match index
+            .checked_sub(1)
+            .map(|previous| &block.records[previous])
+        {
+            Some(previous) => {
+                let at = previous.at;
+                write_u16(&mut block.data, at + 4, (previous.len + record.len) as u16)
+            }
+            None => write_u32(&mut block.data, record.at, 0),
+        }
+        self.write_bytes(self.block_offset(block.block), &block.data)
+    }
+
+    /// Frees the blocks & the inode `number`
+    fn free_inode(&self, state: &mut State, number: u32, inode: &mut Inode) -> Result<(), FsError> {
+        self.cut(state, inode, 0)?;
+        self.write_bytes(self.inode_offset(number)?, &[0; INODE_SIZE])?;
+        self.release(state, Bitmap::Inodes, number)
+    }
+}
+
+impl FileSystem for Ext2 {
+    fn root(&self) -> NodeId {
+        ROOT
+    }
+
+    fn lookup(&self, dir: NodeId, name: &str) -> Result<NodeId, FsError> {
+        let _state = self.state.lock();
+        let (_, inode) = self.dir_inode(dir)?;
+        let (block, index) = self.find(&inode, name)?;
+        Ok(block.records[index].inode as NodeId)
+    }
+
+    fn metadata(&self, node: NodeId) -> Result<Metadata, FsError> {
+        let _state = self.state.lock();
+        let number = u32::try_from(node).map_err(|_| FsError::NotFound)?;
+        let inode = self.read_inode(number)?;
+        Ok(Metadata {
+            kind: inode.kind(),
+            size: match inode.kind() {
+                FileType::File => inode.size,
+                FileType::Directory => 0,
+            },
+        })
+    }
+
+    fn read_dir(&self, dir: NodeId) -> Result<Vec<DirEntry>, FsError> {
+        let _state = self.state.lock();
+        let (_, inode) = self.dir_inode(dir)?;
+        let mut entries = Vec::new();
+        for block in self.dir_blocks(&inode)? {
+            for record in block.records {
+                if record.inode == 0 || record.name == b"." || record.name == b".." {
+                    continue;
+                }
+                let kind = match record.kind {
+                    ENTRY_FILE => FileType::File,
+                    ENTRY_DIRECTORY => FileType::Directory,
+                    _ if !self.layout.filetype => self.read_inode(record.inode)?.kind(),
+                    // devices, symbolic links & the like
+                    _ => FileType::File,
+                };
+                entries.push(DirEntry {
+                    name: String::from_utf8_lossy(&record.name).into_owned(),
+                    kind,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn read(&self, file: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let _state = self.state.lock();
+        let (_, inode) = self.file_inode(file)?;
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((inode.size - offset) as usize);
+        let block_size = self.layout.block_size;
+        let mut done = 0;
+        while done < len {
+            let at = offset + done as u64;
+            let run = ((block_size - at % block_size) as usize).min(len - done);
+            match self.block_of(&inode, at / block_size)? {
+                0 => buffer[done..done + run].fill(0),
+                block => {
+                    let start = self.block_offset(block) + at % block_size;
+                    self.read_bytes(start, &mut buffer[done..done + run])?
+                }
+            }
+            done += run;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, file: NodeId, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let (number, mut inode) = self.file_inode(file)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = offset + data.len() as u64;
+        if end > self.layout.max_size() {
+            return Err(FsError::NoSpace);
+        }
+        let block_size = self.layout.block_size;
+        let mut done = 0;
+        while done < data.len() {
+            let at = offset + done as u64;
+            let run = ((block_size - at % block_size) as usize).min(data.len() - done);
+            let written = self
+                .map(&mut state, number, &mut inode, at / block_size)
+                .and_then(|block| {
+                    let start = self.block_offset(block) + at % block_size;
+                    self.write_bytes(start, &data[done..done + run])
+                });
+            if let Err(e) = written {
+                // the blocks added past the end are given back
+                let keep = inode.size.div_ceil(block_size);
+                self.cut(&mut state, &mut inode, keep)?;
+                self.write_inode(number, &inode)?;
+                return Err(e);
+            }
+            done += run;
+        }
+        inode.size = inode.size.max(end);
+        self.write_inode(number, &inode)
+    }
+
+    fn truncate(&self, file: NodeId, size: u64) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if size > self.layout.max_size() {
+            return Err(FsError::NoSpace);
+        }
+        let (number, mut inode) = self.file_inode(file)?;
+        self.resize(&mut state, number, &mut inode, size)
+    }
+
+    fn create(&self, dir: NodeId, name: &str, kind: FileType) -> Result<NodeId, FsError> {
+        let mut state = self.state.lock();
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !valid_name(name) {
+            return Err(FsError::InvalidName);
+        }
+        let (parent, mut dir) = self.dir_inode(dir)?;
+        match self.find(&dir, name) {
+            Ok(_) => return Err(FsError::Exists),
+            Err(FsError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+        if kind == FileType::Directory && dir.links >= MAX_LINKS {
+            return Err(FsError::NoSpace);
+        }
+
+        let group = self.group_of_inode(parent);
+        let number = self.allocate(&mut state, Bitmap::Inodes, group)?;
+        let mut inode = match kind {
+            FileType::File => Inode::new(FILE_MODE, 1),
+            FileType::Directory => Inode::new(DIRECTORY_MODE, 2),
+        };
+        let mut created = Ok(());
+        if kind == FileType::Directory {
+            created = self
+                .map(&mut state, number, &mut inode, 0)
+                .and_then(|block| {
+                    let block_size = self.layout.block_size as usize;
+                    let mut entries = DirBlock {
+                        block,
+                        data: vec![0; block_size],
+                        records: Vec::new(),
+                    };
+                    let kind = self.entry_kind(FileType::Directory);
+                    entries.set(0, record_len(1), number, kind, b".");
+                    entries.set(
+                        record_len(1),
+                        block_size - record_len(1),
+                        parent,
+                        kind,
+                        b"..",
+                    );
+                    inode.size = block_size as u64;
+                    self.write_bytes(self.block_offset(block), &entries.data)
+                });
+        }
+        let created = created
+            .and_then(|()| self.write_inode(number, &inode))
+            .and_then(|()| self.link(&mut state, parent, &mut dir, name, number, kind));
+        if let Err(e) = created {
+            self.free_inode(&mut state, number, &mut inode)?;
+            return Err(e);
+        }
+
+        if kind == FileType::Directory {
+            dir.links += 1;
+            self.write_inode(parent, &dir)?;
+            let group = self.group_of_inode(number) as usize;
+            state.groups[group].directories += 1;
+            self.write_counts(&state, group)?;
+        }
+        Ok(number as NodeId)
+    }
+
+    fn remove(&self, dir: NodeId, name: &str) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let (parent, mut dir) = self.dir_inode(dir)?;
+        let (block, index) = self.find(&dir, name)?;
+        let number = block.records[index].inode;
+        let mut inode = self.read_inode(number)?;
+        if inode.kind() == FileType::Directory {
+            let empty = self.dir_blocks(&inode)?.iter().all(|block| {
+                block
+                    .records
+                    .iter()
+                    .all(|record| record.inode == 0 || record.name == b"." || record.name == b"..")
+            });
+            if !empty {
+                return Err(FsError::NotEmpty);
+            }
+        }
+        self.unlink(block, index)?;
+
+        match inode.kind() {
+            FileType::Directory => {
+                dir.links = dir.links.saturating_sub(1);
+                self.write_inode(parent, &dir)?;
+                self.free_inode(&mut state, number, &mut inode)?;
+                let group = self.group_of_inode(number) as usize;
+                let entry = &mut state.groups[group];
+                entry.directories = entry.directories.saturating_sub(1);
+                self.write_counts(&state, group)
+            }
+            FileType::File => {
+                inode.links = inode.links.saturating_sub(1);
+                match inode.links {
+                    0 => self.free_inode(&mut state, number, &mut inode),
+                    _ => self.write_inode(number, &inode),
+                }
+            }
+        }
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(block::block_on(self.device.flush())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    /// Made with mke2fs by the `ext2_test_image` task of the smeltfile: 128 blocks of 1 KiB
+    #[cfg(ext2_image)]
+    static IMAGE: &[u8] = include_bytes!("../../../build/ext2-test.img");
+
+    #[cfg(ext2_image)]
+    fn image() -> Ext2 {
+        let disk = crate::driver::block::RamDisk::from_static(512, IMAGE);
+        Ext2::new(Arc::new(disk)).unwrap()
+    }
+
+    #[cfg(ext2_image)]
+    fn free(fs: &Ext2) -> (u32, u32) {
+        let state = fs.state.lock();
+        (state.free_blocks, state.free_inodes)
+    }
+
+    unit_test! {
+        fn block_paths() {
+            // 1 KiB blocks hold 256 block numbers
+            assert_eq!(block_path(256, 11), Some((11, [0; 3], 0)));
+            assert_eq!(block_path(256, 12), Some((12, [0; 3], 1)));
+            assert_eq!(block_path(256, 12 + 255), Some((12, [255, 0, 0], 1)));
+            assert_eq!(block_path(256, 12 + 256 + 257), Some((13, [1, 1, 0], 2)));
+            let triple = 12 + 256 + 256 * 256;
+            assert_eq!(block_path(256, triple + 65536 + 2), Some((14, [1, 0, 2], 3)));
+            assert_eq!(block_path(256, triple + 256 * 256 * 256), None);
+        }
+
+        #[cfg(ext2_image)]
+        fn image_files() {
+            let fs = image();
+            let mut names: Vec<_> =
+                fs.read_dir(ROOT).unwrap().into_iter().map(|e| (e.name, e.kind)).collect();
+            names.sort_by(|a, b| a.0.cmp(&b.0));
+            let directory = |name: &str| (String::from(name), FileType::Directory);
+            let file = (String::from("hello.txt"), FileType::File);
+            assert_eq!(names, [directory("dir"), file, directory("lost+found"), directory("many")]);
+
+            let hello = fs.lookup(ROOT, "hello.txt").unwrap();
+            let mut buffer = [0; 64];
+            assert_eq!(fs.read(hello, 0, &mut buffer), Ok(17));
+            assert_eq!(&buffer[..17], b"Hello from ext2!\n");
+            // the entries take 2 blocks
+            let many = fs.lookup(ROOT, "many").unwrap();
+            assert_eq!(fs.read_dir(many).unwrap().len(), 40);
+            let last = fs.lookup(many, "file-with-a-long-name-39").unwrap();
+            assert_eq!(fs.read(last, 0, &mut buffer), Ok(3));
+            assert_eq!(&buffer[..3], b"39\n");
+
+            let dir = fs.lookup(ROOT, "dir").unwrap();
+            let sparse = fs.lookup(dir, "sparse.bin").unwrap();
+            assert_eq!(fs.metadata(sparse).unwrap().size, (70 << 20) + 6);
+            for (offset, text) in [(0, b"direct"), (20 << 10, b"single"), (300 << 10, b"double"),
+                (70 << 20, b"triple")]
+            {
+                assert_eq!(fs.read(sparse, offset, &mut buffer[..6]), Ok(6));
+                assert_eq!(&buffer[..6], text);
+            }
+            buffer.fill(0xFF);
+            assert_eq!(fs.read(sparse, 1 << 20, &mut buffer), Ok(64));
+            assert!(buffer.iter().all(|b| *b == 0));
+            assert_eq!(fs.read(dir, 0, &mut buffer), Err(FsError::IsADirectory));
+            assert_eq!(fs.lookup(dir, "missing"), Err(FsError::NotFound));
+        }
+
+        #[cfg(ext2_image)]
+        fn image_changes() {
+            let fs = image();
+            let start = free(&fs);
+            let dir = fs.lookup(ROOT, "dir").unwrap();
+            let sparse = fs.lookup(dir, "sparse.bin").unwrap();
+            // 4 data blocks & 6 indirect ones
+            fs.truncate(sparse, 0).unwrap();
+            assert_eq!(free(&fs), (start.0 + 10, start.1));
+            fs.remove(dir, "sparse.bin").unwrap();
+            assert_eq!(fs.read_dir(dir), Ok(Vec::new()));
+            let start = free(&fs);
+            let root_links = fs.read_inode(ROOT as u32).unwrap().links;
+
+            let new = fs.create(ROOT, "new", FileType::Directory).unwrap();
+            let file = fs.create(new, "data", FileType::File).unwrap();
+            assert_eq!(fs.create(new, "data", FileType::File), Err(FsError::Exists));
+            assert_eq!(fs.create(new, "a/b", FileType::File), Err(FsError::InvalidName));
+            // the last 2 blocks are behind the single indirect block
+            let data: Vec<u8> = (0..14 * 1024).map(|i| (i % 251) as u8).collect();
+            fs.write(file, 0, &data).unwrap();
+            let mut content = vec![0xFF; data.len()];
+            assert_eq!(fs.read(file, 0, &mut content), Ok(data.len()));
+            assert_eq!(content, data);
+            assert_eq!(fs.read_inode(file as u32).unwrap().sectors, 15 * 2);
+
+            // growing again after cutting in the middle of a block reads zeros
+            fs.truncate(file, 1500).unwrap();
+            fs.truncate(file, 3000).unwrap();
+            assert_eq!(fs.read(file, 0, &mut content), Ok(3000));
+            assert_eq!(content[..1500], data[..1500]);
+            assert!(content[1500..3000].iter().all(|b| *b == 0));
+            fs.write(file, 70 << 20, b"far").unwrap();
+            assert_eq!(fs.read(file, 70 << 20, &mut content[..8]), Ok(3));
+            assert_eq!(&content[..3], b"far");
+
+            // the root directory grows by a block
+            let name = |index| alloc::format!("a long name to fill the root directory {}", index);
+            for index in 0..30 {
+                fs.create(ROOT, &name(index), FileType::File).unwrap();
+            }
+            assert_eq!(fs.read_dir(ROOT).unwrap().len(), 35);
+            assert_eq!(fs.read_inode(ROOT as u32).unwrap().size, 2048);
+            for index in 0..30 {
+                fs.remove(ROOT, &name(index)).unwrap();
+            }
+            assert_eq!(fs.read_inode(ROOT as u32).unwrap().links, root_links + 1);
+            assert_eq!(fs.remove(ROOT, "new"), Err(FsError::NotEmpty));
+            fs.remove(new, "data").unwrap();
+            fs.remove(ROOT, "new").unwrap();
+            assert_eq!(fs.read_inode(ROOT as u32).unwrap().links, root_links);
+            // everything is given back but the added block of the root directory
+            assert_eq!(free(&fs), (start.0 - 1, start.1));
+        }
+    }
+}
@@ -0,0 +1,1221 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! FAT12, FAT16 & FAT32 with long file names (VFAT), e.g. the boot partition of limine.
+//!
+//! The `NodeId` of a file is the byte offset of its short directory entry on the volume, the
+//! root directory has no entry & is `ROOT`. The volume is read & written in bytes, which
+//! `read_bytes()` & `write_bytes()` turn into whole sectors of the device.
+//!
+//! Nothing is cached, every operation holds the lock of the volume until it is done. Files get
+//! the date 1980-01-01, there is no clock yet.
+//!
+//! main source: Microsoft Extensible Firmware Initiative FAT32 File System Specification 1.03
+
+use super::{DirEntry, FileSystem, FileType, FsError, FsType, Metadata, NodeId};
+use crate::driver::block::{self, BlockDevice};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub static TYPE: FsType = FsType { name: "fat", open };
+
+fn open(device: Arc<dyn BlockDevice>) -> Result<Arc<dyn FileSystem>, FsError> {
+    Ok(Arc::new(Fat::new(device)?))
+}
+
+/// The root directory, offset 0 is in the boot sector
+const ROOT: NodeId = 0;
+
+const ENTRY_SIZE: usize = 32;
+/// Most entries in a directory
+const MAX_ENTRIES: usize = 65536;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Attributes of a long name entry, compared under `ATTR_LONG_NAME_MASK`
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_LONG_NAME_MASK: u8 = 0x3F;
+/// First name byte of an unused entry, an entry starting with 0 ends the directory
+const FREE: u8 = 0xE5;
+/// Set in the order of the long name entry that comes first, which holds the end of the name
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Offsets of the UTF-16 code units in a long name entry
+const LONG_NAME_UNITS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Most UTF-16 code units in a long name
+const MAX_NAME: usize = 255;
+/// Case flags of the short name, set by Windows NT & Linux for names in one case
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXTENSION: u8 = 0x10;
+/// Characters of short names besides letters & digits
+const SHORT_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+/// Characters that are in no name
+const INVALID: &str = "\"*/:<>?\\|";
+/// DOS date of 1980-01-01
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FSINFO_SIGNATURE: u32 = 0x61417272;
+const FSINFO_FREE_COUNT: u64 = 488;
+const FSINFO_NEXT_FREE: u64 = 492;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Where the root directory is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Root {
+    /// FAT12 & FAT16 have a fixed number of entries after the FATs
+    Fixed {
+        start: u64,
+        entries: usize,
+    },
+    Cluster(u32),
+}
+
+/// A directory, by where its entries are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    Fixed {
+        start: u64,
+        entries: usize,
+    },
+    /// First cluster of the chain
+    Chain(u32),
+}
+
+/// Where the structures are, from the boot sector. Offsets & sizes are in bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Layout {
+    kind: FatType,
+    /// Size of the volume
+    size: u64,
+    cluster_size: usize,
+    fat_start: u64,
+    fat_size: u64,
+    fat_count: u64,
+    /// The FAT that is read
+    active_fat: u64,
+    /// Changes are written to every FAT, else only to the active one
+    mirror: bool,
+    root: Root,
+    data_start: u64,
+    /// Number of data clusters, they are numbered from 2 on
+    clusters: u32,
+    /// Offset of the FAT32 FSInfo sector
+    fsinfo: Option<u64>,
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+impl Layout {
+    /// Reads the BIOS parameter block in `boot`, `None` if it is not a FAT boot sector
+    fn parse(boot: &[u8]) -> Option<Self> {
+        if boot.len() < 512 || !matches!(boot[0], 0xEB | 0xE9) || boot[510..512] != [0x55, 0xAA] {
+            return None;
+        }
+        let sector_size = read_u16(boot, 11) as u64;
+        let per_cluster = boot[13] as u64;
+        let reserved = read_u16(boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = read_u16(boot, 17) as usize;
+        let sectors = match read_u16(boot, 19) {
+            0 => read_u32(boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = match read_u16(boot, 22) {
+            0 => read_u32(boot, 36) as u64,
+            sectors => sectors as u64,
+        };
+        if !sector_size.is_power_of_two()
+            || !(512..=4096).contains(&sector_size)
+            || !per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return None;
+        }
+        let root_sectors = (root_entries * ENTRY_SIZE).div_ceil(sector_size as usize) as u64;
+        let data = reserved + fat_count * fat_sectors + root_sectors;
+        let clusters = sectors.checked_sub(data)? / per_cluster;
+        // the type only depends on the number of clusters
+        let kind = match clusters {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let fat_size = fat_sectors * sector_size;
+        let needed = match kind {
+            FatType::Fat12 => (clusters + 2) * 3 / 2 + 1,
+            FatType::Fat16 => (clusters + 2) * 2,
+            FatType::Fat32 => (clusters + 2) * 4,
+        };
+        if clusters == 0 || clusters > 0x0FFF_FFF5 || fat_size < needed {
+            return None;
+        }
+        let fat_start = reserved * sector_size;
+        let mut layout = Self {
+            kind,
+            size: sectors * sector_size,
+            cluster_size: (per_cluster * sector_size) as usize,
+            fat_start,
+            fat_size,
+            fat_count,
+            active_fat: 0,
+            mirror: true,
+            root: Root::Fixed {
+                start: fat_start + fat_count * fat_size,
+                entries: root_entries,
+            },
+            data_start: data * sector_size,
+            clusters: clusters as u32,
+            fsinfo: None,
+        };
+        match kind {
+            FatType::Fat32 => {
+                let flags = read_u16(boot, 40);
+                let root = read_u32(boot, 44);
+                // version 0.0 is the only one
+                if root_entries != 0 || read_u16(boot, 42) != 0 || !layout.valid(root) {
+                    return None;
+                }
+                layout.mirror = flags & 0x80 == 0;
+                layout.active_fat = match layout.mirror {
+                    true => 0,
+                    false => (flags & 0xF) as u64,
+                };
+                if layout.active_fat >= fat_count {
+                    return None;
+                }
+                layout.root = Root::Cluster(root);
+                layout.fsinfo = match read_u16(boot, 48) {
+                    0 | 0xFFFF => None,
+                    sector => Some(sector as u64 * sector_size),
+                };
+            }
+            _ if root_entries == 0 => return None,
+            _ => {}
+        }
+        Some(layout)
+    }
+
+    /// Whether `cluster` is a data cluster
+    fn valid(&self, cluster: u32) -> bool {
+        (2..self.clusters + 2).contains(&cluster)
+    }
+}
+
+/// What may change while the volume is mounted
+struct State {
+    /// Where to look for a free cluster first
+    next_free: u32,
+    /// The free count in FSInfo is marked unknown, it is not kept up to date
+    fsinfo_unknown: bool,
+}
+
+/// A FAT volume, see the module documentation
+pub struct Fat {
+    device: Arc<dyn BlockDevice>,
+    layout: Layout,
+    read_only: bool,
+    state: Mutex<State>,
+}
+
+/// The raw entries of a directory
+struct Slots {
+    data: Vec<u8>,
+    /// (offset on the volume, length) of the parts of the directory
+    spans: Vec<(u64, usize)>,
+}
+
+impl Slots {
+    fn len(&self) -> usize {
+        self.data.len() / ENTRY_SIZE
+    }
+
+    fn get(&self, index: usize) -> &[u8] {
+        &self.data[index * ENTRY_SIZE..][..ENTRY_SIZE]
+    }
+
+    /// Offset of entry `index` on the volume
+    fn address(&self, index: usize) -> u64 {
+        let mut offset = index * ENTRY_SIZE;
+        for (start, len) in &self.spans {
+            if offset < *len {
+                return start + offset as u64;
+            }
+            offset -= len;
+        }
+        unreachable!("directory entry {} out of range", index)
+    }
+}
+
+/// A file or directory in `Slots`
+struct Entry {
+    /// The long name, or the short name if there is none
+    name: String,
+    short: [u8; 11],
+    /// The first long name entry, or the short entry if there is no long name
+    first: usize,
+    /// The short entry
+    slot: usize,
+    attributes: u8,
+    /// First cluster with the high half, which only FAT32 has
+    cluster: u32,
+}
+
+impl Entry {
+    fn kind(&self) -> FileType {
+        match self.attributes & ATTR_DIRECTORY != 0 {
+            true => FileType::Directory,
+            false => FileType::File,
+        }
+    }
+
+    /// Whether the long or the short name is `name`
+    fn is_called(&self, name: &str) -> bool {
+        same_name(&self.name, name) || same_name(&short_text(&self.short, 0), name)
+    }
+}
+
+/// Checksum of a short name, stored in its long name entries
+fn checksum(short: &[u8; 11]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte))
+}
+
+/// The short name as text, lower case if the case flags say so
+fn short_text(short: &[u8; 11], case: u8) -> String {
+    let mut bytes = *short;
+    // a name starting with 0xE5 is stored with 0x05
+    if bytes[0] == 0x05 {
+        bytes[0] = FREE;
+    }
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let len = bytes
+            .iter()
+            .rposition(|b| *b != b' ')
+            .map_or(0, |end| end + 1);
+        bytes[..len]
+            .iter()
+            .map(|b| match lower {
+                true => b.to_ascii_lowercase() as char,
+                // other bytes are in the OEM code page, read as Latin-1
+                false => *b as char,
+            })
+            .collect()
+    };
+    let mut name = part(&bytes[..8], case & LOWER_BASE != 0);
+    let extension = part(&bytes[8..], case & LOWER_EXTENSION != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+/// Compares file names like FAT does, ignoring case
+fn same_name(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_lowercase)
+        .eq(b.chars().flat_map(char::to_lowercase))
+}
+
+/// Whether a name can be stored in a directory
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.encode_utf16().count() <= MAX_NAME
+        && name != "."
+        && name != ".."
+        && !name.ends_with(['.', ' '])
+        && !name.chars().any(|c| c < ' ' || INVALID.contains(c))
+}
+
+fn short_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || SHORT_SPECIAL.contains(&byte)
+}
+
+/// The short name & case flags if `name` is a short name in one case per part, then it needs
+/// no long name
+fn exact_short(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    for (part, at, flag) in [(base, 0, LOWER_BASE), (extension, 8, LOWER_EXTENSION)] {
+        if !part.bytes().all(|b| short_char(b.to_ascii_uppercase())) {
+            return None;
+        }
+        match (
+            part.bytes().any(|b| b.is_ascii_lowercase()),
+            part.bytes().any(|b| b.is_ascii_uppercase()),
+        ) {
+            (true, true) => return None,
+            (true, false) => case |= flag,
+            _ => {}
+        }
+        short[at..at + part.len()].copy_from_slice(part.to_ascii_uppercase().as_bytes());
+    }
+    Some((short, case))
+}
+
+/// A numbered short name for `name` like "LONGNA~1TXT", which is not `taken`
+fn numbered_short(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> Option<[u8; 11]> {
+    let convert = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|c| !matches!(c, ' ' | '.'))
+            .map(
+                |c| match c.is_ascii() && short_char(c.to_ascii_uppercase() as u8) {
+                    true => c.to_ascii_uppercase() as u8,
+                    false => b'_',
+                },
+            )
+            .collect()
+    };
+    let name = name.trim_start_matches('.');
+    let (base, extension) = name.rsplit_once('.').unwrap_or((name, ""));
+    let mut base = convert(base);
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    let extension = convert(extension);
+    let mut short = [b' '; 11];
+    let extension = &extension[..extension.len().min(3)];
+    short[8..8 + extension.len()].copy_from_slice(extension);
+    for number in 1..1_000_000 {
+        let tail = alloc::format!("~{}", number);
+        let len = base.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..len].copy_from_slice(&base[..len]);
+        short[len..len + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken(&short) {
+            return Some(short);
+        }
+    }
+    None
+}
+
+/// The files & directories in `slots`, without "." & ".." and the volume label
+fn entries(slots: &Slots) -> Vec<Entry> {
+    // units of the long name read so far, the next order expected & the checksum
+    let mut long: Option<(Vec<u16>, u8, u8, usize)> = None;
+    let mut entries = Vec::new();
+    for index in 0..slots.len() {
+        let slot = slots.get(index);
+        match slot[0] {
+            0 => break,
+            FREE => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+        if slot[11] & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME {
+            let order = slot[0] & !LAST_LONG_ENTRY;
+            if slot[0] & LAST_LONG_ENTRY != 0 {
+                long = Some((vec![0; order as usize * 13], order, slot[13], index));
+            }
+            long = match long.take() {
+                Some((mut units, next, sum, first))
+                    if next == order && order > 0 && sum == slot[13] =>
+                {
+                    let at = (order as usize - 1) * 13;
+                    for (unit, offset) in units[at..at + 13].iter_mut().zip(LONG_NAME_UNITS) {
+                        *unit = read_u16(slot, offset);
+                    }
+                    Some((units, next - 1, sum, first))
+                }
+                _ => None,
+            };
+            continue;
+        }
+        let short: [u8; 11] = slot[..11].try_into().unwrap();
+        let long_name = long.take();
+        if slot[11] & ATTR_VOLUME_ID != 0 || short[0] == b'.' {
+            continue;
+        }
+        let (name, first) = match long_name {
+            Some((units, 0, sum, first)) if sum == checksum(&short) => {
+                let units = units.into_iter().take_while(|unit| *unit != 0);
+                let name = char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (name, first)
+            }
+            _ => (short_text(&short, slot[12]), index),
+        };
+        entries.push(Entry {
+            name,
+            short,
+            first,
+            slot: index,
+            attributes: slot[11],
+            cluster: ((read_u16(slot, 20) as u32) << 16) | read_u16(slot, 26) as u32,
+        });
+    }
+    entries
+}
+
+/// A short directory entry for a new file or directory
+fn short_entry(short: &[u8; 11], case: u8, attributes: u8, cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+    entry[..11].copy_from_slice(short);
+    entry[11] = attributes;
+    entry[12] = case;
+    for date in [16, 18, 24] {
+        entry[date..date + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    }
+    set_cluster(&mut entry, cluster);
+    entry
+}
+
+fn set_cluster(entry: &mut [u8], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+impl Fat {
+    /// Reads the boot sector, `NotRecognized` if the device holds no FAT volume
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Self, FsError> {
+        let mut boot = vec![0; device.sector_size()];
+        let layout = match block::block_on(device.read(0, &mut boot)) {
+            Ok(()) => Layout::parse(&boot).ok_or(FsError::NotRecognized)?,
+            Err(_) if device.sectors() == 0 => return Err(FsError::NotRecognized),
+            Err(e) => return Err(e.into()),
+        };
+        if layout.size > device.sectors() * device.sector_size() as u64 {
+            return Err(FsError::Corrupted);
+        }
+        let fat = Self {
+            read_only: device.read_only(),
+            device,
+            layout,
+            state: Mutex::new(State {
+                next_free: 2,
+                fsinfo_unknown: false,
+            }),
+        };
+        if let Some(fsinfo) = fat.layout.fsinfo {
+            let mut sector = [0; 512];
+            fat.read_bytes(fsinfo, &mut sector)?;
+            let next = read_u32(&sector, FSINFO_NEXT_FREE as usize);
+            if read_u32(&sector, 0) == FSINFO_LEAD_SIGNATURE
+                && read_u32(&sector, 484) == FSINFO_SIGNATURE
+            {
+                if fat.layout.valid(next) {
+                    fat.state.lock().next_free = next;
+                }
+            } else {
+                fat.state.lock().fsinfo_unknown = true;
+            }
+        }
+        Ok(fat)
+    }
+
+    /// Reads `buffer.len()` bytes at `offset` of the volume
+    fn read_bytes(&self, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        super::read_bytes(&*self.device, offset, buffer)
+    }
+
+    fn write_bytes(&self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        super::write_bytes(&*self.device, offset, data)
+    }
+
+    /// Offset of the FAT entry of `cluster` in FAT number `copy`
+    fn fat_offset(&self, copy: u64, cluster: u32) -> u64 {
+        let layout = &self.layout;
+        let within = match layout.kind {
+            FatType::Fat12 => cluster as u64 * 3 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        };
+        layout.fat_start + copy * layout.fat_size + within
+    }
+
+    /// Value that ends a chain
+    fn end_of_chain(&self) -> u32 {
+        match self.layout.kind {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// The FAT entry of `cluster`: the next cluster of the chain, 0 if it is free
+    fn next(&self, cluster: u32) -> Result<u32, FsError> {
+        let at = self.fat_offset(self.layout.active_fat, cluster);
+        Ok(match self.layout.kind {
+            FatType::Fat12 => {
+                let mut bytes = [0; 2];
+                self.read_bytes(at, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes);
+                match cluster & 1 {
+                    1 => value >> 4,
+                    _ => value & 0xFFF,
+                }
+                .into()
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0; 2];
+                self.read_bytes(at, &mut bytes)?;
+                u16::from_le_bytes(bytes).into()
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0; 4];
+                self.read_bytes(at, &mut bytes)?;
+                u32::from_le_bytes(bytes) & 0x0FFF_FFFF
+            }
+        })
+    }
+
+    /// Sets the FAT entry of `cluster` in every FAT that is written
+    fn set_next(&self, state: &mut State, cluster: u32, value: u32) -> Result<(), FsError> {
+        if let (Some(fsinfo), false) = (self.layout.fsinfo, state.fsinfo_unknown) {
+            // the free count is only a hint, it is marked unknown instead of kept up to date
+            self.write_bytes(fsinfo + FSINFO_FREE_COUNT, &u32::MAX.to_le_bytes())?;
+            state.fsinfo_unknown = true;
+        }
+        let copies = match self.layout.mirror {
+            true => 0..self.layout.fat_count,
+            false => self.layout.active_fat..self.layout.active_fat + 1,
+        };
+        for copy in copies {
+            let at = self.fat_offset(copy, cluster);
+            match self.layout.kind {
+                FatType::Fat12 => {
+                    let mut bytes = [0; 2];
+                    self.read_bytes(at, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let new = match cluster & 1 {
+                        1 => (old & 0x000F) | ((value as u16) << 4),
+                        _ => (old & 0xF000) | value as u16,
+                    };
+                    self.write_bytes(at, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.write_bytes(at, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // the upper 4 bits are reserved
+                    let mut bytes = [0; 4];
+                    self.read_bytes(at, &mut bytes)?;
+                    let new = (u32::from_le_bytes(bytes) & 0xF000_0000) | value;
+                    self.write_bytes(at, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The clusters of the chain starting with `first`, none if it is 0
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        if first == 0 {
+            return Ok(chain);
+        }
+        loop {
+            if !self.layout.valid(cluster) || chain.len() >= self.layout.clusters as usize {
+                return Err(FsError::Corrupted);
+            }
+            chain.push(cluster);
+            let next = self.next(cluster)?;
+            // 0x?FF8 & above end the chain
+            if next >= self.end_of_chain() - 7 {
+                return Ok(chain);
+            }
+            cluster = next;
+        }
+    }
+
+    /// Appends `count` free clusters to `chain`. If there are not enough, the chain is left as
+    /// it was.
+    fn extend(&self, state: &mut State, chain: &mut Vec<u32>, count: usize) -> Result<(), FsError> {
+        let old = chain.len();
+        let clusters = self.layout.clusters;
+        let mut candidate = state.next_free;
+        let mut searched = 0;
+        while chain.len() < old + count {
+            if searched == clusters {
+                self.shorten(state, chain, old)?;
+                return Err(FsError::NoSpace);
+            }
+            if !self.layout.valid(candidate) {
+                candidate = 2;
+            }
+            if self.next(candidate)? == 0 {
+                self.set_next(state, candidate, self.end_of_chain())?;
+                if let Some(last) = chain.last() {
+                    self.set_next(state, *last, candidate)?;
+                }
+                chain.push(candidate);
+            }
+            candidate += 1;
+            searched += 1;
+        }
+        state.next_free = candidate;
+        Ok(())
+    }
+
+    /// Frees the clusters of `chain` from index `keep` on
+    fn shorten(&self, state: &mut State, chain: &mut Vec<u32>, keep: usize) -> Result<(), FsError> {
+        if keep > 0 && keep < chain.len() {
+            self.set_next(state, chain[keep - 1], self.end_of_chain())?;
+        }
+        for cluster in chain.drain(keep.min(chain.len())..) {
+            self.set_next(state, cluster, 0)?;
+        }
+        Ok(())
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.layout.data_start + (cluster as u64 - 2) * self.layout.cluster_size as u64
+    }
+
+    /// Where `len` bytes from `start` of the file stored in `chain` are on the volume, as
+    /// (offset, length). Runs of consecutive clusters are merged.
+    fn runs(&self, chain: &[u32], start: u64, len: usize) -> Vec<(u64, usize)> {
+        let cluster_size = self.layout.cluster_size as u64;
+        let mut runs: Vec<(u64, usize)> = Vec::new();
+        let mut position = start;
+        let end = start + len as u64;
+        while position < end {
+            let within = position % cluster_size;
+            let count = (cluster_size - within).min(end - position) as usize;
+            let at = self.cluster_offset(chain[(position / cluster_size) as usize]) + within;
+            match runs.last_mut() {
+                Some((run, run_len)) if *run + *run_len as u64 == at => *run_len += count,
+                _ => runs.push((at, count)),
+            }
+            position += count as u64;
+        }
+        runs
+    }
+
+    /// Writes zeros into bytes `start..end` of the file stored in `chain`
+    fn zero(&self, chain: &[u32], start: u64, end: u64) -> Result<(), FsError> {
+        let zeros = vec![0; self.layout.cluster_size];
+        for (at, len) in self.runs(chain, start, (end - start) as usize) {
+            for offset in (0..len).step_by(zeros.len()) {
+                let count = zeros.len().min(len - offset);
+                self.write_bytes(at + offset as u64, &zeros[..count])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_entry(&self, node: NodeId) -> Result<[u8; ENTRY_SIZE], FsError> {
+        let mut entry = [0; ENTRY_SIZE];
+        self.read_bytes(node, &mut entry)?;
+        Ok(entry)
+    }
+
+    /// First cluster of a short entry, FAT12 & FAT16 have no high half
+    fn entry_cluster(&self, entry: &[u8]) -> u32 {
+        let high = match self.layout.kind {
+            FatType::Fat32 => read_u16(entry, 20) as u32,
+            _ => 0,
+        };
+        (high << 16) | read_u16(entry, 26) as u32
+    }
+
+    /// The clusters of the file with `entry`, which cover its size
+    fn file_chain(&self, entry: &[u8]) -> Result<Vec<u32>, FsError> {
+        let chain = self.chain(self.entry_cluster(entry))?;
+        let size = read_u32(entry, 28) as u64;
+        match (chain.len() as u64) < size.div_ceil(self.layout.cluster_size as u64) {
+            true => Err(FsError::Corrupted),
+            false => Ok(chain),
+        }
+    }
+
+    /// The entry of the file `node`, `IsADirectory` for a directory
+    fn file_entry(&self, node: NodeId) -> Result<[u8; ENTRY_SIZE], FsError> {
+        if node == ROOT {
+            return Err(FsError::IsADirectory);
+        }
+        let entry = self.read_entry(node)?;
+        match entry[11] & ATTR_DIRECTORY {
+            0 => Ok(entry),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    fn dir(&self, node: NodeId) -> Result<Dir, FsError> {
+        if node == ROOT {
+            return Ok(match self.layout.root {
+                Root::Fixed { start, entries } => Dir::Fixed { start, entries },
+                Root::Cluster(cluster) => Dir::Chain(cluster),
+            });
+        }
+        let entry = self.read_entry(node)?;
+        if entry[11] & ATTR_DIRECTORY == 0 {
+            return Err(FsError::NotADirectory);
+        }
+        match self.entry_cluster(&entry) {
+            0 => Err(FsError::Corrupted),
+            cluster => Ok(Dir::Chain(cluster)),
+        }
+    }
+
+    fn slots(&self, dir: Dir) -> Result<Slots, FsError> {
+        let spans: Vec<(u64, usize)> = match dir {
+            Dir::Fixed { start, entries } => vec![(start, entries * ENTRY_SIZE)],
+            Dir::Chain(first) => self
+                .chain(first)?
+                .into_iter()
+                .map(|cluster| (self.cluster_offset(cluster), self.layout.cluster_size))
+                .collect(),
+        };
+        let mut data = vec![0; spans.iter().map(|(_, len)| len).sum()];
+        let mut at = 0;
+        for (start, len) in &spans {
+            self.read_bytes(*start, &mut data[at..at + len])?;
+            at += len;
+        }
+        Ok(Slots { data, spans })
+    }
+
+    /// The slots of `dir` & the entry called `name` in there
+    fn find(&self, dir: NodeId, name: &str) -> Result<(Slots, Entry), FsError> {
+        let slots = self.slots(self.dir(dir)?)?;
+        let entry = entries(&slots)
+            .into_iter()
+            .find(|entry| entry.is_called(name))
+            .ok_or(FsError::NotFound)?;
+        Ok((slots, entry))
+    }
+
+    /// The slots of `dir` & the index of the first of `count` consecutive unused ones, the
+    /// directory is extended if there are none
+    fn free_slots(
+        &self,
+        state: &mut State,
+        dir: Dir,
+        count: usize,
+    ) -> Result<(Slots, usize), FsError> {
+        let slots = self.slots(dir)?;
+        // unused slots before the current one
+        let mut run = 0;
+        for index in 0..slots.len() {
+            match slots.get(index)[0] {
+                // everything from the end of the directory on is unused
+                0 => {
+                    let first = index - run;
+                    if slots.len() - first >= count {
+                        self.keep_end(&slots, first + count)?;
+                        return Ok((slots, first));
+                    }
+                    run = slots.len() - first;
+                    break;
+                }
+                FREE => run += 1,
+                _ => run = 0,
+            }
+            if run == count {
+                return Ok((slots, index + 1 - count));
+            }
+        }
+        let Dir::Chain(first) = dir else {
+            return Err(FsError::NoSpace);
+        };
+        let cluster_size = self.layout.cluster_size;
+        let added = ((count - run) * ENTRY_SIZE).div_ceil(cluster_size);
+        if slots.len() + added * cluster_size / ENTRY_SIZE > MAX_ENTRIES {
+            return Err(FsError::NoSpace);
+        }
+        let mut chain = self.chain(first)?;
+        let old = chain.len();
+        self.extend(state, &mut chain, added)?;
+        self.zero(
+            &chain,
+            (old * cluster_size) as u64,
+            (chain.len() * cluster_size) as u64,
+        )?;
+        let start = slots.len() - run;
+        Ok((self.slots(dir)?, start))
+    }
+
+    /// Ends the directory at slot `index` if it was ended before, so the unused slots after
+    /// the new entries stay unused
+    fn keep_end(&self, slots: &Slots, index: usize) -> Result<(), FsError> {
+        match index < slots.len() && slots.get(index)[0] != 0 {
+            true => self.write_bytes(slots.address(index), &[0]),
+            false => Ok(()),
+        }
+    }
+
+    /// Updates size & first cluster in the short entry `node`
+    fn set_entry(&self, node: NodeId, cluster: u32, size: u64) -> Result<(), FsError> {
+        let mut entry = self.read_entry(node)?;
+        set_cluster(&mut entry, cluster);
+        entry[28..32].copy_from_slice(&(size as u32).to_le_bytes());
+        entry[11] |= ATTR_ARCHIVE;
+        self.write_bytes(node, &entry)
+    }
+
+    /// Cuts or extends the file with `entry` to `size` bytes
+    fn resize(
+        &self,
+        state: &mut State,
+        node: NodeId,
+        entry: &[u8],
+        size: u64,
+    ) -> Result<Vec<u32>, FsError> {
+        let old = read_u32(entry, 28) as u64;
+        let mut chain = self.file_chain(entry)?;
+        let needed = size.div_ceil(self.layout.cluster_size as u64) as usize;
+        match needed.checked_sub(chain.len()) {
+            Some(missing) => self.extend(state, &mut chain, missing)?,
+            None => self.shorten(state, &mut chain, needed)?,
+        }
+        if size > old {
+            self.zero(&chain, old, size)?;
+        }
+        self.set_entry(node, chain.first().copied().unwrap_or(0), size)?;
+        Ok(chain)
+    }
+}
+
+impl FileSystem for Fat {
+    fn root(&self) -> NodeId {
+        ROOT
+    }
+
+    fn lookup(&self, dir: NodeId, name: &str) -> Result<NodeId, FsError> {
+        let _state = self.state.lock();
+        let (slots, entry) = self.find(dir, name)?;
+        Ok(slots.address(entry.slot))
+    }
+
+    fn metadata(&self, node: NodeId) -> Result<Metadata, FsError> {
+        let _state = self.state.lock();
+        if node == ROOT {
+            return Ok(Metadata {
+                kind: FileType::Directory,
+                size: 0,
+            });
+        }
+        let entry = self.read_entry(node)?;
+        Ok(match entry[11] & ATTR_DIRECTORY {
+            0 => Metadata {
+                kind: FileType::File,
+                size: read_u32(&entry, 28) as u64,
+            },
+            _ => Metadata {
+                kind: FileType::Directory,
+                size: 0,
+            },
+        })
+    }
+
+    fn read_dir(&self, dir: NodeId) -> Result<Vec<DirEntry>, FsError> {
+        let _state = self.state.lock();
+        let slots = self.slots(self.dir(dir)?)?;
+        Ok(entries(&slots)
+            .into_iter()
+            .map(|entry| DirEntry {
+                kind: entry.kind(),
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn read(&self, file: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let _state = self.state.lock();
+        let entry = self.file_entry(file)?;
+        let size = read_u32(&entry, 28) as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((size - offset) as usize);
+        let chain = self.file_chain(&entry)?;
+        let mut done = 0;
+        for (at, run) in self.runs(&chain, offset, len) {
+            self.read_bytes(at, &mut buffer[done..done + run])?;
+            done += run;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, file: NodeId, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let entry = self.file_entry(file)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = offset + data.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let size = read_u32(&entry, 28) as u64;
+        let chain = match end > size {
+            true => self.resize(&mut state, file, &entry, end)?,
+            false => self.file_chain(&entry)?,
+        };
+        let mut done = 0;
+        for (at, run) in self.runs(&chain, offset, data.len()) {
+            self.write_bytes(at, &data[done..done + run])?;
+            done += run;
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, file: NodeId, size: u64) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if size > u32::MAX as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let entry = self.file_entry(file)?;
+        self.resize(&mut state, file, &entry, size).map(|_| ())
+    }
+
+    fn create(&self, dir: NodeId, name: &str, kind: FileType) -> Result<NodeId, FsError> {
+        let mut state = self.state.lock();
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        if !valid_name(name) {
+            return Err(FsError::InvalidName);
+        }
+        let location = self.dir(dir)?;
+        let existing = entries(&self.slots(location)?);
+        if existing.iter().any(|entry| entry.is_called(name)) {
+            return Err(FsError::Exists);
+        }
+        let taken = |short: &[u8; 11]| existing.iter().any(|entry| entry.short == *short);
+        let (short, case, long) = match exact_short(name) {
+            Some((short, case)) if !taken(&short) => (short, case, None),
+            _ => {
+                let short = numbered_short(name, taken).ok_or(FsError::NoSpace)?;
+                let units: Vec<u16> = name.encode_utf16().collect();
+                (short, 0, Some(units))
+            }
+        };
+        let long_count = long.as_ref().map_or(0, |units| units.len().div_ceil(13));
+        let (slots, first) = self.free_slots(&mut state, location, long_count + 1)?;
+
+        let (attributes, cluster) = match kind {
+            FileType::File => (ATTR_ARCHIVE, 0),
+            FileType::Directory => {
+                let mut chain = Vec::new();
+                self.extend(&mut state, &mut chain, 1)?;
+                let cluster_size = self.layout.cluster_size as u64;
+                self.zero(&chain, 0, cluster_size)?;
+                let parent = match location {
+                    Dir::Chain(cluster) if dir != ROOT => cluster,
+                    // ".." of a directory in the root is 0, on FAT32 as well
+                    _ => 0,
+                };
+                let dot = short_entry(b".          ", 0, ATTR_DIRECTORY, chain[0]);
+                let dot_dot = short_entry(b"..         ", 0, ATTR_DIRECTORY, parent);
+                let at = self.cluster_offset(chain[0]);
+                self.write_bytes(at, &dot)?;
+                self.write_bytes(at + ENTRY_SIZE as u64, &dot_dot)?;
+                (ATTR_DIRECTORY, chain[0])
+            }
+        };
+
+        if let Some(units) = long {
+            let sum = checksum(&short);
+            for index in 0..long_count {
+                let order = long_count - index;
+                let mut slot = [0; ENTRY_SIZE];
+                slot[0] = order as u8;
+                if index == 0 {
+                    slot[0] |= LAST_LONG_ENTRY;
+                }
+                slot[11] = ATTR_LONG_NAME;
+                slot[13] = sum;
+                // the name ends with a 0 if there is room, the rest is padded with 0xFFFF
+                for (position, offset) in LONG_NAME_UNITS.iter().enumerate() {
+                    let unit = match (order - 1) * 13 + position {
+                        at if at < units.len() => units[at],
+                        at if at == units.len() => 0,
+                        _ => 0xFFFF,
+                    };
+                    slot[*offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+                }
+                self.write_bytes(slots.address(first + index), &slot)?;
+            }
+        }
+        let node = slots.address(first + long_count);
+        self.write_bytes(node, &short_entry(&short, case, attributes, cluster))?;
+        Ok(node)
+    }
+
+    fn remove(&self, dir: NodeId, name: &str) -> Result<(), FsError> {
+        let mut state = self.state.lock();
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let (slots, entry) = self.find(dir, name)?;
+        let cluster = match self.layout.kind {
+            FatType::Fat32 => entry.cluster,
+            _ => entry.cluster & 0xFFFF,
+        };
+        if entry.kind() == FileType::Directory
+            && !entries(&self.slots(Dir::Chain(cluster))?).is_empty()
+        {
+            return Err(FsError::NotEmpty);
+        }
+        let mut chain = self.chain(cluster)?;
+        self.shorten(&mut state, &mut chain, 0)?;
+        for index in entry.first..=entry.slot {
+            self.write_bytes(slots.address(index), &[FREE])?;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(block::block_on(self.device.flush())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::block::{block_on, RamDisk};
+    use crate::unit_test;
+
+    const SECTOR: usize = 512;
+
+    /// A boot sector of `sectors` sectors with one sector per cluster & 2 FATs of
+    /// `fat_sectors`, FAT32 without a fixed root directory
+    fn boot_sector(sectors: u32, fat_sectors: u32, fat32: bool) -> [u8; SECTOR] {
+        let mut boot = [0; SECTOR];
+        boot[0] = 0xEB;
+        boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+        boot[13] = 1;
+        boot[14] = 1;
+        boot[16] = 2;
+        boot[21] = 0xF8;
+        boot[32..36].copy_from_slice(&sectors.to_le_bytes());
+        match fat32 {
+            true => {
+                boot[36..40].copy_from_slice(&fat_sectors.to_le_bytes());
+                boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+            }
+            false => {
+                boot[17..19].copy_from_slice(&16u16.to_le_bytes());
+                boot[22..24].copy_from_slice(&(fat_sectors as u16).to_le_bytes());
+            }
+        }
+        boot[510..512].copy_from_slice(&[0x55, 0xAA]);
+        boot
+    }
+
+    /// A FAT12 volume of 64 sectors: boot sector, 2 FATs, the root directory & 60 clusters
+    fn fat12() -> Fat {
+        let disk = RamDisk::new(SECTOR, 64);
+        block_on(disk.write(0, &boot_sector(64, 1, false))).unwrap();
+        let mut fat = [0; SECTOR];
+        fat[..3].copy_from_slice(&[0xF8, 0xFF, 0xFF]);
+        block_on(disk.write(1, &fat)).unwrap();
+        block_on(disk.write(2, &fat)).unwrap();
+        Fat::new(Arc::new(disk)).unwrap()
+    }
+
+    unit_test! {
+        fn fat_types() {
+            let layout = Layout::parse(&boot_sector(64, 1, false)).unwrap();
+            assert_eq!((layout.kind, layout.clusters), (FatType::Fat12, 60));
+            assert_eq!(layout.data_start, 4 * SECTOR as u64);
+            let layout = Layout::parse(&boot_sector(8192, 32, false)).unwrap();
+            assert_eq!((layout.kind, layout.clusters), (FatType::Fat16, 8126));
+            let layout = Layout::parse(&boot_sector(70000, 600, true)).unwrap();
+            assert_eq!((layout.kind, layout.root), (FatType::Fat32, Root::Cluster(2)));
+            // a FAT too small for the clusters
+            assert_eq!(Layout::parse(&boot_sector(70000, 500, true)), None);
+            assert_eq!(Layout::parse(&[0; SECTOR]), None);
+        }
+
+        fn short_names() {
+            assert_eq!(exact_short("KERNEL"), Some((*b"KERNEL     ", 0)));
+            assert_eq!(exact_short("limine.CFG"), Some((*b"LIMINE  CFG", LOWER_BASE)));
+            assert_eq!(exact_short("Limine.cfg"), None);
+            assert_eq!(exact_short("long name.txt"), None);
+            assert_eq!(numbered_short("long name.txt", |_| false), Some(*b"LONGNA~1TXT"));
+            let taken = |short: &[u8; 11]| short == b"BASHRC~1   ";
+            assert_eq!(numbered_short(".bashrc", taken), Some(*b"BASHRC~2   "));
+            assert_eq!(short_text(b"LIMINE  CFG", LOWER_BASE | LOWER_EXTENSION), "limine.cfg");
+            assert!(!valid_name("a:b") && !valid_name("..") && !valid_name("a."));
+        }
+
+        fn files_and_directories() {
+            let fat = fat12();
+            let dir = fat.create(ROOT, "boot", FileType::Directory).unwrap();
+            let name = "A long file name, with Ünïcode.txt";
+            let file = fat.create(dir, name, FileType::File).unwrap();
+            assert_eq!(fat.create(dir, "a LONG file name, with üNÏCODE.TXT", FileType::File),
+                Err(FsError::Exists));
+            assert_eq!(fat.lookup(dir, "ALONGF~1.TXT"), Ok(file));
+            assert_eq!(fat.lookup(dir, &name.to_uppercase()), Ok(file));
+
+            // 3 clusters, with a gap in the middle
+            fat.write(file, 0, &[1; 100]).unwrap();
+            fat.write(file, 1200, &[2; 100]).unwrap();
+            assert_eq!(fat.metadata(file).unwrap().size, 1300);
+            let mut content = [0xFF; 1400];
+            assert_eq!(fat.read(file, 0, &mut content), Ok(1300));
+            assert!(content[..100].iter().all(|b| *b == 1));
+            assert!(content[100..1200].iter().all(|b| *b == 0));
+            assert!(content[1200..1300].iter().all(|b| *b == 2));
+
+            // growing again after cutting reads zeros
+            fat.truncate(file, 50).unwrap();
+            fat.truncate(file, 600).unwrap();
+            assert_eq!(fat.read(file, 40, &mut content), Ok(560));
+            assert!(content[..10].iter().all(|b| *b == 1));
+            assert!(content[10..560].iter().all(|b| *b == 0));
+
+            let names: Vec<_> = fat.read_dir(dir).unwrap().into_iter().map(|e| e.name).collect();
+            assert_eq!(names, [name]);
+            assert_eq!(fat.remove(ROOT, "boot"), Err(FsError::NotEmpty));
+            fat.remove(dir, name).unwrap();
+            fat.remove(ROOT, "BOOT").unwrap();
+            assert_eq!(fat.read_dir(ROOT), Ok(Vec::new()));
+            // every cluster is free again
+            assert!((2..62).all(|cluster| fat.next(cluster) == Ok(0)));
+        }
+
+        fn full_volume() {
+            let fat = fat12();
+            let file = fat.create(ROOT, "big", FileType::File).unwrap();
+            assert_eq!(fat.write(file, 0, &[3; 61 * SECTOR]), Err(FsError::NoSpace));
+            assert_eq!(fat.metadata(file).unwrap().size, 0);
+            fat.write(file, 0, &[3; 60 * SECTOR]).unwrap();
+            // the fixed root directory has 16 entries
+            for index in 0..15 {
+                fat.create(ROOT, &alloc::format!("F{}", index), FileType::File).unwrap();
+            }
+            assert_eq!(fat.create(ROOT, "F15", FileType::File), Err(FsError::NoSpace));
+        }
+    }
+}
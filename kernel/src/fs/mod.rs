@@ -0,0 +1,458 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Virtual filesystem: one tree of absolute paths over the mounted filesystems.
+//!
+//! A filesystem implements `FileSystem`, which works on the nodes (files & directories) it
+//! identifies by `NodeId`. `mount()` hangs it into the tree at a path, the functions here resolve
+//! paths to the filesystem mounted deepest & the node in there. `mount_device()` finds the
+//! filesystem on a block device by trying every entry of `TYPES`.
+//!
+//! Filesystems are synchronous for now, they wait for their disks with `block_on()`.
+
+use crate::driver::block::{self, BlockDevice, BlockError};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+pub mod ext2;
+pub mod fat;
+
+/// Error of a filesystem operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    Exists,
+    /// The directory to remove has entries
+    NotEmpty,
+    /// Paths start with a '/'
+    InvalidPath,
+    /// The filesystem can not store the name
+    InvalidName,
+    NoSpace,
+    ReadOnly,
+    /// The filesystem structures are inconsistent
+    Corrupted,
+    /// The filesystem does not support the operation or a feature of the volume
+    Unsupported,
+    /// The device does not hold a filesystem of the type
+    NotRecognized,
+    /// Something is mounted at or below the path
+    Busy,
+    Block(BlockError),
+}
+
+impl From<BlockError> for FsError {
+    fn from(e: BlockError) -> Self {
+        Self::Block(e)
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "no such file or directory"),
+            Self::NotADirectory => write!(f, "not a directory"),
+            Self::IsADirectory => write!(f, "is a directory"),
+            Self::Exists => write!(f, "file exists"),
+            Self::NotEmpty => write!(f, "directory is not empty"),
+            Self::InvalidPath => write!(f, "path is not absolute"),
+            Self::InvalidName => write!(f, "invalid file name"),
+            Self::NoSpace => write!(f, "no space left on the filesystem"),
+            Self::ReadOnly => write!(f, "filesystem is read-only"),
+            Self::Corrupted => write!(f, "filesystem is corrupted"),
+            Self::Unsupported => write!(f, "operation is not supported by the filesystem"),
+            Self::NotRecognized => write!(f, "no known filesystem on the device"),
+            Self::Busy => write!(f, "a filesystem is mounted there"),
+            Self::Block(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Identifies a file or directory inside of its filesystem
+pub type NodeId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: FileType,
+    /// Length of a file in bytes
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileType,
+}
+
+/// A mountable filesystem. Names passed in are single path components, never "." or "..".
+pub trait FileSystem: Send + Sync {
+    fn root(&self) -> NodeId;
+
+    /// The entry called `name` in the directory `dir`
+    fn lookup(&self, dir: NodeId, name: &str) -> Result<NodeId, FsError>;
+
+    fn metadata(&self, node: NodeId) -> Result<Metadata, FsError>;
+
+    /// The entries of `dir`, without "." & ".."
+    fn read_dir(&self, dir: NodeId) -> Result<Vec<DirEntry>, FsError>;
+
+    /// Reads from `offset` on into `buffer`, returns the number of bytes read, which is only
+    /// less than `buffer.len()` at the end of the file
+    fn read(&self, file: NodeId, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Writes `data` at `offset`, the file grows if needed. A gap before `offset` reads as zeros.
+    fn write(&self, file: NodeId, offset: u64, data: &[u8]) -> Result<(), FsError>;
+
+    /// Cuts or extends the file to `size` bytes
+    fn truncate(&self, file: NodeId, size: u64) -> Result<(), FsError>;
+
+    /// Adds an empty file or directory called `name` to `dir`
+    fn create(&self, dir: NodeId, name: &str, kind: FileType) -> Result<NodeId, FsError>;
+
+    /// Removes the file or empty directory called `name` from `dir`
+    fn remove(&self, dir: NodeId, name: &str) -> Result<(), FsError>;
+
+    /// Writes everything that is cached back to the device
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// Opens the filesystem on a device, `NotRecognized` if it holds another one
+pub type Open = fn(Arc<dyn BlockDevice>) -> Result<Arc<dyn FileSystem>, FsError>;
+
+/// A filesystem driver
+pub struct FsType {
+    pub name: &'static str,
+    pub open: Open,
+}
+
+/// The filesystems `mount_device()` tries, in this order
+pub static TYPES: &[&FsType] = &[&fat::TYPE, &ext2::TYPE];
+
+/// A filesystem hung into the tree
+#[derive(Clone)]
+pub struct Mount {
+    /// Normalized absolute path
+    pub path: String,
+    /// Name of the block device
+    pub device: String,
+    pub kind: &'static str,
+    pub fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// Reads `buffer.len()` bytes at byte `offset` of `device`, for the drivers that do not work in
+/// whole sectors
+fn read_bytes(device: &dyn BlockDevice, offset: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+    let sector = device.sector_size() as u64;
+    if offset % sector == 0 && buffer.len() as u64 % sector == 0 {
+        return Ok(block::block_on(device.read(offset / sector, buffer))?);
+    }
+    let first = offset / sector;
+    let end = (offset + buffer.len() as u64).div_ceil(sector);
+    let mut sectors = alloc::vec![0; ((end - first) * sector) as usize];
+    block::block_on(device.read(first, &mut sectors))?;
+    let start = (offset - first * sector) as usize;
+    buffer.copy_from_slice(&sectors[start..start + buffer.len()]);
+    Ok(())
+}
+
+/// Writes `data` at byte `offset` of `device`, partly written sectors are read first
+fn write_bytes(device: &dyn BlockDevice, offset: u64, data: &[u8]) -> Result<(), FsError> {
+    let sector = device.sector_size() as u64;
+    if offset % sector == 0 && data.len() as u64 % sector == 0 {
+        return Ok(block::block_on(device.write(offset / sector, data))?);
+    }
+    let first = offset / sector;
+    let end = (offset + data.len() as u64).div_ceil(sector);
+    let mut sectors = alloc::vec![0; ((end - first) * sector) as usize];
+    block::block_on(device.read(first, &mut sectors))?;
+    let start = (offset - first * sector) as usize;
+    sectors[start..start + data.len()].copy_from_slice(data);
+    Ok(block::block_on(device.write(first, &sectors))?)
+}
+
+/// The components of an absolute path, with "." & ".." resolved
+fn components(path: &str) -> Result<Vec<&str>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    Ok(components)
+}
+
+fn join(components: &[&str]) -> String {
+    match components.is_empty() {
+        true => "/".to_string(),
+        false => components.iter().flat_map(|c| ["/", c]).collect(),
+    }
+}
+
+/// The filesystem mounted deepest on the way to `components` & the number of components that
+/// lead to its root
+fn mount_of(components: &[&str]) -> Result<(Arc<dyn FileSystem>, usize), FsError> {
+    MOUNTS
+        .lock()
+        .iter()
+        .filter_map(|mount| {
+            let at = self::components(&mount.path).unwrap();
+            components
+                .starts_with(&at)
+                .then(|| (mount.fs.clone(), at.len()))
+        })
+        .max_by_key(|(_, depth)| *depth)
+        .ok_or(FsError::NotFound)
+}
+
+/// The filesystem & the node `components` lead to
+fn resolve(components: &[&str]) -> Result<(Arc<dyn FileSystem>, NodeId), FsError> {
+    let (fs, depth) = mount_of(components)?;
+    let mut node = fs.root();
+    for name in &components[depth..] {
+        node = fs.lookup(node, name)?;
+    }
+    Ok((fs, node))
+}
+
+/// The filesystem, the parent directory & the last component of `path`, which must not be a
+/// mount point
+fn resolve_parent(path: &str) -> Result<(Arc<dyn FileSystem>, NodeId, &str), FsError> {
+    let components = components(path)?;
+    let Some((name, parent)) = components.split_last() else {
+        return Err(FsError::Busy);
+    };
+    let (fs, depth) = mount_of(&components)?;
+    if depth == components.len() {
+        return Err(FsError::Busy);
+    }
+    let mut node = fs.root();
+    for component in &parent[depth..] {
+        node = fs.lookup(node, component)?;
+    }
+    Ok((fs, node, name))
+}
+
+/// Hangs `fs` into the tree at `path`, `device` names where it is stored
+pub fn mount(
+    path: &str,
+    device: &str,
+    kind: &'static str,
+    fs: Arc<dyn FileSystem>,
+) -> Result<(), FsError> {
+    let path = join(&components(path)?);
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(FsError::Busy);
+    }
+    mounts.push(Mount {
+        path,
+        device: device.into(),
+        kind,
+        fs,
+    });
+    Ok(())
+}
+
+/// Mounts the filesystem on the block device `device` at `path`, returns its type
+pub fn mount_device(path: &str, device: &str) -> Result<&'static str, FsError> {
+    let disk = block::get(device).ok_or(FsError::NotFound)?;
+    for kind in TYPES {
+        match (kind.open)(disk.clone()) {
+            Ok(fs) => return mount(path, device, kind.name, fs).map(|()| kind.name),
+            Err(FsError::NotRecognized) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Err(FsError::NotRecognized)
+}
+
+/// Writes the filesystem at `path` back & takes it out of the tree
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = join(&components(path)?);
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == path)
+        .ok_or(FsError::NotFound)?;
+    let below = |mount: &Mount| match mount.path.strip_prefix(path.as_str()) {
+        Some(rest) => path == "/" || rest.starts_with('/'),
+        None => false,
+    };
+    if mounts
+        .iter()
+        .any(|mount| mount.path != path && below(mount))
+    {
+        return Err(FsError::Busy);
+    }
+    mounts[index].fs.sync()?;
+    mounts.remove(index);
+    Ok(())
+}
+
+/// Calls `f` with every mounted filesystem, in the order they were mounted
+pub fn with_mounts<R>(f: impl FnOnce(&[Mount]) -> R) -> R {
+    f(&MOUNTS.lock())
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, FsError> {
+    let (fs, node) = resolve(&components(path)?)?;
+    fs.metadata(node)
+}
+
+/// The entries of the directory at `path`, including the mount points in there
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let components = components(path)?;
+    let (fs, node) = resolve(&components)?;
+    let mut entries = fs.read_dir(node)?;
+    for mount in MOUNTS.lock().iter() {
+        let at = self::components(&mount.path).unwrap();
+        if let Some((name, parent)) = at.split_last() {
+            if parent == components.as_slice() && !entries.iter().any(|e| e.name == *name) {
+                entries.push(DirEntry {
+                    name: name.to_string(),
+                    kind: FileType::Directory,
+                });
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub fn create_dir(path: &str) -> Result<(), FsError> {
+    let (fs, dir, name) = resolve_parent(path)?;
+    fs.create(dir, name, FileType::Directory).map(|_| ())
+}
+
+/// Removes the file or empty directory at `path`
+pub fn remove(path: &str) -> Result<(), FsError> {
+    let (fs, dir, name) = resolve_parent(path)?;
+    fs.remove(dir, name)
+}
+
+/// The whole content of the file at `path`
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let mut file = File::open(path)?;
+    let mut content = alloc::vec![0; file.size()? as usize];
+    let len = file.read(&mut content)?;
+    content.truncate(len);
+    Ok(content)
+}
+
+/// Replaces the content of the file at `path` with `data`, the file is created if needed
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), FsError> {
+    File::create(path)?.write(data)
+}
+
+/// An open file, reads & writes move its position
+pub struct File {
+    fs: Arc<dyn FileSystem>,
+    node: NodeId,
+    position: u64,
+}
+
+impl File {
+    /// Opens the existing file at `path`
+    pub fn open(path: &str) -> Result<Self, FsError> {
+        let (fs, node) = resolve(&components(path)?)?;
+        if fs.metadata(node)?.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(Self {
+            fs,
+            node,
+            position: 0,
+        })
+    }
+
+    /// Opens the file at `path` emptied, it is created if it does not exist
+    pub fn create(path: &str) -> Result<Self, FsError> {
+        let (fs, dir, name) = resolve_parent(path)?;
+        let node = match fs.lookup(dir, name) {
+            Ok(node) => {
+                if fs.metadata(node)?.kind == FileType::Directory {
+                    return Err(FsError::IsADirectory);
+                }
+                fs.truncate(node, 0)?;
+                node
+            }
+            Err(FsError::NotFound) => fs.create(dir, name, FileType::File)?,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            fs,
+            node,
+            position: 0,
+        })
+    }
+
+    /// Length of the file in bytes
+    pub fn size(&self) -> Result<u64, FsError> {
+        Ok(self.fs.metadata(self.node)?.size)
+    }
+
+    /// Moves the position to `position` bytes from the start
+    pub fn seek(&mut self, position: u64) {
+        self.position = position;
+    }
+
+    /// Reads from the position on, returns the number of bytes read
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let len = self.fs.read(self.node, self.position, buffer)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    /// Writes at the position
+    pub fn write(&mut self, data: &[u8]) -> Result<(), FsError> {
+        self.fs.write(self.node, self.position, data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    /// Cuts or extends the file to `size` bytes, the position stays
+    pub fn truncate(&mut self, size: u64) -> Result<(), FsError> {
+        self.fs.truncate(self.node, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    unit_test! {
+        fn path_components() {
+            assert_eq!(components("/"), Ok(Vec::new()));
+            assert_eq!(components("//boot/./limine/../kernel/"), Ok(alloc::vec!["boot", "kernel"]));
+            assert_eq!(components("/.."), Ok(Vec::new()));
+            assert_eq!(components("boot"), Err(FsError::InvalidPath));
+            assert_eq!(join(&["boot", "kernel"]), "/boot/kernel");
+            assert_eq!(join(&[]), "/");
+        }
+    }
+}
@@ -0,0 +1,112 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Custom test framework that runs `#[test_case]` functions inside of the booted kernel.
+//!
+//! The test kernel is built with `cargo rustc --profile test` and booted in QEMU by the `test`
+//! task in `smeltfile.py`. Results are reported over serial through `log!()`, then QEMU is
+//! exited with a pass/fail code. On x86_64 this uses the isa-debug-exit device
+//! (`-device isa-debug-exit,iobase=0xf4,iosize=0x04`), on AArch64 semihosting
+//! (`-semihosting`).
+//!
+//! A failing test panics, so the panic handler reports the failure and exits QEMU.
+//! `test.filter=<text>` on the kernel command line only runs tests whose name contains `<text>`.
+
+use crate::arch::{Cpu, Current};
+use crate::cmdline::{AnyParam, Param};
+use crate::log;
+use core::any::type_name;
+
+pub static FILTER: Param<&str> = Param::new(
+    "test.filter",
+    "only run kernel tests whose name contains this text",
+    "",
+);
+
+/// Command line options of the test framework
+pub static PARAMS: &[&dyn AnyParam] = &[&FILTER];
+
+/// Exit codes written to the isa-debug-exit device, QEMU then exits with `(code << 1) | 1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Implemented for every test function, prints the name of the test and its result
+pub trait Testable {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn name(&self) -> &'static str {
+        type_name::<T>()
+    }
+
+    fn run(&self) {
+        log!("test {} ... ", self.name());
+        self();
+        log!("ok\n");
+    }
+}
+
+/// Called by the generated `test_main()` with all `#[test_case]` functions in the crate
+pub fn runner(tests: &[&dyn Testable]) {
+    log!("[ Kernel Tests ]\n");
+    let filter = FILTER.get();
+    let selected = || tests.iter().filter(|t| t.name().contains(filter));
+    let count = selected().count();
+    log!("running {} tests\n", count);
+    for test in selected() {
+        test.run();
+    }
+    log!(
+        "test result: ok. {} passed; {} filtered out\n",
+        count,
+        tests.len() - count
+    );
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Exits QEMU with the given exit code
+#[cfg(target_arch = "x86_64")]
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    use crate::arch::amd64::portio;
+
+    /// The port must match the `iobase` passed to QEMU
+    const ISA_DEBUG_EXIT_PORT: u16 = 0xF4;
+
+    unsafe { portio::output_long(ISA_DEBUG_EXIT_PORT, code as u32) };
+    // only reached when running without the isa-debug-exit device
+    loop {
+        unsafe { Current::halt() };
+    }
+}
+
+/// Exits QEMU with the given exit code
+#[cfg(target_arch = "aarch64")]
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    /// semihosting operation number of SYS_EXIT
+    const SYS_EXIT: u64 = 0x18;
+    /// reason passed to SYS_EXIT
+    const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+    let block: [u64; 2] = [ADP_STOPPED_APPLICATION_EXIT, code as u64];
+    unsafe {
+        core::arch::asm!(
+            "hlt #0xf000",
+            in("x0") SYS_EXIT,
+            in("x1") block.as_ptr(),
+            options(nostack)
+        )
+    };
+    // only reached when running without semihosting
+    loop {
+        unsafe { Current::halt() };
+    }
+}
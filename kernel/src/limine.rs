@@ -4,6 +4,13 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+//! Typed access to the limine responses.
+//!
+//! The responses are located in bootloader-reclaimable memory, so `init()` copies them into
+//! kernel-owned memory before anything else runs. All accessors read from that copy and keep
+//! working after `memman::reclaim::reclaim_bootloader()`, which keeps the frames of the device
+//! tree instead, as it is too large to be copied. A feature is unavailable when the bootloader
+//! did not answer its request, or answered with an unsupported revision.
 //!
 //! <br> See more about the protocol: `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md`
 
@@ -11,26 +18,40 @@ use embedded_graphics::pixelcolor::raw::RawU32;
 use embedded_graphics::pixelcolor::{Rgb555, Rgb565, Rgb888};
 use embedded_graphics::prelude::*;
 
+use crate::config::{BOOT_STACK_SIZE, PAGING_MODE_5_LEVEL};
 use crate::enum_names;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
 use core::convert::TryFrom;
 use core::ffi::CStr;
+use core::fmt;
 use core::iter::Iterator;
+use spin::once::Once;
 
 /// simple pointer wrapper that can be replaced in the future for something like `NonNull<T>`
 type Ptr<T> = *const T;
 /// simple mutable pointer wrapper that can be replaced in the future for something like `NonNull<T>`
 type MutPtr<T> = *mut T;
 
-// Linked from kentry/limine.asm
-extern "C" {
-    static LIMINE_REQUEST_BOOT_INFO: RequestBootInfo;
-    static LIMINE_REQUEST_MEMORY_MAP: RequestMemoryMap;
-    static LIMINE_REQUEST_BOOT_TIME: RequestBootTime;
-    static LIMINE_REQUEST_KERNEL_ADDRESS: RequestKernelAddress;
-    static LIMINE_REQUEST_HHDM: RequestHHDM;
-    static LIMINE_REQUEST_STACK_SIZE: RequestStackSize;
-    static LIMINE_REQUEST_FRAMEBUFFER: RequestFrameBuffer;
-}
+/// First half of every request id
+const MAGIC_COMMON: [u64; 2] = [0xc7b1dd30df4c8b88, 0x0a82e883a194f07b];
+
+// The bootloader only looks for requests between these markers, the linker scripts place them
+// around the `.limine_reqs` section.
+
+#[used]
+#[link_section = ".limine_reqs.start"]
+static LIMINE_REQUESTS_START_MARKER: [u64; 4] = [
+    0xf6b8f4b39de7d1ae,
+    0xfab91a6940fcb9cf,
+    0x785c6ed015d3e316,
+    0x181e920a7852b9d9,
+];
+
+#[used]
+#[link_section = ".limine_reqs.end"]
+static LIMINE_REQUESTS_END_MARKER: [u64; 2] = [0xadc0e0531bb10d03, 0x9572709f31764c62];
 
 /*
 /// public interface to print to TERM0
@@ -43,30 +64,79 @@ pub fn print_bytes(s: &[u8]) {
 }
 */
 
-/// macro that completes a request and response struct with all the default fields.
+/// Reasons why a limine feature is not available
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimineError {
+    /// the bootloader did not answer the request of the named feature
+    NoResponse(&'static str),
+    /// the response of the named feature is older than the revision the kernel understands
+    UnsupportedRevision {
+        feature: &'static str,
+        revision: u64,
+    },
+    /// a string in the response of the named feature is not valid UTF-8
+    InvalidUtf8(&'static str),
+    /// the response of the named feature holds a value the kernel does not know
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for LimineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoResponse(feature) => write!(f, "no response to the {} request", feature),
+            Self::UnsupportedRevision { feature, revision } => {
+                write!(f, "unsupported {} response revision {}", feature, revision)
+            }
+            Self::InvalidUtf8(feature) => write!(f, "{} response is not valid UTF-8", feature),
+            Self::InvalidValue(feature) => write!(f, "{} response holds an invalid value", feature),
+        }
+    }
+}
+
+/// macro that completes a request and response struct with all the default fields,
+/// and places the request in the `.limine_reqs` section as `static`.
 ///
+/// `feature` names the feature in `LimineError`s, `revision` is the requested revision and the
+/// oldest response revision whose layout matches the response struct, `id` is the feature
+/// specific half of the request id. Extra request fields are initialized with the given value. <br>
 /// The workings of this macro can be deduced from the source code or context. <br>
 /// See more about limine features: `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#features`
 macro_rules! limine_feature {
     (
         #[doc = $doc:expr]
         struct $request:ident {
-            $($req_field_key:ident : $req_field_type:ty,)*
+            $($req_field_key:ident : $req_field_type:ty = $req_field_value:expr,)*
         }
 
         struct $response:ident {
             $($res_field_key:ident : $res_field_type:ty,)*
         }
+
+        feature = $feature:literal, revision = $revision:literal, id = [$id_a:literal, $id_b:literal];
+        static $static:ident;
     ) => {
         #[repr(C)]
         #[doc = $doc]
         struct $request {
             id: [u64; 4],
             revision: u64,
-            response: Ptr<$response>,
+            /// written by the bootloader
+            response: UnsafeCell<Ptr<$response>>,
             $($req_field_key : $req_field_type,)*
         }
 
+        // SAFETY: the response pointer is only written by the bootloader, before the kernel runs
+        unsafe impl Sync for $request {}
+
+        #[used]
+        #[link_section = ".limine_reqs"]
+        static $static: $request = $request {
+            id: [MAGIC_COMMON[0], MAGIC_COMMON[1], $id_a, $id_b],
+            revision: $request::REVISION,
+            response: UnsafeCell::new(core::ptr::null()),
+            $($req_field_key : $req_field_value,)*
+        };
+
         #[repr(C)]
         #[derive(Clone)]
         #[doc = $doc]
@@ -74,27 +144,125 @@ macro_rules! limine_feature {
             revision: u64,
             $($res_field_key : $res_field_type,)*
         }
+
+        impl $request {
+            const REVISION: u64 = $revision;
+
+            /// Returns the response, if the bootloader answered with a supported revision
+            #[allow(dead_code)]
+            fn response(&self) -> Result<&'static $response, LimineError> {
+                // written by the bootloader, the compiler can not know it changed
+                let ptr = unsafe { core::ptr::read_volatile(self.response.get()) };
+                if ptr.is_null() {
+                    return Err(LimineError::NoResponse($feature));
+                }
+                let response = unsafe { &*ptr };
+                if response.revision < Self::REVISION {
+                    return Err(LimineError::UnsupportedRevision {
+                        feature: $feature,
+                        revision: response.revision,
+                    });
+                }
+                Ok(response)
+            }
+        }
     };
 }
 
+// ======= Kernel-owned copies
+
+/// Everything the kernel uses from the limine responses, copied out of bootloader memory
+struct BootInfo {
+    bootloader: Result<(String, String), LimineError>,
+    memory_map: Result<Vec<MemmapItem>, LimineError>,
+    boot_time: Result<i64, LimineError>,
+    kernel_address: Result<(usize, usize), LimineError>,
+    hhdm: Result<usize, LimineError>,
+    framebuffers: Result<Vec<Framebuffer>, LimineError>,
+    paging_mode: Result<PagingMode, LimineError>,
+    entry_point: Result<usize, LimineError>,
+    kernel_file: Result<File, LimineError>,
+    modules: Result<Vec<File>, LimineError>,
+    rsdp: Result<usize, LimineError>,
+    smbios: Result<SmbiosEntries, LimineError>,
+    efi_system_table: Result<usize, LimineError>,
+    efi_memory_map: Result<EfiMemoryMap, LimineError>,
+    device_tree: Result<&'static [u8], LimineError>,
+    firmware_type: Result<FirmwareType, LimineError>,
+}
+
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+/// Copies the limine responses into kernel-owned memory.
+///
+/// WARNING: must be called before bootloader memory is reclaimed, the accessors panic before
+pub fn init() {
+    BOOT_INFO.call_once(|| unsafe {
+        BootInfo {
+            bootloader: copy_bootloader_info(),
+            memory_map: copy_memory_map(),
+            boot_time: LIMINE_REQUEST_BOOT_TIME.response().map(|r| r.time),
+            kernel_address: LIMINE_REQUEST_KERNEL_ADDRESS
+                .response()
+                .map(|r| (r.physical_base as usize, r.virtual_base as usize)),
+            hhdm: LIMINE_REQUEST_HHDM.response().map(|r| r.offset as usize),
+            framebuffers: copy_framebuffers(),
+            paging_mode: LIMINE_REQUEST_PAGING_MODE.response().and_then(|r| {
+                PagingMode::try_from(r.mode).map_err(|_| LimineError::InvalidValue("paging mode"))
+            }),
+            entry_point: LIMINE_REQUEST_ENTRY_POINT
+                .response()
+                .map(|_| LIMINE_REQUEST_ENTRY_POINT.entry as usize),
+            kernel_file: LIMINE_REQUEST_KERNEL_FILE
+                .response()
+                .and_then(|r| File::copy(&*r.kernel_file)),
+            modules: copy_modules(),
+            rsdp: LIMINE_REQUEST_RSDP.response().map(|r| r.address as usize),
+            smbios: LIMINE_REQUEST_SMBIOS.response().map(|r| SmbiosEntries {
+                entry_32: (r.entry_32 != 0).then_some(r.entry_32 as usize),
+                entry_64: (r.entry_64 != 0).then_some(r.entry_64 as usize),
+            }),
+            efi_system_table: LIMINE_REQUEST_EFI_SYSTEM_TABLE
+                .response()
+                .map(|r| r.address as usize),
+            efi_memory_map: copy_efi_memory_map(),
+            device_tree: find_device_tree(),
+            firmware_type: LIMINE_REQUEST_FIRMWARE_TYPE.response().and_then(|r| {
+                FirmwareType::try_from(r.firmware_type)
+                    .map_err(|_| LimineError::InvalidValue("firmware type"))
+            }),
+        }
+    });
+}
+
+fn boot_info() -> &'static BootInfo {
+    BOOT_INFO
+        .get()
+        .expect("limine responses were not copied, call limine::init() first!")
+}
+
 // ======= Boot Info feature
-// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#terminal-feature
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#bootloader-info-feature
 
 /// returns the bootloaders name and version
-///
-/// WARNING: assumes the response has static lifetime
-pub fn bootloader_info() -> (&'static [u8], &'static [u8]) {
-    let response = unsafe { &*(LIMINE_REQUEST_BOOT_INFO.response) };
-    (
-        // SAFETY: ptr must not be null and must hold null terminated string
-        unsafe { CStr::from_ptr(response.name as *const i8).to_bytes() },
-        unsafe { CStr::from_ptr(response.version as *const i8).to_bytes() },
-    )
+pub fn bootloader_info() -> Result<(&'static str, &'static str), LimineError> {
+    match &boot_info().bootloader {
+        Ok((name, version)) => Ok((name, version)),
+        Err(e) => Err(*e),
+    }
+}
+
+unsafe fn copy_bootloader_info() -> Result<(String, String), LimineError> {
+    let response = LIMINE_REQUEST_BOOT_INFO.response()?;
+    Ok((
+        copy_str(response.name, "bootloader info")?,
+        copy_str(response.version, "bootloader info")?,
+    ))
 }
 
 limine_feature! {
 
-    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#terminal-feature`
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#bootloader-info-feature`
 
     struct RequestBootInfo{}
 
@@ -102,6 +270,9 @@ limine_feature! {
         name: *const u8,
         version: *const u8,
     }
+
+    feature = "bootloader info", revision = 0, id = [0xf55038d8e2a1202f, 0x279426fcf5f59740];
+    static LIMINE_REQUEST_BOOT_INFO;
 }
 
 // ======= Memory Map feature
@@ -120,6 +291,9 @@ limine_feature! {
         // has length of entry_count
         entries: Ptr<Ptr<MemoryMapEntry>>,
     }
+
+    feature = "memory map", revision = 0, id = [0x67cf3d9d378a806f, 0xe304acdfc50c3c62];
+    static LIMINE_REQUEST_MEMORY_MAP;
 }
 
 /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#memory-map-feature`
@@ -130,6 +304,20 @@ struct MemoryMapEntry {
     typ: u64, // cast to MemmapEntryType
 }
 
+unsafe fn copy_memory_map() -> Result<Vec<MemmapItem>, LimineError> {
+    let response = LIMINE_REQUEST_MEMORY_MAP.response()?;
+    let mut entries = Vec::with_capacity(response.entry_count as usize);
+    for i in 0..response.entry_count as usize {
+        let e = &**response.entries.add(i);
+        entries.push(MemmapItem {
+            range: (e.base as usize, (e.length + e.base) as usize),
+            // unknown memory must not be used, which is the same as reserved memory
+            typ: MemmapEntryType::try_from(e.typ).unwrap_or(MemmapEntryType::Reserved),
+        });
+    }
+    Ok(entries)
+}
+
 // public
 
 // `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#memory-map-feature`
@@ -166,45 +354,37 @@ impl TryFrom<u64> for MemmapEntryType {
     }
 }
 
-/// extern interface function used by the rest of the kernel
-pub fn memory_map() -> MemoryMap {
-    MemoryMap::new(unsafe { &*(LIMINE_REQUEST_MEMORY_MAP.response) })
+/// Iterates over the memory map, sorted by address
+pub fn memory_map() -> Result<MemoryMap, LimineError> {
+    match &boot_info().memory_map {
+        Ok(entries) => Ok(MemoryMap {
+            entries: entries.iter(),
+        }),
+        Err(e) => Err(*e),
+    }
 }
 
-/// rust-friendly version of `ResponseMemoryMap`
+/// Iterator over the kernel-owned copy of the memory map
 pub struct MemoryMap {
-    icount: u64,
-    resp_copy: ResponseMemoryMap,
-}
-
-impl MemoryMap {
-    fn new(base: &ResponseMemoryMap) -> Self {
-        Self {
-            icount: 0,
-            resp_copy: base.clone(),
-        }
-    }
+    entries: core::slice::Iter<'static, MemmapItem>,
 }
 
 impl Iterator for MemoryMap {
     type Item = MemmapItem;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.icount >= self.resp_copy.entry_count {
-            return None;
-        }
-
-        let e = unsafe { &*((*(self.resp_copy.entries)).offset(self.icount as isize)) };
+        self.entries.next().cloned()
+    }
+}
 
-        self.icount += 1;
-        Some(Self::Item {
-            range: (e.base as usize, (e.length + e.base) as usize),
-            typ: MemmapEntryType::try_from(e.typ).unwrap(),
-        })
+impl DoubleEndedIterator for MemoryMap {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.entries.next_back().cloned()
     }
 }
 
 /// rust-friendly version of `MemoryMapEntry`
+#[derive(Debug, Clone, Copy)]
 pub struct MemmapItem {
     pub range: (usize, usize),
     pub typ: MemmapEntryType,
@@ -222,11 +402,14 @@ limine_feature! {
     struct ResponseBootTime {
         time: i64,
     }
+
+    feature = "boot time", revision = 0, id = [0x502746e184c088aa, 0xfbc5ec83e6327893];
+    static LIMINE_REQUEST_BOOT_TIME;
 }
 
 /// Gets the unix time at boot
-pub fn boot_time_stamp() -> i64 {
-    unsafe { (*LIMINE_REQUEST_BOOT_TIME.response).time }
+pub fn boot_time_stamp() -> Result<i64, LimineError> {
+    boot_info().boot_time
 }
 
 // ======= Kernel Address feature
@@ -242,16 +425,19 @@ limine_feature! {
         physical_base: u64,
         virtual_base: u64,
     }
+
+    feature = "kernel address", revision = 0, id = [0x71ba76863cc55f63, 0xb2644a48c516a487];
+    static LIMINE_REQUEST_KERNEL_ADDRESS;
 }
 
 /// Get the physical base address for the kernel
-pub fn kernel_address_physical() -> usize {
-    (unsafe { (*LIMINE_REQUEST_KERNEL_ADDRESS.response).physical_base }) as usize
+pub fn kernel_address_physical() -> Result<usize, LimineError> {
+    boot_info().kernel_address.map(|(physical, _)| physical)
 }
 
 /// Get the virtual base address for the kernel
-pub fn kernel_address_virtual() -> usize {
-    (unsafe { (*LIMINE_REQUEST_KERNEL_ADDRESS.response).virtual_base }) as usize
+pub fn kernel_address_virtual() -> Result<usize, LimineError> {
+    boot_info().kernel_address.map(|(_, virt)| virt)
 }
 
 // ======= HHDM (higher half direct map) feature
@@ -266,11 +452,14 @@ limine_feature! {
     struct ResponseHHDM {
         offset: u64,
     }
+
+    feature = "HHDM", revision = 0, id = [0x48dcf1cb8ad2b852, 0x63984e959a98244b];
+    static LIMINE_REQUEST_HHDM;
 }
 
 /// Get the higher half direct map
-pub fn hhdm() -> usize {
-    (unsafe { (*LIMINE_REQUEST_HHDM.response).offset }) as usize
+pub fn hhdm() -> Result<usize, LimineError> {
+    boot_info().hhdm
 }
 
 // ======= Stack Size feature
@@ -281,10 +470,26 @@ limine_feature! {
     /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#stack-size-feature`
 
     struct RequestStackSize {
-        size: u64,
+        size: u64 = BOOT_STACK_SIZE,
     }
 
     struct ResponseStackSize {}
+
+    feature = "stack size", revision = 0, id = [0x224ef0460a8e8926, 0xe1cb0fc25f46ea3d];
+    static LIMINE_REQUEST_STACK_SIZE;
+}
+
+// Written by kentry, from the initial stack pointer & `BOOT_STACK_SIZE`
+extern "C" {
+    static boot_stack_bottom: usize;
+    static boot_stack_top: usize;
+}
+
+/// Get the virtual address range of the boot stack, as requested in the stack size feature.
+///
+/// This is an upper bound, the stack is smaller if the request was not answered
+pub fn boot_stack() -> (usize, usize) {
+    unsafe { (boot_stack_bottom, boot_stack_top) }
 }
 
 // ======= Framebuffer feature
@@ -292,7 +497,7 @@ limine_feature! {
 
 limine_feature! {
 
-    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#stack-size-feature`
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#framebuffer-feature`
 
     struct RequestFrameBuffer {}
 
@@ -300,6 +505,22 @@ limine_feature! {
         framebuffer_count: u64,
         framebuffers: MutPtr<MutPtr<Framebuffer>>,
     }
+
+    feature = "framebuffer", revision = 0, id = [0x9d5827dcd881dd75, 0xa3148604f6fab11b];
+    static LIMINE_REQUEST_FRAMEBUFFER;
+}
+
+unsafe fn copy_framebuffers() -> Result<Vec<Framebuffer>, LimineError> {
+    let response = LIMINE_REQUEST_FRAMEBUFFER.response()?;
+    let mut framebuffers = Vec::with_capacity(response.framebuffer_count as usize);
+    for i in 0..response.framebuffer_count as usize {
+        let mut fb = (**response.framebuffers.add(i)).clone();
+        // the EDID is located in bootloader-reclaimable memory and is not copied
+        fb.edid_size = 0;
+        fb.edid = core::ptr::null();
+        framebuffers.push(fb);
+    }
+    Ok(framebuffers)
 }
 
 /// used by both the Terminal feature and the Framebuffer feature
@@ -307,7 +528,7 @@ limine_feature! {
 /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#framebuffer-feature`
 /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#bootloader-info-feature`
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Framebuffer {
     pub address: MutPtr<u8>,
     pub width: u64,
@@ -326,8 +547,460 @@ pub struct Framebuffer {
     pub edid: Ptr<u8>,
 }
 
-pub fn framebuffer0() -> &'static mut Framebuffer {
-    unsafe { &mut **(*LIMINE_REQUEST_FRAMEBUFFER.response).framebuffers }
+// SAFETY: `address` points to framebuffer memory, which is never reclaimed or remapped
+unsafe impl Send for Framebuffer {}
+unsafe impl Sync for Framebuffer {}
+
+/// Returns copies of the framebuffers, the pixels are still written to the same video memory
+pub fn framebuffers() -> Result<&'static [Framebuffer], LimineError> {
+    match &boot_info().framebuffers {
+        Ok(framebuffers) => Ok(framebuffers),
+        Err(e) => Err(*e),
+    }
+}
+
+// ======= Paging Mode feature
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#paging-mode-feature
+
+limine_feature! {
+
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#paging-mode-feature`
+
+    struct RequestPagingMode {
+        mode: u64 = if PAGING_MODE_5_LEVEL { 1 } else { 0 },
+    }
+
+    struct ResponsePagingMode {
+        mode: u64,
+    }
+
+    feature = "paging mode", revision = 0, id = [0x95c1a0edab0944cb, 0xa4e5cb3842f7488a];
+    static LIMINE_REQUEST_PAGING_MODE;
+}
+
+// Implements `Into<&str>` for its enum variant names
+enum_names! {
+    pub enum PagingMode {
+        Level4,
+        Level5,
+    }
+}
+
+// described in the limine protocol specs, the same for x86_64 & AArch64
+impl TryFrom<u64> for PagingMode {
+    type Error = ();
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Level4),
+            1 => Ok(Self::Level5),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Get the paging mode set up by the bootloader
+pub fn paging_mode() -> Result<PagingMode, LimineError> {
+    boot_info().paging_mode
+}
+
+// ======= Entry Point feature
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#entry-point-feature
+
+// Linked from kentry
+extern "C" {
+    fn _start();
+}
+
+limine_feature! {
+
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#entry-point-feature`
+
+    struct RequestEntryPoint {
+        entry: unsafe extern "C" fn() = _start,
+    }
+
+    struct ResponseEntryPoint {}
+
+    feature = "entry point", revision = 0, id = [0x13d86c035a1cd3e1, 0x2b0caa89d8f3026a];
+    static LIMINE_REQUEST_ENTRY_POINT;
+}
+
+/// Get the address the kernel was entered at, if the bootloader honored the requested entry point
+pub fn entry_point() -> Result<usize, LimineError> {
+    boot_info().entry_point
+}
+
+// ======= Kernel File & Module features
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#kernel-file-feature
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#module-feature
+
+limine_feature! {
+
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#kernel-file-feature`
+
+    struct RequestKernelFile {}
+
+    struct ResponseKernelFile {
+        kernel_file: Ptr<RawFile>,
+    }
+
+    feature = "kernel file", revision = 0, id = [0xad97e90e83f1ed67, 0x31eb5d1c5ff23b69];
+    static LIMINE_REQUEST_KERNEL_FILE;
+}
+
+limine_feature! {
+
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#module-feature`
+
+    struct RequestModule {}
+
+    struct ResponseModule {
+        module_count: u64,
+        // has length of module_count
+        modules: Ptr<Ptr<RawFile>>,
+    }
+
+    feature = "module", revision = 0, id = [0x3e7e279702be32af, 0xca1c4f3bd1280cee];
+    static LIMINE_REQUEST_MODULE;
+}
+
+/// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#file-structure`
+#[repr(C)]
+struct RawFile {
+    revision: u64,
+    address: Ptr<u8>,
+    size: u64,
+    path: Ptr<u8>,
+    cmdline: Ptr<u8>,
+    media_type: u32,
+    unused: u32,
+    tftp_ip: u32,
+    tftp_port: u32,
+    partition_index: u32,
+    mbr_disk_id: u32,
+    gpt_disk_uuid: [u8; 16],
+    gpt_part_uuid: [u8; 16],
+    part_uuid: [u8; 16],
+}
+
+// Implements `Into<&str>` for its enum variant names
+enum_names! {
+    pub enum MediaType {
+        Generic,
+        Optical,
+        Tftp,
+    }
+}
+
+// described in the limine protocol specs
+impl TryFrom<u32> for MediaType {
+    type Error = ();
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Generic),
+            1 => Ok(Self::Optical),
+            2 => Ok(Self::Tftp),
+            _ => Err(()),
+        }
+    }
+}
+
+/// rust-friendly version of `RawFile`, a file loaded by the bootloader
+#[derive(Debug)]
+pub struct File {
+    pub path: String,
+    pub cmdline: String,
+    /// located in `KernelAndModules` memory, which is never reclaimed
+    pub data: &'static [u8],
+    pub media_type: MediaType,
+    /// 1 based, 0 means the file was not loaded from a partition
+    pub partition_index: u32,
+    pub mbr_disk_id: u32,
+    pub gpt_disk_uuid: [u8; 16],
+    pub gpt_part_uuid: [u8; 16],
+    pub part_uuid: [u8; 16],
+}
+
+impl File {
+    unsafe fn copy(raw: &RawFile) -> Result<Self, LimineError> {
+        Ok(Self {
+            path: copy_str(raw.path, "file")?,
+            cmdline: copy_str(raw.cmdline, "file")?,
+            data: core::slice::from_raw_parts(raw.address, raw.size as usize),
+            media_type: MediaType::try_from(raw.media_type)
+                .map_err(|_| LimineError::InvalidValue("file"))?,
+            partition_index: raw.partition_index,
+            mbr_disk_id: raw.mbr_disk_id,
+            gpt_disk_uuid: raw.gpt_disk_uuid,
+            gpt_part_uuid: raw.gpt_part_uuid,
+            part_uuid: raw.part_uuid,
+        })
+    }
+}
+
+/// Copies a null terminated string, null pointers are copied as empty strings
+unsafe fn copy_str(ptr: Ptr<u8>, feature: &'static str) -> Result<String, LimineError> {
+    if ptr.is_null() {
+        return Ok(String::new());
+    }
+    CStr::from_ptr(ptr as *const core::ffi::c_char)
+        .to_str()
+        .map(String::from)
+        .map_err(|_| LimineError::InvalidUtf8(feature))
+}
+
+unsafe fn copy_modules() -> Result<Vec<File>, LimineError> {
+    let response = LIMINE_REQUEST_MODULE.response()?;
+    (0..response.module_count as usize)
+        .map(|i| File::copy(&**response.modules.add(i)))
+        .collect()
+}
+
+/// Get the kernel executable, its `cmdline` is the kernel command line
+pub fn kernel_file() -> Result<&'static File, LimineError> {
+    boot_info().kernel_file.as_ref().map_err(|e| *e)
+}
+
+/// Get the modules loaded alongside the kernel
+pub fn modules() -> Result<&'static [File], LimineError> {
+    boot_info().modules.as_deref().map_err(|e| *e)
+}
+
+// ======= RSDP feature
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#rsdp-feature
+
+limine_feature! {
+
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#rsdp-feature`
+
+    struct RequestRSDP {}
+
+    struct ResponseRSDP {
+        address: u64,
+    }
+
+    feature = "RSDP", revision = 0, id = [0xc5e77b6b397e7b43, 0x27637845accdcf3c];
+    static LIMINE_REQUEST_RSDP;
+}
+
+/// Get the address of the ACPI RSDP table, located in the HHDM
+pub fn rsdp() -> Result<usize, LimineError> {
+    boot_info().rsdp
+}
+
+// ======= SMBIOS feature
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#smbios-feature
+
+limine_feature! {
+
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#smbios-feature`
+
+    struct RequestSMBIOS {}
+
+    struct ResponseSMBIOS {
+        entry_32: u64,
+        entry_64: u64,
+    }
+
+    feature = "SMBIOS", revision = 0, id = [0x9e9046f11e095391, 0xaa4a520fefbde5ee];
+    static LIMINE_REQUEST_SMBIOS;
+}
+
+/// Addresses of the SMBIOS entry points, located in the HHDM
+#[derive(Debug, Clone, Copy)]
+pub struct SmbiosEntries {
+    pub entry_32: Option<usize>,
+    pub entry_64: Option<usize>,
+}
+
+/// Get the SMBIOS entry points
+pub fn smbios() -> Result<SmbiosEntries, LimineError> {
+    boot_info().smbios
+}
+
+// ======= EFI System Table feature
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#efi-system-table-feature
+
+limine_feature! {
+
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#efi-system-table-feature`
+
+    struct RequestEfiSystemTable {}
+
+    struct ResponseEfiSystemTable {
+        address: u64,
+    }
+
+    feature = "EFI system table", revision = 0, id = [0x5ceba5163eaaf6d6, 0x0a6981610cf65fcc];
+    static LIMINE_REQUEST_EFI_SYSTEM_TABLE;
+}
+
+/// Get the address of the EFI system table, only available when booted through UEFI
+pub fn efi_system_table() -> Result<usize, LimineError> {
+    boot_info().efi_system_table
+}
+
+// ======= EFI Memory Map feature
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#efi-memory-map-feature
+
+limine_feature! {
+
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#efi-memory-map-feature`
+
+    struct RequestEfiMemoryMap {}
+
+    struct ResponseEfiMemoryMap {
+        memmap: Ptr<u8>,
+        memmap_size: u64,
+        desc_size: u64,
+        desc_version: u64,
+    }
+
+    feature = "EFI memory map", revision = 0, id = [0x7df62a431d6872d5, 0xa4fcdfb3e57306c8];
+    static LIMINE_REQUEST_EFI_MEMORY_MAP;
+}
+
+/// Copy of the EFI memory map, as returned by `GetMemoryMap()`
+#[derive(Debug)]
+pub struct EfiMemoryMap {
+    pub data: Vec<u8>,
+    pub descriptor_size: usize,
+    pub descriptor_version: u64,
+}
+
+/// One `EFI_MEMORY_DESCRIPTOR`
+#[derive(Debug, Clone, Copy)]
+pub struct EfiMemoryDescriptor {
+    pub typ: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub pages: u64,
+    pub attribute: u64,
+}
+
+impl EfiMemoryMap {
+    /// Iterates over the descriptors, which may be larger than `EfiMemoryDescriptor`
+    pub fn descriptors(&self) -> impl Iterator<Item = EfiMemoryDescriptor> + '_ {
+        // UEFI aligns the fields naturally, so there is padding after the type
+        let u64_at = |d: &[u8], at: usize| u64::from_le_bytes(d[at..at + 8].try_into().unwrap());
+        self.data
+            .chunks_exact(self.descriptor_size)
+            .filter(|d| d.len() >= 40)
+            .map(move |d| EfiMemoryDescriptor {
+                typ: u32::from_le_bytes(d[0..4].try_into().unwrap()),
+                physical_start: u64_at(d, 8),
+                virtual_start: u64_at(d, 16),
+                pages: u64_at(d, 24),
+                attribute: u64_at(d, 32),
+            })
+    }
+}
+
+unsafe fn copy_efi_memory_map() -> Result<EfiMemoryMap, LimineError> {
+    let response = LIMINE_REQUEST_EFI_MEMORY_MAP.response()?;
+    if response.desc_size == 0 {
+        return Err(LimineError::InvalidValue("EFI memory map"));
+    }
+    Ok(EfiMemoryMap {
+        data: core::slice::from_raw_parts(response.memmap, response.memmap_size as usize).to_vec(),
+        descriptor_size: response.desc_size as usize,
+        descriptor_version: response.desc_version,
+    })
+}
+
+/// Get the EFI memory map, only available when booted through UEFI
+pub fn efi_memory_map() -> Result<&'static EfiMemoryMap, LimineError> {
+    boot_info().efi_memory_map.as_ref().map_err(|e| *e)
+}
+
+// ======= Device Tree Blob feature
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#device-tree-blob-feature
+
+limine_feature! {
+
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#device-tree-blob-feature`
+
+    struct RequestDeviceTree {}
+
+    struct ResponseDeviceTree {
+        dtb: Ptr<u8>,
+    }
+
+    feature = "device tree", revision = 0, id = [0xb40ddb48fb54bac7, 0x545081493f81ffb7];
+    static LIMINE_REQUEST_DEVICE_TREE;
+}
+
+/// Big endian magic at the start of every flattened device tree
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+/// The device tree is not copied, it can be as large as 1 MiB. It stays in the
+/// bootloader-reclaimable memory, which `memman::reclaim` keeps for it.
+unsafe fn find_device_tree() -> Result<&'static [u8], LimineError> {
+    let response = LIMINE_REQUEST_DEVICE_TREE.response()?;
+    if response.dtb.is_null() {
+        return Err(LimineError::NoResponse("device tree"));
+    }
+    // the header starts with the magic and the total size, both big endian
+    let header = core::slice::from_raw_parts(response.dtb, 8);
+    if u32::from_be_bytes(header[0..4].try_into().unwrap()) != FDT_MAGIC {
+        return Err(LimineError::InvalidValue("device tree"));
+    }
+    let size = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    Ok(core::slice::from_raw_parts(response.dtb, size))
+}
+
+/// Get the flattened device tree blob, located in the HHDM
+pub fn device_tree() -> Result<&'static [u8], LimineError> {
+    boot_info().device_tree
+}
+
+// ======= Firmware Type feature
+// See: https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#firmware-type-feature
+
+limine_feature! {
+
+    /// `https://github.com/limine-bootloader/limine/blob/trunk/PROTOCOL.md#firmware-type-feature`
+
+    struct RequestFirmwareType {}
+
+    struct ResponseFirmwareType {
+        firmware_type: u64,
+    }
+
+    feature = "firmware type", revision = 0, id = [0x8c2f75d90bef28a8, 0x7045a4688eac00c3];
+    static LIMINE_REQUEST_FIRMWARE_TYPE;
+}
+
+// Implements `Into<&str>` for its enum variant names
+enum_names! {
+    pub enum FirmwareType {
+        X86Bios,
+        Uefi32,
+        Uefi64,
+        Sbi,
+    }
+}
+
+// described in the limine protocol specs
+impl TryFrom<u64> for FirmwareType {
+    type Error = ();
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::X86Bios),
+            1 => Ok(Self::Uefi32),
+            2 => Ok(Self::Uefi64),
+            3 => Ok(Self::Sbi),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Get the firmware the bootloader was started by
+pub fn firmware_type() -> Result<FirmwareType, LimineError> {
+    boot_info().firmware_type
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -411,11 +1084,11 @@ pub fn draw_pixel(
     y: usize,
     color: (u8, u8, u8),
 ) -> Result<(), DrawPixelError> {
-    if x > fb.width as usize {
+    if x >= fb.width as usize {
         return Err(DrawPixelError::InvalidX);
     }
 
-    if y > fb.height as usize {
+    if y >= fb.height as usize {
         return Err(DrawPixelError::InvalidY);
     }
 
@@ -428,10 +1101,10 @@ pub fn draw_pixel(
         | ((green as u32) << fb.green_mask_shift)
         | ((blue as u32) << fb.blue_mask_shift);
 
+    // rows can be padded, so they are `pitch` bytes apart
     unsafe {
-        *(fb.address as *mut u32)
-            .wrapping_add(x)
-            .wrapping_add(fb.width as usize * y) = pixc;
+        let row = fb.address.wrapping_add(fb.pitch as usize * y);
+        *row.cast::<u32>().wrapping_add(x) = pixc;
     }
 
     Ok(())
@@ -6,15 +6,15 @@
 
 //! All logs are passed to a `GlobalLog` object that then stores/outputs them.
 
-use crate::limine;
+use crate::cmdline::{AnyParam, FromParam, Param};
 use arrayvec::ArrayString;
 use core::fmt;
 use core::fmt::{Arguments, Write};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-/// The selected logger at compile time. It must implement a `new()` function that returns `Self` &
-/// the `core::fmt::Write` trait.
+/// The selected logger at compile time. It must implement a `new()` function that returns `Self`,
+/// a `tail()` function that returns the last bytes of the log & the `core::fmt::Write` trait.
 type GlobalLog = StaticLog;
 
 lazy_static! {
@@ -26,12 +26,85 @@ lazy_static! {
 /// implementation.
 const PRINT_PANIC: &'static str = "Could not write to GLOBAL_LOG!";
 
+/// Importance of a log message, messages less important than `log.level` are dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromParam for LogLevel {
+    fn from_param(value: &'static str) -> Result<Self, &'static str> {
+        match value {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            "trace" => Ok(Self::Trace),
+            _ => Err("expected error, warn, info, debug or trace"),
+        }
+    }
+}
+
+/// Outputs of `GlobalLog`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogSinks {
+    pub serial: bool,
+    /// the in-memory log, also used for the crash dump
+    pub buffer: bool,
+}
+
+impl FromParam for LogSinks {
+    fn from_param(value: &'static str) -> Result<Self, &'static str> {
+        let mut sinks = Self {
+            serial: false,
+            buffer: false,
+        };
+        for sink in value.split(',').filter(|s| !s.is_empty()) {
+            match sink {
+                "serial" => sinks.serial = true,
+                "buffer" => sinks.buffer = true,
+                _ => return Err("expected a comma separated list of serial, buffer"),
+            }
+        }
+        Ok(sinks)
+    }
+}
+
+pub static LOG_LEVEL: Param<LogLevel> = Param::new(
+    "log.level",
+    "least important messages logged: error, warn, info, debug, trace",
+    LogLevel::Info,
+);
+
+pub static LOG_SINKS: Param<LogSinks> = Param::new(
+    "log.sinks",
+    "comma separated log outputs: serial, buffer",
+    LogSinks {
+        serial: true,
+        buffer: true,
+    },
+);
+
+/// Command line options of the logger
+pub static PARAMS: &[&dyn AnyParam] = &[&LOG_LEVEL, &LOG_SINKS];
+
 /// Used in the `log!()` macro as utility function to reach `GLOBAL_LOG`
 pub fn print(msg: Arguments) {
-    GLOBAL_LOG.lock().write_fmt(msg).expect(PRINT_PANIC)
+    print_at(LogLevel::Info, msg)
+}
+
+/// Used in the `log_at!()` macro, drops messages less important than `LOG_LEVEL`
+pub fn print_at(level: LogLevel, msg: Arguments) {
+    if level <= LOG_LEVEL.get() {
+        GLOBAL_LOG.lock().write_fmt(msg).expect(PRINT_PANIC)
+    }
 }
 
-/// Main macro used to log data, similar syntax to the standart `print!()`
+/// Main macro used to log data, similar syntax to the standart `print!()`. Logs at `LogLevel::Info`
 ///
 /// WARNING: In newer rust version using padding -> blocks the main thread for an uknown reason
 #[macro_export]
@@ -39,13 +112,121 @@ macro_rules! log {
     ($($arg:tt)*) => ($crate::log::print(format_args!($($arg)*)));
 }
 
+/// Same as `log!()`, but at the given `LogLevel`
+#[macro_export]
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => ($crate::log::print_at($level, format_args!($($arg)*)));
+}
+
+/// Used by the panic handler instead of `print()`. It never panics and breaks the lock on
+/// `GLOBAL_LOG` if the panic happened while it was held, e.g. inside of `log!()`.
+///
+/// WARNING: Only call this after all other CPUs have been stopped.
+pub fn panic_print(msg: Arguments) {
+    if GLOBAL_LOG.is_locked() {
+        unsafe { GLOBAL_LOG.force_unlock() };
+    }
+    // a full buffer is ignored, the message has already been written to serial
+    let _ = GLOBAL_LOG.lock().write_fmt(msg);
+}
+
+/// Calls `f` with at most the last `len` bytes of the in-memory log.
+///
+/// WARNING: logging inside of `f` deadlocks
+pub fn with_tail<R>(len: usize, f: impl FnOnce(&str) -> R) -> R {
+    f(GLOBAL_LOG.lock().tail(len))
+}
+
+/// Same as `log!()`, but uses `panic_print()`
+#[macro_export]
+macro_rules! panic_log {
+    ($($arg:tt)*) => ($crate::log::panic_print(format_args!($($arg)*)));
+}
+
+// Crash dump
+
+use crate::config::CRASH_DUMP_LOG_TAIL;
+use crate::driver::serial;
+use crate::trace::Frame;
+
+/// Writes a machine-parsable crash dump to serial. The format is line based:
+///
+/// ```text
+/// === REZOS CRASH DUMP BEGIN v1 ===
+/// message: <escaped panic message>
+/// location: <file:line:column>
+/// backtrace: <return addresses, comma separated>
+/// log-tail: <N>
+/// <exactly N bytes of the end of the kernel log>
+/// === REZOS CRASH DUMP END ===
+/// ```
+///
+/// Newlines and backslashes in `message` are escaped as `\n` and `\\`.
+/// WARNING: Only call this after all other CPUs have been stopped.
+pub fn crash_dump(info: &core::panic::PanicInfo<'_>, backtrace: &[Frame]) {
+    let mut out = SerialWriter { escape: false };
+    // writing to serial never fails
+    let _ = write!(out, "=== REZOS CRASH DUMP BEGIN v1 ===\nmessage: ");
+    out.escape = true;
+    let _ = write!(out, "{:?}", info.message());
+    out.escape = false;
+    let _ = match info.location() {
+        Some(loc) => write!(out, "\nlocation: {}\n", loc),
+        None => write!(out, "\nlocation: unknown\n"),
+    };
+    let _ = write!(out, "backtrace: ");
+    for (i, frame) in backtrace.iter().enumerate() {
+        let sep = if i == 0 { "" } else { "," };
+        let _ = write!(out, "{}0x{:X}", sep, frame.return_address);
+    }
+
+    if GLOBAL_LOG.is_locked() {
+        unsafe { GLOBAL_LOG.force_unlock() };
+    }
+    let log = GLOBAL_LOG.lock();
+    let tail = log.tail(CRASH_DUMP_LOG_TAIL);
+    let _ = write!(out, "\nlog-tail: {}\n", tail.len());
+    serial::write(tail);
+    let _ = write!(out, "\n=== REZOS CRASH DUMP END ===\n");
+}
+
+/// Writes directly to serial, bypassing `GLOBAL_LOG` so the dump does not end up in the log
+struct SerialWriter {
+    escape: bool,
+}
+
+impl Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !self.escape {
+            serial::write(s);
+            return Ok(());
+        }
+        for part in s.split_inclusive(['\n', '\\']) {
+            match part.strip_suffix('\n') {
+                Some(line) => {
+                    serial::write(line);
+                    serial::write("\\n");
+                }
+                None => match part.strip_suffix('\\') {
+                    Some(text) => {
+                        serial::write(text);
+                        serial::write("\\\\");
+                    }
+                    None => serial::write(part),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
 // Static Log implementation
 
 use crate::config::LOG_STATIC_CAPACITY;
 
 /// Simple implementation of `GlobalLog` with a static size/limit.
 ///
-/// This writes all info to `limine::print_bytes` and stores it in a static buffer.
+/// This writes all info to serial and stores it in a static buffer, as selected by `LOG_SINKS`.
 /// One big issue with this is that if the buffer fills using `log!()` will cause a `PRINT_PANIC`.
 /// The only possible fix is to increase `LOG_STATIC_CAPACITY`,
 /// recompile and hope it does not fill again.
@@ -59,11 +240,26 @@ impl StaticLog {
             content: ArrayString::<LOG_STATIC_CAPACITY>::new(),
         }
     }
+
+    /// Returns at most the last `len` bytes of the log, cut at a character boundary
+    fn tail(&self, len: usize) -> &str {
+        let mut start = self.content.len().saturating_sub(len);
+        while !self.content.is_char_boundary(start) {
+            start += 1;
+        }
+        &self.content[start..]
+    }
 }
 
 impl Write for StaticLog {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        crate::driver::serial::write(s);
+        let sinks = LOG_SINKS.get();
+        if sinks.serial {
+            crate::driver::serial::write(s);
+        }
+        if !sinks.buffer {
+            return Ok(());
+        }
         if s.len() > self.content.remaining_capacity() {
             return Err(fmt::Error);
         }
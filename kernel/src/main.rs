@@ -9,12 +9,15 @@
 #![no_std]
 #![no_main]
 #![crate_type = "staticlib"]
-#![feature(layout_for_ptr)]
 // required by tools.rs
 #![feature(const_trait_impl)]
 #![feature(const_mut_refs)]
 // required by panic handler
 #![feature(panic_info_message)]
+// required by the in-kernel test framework, see ktest.rs
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::ktest::runner)]
+#![reexport_test_harness_main = "test_main"]
 
 /*
 // required by const-bitfields
@@ -23,49 +26,121 @@
 #![feature(const_trait_impl)] // always required
 */
 
+use arch::{Arch, Cpu, Current};
+use arrayvec::ArrayVec;
 use core::panic::{self, PanicInfo};
+use core::sync::atomic::{AtomicBool, Ordering};
 extern crate alloc;
 // Do not remove these imports, they may look useless but they prevent link errors
 #[allow(unused_imports)]
 use rlibc;
 use rlibcex;
 
+/// set by the first panic, used to detect panics inside of the panic handler and by the other
+/// CPUs to tell the NMI of `Cpu::stop_others()` apart from other ones
+pub(crate) static PANICKING: AtomicBool = AtomicBool::new(false);
+
 /// kernel panic handler, uses the `log` module internally
+///
+/// Stops all CPUs, logs the panic with a backtrace and writes a crash dump to serial.
 #[panic_handler]
 fn kpanic(info: &core::panic::PanicInfo<'_>) -> ! {
-    log!("\nKERNEL PANIC!!!\n");
+    Current::disable_interrupts();
+    // a panic inside of the handler must not recurse again
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        driver::serial::write("\nRECURSIVE KERNEL PANIC!!!\n");
+        halt();
+    }
+    unsafe { Current::stop_others() };
+
+    panic_log!("\nKERNEL PANIC!!!\n");
     // payload
     match info.payload().downcast_ref::<&str>() {
-        Some(p) => log!("Payload: {:?}\n", p),
-        None => log!("Payload: unknown\n"),
+        Some(p) => panic_log!("Payload: {:?}\n", p),
+        None => panic_log!("Payload: unknown\n"),
     }
     // message
-    log!("{:?}", info.message());
+    panic_log!("{:?}", info.message());
     // location
     match info.location() {
-        Some(loc) => log!("Location: {}\n", loc),
-        None => log!("Location: unknown"),
+        Some(loc) => panic_log!("Location: {}\n", loc),
+        None => panic_log!("Location: unknown"),
+    }
+    // exceptions are unwound from the faulting code, see `trace::set_exception()`
+    let unwind = match trace::take_exception() {
+        Some((pc, fp)) => {
+            let frame = trace::Frame {
+                frame_pointer: fp,
+                return_address: pc,
+            };
+            panic_log!("Exception at {}\n", frame);
+            trace::Backtrace::from_frame_pointer(fp)
+        }
+        None => trace::Backtrace::new(),
+    };
+    // walked once, the crash dump reuses the frames
+    let backtrace: ArrayVec<trace::Frame, { config::BACKTRACE_MAX_DEPTH }> = unwind.collect();
+    panic_log!("Backtrace:\n");
+    for (i, frame) in backtrace.iter().enumerate() {
+        panic_log!("{}: {}\n", i, frame);
+    }
+    log::crash_dump(info, &backtrace);
+    #[cfg(test)]
+    ktest::exit_qemu(ktest::QemuExitCode::Failed);
+    #[cfg(not(test))]
+    halt();
+}
+
+/// halts the current CPU forever
+fn halt() -> ! {
+    loop {
+        unsafe { Current::halt() };
     }
-    loop {}
 }
 
+/// ACPI table lookup.
+pub mod acpi;
 /// contains architecture specific code.
 pub mod arch;
+/// kernel command line parsing.
+pub mod cmdline;
 /// Generated by `config.sh`
 pub mod config;
+/// keyboard input & console.
+pub mod console;
 /// contains device drivers
 pub mod driver;
+/// flattened device tree parsing.
+pub mod fdt;
+/// virtual filesystem & filesystem drivers.
+pub mod fs;
+/// in-kernel test framework.
+#[cfg(test)]
+pub mod ktest;
 /// This module handles all things limine.
 pub mod limine;
 /// Handles logging info in the kernel runtime.
 pub mod log;
 /// handles memory managment.
 pub mod memman;
+/// interactive debug shell.
+pub mod shell;
 /// contains various utilities used everywhere.
 pub mod tools;
+/// stack unwinding and kernel symbol lookup.
+pub mod trace;
 
-use memman::map::{MapArea, MemoryMapper};
-use tinyvec::ArrayVec;
+/// Command line options of every subsystem, see `cmdline`
+#[cfg(not(test))]
+static KERNEL_PARAMS: &[&[&dyn cmdline::AnyParam]] =
+    &[log::PARAMS, driver::serial::PARAMS, console::PARAMS];
+#[cfg(test)]
+static KERNEL_PARAMS: &[&[&dyn cmdline::AnyParam]] = &[
+    log::PARAMS,
+    driver::serial::PARAMS,
+    console::PARAMS,
+    ktest::PARAMS,
+];
 
 /// kernel main function called & linked by `kentry`
 
@@ -75,79 +150,134 @@ pub extern "C" fn kmain() {
 
     //loop {}
     log!("{}", config::MESSAGE_FIRST);
+    limine::init();
+    if let Ok(kernel_file) = limine::kernel_file() {
+        for rejected in cmdline::apply(&kernel_file.cmdline, KERNEL_PARAMS) {
+            log_at!(log::LogLevel::Warn, "[WARN] kernel option {}\n", rejected);
+        }
+    }
+    driver::serial::configure();
 
     // hardware
-    let boot_time = limine::boot_time_stamp();
     log!("[ Hardware Info ]\n");
-    log!("UNIX Boot time: {}\n", boot_time);
+    match limine::boot_time_stamp() {
+        Ok(boot_time) => log!("UNIX Boot time: {}\n", boot_time),
+        Err(e) => log!("UNIX Boot time: unknown, {}\n", e),
+    }
 
     // arch
     log!("CPU Architecture: ");
-    match arch::get_arch() {
+    match Current::TYPE {
         arch::ArchType::X86_64 => log!("amd64/x86_64\n"),
         arch::ArchType::AArch64 => log!("arm64/AArch64\n"),
     };
-    arch::init();
+    Current::init();
 
     // boot loader
-    let (bootloader_name, bootloader_version) = limine::bootloader_info();
     log!("[ Bootloader info ]\n");
-    log!("name: {}\n", core::str::from_utf8(bootloader_name).unwrap());
-    log!(
-        "version: {}\n",
-        core::str::from_utf8(bootloader_version).unwrap()
-    );
+    match limine::bootloader_info() {
+        Ok((name, version)) => {
+            log!("name: {}\n", name);
+            log!("version: {}\n", version);
+        }
+        Err(e) => log!("unknown, {}\n", e),
+    }
+    if let Ok(firmware) = limine::firmware_type() {
+        log!("firmware: {:?}\n", firmware);
+    }
+    if let Ok(mode) = limine::paging_mode() {
+        log!("paging mode: {:?}\n", mode);
+    }
+    if let Ok(kernel_file) = limine::kernel_file() {
+        log!("kernel: {}\n", kernel_file.path);
+        log!("cmdline: {}\n", kernel_file.cmdline);
+    }
+    if let Ok(modules) = limine::modules() {
+        log!("modules: {}\n", modules.len());
+    }
 
     // memory map
     log!("[ Memory Map ]\n");
 
-    let ram_size = limine::memory_map().last().unwrap().range.1;
-    unsafe { memman::map::set_global((0, ram_size)) };
-    let mut map_area_pool = ArrayVec::<[MapArea; 25]>::new();
-    map_area_pool.push(memman::map::claim_global((0, 1000)).unwrap());
-    for region in limine::memory_map() {
+    let hhdm = limine::hhdm().expect("Physical memory is not mapped (HHDM)!");
+    memman::map::set_physical_offset(hhdm);
+    let mut memory_map = limine::memory_map().expect("No memory map!");
+    let ram_size = memory_map.next_back().map_or(0, |region| region.range.1);
+    unsafe { memman::map::set_global((0, ram_size.max(memman::map::PHYSICAL_END))) };
+    for region in limine::memory_map().unwrap() {
         let (start, end) = region.range;
         match region.typ {
             limine::MemmapEntryType::Usable => {}
             _ => {
                 let ma = memman::map::claim_global(region.range)
                     .expect("Limine map entry could not be claimed!");
-                if let Some(_) = map_area_pool.try_push(ma) {
-                    log!("[ERROR] map_area_pool is full, limine entry will be dropped!\n");
-                }
+                memman::reclaim::hold(region.typ, ma);
             }
         }
         let print_typ: &str = region.typ.into();
         // original: log!("{:023} 0x{:016X} - 0x{:016X}\n", print_typ, start, end);
         log!("{} 0x{:X} - 0x{:X}\n", print_typ, start, end);
     }
-
-    /*
-    // log GLOBAL_MEMORY_MAPPER entries
-    use memman::map::MemoryMapper;
-    for a in memman::map::GLOBAL_MEMORY_MAPPER.get().unwrap().iter() {
-        limine::print_hex(a.0);
-        log!(b"\n");
+    // keep the null page out of use, unless limine already reserved it
+    if let Ok(ma) = memman::map::claim_global((0, 1000)) {
+        memman::reclaim::hold(limine::MemmapEntryType::Reserved, ma);
     }
-    */
+
+    log!("[ Global Memory Map ]\n");
+    memman::stats::log_claimed();
+
+    memman::stats::collect_boot_stats();
+    log!("[ Memory Statistics ]\n");
+    log!("{}", memman::stats::stats());
+
+    // devices, needs the global memory map
+    log!("[ Devices ]\n");
+    Current::init_devices();
+    driver::register_builtin();
+    driver::discover();
+    driver::init();
+    driver::partition::scan();
 
     // kernel address
-    let kernel_physical_address = limine::kernel_address_physical();
-    let kernel_virtual_address = limine::kernel_address_virtual();
     log!("[ Kernel Address ]\n");
-    // original: log!("physical: {:016X}\n", kernel_physical_address);
-    log!("physical: 0x{:X}\n", kernel_physical_address);
-    // original: log!("virtual:  {:016X}\n", kernel_virtual_address);
-    log!("virtual:  0x{:X}\n", kernel_virtual_address);
+    match (
+        limine::kernel_address_physical(),
+        limine::kernel_address_virtual(),
+    ) {
+        (Ok(kernel_physical_address), Ok(kernel_virtual_address)) => {
+            // original: log!("physical: {:016X}\n", kernel_physical_address);
+            log!("physical: 0x{:X}\n", kernel_physical_address);
+            // original: log!("virtual:  {:016X}\n", kernel_virtual_address);
+            log!("virtual:  0x{:X}\n", kernel_virtual_address);
+        }
+        (Err(e), _) | (_, Err(e)) => log!("unknown, {}\n", e),
+    }
 
     // HHDM
-    let hhdm = limine::hhdm();
-    log!("HHDM: 0x{:016X}\n", hhdm);
+    log!("HHDM: 0x{:X}\n", hhdm);
 
-    log!("Framebuffer 0: {:?}\n", limine::framebuffer0());
+    match limine::framebuffers() {
+        Ok(framebuffers) => log!("Framebuffer 0: {:?}\n", framebuffers.first()),
+        Err(e) => log!("Framebuffer 0: none, {}\n", e),
+    }
     alloc::boxed::Box::new(4);
     driver::lfb::init();
 
-    log!("Nothing to do!\n");
-    panic!("Nothing to do!");
+    // the limine responses were copied by `limine::init()`, the drivers are bound, so only the
+    // ACPI tables parsed later on are still needed
+    log!("[ Memory Reclaim ]\n");
+    if let Ok(rsdp) = limine::rsdp() {
+        unsafe { acpi::copy_tables(rsdp) };
+    }
+    let recovered = unsafe { memman::reclaim::reclaim_bootloader() };
+    log!("bootloader: {}\n", memman::stats::Size(recovered));
+    let recovered = unsafe { memman::reclaim::reclaim_acpi() };
+    log!("ACPI: {}\n", memman::stats::Size(recovered));
+
+    #[cfg(test)]
+    test_main();
+
+    log!("Booted, starting the shell\n");
+    driver::fbcon::init();
+    shell::run();
 }
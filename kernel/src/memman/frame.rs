@@ -0,0 +1,83 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Claims page frames of usable physical memory, for page tables, DMA buffers & such.
+//!
+//! The global memory map only tracks claimed regions, its gaps can also be memory holes or MMIO,
+//! so frames are only taken from gaps inside of usable limine memory map entries and inside of
+//! the regions released by `reclaim`.
+
+use super::map::{self, MapArea, MapItem, MemoryMapper, MemoryMapperError};
+use super::reclaim;
+use crate::arch::PAGE_SIZE;
+use crate::limine::{self, MemmapEntryType};
+
+/// Merges two iterators over disjoint regions sorted by address into one sorted iterator
+fn merge_sorted(
+    a: impl Iterator<Item = MapItem>,
+    b: impl Iterator<Item = MapItem>,
+) -> impl Iterator<Item = MapItem> {
+    let (mut a, mut b) = (a.peekable(), b.peekable());
+    core::iter::from_fn(move || match (a.peek(), b.peek()) {
+        (Some(x), Some(y)) if y < x => b.next(),
+        (Some(_), _) => a.next(),
+        (None, _) => b.next(),
+    })
+}
+
+/// Finds `size` bytes of unclaimed usable memory, aligned to `PAGE_SIZE`.
+///
+/// The usable regions and the gaps are both sorted by address, so they are intersected in a
+/// single pass over each.
+fn find_free(size: usize) -> Option<usize> {
+    let released = reclaim::released();
+    // limine sorts its memory map by address
+    let usable = limine::memory_map()
+        .ok()?
+        .filter(|region| region.typ == MemmapEntryType::Usable)
+        .map(|region| region.range);
+    let mut usable = merge_sorted(usable, released.iter().copied()).peekable();
+    for (gap_start, gap_end) in map::global().gaps() {
+        while let Some(&(region_start, region_end)) = usable.peek() {
+            if region_start >= gap_end {
+                break;
+            }
+            let start = gap_start.max(region_start).next_multiple_of(PAGE_SIZE);
+            let end = gap_end.min(region_end);
+            if start + size <= end {
+                return Some(start);
+            }
+            // the rest of the region may lie in the next gap
+            if region_end > gap_end {
+                break;
+            }
+            usable.next();
+        }
+    }
+    None
+}
+
+/// Claims `count` physically contiguous page frames from the global memory map.
+/// The content of the frames is undefined. Returns `None` if there is not enough usable memory.
+pub fn claim_frames(count: usize) -> Option<MapArea> {
+    let size = count * PAGE_SIZE;
+    loop {
+        let start = find_free(size)?;
+        match map::claim_global((start, start + size)) {
+            Ok(area) => return Some(area),
+            // claimed by someone else in the meantime
+            Err(MemoryMapperError::AlreadyOccupiedBy(_)) => continue,
+            Err(_) => return None,
+        }
+    }
+}
+
+/// Same as `claim_frames()`, but the frames are filled with zeroes
+pub fn claim_zeroed_frames(count: usize) -> Option<MapArea> {
+    let area = claim_frames(count)?;
+    unsafe { core::ptr::write_bytes(area.virtual_address() as *mut u8, 0, area.len()) };
+    Some(area)
+}
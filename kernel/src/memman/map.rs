@@ -6,16 +6,28 @@
 
 //! This module handles the memory map and claiming physical regions
 
+use crate::arch::{Current, Paging, PAGE_SIZE};
 use crate::log;
+use alloc::collections::BTreeMap;
+use core::marker::PhantomData;
 use core::mem;
+use core::ops::Range;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::once::Once;
 use spin::Mutex;
 
-/// Alias for the selected global `MemoryMapper` implementation (at compile time)
+/// Alias for the selected global `MemoryMapper` implementation (at compile time). The table is
+/// the default, the tree has no entry limit but lives on the bump heap, which it keeps from being
+/// reset.
 pub type GlobalMemoryMapper = TableMemoryMapper;
 /// Global memory map for the whole kernel runtime
 pub static GLOBAL_MEMORY_MAPPER: Once<GlobalMemoryMapper> = Once::new();
 
+/// End of the physical address space given to the global memory map by `kmain`. Device memory
+/// above the RAM, like PCI ECAM & BARs, is claimed through the global memory map as well.
+pub const PHYSICAL_END: usize = 1 << 40;
+
 /// Setup the global memory map in specified region
 ///
 /// WARNING: region must not be used and must have full priviliges for ring0 or it may lead to undefined behaviour
@@ -25,20 +37,60 @@ pub unsafe fn set_global(region: (usize, usize)) {
 
 /// Globally claims a physical memory region and adds a global memory map entry for it
 pub fn claim_global(region: (usize, usize)) -> Result<MapArea, MemoryMapperError> {
+    global().claim(region)
+}
+
+/// Frees a globally claimed `MapArea`, see `MemoryMapper::free()`
+pub fn free_global(area: MapArea) {
+    global().free(area)
+}
+
+/// Splits a globally claimed `MapArea`, see `MemoryMapper::split()`
+pub fn split_global(area: MapArea, at: usize) -> Result<(MapArea, MapArea), MapArea> {
+    global().split(area, at)
+}
+
+/// Merges two globally claimed `MapArea`s, see `MemoryMapper::merge()`
+pub fn merge_global(a: MapArea, b: MapArea) -> Result<MapArea, (MapArea, MapArea)> {
+    global().merge(a, b)
+}
+
+/// The global memory map, panics if it was not setup
+pub(crate) fn global() -> &'static GlobalMemoryMapper {
     GLOBAL_MEMORY_MAPPER
         .get()
         .expect("GLOBAL_MEMORY_MAPPER not setup!")
-        .claim(region)
 }
 
-/// Represents a memory region
-type MapItem = (usize, usize);
+/// Offset added to physical addresses to reach them from the kernel address space, by default
+/// the limine HHDM. See `set_physical_offset()`
+static PHYSICAL_OFFSET: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the offset at which physical memory is mapped in the virtual address space (the HHDM),
+/// used by `MapArea` to access its region.
+///
+/// WARNING: must be called before any `MapArea` is accessed
+pub fn set_physical_offset(offset: usize) {
+    PHYSICAL_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Offset at which physical memory is mapped, see `set_physical_offset()`
+pub fn physical_offset() -> usize {
+    PHYSICAL_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Represents a memory region as (start, end), where end is exclusive
+pub type MapItem = (usize, usize);
 
 /// Implement for object that manage a memory map of physical regions. They must use interior
 /// mutability as they should work in a concurrent context.
 ///
-/// I -> public iterator returned when reading the memory map is requested
-pub trait MemoryMapper<I: Iterator<Item = MapItem>> {
+/// Iter -> public iterator returned when reading the memory map is requested
+pub trait MemoryMapper {
+    type Iter<'a>: Iterator<Item = MapItem>
+    where
+        Self: 'a;
+
     /// Creates and mounts a `MemoryMapper` in specified region
     /// ## SAFETY: region must adhere to the following:
     /// - must have read & write priviliges for ring0
@@ -57,6 +109,17 @@ pub trait MemoryMapper<I: Iterator<Item = MapItem>> {
     ///     the mean time -> UB
     fn free(&self, area: MapArea);
 
+    /// Split a claimed area into two at address `at`, which must lie inside of it.
+    /// The map entry gets split too, so both halves can be freed separately.
+    ///
+    /// Returns the area back if `at` is not inside of it.
+    fn split(&self, area: MapArea, at: usize) -> Result<(MapArea, MapArea), MapArea>;
+
+    /// Merge two adjacent claimed areas (in any order) into one, joining their map entries.
+    ///
+    /// Returns both areas back if they are not adjacent.
+    fn merge(&self, a: MapArea, b: MapArea) -> Result<MapArea, (MapArea, MapArea)>;
+
     /// Forcefully remove a region entry in the map
     /// ## SAFETY: The region must not be claimed as it may create an orphan `MapArea` which leads
     /// to UB
@@ -64,14 +127,16 @@ pub trait MemoryMapper<I: Iterator<Item = MapItem>> {
         self.free(MapArea::new(region));
     }
 
-    /// Iterate through claimed regions
-    fn iter(&self) -> I;
+    /// Iterate through claimed regions, sorted by address
+    fn iter(&self) -> Self::Iter<'_>;
 
     /// Iterate through unclaimed regions between the claimed regions in the map
-    fn gaps(&self) -> MapGaps<I> {
+    fn gaps(&self) -> MapGaps<Self::Iter<'_>> {
+        let (start, end) = self.dimensions();
         MapGaps {
             iter: self.iter(),
-            last: self.dimensions().0,
+            last: start,
+            end,
         }
     }
 
@@ -79,10 +144,11 @@ pub trait MemoryMapper<I: Iterator<Item = MapItem>> {
     fn dimensions(&self) -> MapItem;
 }
 
-/// Iterate through the free space between map entries `I`
+/// Iterate through the free space between map entries `I`, which must be sorted by address
 pub struct MapGaps<I: Iterator<Item = MapItem>> {
     iter: I,
     last: usize,
+    end: usize,
 }
 
 impl<I> Iterator for MapGaps<I>
@@ -100,6 +166,12 @@ where
             }
             self.last = claimed.1;
         }
+        // the space after the last entry
+        if self.last < self.end {
+            let cache = self.last;
+            self.last = self.end;
+            return Some((cache, self.end));
+        }
         None
     }
 }
@@ -110,44 +182,218 @@ where
 /// - `AlreadyOccupiedBy` : The requested region intersects with a claimed region, contains the
 /// occupant region
 /// - `OutOfBound` : The requested region does not fit into into the map, returns the allowed dimensions
+/// - `Empty` : The requested region does not contain any bytes (start >= end)
 #[derive(Debug)]
 pub enum MemoryMapperError {
     AlreadyOccupiedBy((usize, usize)), // contains the occupant region
     OutOfBound((usize, usize)),        // contains the valid Mapper region
+    Empty,
 }
 
 /// Capability representing ownage of a claimed region
-#[derive(Default)]
+///
+/// The region is stored as physical (start, end), the same way it is stored in the map. Memory
+/// inside of it can be accessed with volatile reads & writes through the HHDM, which makes it
+/// usable for MMIO as well as for plain physical memory.
+#[derive(Default, Debug)]
 pub struct MapArea {
     region: (usize, usize),
 }
 
-impl<'a> MapArea {
-    // this function must be called only from inside MemoryMapper::claim() or MemoryMapper::force_free()
+impl MapArea {
+    // this function must be called only from inside of MemoryMapper implementations
     fn new(region: (usize, usize)) -> Self {
         Self { region }
     }
 
-    #[inline]
-    fn validate<T>(&self, ptr: *const T) -> bool {
-        ptr as usize >= self.region.0
-            && ptr as usize + unsafe { mem::size_of_val_raw(ptr) } <= self.region.1
+    /// The physical region owned by this area as (start, end)
+    pub fn region(&self) -> MapItem {
+        self.region
+    }
+
+    /// Size of the area in bytes
+    pub fn len(&self) -> usize {
+        self.region.1 - self.region.0
     }
 
-    fn create_ptr<T>(&self, addr: usize) -> Option<*const T> {
-        if self.validate(addr as *const T) {
-            return Some(addr as *const T);
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Virtual address of the start of the area in the HHDM
+    pub fn virtual_address(&self) -> usize {
+        self.region.0 + PHYSICAL_OFFSET.load(Ordering::Relaxed)
+    }
+
+    /// Read-only view of the whole area
+    pub fn view(&self) -> AreaRef<'_> {
+        AreaRef {
+            region: self.region,
+            _area: PhantomData,
         }
-        // invalid address
-        None
     }
 
-    unsafe fn get<T>(&self, ptr: *const T) -> Option<&'a T> {
-        if self.validate(ptr) {
-            return Some(&*ptr);
+    /// Writable view of the whole area
+    pub fn view_mut(&mut self) -> AreaMut<'_> {
+        AreaMut {
+            region: self.region,
+            _area: PhantomData,
+        }
+    }
+
+    /// Volatile read of a `T` at `offset` bytes from the start of the area
+    pub fn read<T: Plain>(&self, offset: usize) -> Result<T, AccessError> {
+        self.view().read(offset)
+    }
+
+    /// Volatile write of a `T` at `offset` bytes from the start of the area
+    pub fn write<T: Plain>(&mut self, offset: usize, value: T) -> Result<(), AccessError> {
+        self.view_mut().write(offset, value)
+    }
+
+    /// Read-only view of the sub-area `range`, relative to the start of the area
+    pub fn slice(&self, range: Range<usize>) -> Result<AreaRef<'_>, AccessError> {
+        self.view().slice(range)
+    }
+
+    /// Writable view of the sub-area `range`, relative to the start of the area
+    pub fn slice_mut(&mut self, range: Range<usize>) -> Result<AreaMut<'_>, AccessError> {
+        let region = sub_region(self.region, range)?;
+        Ok(AreaMut {
+            region,
+            _area: PhantomData,
+        })
+    }
+}
+
+/// Marks types that are valid for any bit pattern, so they can be read from raw memory.
+///
+/// # Safety
+/// Every possible bit pattern of the size of `Self` must be a valid `Self` and the type must not
+/// contain padding
+pub unsafe trait Plain: Copy {}
+
+macro_rules! impl_plain {
+    ($($typ:ty),*) => {
+        $(unsafe impl Plain for $typ {})*
+    };
+}
+
+impl_plain!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+
+/// Error returned by `MapArea` accessors
+///
+/// ## Variants:
+/// - `OutOfBounds` : The accessed range does not fit into the area
+/// - `Misaligned` : The accessed address is not aligned for the type
+/// - `Unmapped` : The accessed address is not mapped, e.g. a hole above the RAM that was never
+///   mapped with `Paging::map_device()`
+#[derive(Debug, PartialEq, Eq)]
+pub enum AccessError {
+    OutOfBounds,
+    Misaligned,
+    Unmapped,
+}
+
+/// Computes the absolute sub-region for a relative `range`
+fn sub_region(region: MapItem, range: Range<usize>) -> Result<MapItem, AccessError> {
+    let start = region.0.checked_add(range.start);
+    let end = region.0.checked_add(range.end);
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end && end <= region.1 => Ok((start, end)),
+        _ => Err(AccessError::OutOfBounds),
+    }
+}
+
+/// Checks that a `T` fits at `offset` into `region` and is mapped, returns its virtual address.
+///
+/// Claimed regions reach up to `PHYSICAL_END`, so every page of the access is looked up in the
+/// page tables, an unmapped one would fault.
+fn access<T>(region: MapItem, offset: usize) -> Result<usize, AccessError> {
+    let (start, _) = sub_region(region, offset..offset.wrapping_add(mem::size_of::<T>()))?;
+    let address = start + PHYSICAL_OFFSET.load(Ordering::Relaxed);
+    if address % mem::align_of::<T>() != 0 {
+        return Err(AccessError::Misaligned);
+    }
+    let first_page = address - address % PAGE_SIZE;
+    let mut pages = (first_page..address + mem::size_of::<T>()).step_by(PAGE_SIZE);
+    if !pages.all(|page| Current::translate(page).is_some()) {
+        return Err(AccessError::Unmapped);
+    }
+    Ok(address)
+}
+
+/// Borrowed read-only view into (a part of) a `MapArea`
+#[derive(Debug, Clone, Copy)]
+pub struct AreaRef<'a> {
+    region: MapItem,
+    _area: PhantomData<&'a MapArea>,
+}
+
+impl<'a> AreaRef<'a> {
+    /// The physical region of the view as (start, end)
+    pub fn region(&self) -> MapItem {
+        self.region
+    }
+
+    /// Volatile read of a `T` at `offset` bytes from the start of the view
+    pub fn read<T: Plain>(&self, offset: usize) -> Result<T, AccessError> {
+        let address = access::<T>(self.region, offset)?;
+        // SAFETY: the address is mapped, inside of a claimed region and T is valid for any bit
+        // pattern
+        Ok(unsafe { ptr::read_volatile(address as *const T) })
+    }
+
+    /// Narrows the view to `range`, relative to the start of the view
+    pub fn slice(&self, range: Range<usize>) -> Result<AreaRef<'a>, AccessError> {
+        Ok(AreaRef {
+            region: sub_region(self.region, range)?,
+            _area: PhantomData,
+        })
+    }
+}
+
+/// Borrowed writable view into (a part of) a `MapArea`
+#[derive(Debug)]
+pub struct AreaMut<'a> {
+    region: MapItem,
+    _area: PhantomData<&'a mut MapArea>,
+}
+
+impl AreaMut<'_> {
+    /// The physical region of the view as (start, end)
+    pub fn region(&self) -> MapItem {
+        self.region
+    }
+
+    /// Volatile read of a `T` at `offset` bytes from the start of the view
+    pub fn read<T: Plain>(&self, offset: usize) -> Result<T, AccessError> {
+        self.as_ref().read(offset)
+    }
+
+    /// Volatile write of a `T` at `offset` bytes from the start of the view
+    pub fn write<T: Plain>(&mut self, offset: usize, value: T) -> Result<(), AccessError> {
+        let address = access::<T>(self.region, offset)?;
+        // SAFETY: the address is mapped & inside of a claimed region that we borrow mutably
+        unsafe { ptr::write_volatile(address as *mut T, value) };
+        Ok(())
+    }
+
+    /// Narrows the view to `range`, relative to the start of the view
+    pub fn slice_mut(&mut self, range: Range<usize>) -> Result<AreaMut<'_>, AccessError> {
+        Ok(AreaMut {
+            region: sub_region(self.region, range)?,
+            _area: PhantomData,
+        })
+    }
+
+    /// Read-only version of this view
+    pub fn as_ref(&self) -> AreaRef<'_> {
+        AreaRef {
+            region: self.region,
+            _area: PhantomData,
         }
-        // invalid address
-        None
     }
 }
 
@@ -208,7 +454,9 @@ impl Iterator for TableMap {
     }
 }
 
-impl MemoryMapper<TableMap> for TableMemoryMapper {
+impl MemoryMapper for TableMemoryMapper {
+    type Iter<'a> = TableMap;
+
     unsafe fn manage(region: (usize, usize)) -> Self {
         Self {
             start: region.0,
@@ -219,6 +467,9 @@ impl MemoryMapper<TableMap> for TableMemoryMapper {
 
     fn claim(&self, region: (usize, usize)) -> Result<MapArea, MemoryMapperError> {
         let (start, end) = region;
+        if start >= end {
+            return Err(MemoryMapperError::Empty);
+        }
         // bound check the request
         if start < self.start || end > self.end {
             return Err(MemoryMapperError::OutOfBound((self.start, self.end)));
@@ -229,25 +480,19 @@ impl MemoryMapper<TableMap> for TableMemoryMapper {
         for slot in table.iter() {
             // slot may be None and not hold an entry
             if let Some((first, last)) = *slot {
-                // cover all possible intersections of regions
-                if (first < start && last > start) || (first > start && first < end) {
+                // two regions intersect if each one starts before the other one ends
+                if first < end && start < last {
                     return Err(MemoryMapperError::AlreadyOccupiedBy((first, last)));
                 }
             }
         }
 
         // get first empty slot to store our entry
-        let mut i = 0;
-        while table[i].is_some() {
-            i += 1
-        }
-        // panic if no empty slot has been found
-        if i >= TABLE_SIZE {
-            panic!("Maximum number of MemoryMapper entries reached!");
+        match table.iter().position(|slot| slot.is_none()) {
+            Some(i) => table[i] = Some(region),
+            None => panic!("Maximum number of MemoryMapper entries reached!"),
         }
-
-        table[i] = Some(region);
-        Ok(MapArea::new((start, end - start)))
+        Ok(MapArea::new(region))
     }
 
     // WARNING: calling free() on a region that is still used may lead to undefind behaviour
@@ -265,6 +510,51 @@ impl MemoryMapper<TableMap> for TableMemoryMapper {
             Some(i) => table[i] = None,
             None => panic!("Poisoned MapArea could not be freed!"),
         }
+        // the handle has been consumed properly, so dropping it must not warn
+        mem::forget(area);
+    }
+
+    fn split(&self, area: MapArea, at: usize) -> Result<(MapArea, MapArea), MapArea> {
+        let (start, end) = area.region;
+        if at <= start || at >= end {
+            return Err(area);
+        }
+        let mut table = self.table.lock();
+        let i = table
+            .iter()
+            .position(|slot| *slot == Some(area.region))
+            .expect("Poisoned MapArea could not be split!");
+        let free = match table.iter().position(|slot| slot.is_none()) {
+            Some(free) => free,
+            None => panic!("Maximum number of MemoryMapper entries reached!"),
+        };
+        table[i] = Some((start, at));
+        table[free] = Some((at, end));
+        mem::forget(area);
+        Ok((MapArea::new((start, at)), MapArea::new((at, end))))
+    }
+
+    fn merge(&self, a: MapArea, b: MapArea) -> Result<MapArea, (MapArea, MapArea)> {
+        let (first, second) = if a.region.1 == b.region.0 {
+            (a.region, b.region)
+        } else if b.region.1 == a.region.0 {
+            (b.region, a.region)
+        } else {
+            return Err((a, b));
+        };
+        let mut table = self.table.lock();
+        let i = table.iter().position(|slot| *slot == Some(first));
+        let j = table.iter().position(|slot| *slot == Some(second));
+        match (i, j) {
+            (Some(i), Some(j)) => {
+                table[i] = Some((first.0, second.1));
+                table[j] = None;
+            }
+            _ => panic!("Poisoned MapArea could not be merged!"),
+        }
+        mem::forget(a);
+        mem::forget(b);
+        Ok(MapArea::new((first.0, second.1)))
     }
 
     fn iter(&self) -> TableMap {
@@ -280,6 +570,8 @@ impl MemoryMapper<TableMap> for TableMemoryMapper {
             i += 1;
         }
         tm.limit = i;
+        // entries are returned sorted by address, which `MapGaps` relies on
+        tm.entries[..i].sort_unstable();
         tm
     }
 
@@ -287,3 +579,318 @@ impl MemoryMapper<TableMap> for TableMemoryMapper {
         (self.start, self.end)
     }
 }
+
+// TREE MEMORY MAPPER
+// Implementation of MemoryMapper that stores entries in a B-tree sorted by their start address.
+// Every operation is O(log n) and the number of entries is only limited by the heap.
+// WARNING: the nodes are heap allocations that live as long as the mapper, so with the bump
+// allocator of `staticalloc.rs` the heap is never reset while it is used
+
+/// `MemoryMapper` implementation that stores claimed entries in a `BTreeMap` (start -> end)
+pub struct TreeMemoryMapper {
+    start: usize,
+    end: usize,
+    tree: Mutex<BTreeMap<usize, usize>>,
+}
+
+/// `Iterator` object for `TreeMemoryMapper` claimed entries, sorted by address.
+///
+/// Every step looks up the next entry in the tree, so the lock is not held in between and the map
+/// can be changed while iterating.
+pub struct TreeMap<'a> {
+    tree: &'a Mutex<BTreeMap<usize, usize>>,
+    next: usize,
+}
+
+impl Iterator for TreeMap<'_> {
+    type Item = MapItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&start, &end) = self.tree.lock().range(self.next..).next()?;
+        self.next = end;
+        Some((start, end))
+    }
+}
+
+impl TreeMemoryMapper {
+    /// Returns the claimed entry that intersects with `region`, if there is any.
+    ///
+    /// Entries never overlap, so only the last entry starting before the end of `region` can
+    /// intersect with it.
+    fn occupant(tree: &BTreeMap<usize, usize>, region: MapItem) -> Option<MapItem> {
+        let (start, end) = region;
+        match tree.range(..end).next_back() {
+            Some((&first, &last)) if last > start => Some((first, last)),
+            _ => None,
+        }
+    }
+}
+
+impl MemoryMapper for TreeMemoryMapper {
+    type Iter<'a> = TreeMap<'a>;
+
+    unsafe fn manage(region: (usize, usize)) -> Self {
+        Self {
+            start: region.0,
+            end: region.1,
+            tree: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn claim(&self, region: (usize, usize)) -> Result<MapArea, MemoryMapperError> {
+        let (start, end) = region;
+        if start >= end {
+            return Err(MemoryMapperError::Empty);
+        }
+        if start < self.start || end > self.end {
+            return Err(MemoryMapperError::OutOfBound((self.start, self.end)));
+        }
+
+        let mut tree = self.tree.lock();
+        if let Some(occupant) = Self::occupant(&tree, region) {
+            return Err(MemoryMapperError::AlreadyOccupiedBy(occupant));
+        }
+        tree.insert(start, end);
+        Ok(MapArea::new(region))
+    }
+
+    fn free(&self, area: MapArea) {
+        let (start, end) = area.region;
+        let mut tree = self.tree.lock();
+        match tree.get(&start) {
+            Some(&last) if last == end => tree.remove(&start),
+            _ => panic!("Poisoned MapArea could not be freed!"),
+        };
+        mem::forget(area);
+    }
+
+    fn split(&self, area: MapArea, at: usize) -> Result<(MapArea, MapArea), MapArea> {
+        let (start, end) = area.region;
+        if at <= start || at >= end {
+            return Err(area);
+        }
+        let mut tree = self.tree.lock();
+        match tree.get_mut(&start) {
+            Some(last) if *last == end => *last = at,
+            _ => panic!("Poisoned MapArea could not be split!"),
+        }
+        tree.insert(at, end);
+        mem::forget(area);
+        Ok((MapArea::new((start, at)), MapArea::new((at, end))))
+    }
+
+    fn merge(&self, a: MapArea, b: MapArea) -> Result<MapArea, (MapArea, MapArea)> {
+        let (first, second) = if a.region.1 == b.region.0 {
+            (a.region, b.region)
+        } else if b.region.1 == a.region.0 {
+            (b.region, a.region)
+        } else {
+            return Err((a, b));
+        };
+        let mut tree = self.tree.lock();
+        if tree.get(&first.0) != Some(&first.1) || tree.get(&second.0) != Some(&second.1) {
+            panic!("Poisoned MapArea could not be merged!");
+        }
+        tree.remove(&second.0);
+        tree.insert(first.0, second.1);
+        mem::forget(a);
+        mem::forget(b);
+        Ok(MapArea::new((first.0, second.1)))
+    }
+
+    fn iter(&self) -> TreeMap<'_> {
+        TreeMap {
+            tree: &self.tree,
+            next: self.start,
+        }
+    }
+
+    fn dimensions(&self) -> MapItem {
+        (self.start, self.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+
+    /// generates the same tests for every `MemoryMapper` implementation
+    macro_rules! mapper_tests {
+        ($module:ident, $mapper:ty) => {
+            mod $module {
+                use super::*;
+
+                fn mapper() -> $mapper {
+                    unsafe { <$mapper>::manage((0, 1000)) }
+                }
+
+                fn claimed(mapper: &$mapper, expected: &[MapItem]) -> bool {
+                    mapper.iter().eq(expected.iter().copied())
+                }
+
+                unit_test! {
+                    fn claim_in_bounds() {
+                        let mapper = mapper();
+                        let _area = mapper.claim((100, 200)).unwrap();
+                        assert!(claimed(&mapper, &[(100, 200)]));
+                    }
+
+                    fn claim_out_of_bound() {
+                        let mapper = mapper();
+                        assert!(matches!(
+                            mapper.claim((900, 1001)),
+                            Err(MemoryMapperError::OutOfBound((0, 1000)))
+                        ));
+                    }
+
+                    fn claim_empty() {
+                        let mapper = mapper();
+                        assert!(matches!(mapper.claim((100, 100)), Err(MemoryMapperError::Empty)));
+                        assert!(matches!(mapper.claim((200, 100)), Err(MemoryMapperError::Empty)));
+                    }
+
+                    fn claim_overlapping() {
+                        let mapper = mapper();
+                        let _area = mapper.claim((100, 200)).unwrap();
+                        // partial from the left & right, same start, same end, containing, contained, equal
+                        for region in [
+                            (50, 150),
+                            (150, 250),
+                            (100, 150),
+                            (150, 200),
+                            (50, 250),
+                            (120, 180),
+                            (100, 200),
+                        ] {
+                            assert!(matches!(
+                                mapper.claim(region),
+                                Err(MemoryMapperError::AlreadyOccupiedBy((100, 200)))
+                            ));
+                        }
+                        assert!(claimed(&mapper, &[(100, 200)]));
+                    }
+
+                    fn claim_adjacent() {
+                        let mapper = mapper();
+                        let _a = mapper.claim((100, 200)).unwrap();
+                        let _b = mapper.claim((200, 300)).unwrap();
+                        let _c = mapper.claim((0, 100)).unwrap();
+                        assert!(claimed(&mapper, &[(0, 100), (100, 200), (200, 300)]));
+                    }
+
+                    fn free_and_reclaim() {
+                        let mapper = mapper();
+                        let area = mapper.claim((100, 200)).unwrap();
+                        mapper.free(area);
+                        assert!(claimed(&mapper, &[]));
+                        let _area = mapper.claim((100, 200)).unwrap();
+                    }
+
+                    fn split_area() {
+                        let mapper = mapper();
+                        let area = mapper.claim((100, 200)).unwrap();
+                        let area = mapper.split(area, 100).unwrap_err();
+                        let area = mapper.split(area, 200).unwrap_err();
+                        let (low, high) = mapper.split(area, 150).unwrap();
+                        assert!(claimed(&mapper, &[(100, 150), (150, 200)]));
+                        mapper.free(low);
+                        assert!(claimed(&mapper, &[(150, 200)]));
+                        mapper.free(high);
+                    }
+
+                    fn merge_areas() {
+                        let mapper = mapper();
+                        let a = mapper.claim((100, 200)).unwrap();
+                        let b = mapper.claim((200, 300)).unwrap();
+                        let c = mapper.claim((400, 500)).unwrap();
+                        // merging works in any order
+                        let ab = mapper.merge(b, a).unwrap();
+                        assert!(claimed(&mapper, &[(100, 300), (400, 500)]));
+                        let (ab, c) = mapper.merge(ab, c).unwrap_err();
+                        mapper.free(ab);
+                        mapper.free(c);
+                        assert!(claimed(&mapper, &[]));
+                    }
+
+                    fn gaps_between_unsorted_claims() {
+                        let mapper = mapper();
+                        let _a = mapper.claim((500, 600)).unwrap();
+                        let _b = mapper.claim((100, 200)).unwrap();
+                        assert!(mapper.gaps().eq([(0, 100), (200, 500), (600, 1000)]));
+                    }
+
+                    fn gaps_empty_map() {
+                        let mapper = mapper();
+                        assert!(mapper.gaps().eq([(0, 1000)]));
+                    }
+
+                    fn gaps_full_map() {
+                        let mapper = mapper();
+                        let _a = mapper.claim((0, 400)).unwrap();
+                        let _b = mapper.claim((400, 1000)).unwrap();
+                        assert_eq!(mapper.gaps().count(), 0);
+                    }
+                }
+            }
+        };
+    }
+
+    mapper_tests!(table, TableMemoryMapper);
+    mapper_tests!(tree, TreeMemoryMapper);
+
+    /// Claims the memory of `buffer` in a new mapper, translating it back to a physical address
+    fn claim_buffer(buffer: &mut [u64]) -> (TreeMemoryMapper, MapArea) {
+        let start =
+            (buffer.as_mut_ptr() as usize).wrapping_sub(PHYSICAL_OFFSET.load(Ordering::Relaxed));
+        let region = (start, start + mem::size_of_val(buffer));
+        let mapper = unsafe { TreeMemoryMapper::manage(region) };
+        let area = mapper.claim(region).unwrap();
+        (mapper, area)
+    }
+
+    unit_test! {
+        fn area_read_write() {
+            let mut buffer = [0u64; 4];
+            let (mapper, mut area) = claim_buffer(&mut buffer);
+            assert_eq!(area.len(), 32);
+            area.write::<u64>(8, 0x1122_3344_5566_7788).unwrap();
+            area.write::<u8>(31, 0xAB).unwrap();
+            assert_eq!(area.read::<u32>(8), Ok(0x5566_7788));
+            assert_eq!(area.read::<u16>(14), Ok(0x1122));
+            assert_eq!(area.read::<[u8; 2]>(30), Ok([0, 0xAB]));
+            mapper.free(area);
+            assert_eq!(buffer[1], 0x1122_3344_5566_7788);
+        }
+
+        fn area_bounds_and_alignment() {
+            let mut buffer = [0u64; 4];
+            let (mapper, mut area) = claim_buffer(&mut buffer);
+            assert_eq!(area.read::<u64>(25), Err(AccessError::OutOfBounds));
+            assert_eq!(area.read::<u8>(32), Err(AccessError::OutOfBounds));
+            assert_eq!(area.read::<u8>(usize::MAX), Err(AccessError::OutOfBounds));
+            assert_eq!(area.write::<u32>(2, 0), Err(AccessError::Misaligned));
+            assert_eq!(area.read::<u64>(24), Ok(0));
+            mapper.free(area);
+        }
+
+        fn area_slices() {
+            let mut buffer = [0u64; 4];
+            let (mapper, mut area) = claim_buffer(&mut buffer);
+            {
+                let mut sub = area.slice_mut(8..24).unwrap();
+                sub.write::<u64>(0, 7).unwrap();
+                assert_eq!(sub.write::<u64>(16, 7), Err(AccessError::OutOfBounds));
+                let mut inner = sub.slice_mut(8..16).unwrap();
+                inner.write::<u64>(0, 9).unwrap();
+                assert!(sub.slice_mut(8..17).is_err());
+            }
+            let sub = area.slice(8..24).unwrap();
+            assert_eq!(sub.region(), (area.region().0 + 8, area.region().0 + 24));
+            assert_eq!(sub.read::<u64>(0), Ok(7));
+            assert_eq!(sub.slice(8..16).unwrap().read::<u64>(0), Ok(9));
+            assert!(area.slice(24..8).is_err());
+            mapper.free(area);
+        }
+    }
+}
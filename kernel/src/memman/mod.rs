@@ -4,6 +4,9 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+pub mod frame;
 pub mod mall;
 pub mod map;
+pub mod reclaim;
 pub mod staticalloc;
+pub mod stats;
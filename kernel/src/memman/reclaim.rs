@@ -0,0 +1,210 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Holds the `MapArea`s of non-usable limine memory map entries, and releases the reclaimable
+//! ones back to the global memory map once the kernel no longer needs them.
+//!
+//! Bootloader-reclaimable memory contains the limine responses, the boot stack and the page
+//! tables set up by limine. The page tables, the stack and the device tree are still in use after
+//! boot, so their frames are kept when the rest is reclaimed.
+
+use super::map::{self, MapArea, MapItem, MemoryMapper};
+use crate::arch::{Current, Paging, PAGE_SIZE};
+use crate::limine::{self, MemmapEntryType};
+use alloc::vec::Vec;
+use spin::{Mutex, MutexGuard};
+
+/// Areas claimed from the limine memory map, by how long they have to be kept
+struct HeldAreas {
+    /// never released, e.g. reserved or kernel memory
+    permanent: Vec<MapArea>,
+    bootloader_reclaimable: Vec<MapArea>,
+    acpi_reclaimable: Vec<MapArea>,
+}
+
+static HELD_AREAS: Mutex<HeldAreas> = Mutex::new(HeldAreas {
+    permanent: Vec::new(),
+    bootloader_reclaimable: Vec::new(),
+    acpi_reclaimable: Vec::new(),
+});
+
+/// Regions released back to the global memory map, `frame` claims frames from them
+static RELEASED: Mutex<Vec<MapItem>> = Mutex::new(Vec::new());
+
+/// Keeps a claimed limine memory map entry of type `typ`, until its type is reclaimed
+pub fn hold(typ: MemmapEntryType, area: MapArea) {
+    let mut held = HELD_AREAS.lock();
+    match typ {
+        MemmapEntryType::BootloaderReclaimable => held.bootloader_reclaimable.push(area),
+        MemmapEntryType::AcpiReclaimable => held.acpi_reclaimable.push(area),
+        _ => held.permanent.push(area),
+    }
+}
+
+/// Releases bootloader-reclaimable memory, except for the active page tables, the stack and the
+/// device tree.
+/// Returns the number of bytes recovered.
+///
+/// # Safety
+/// The limine responses are invalid afterwards, `limine::init()` must have copied them and nothing
+/// may still reference them
+pub unsafe fn reclaim_bootloader() -> usize {
+    let mut keep = Vec::new();
+    Current::for_each_table_frame(map::physical_offset(), &mut |frame| {
+        keep.push((frame, frame + PAGE_SIZE))
+    });
+    // the stack is only in the HHDM if limine honored the stack size request, otherwise it is not
+    // in bootloader-reclaimable memory either
+    let (stack_low, stack_high) = limine::boot_stack();
+    let stack_low = stack_low.checked_sub(map::physical_offset());
+    let stack_high = stack_high.checked_sub(map::physical_offset());
+    if let (Some(stack_low), Some(stack_high)) = (stack_low, stack_high) {
+        keep.push((
+            stack_low - stack_low % PAGE_SIZE,
+            stack_high.next_multiple_of(PAGE_SIZE),
+        ));
+    }
+    if let Ok(device_tree) = limine::device_tree() {
+        let start = device_tree.as_ptr() as usize - map::physical_offset();
+        keep.push((
+            start - start % PAGE_SIZE,
+            (start + device_tree.len()).next_multiple_of(PAGE_SIZE),
+        ));
+    }
+    let keep = coalesce(keep);
+
+    let mut held = HELD_AREAS.lock();
+    let areas = core::mem::take(&mut held.bootloader_reclaimable);
+    let mut released = Vec::new();
+    for area in areas {
+        release(
+            map::global(),
+            area,
+            &keep,
+            &mut held.permanent,
+            &mut released,
+        );
+    }
+    record(released)
+}
+
+/// Releases ACPI-reclaimable memory. Returns the number of bytes recovered.
+///
+/// # Safety
+/// The ACPI tables are invalid afterwards, `acpi::copy_tables()` must have copied the ones still
+/// in use and nothing may still reference the originals
+pub unsafe fn reclaim_acpi() -> usize {
+    let mut held = HELD_AREAS.lock();
+    let areas = core::mem::take(&mut held.acpi_reclaimable);
+    let mut released = Vec::new();
+    for area in areas {
+        release(map::global(), area, &[], &mut held.permanent, &mut released);
+    }
+    record(released)
+}
+
+/// Adds regions to `RELEASED`, which is kept sorted by address, returns their size in bytes
+fn record(released: Vec<MapItem>) -> usize {
+    let size = released.iter().map(|(start, end)| end - start).sum();
+    let mut all = RELEASED.lock();
+    all.extend(released);
+    all.sort_unstable();
+    size
+}
+
+/// Regions of reclaimed memory, they are usable like the usable limine memory map entries
+pub fn released() -> MutexGuard<'static, Vec<MapItem>> {
+    RELEASED.lock()
+}
+
+/// Sorts the ranges and merges the overlapping or adjacent ones
+fn coalesce(mut ranges: Vec<MapItem>) -> Vec<MapItem> {
+    ranges.sort_unstable();
+    let mut merged: Vec<MapItem> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Frees every part of `area` outside of `keep` (sorted & disjoint), the parts inside are pushed
+/// to `kept` and the freed regions to `freed`.
+fn release<M>(
+    mapper: &M,
+    area: MapArea,
+    keep: &[MapItem],
+    kept: &mut Vec<MapArea>,
+    freed: &mut Vec<MapItem>,
+) where
+    M: MemoryMapper,
+{
+    let mut rest = area;
+    for &(start, end) in keep {
+        let (low, high) = rest.region();
+        if end <= low || start >= high {
+            continue;
+        }
+        // free the part before the kept range
+        if start > low {
+            let (before, after) = mapper.split(rest, start).unwrap();
+            freed.push(before.region());
+            mapper.free(before);
+            rest = after;
+        }
+        if end >= high {
+            kept.push(rest);
+            return;
+        }
+        let (inside, after) = mapper.split(rest, end).unwrap();
+        kept.push(inside);
+        rest = after;
+    }
+    freed.push(rest.region());
+    mapper.free(rest);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memman::map::TreeMemoryMapper;
+    use crate::unit_test;
+    use alloc::vec;
+
+    unit_test! {
+        fn coalesce_ranges() {
+            let ranges = vec![(0x3000, 0x4000), (0x0, 0x1000), (0x1000, 0x2000), (0x3800, 0x5000)];
+            assert_eq!(coalesce(ranges), [(0x0, 0x2000), (0x3000, 0x5000)]);
+        }
+
+        fn release_whole_area() {
+            let mapper = unsafe { TreeMemoryMapper::manage((0, 0x10000)) };
+            let area = mapper.claim((0x1000, 0x4000)).unwrap();
+            let (mut kept, mut freed) = (Vec::new(), Vec::new());
+            release(&mapper, area, &[(0x8000, 0x9000)], &mut kept, &mut freed);
+            assert!(kept.is_empty());
+            assert_eq!(freed, [(0x1000, 0x4000)]);
+            assert_eq!(mapper.iter().count(), 0);
+        }
+
+        fn release_around_kept_ranges() {
+            let mapper = unsafe { TreeMemoryMapper::manage((0, 0x10000)) };
+            let area = mapper.claim((0x1000, 0x8000)).unwrap();
+            let keep = [(0x0, 0x2000), (0x3000, 0x4000), (0x6000, 0x7000)];
+            let (mut kept, mut freed) = (Vec::new(), Vec::new());
+            release(&mapper, area, &keep, &mut kept, &mut freed);
+            assert_eq!(freed, [(0x2000, 0x3000), (0x4000, 0x6000), (0x7000, 0x8000)]);
+            let regions: Vec<_> = kept.iter().map(|a| a.region()).collect();
+            assert_eq!(regions, [(0x1000, 0x2000), (0x3000, 0x4000), (0x6000, 0x7000)]);
+            assert_eq!(mapper.iter().collect::<Vec<_>>(), regions);
+            for area in kept {
+                mapper.free(area);
+            }
+        }
+    }
+}
@@ -13,7 +13,7 @@ pub struct StaticAllocator<const SIZE: usize> {
 }
 
 impl<const SIZE: usize> StaticAllocator<SIZE> {
-    const fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             // just a data holder, not actually referenced but still needed
             buffer: [0; SIZE],
@@ -25,6 +25,26 @@ impl<const SIZE: usize> StaticAllocator<SIZE> {
     }
 }
 
+impl<const SIZE: usize> StaticAllocator<SIZE> {
+    /// Size of the buffer in bytes
+    pub const fn capacity(&self) -> usize {
+        SIZE
+    }
+
+    /// Bytes between the start of the buffer and the bump pointer, including freed holes
+    pub fn used(&self) -> usize {
+        match *self.bump_addr.lock() {
+            0 => 0,
+            bump => bump - self.buffer.as_ptr() as usize,
+        }
+    }
+
+    /// Number of live allocations
+    pub fn allocations(&self) -> usize {
+        *self.refcount.lock()
+    }
+}
+
 unsafe impl<const SIZE: usize> GlobalAlloc for StaticAllocator<SIZE> {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
         // expect by rust docs
@@ -47,7 +67,7 @@ unsafe impl<const SIZE: usize> GlobalAlloc for StaticAllocator<SIZE> {
             first_start
         };
         // if the returned chunk is greater than last address of buffer, we ran out of memory :(
-        if aligned_start + layout.size() >= self.buffer.as_ptr() as usize + self.buffer.len() {
+        if aligned_start + layout.size() > self.buffer.as_ptr() as usize + self.buffer.len() {
             log!("NO SPACE :(!");
             return core::ptr::null_mut();
         }
@@ -70,8 +90,78 @@ unsafe impl<const SIZE: usize> GlobalAlloc for StaticAllocator<SIZE> {
         *refc -= 1;
 
         // similar to an arena, we reset the whole thing when there are no objects in it.
-        if *refc <= 0 {
+        if *refc == 0 {
             *bumplock = self.buffer.as_ptr() as usize;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unit_test;
+    use core::alloc::Layout;
+
+    unit_test! {
+        fn alloc_aligned() {
+            let allocator = StaticAllocator::<1024>::new();
+            unsafe {
+                let a = allocator.alloc(Layout::from_size_align(3, 1).unwrap());
+                let b = allocator.alloc(Layout::from_size_align(8, 64).unwrap());
+                assert!(!a.is_null() && !b.is_null());
+                assert_eq!(b as usize % 64, 0);
+                assert!(b as usize >= a as usize + 3);
+            }
+        }
+
+        fn alloc_zero_sized() {
+            let allocator = StaticAllocator::<1024>::new();
+            let ptr = unsafe { allocator.alloc(Layout::from_size_align(0, 1).unwrap()) };
+            assert!(ptr.is_null());
+        }
+
+        fn alloc_out_of_memory() {
+            let allocator = StaticAllocator::<64>::new();
+            let layout = Layout::from_size_align(64, 1).unwrap();
+            unsafe {
+                assert!(!allocator.alloc(layout).is_null());
+                assert!(allocator.alloc(Layout::from_size_align(1, 1).unwrap()).is_null());
+            }
+        }
+
+        fn dealloc_last_reuses_space() {
+            let allocator = StaticAllocator::<1024>::new();
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            unsafe {
+                let keep = allocator.alloc(layout);
+                let a = allocator.alloc(layout);
+                allocator.dealloc(a, layout);
+                assert_eq!(allocator.alloc(layout), a);
+                assert!(!keep.is_null());
+            }
+        }
+
+        fn usage() {
+            let allocator = StaticAllocator::<1024>::new();
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            assert_eq!(allocator.used(), 0);
+            let a = unsafe { allocator.alloc(layout) };
+            assert!(allocator.used() >= 16);
+            assert_eq!(allocator.allocations(), 1);
+            unsafe { allocator.dealloc(a, layout) };
+            assert_eq!((allocator.used(), allocator.allocations()), (0, 0));
+        }
+
+        fn dealloc_all_resets() {
+            let allocator = StaticAllocator::<1024>::new();
+            let layout = Layout::from_size_align(16, 8).unwrap();
+            unsafe {
+                let a = allocator.alloc(layout);
+                let b = allocator.alloc(layout);
+                allocator.dealloc(a, layout);
+                allocator.dealloc(b, layout);
+                assert_eq!(allocator.alloc(layout), a);
+            }
+        }
+    }
+}
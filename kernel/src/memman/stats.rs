@@ -0,0 +1,191 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Physical memory statistics, summarized from the limine memory map and the global memory map
+
+use super::map::{MemoryMapper, GLOBAL_MEMORY_MAPPER};
+use crate::limine::{MemmapEntryType, MemmapItem};
+use crate::log;
+use core::fmt;
+use spin::once::Once;
+
+/// Summary of the limine memory map, collected once at boot
+static BOOT_STATS: Once<MemoryStats> = Once::new();
+
+/// Amount of physical memory by type, all values are in bytes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// every entry in the memory map
+    pub total: usize,
+    pub usable: usize,
+    pub reserved: usize,
+    pub acpi_reclaimable: usize,
+    pub acpi_nvs: usize,
+    pub bad: usize,
+    pub bootloader_reclaimable: usize,
+    pub kernel: usize,
+    pub framebuffer: usize,
+    /// claimed regions in the global memory map
+    pub claimed: usize,
+    /// unclaimed space in the global memory map, including holes in the memory map
+    pub unclaimed: usize,
+}
+
+impl MemoryStats {
+    /// Sums up memory map entries by their type, mapper totals are left at 0
+    pub fn from_regions(regions: impl Iterator<Item = MemmapItem>) -> Self {
+        let mut stats = Self::default();
+        for region in regions {
+            let size = region.range.1 - region.range.0;
+            stats.total += size;
+            let counter = match region.typ {
+                MemmapEntryType::Usable => &mut stats.usable,
+                MemmapEntryType::Reserved => &mut stats.reserved,
+                MemmapEntryType::AcpiReclaimable => &mut stats.acpi_reclaimable,
+                MemmapEntryType::AcpiNvs => &mut stats.acpi_nvs,
+                MemmapEntryType::BadMemory => &mut stats.bad,
+                MemmapEntryType::BootloaderReclaimable => &mut stats.bootloader_reclaimable,
+                MemmapEntryType::KernelAndModules => &mut stats.kernel,
+                MemmapEntryType::MemmapFramebuffer => &mut stats.framebuffer,
+            };
+            *counter += size;
+        }
+        stats
+    }
+
+    /// Fills in the claimed & unclaimed totals from a `MemoryMapper`
+    pub fn with_mapper<M>(mut self, mapper: &M) -> Self
+    where
+        M: MemoryMapper,
+    {
+        self.claimed = mapper.iter().map(|(start, end)| end - start).sum();
+        self.unclaimed = mapper.gaps().map(|(start, end)| end - start).sum();
+        self
+    }
+}
+
+/// Collects the memory map statistics, the memory map is empty if limine did not provide one
+pub fn collect_boot_stats() {
+    BOOT_STATS
+        .call_once(|| MemoryStats::from_regions(crate::limine::memory_map().into_iter().flatten()));
+}
+
+/// Current memory statistics, the memory map part is the one collected at boot.
+///
+/// The global memory map reaches past the RAM into device memory, that part is not counted as
+/// unclaimed.
+pub fn stats() -> MemoryStats {
+    let stats = *BOOT_STATS
+        .get()
+        .expect("Memory statistics were not collected at boot!");
+    let Some(mapper) = GLOBAL_MEMORY_MAPPER.get() else {
+        return stats;
+    };
+    let mut stats = stats.with_mapper(mapper);
+    let ram_end = crate::limine::memory_map()
+        .ok()
+        .and_then(|mut memory_map| memory_map.next_back().map(|region| region.range.1))
+        .unwrap_or(usize::MAX);
+    stats.unclaimed -= mapper
+        .gaps()
+        .map(|(start, end)| end.saturating_sub(start.max(ram_end)))
+        .sum::<usize>();
+    stats
+}
+
+/// Logs every claimed region in the global memory map
+pub fn log_claimed() {
+    let mapper = GLOBAL_MEMORY_MAPPER
+        .get()
+        .expect("GLOBAL_MEMORY_MAPPER not setup!");
+    for (start, end) in mapper.iter() {
+        log!("0x{:X} - 0x{:X} ({})\n", start, end, Size(end - start));
+    }
+}
+
+/// Formats a byte count in the largest unit that keeps it above 1
+pub struct Size(pub usize);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut value = self.0;
+        let mut unit = 0;
+        // only switch units when it does not lose precision below a tenth
+        while value >= 1024 * 10 && unit < UNITS.len() - 1 {
+            value /= 1024;
+            unit += 1;
+        }
+        write!(f, "{} {}", value, UNITS[unit])
+    }
+}
+
+// no padding in the format strings, see the warning on `log!()`
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "total: {}", Size(self.total))?;
+        writeln!(f, "usable: {}", Size(self.usable))?;
+        writeln!(f, "reserved: {}", Size(self.reserved))?;
+        writeln!(f, "ACPI reclaimable: {}", Size(self.acpi_reclaimable))?;
+        writeln!(f, "ACPI NVS: {}", Size(self.acpi_nvs))?;
+        writeln!(f, "bad: {}", Size(self.bad))?;
+        writeln!(
+            f,
+            "bootloader reclaimable: {}",
+            Size(self.bootloader_reclaimable)
+        )?;
+        writeln!(f, "kernel & modules: {}", Size(self.kernel))?;
+        writeln!(f, "framebuffer: {}", Size(self.framebuffer))?;
+        writeln!(f, "claimed: {}", Size(self.claimed))?;
+        writeln!(f, "unclaimed: {}", Size(self.unclaimed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memman::map::TreeMemoryMapper;
+    use crate::unit_test;
+
+    unit_test! {
+        fn sums_by_type() {
+            let regions = [
+                (0x0, 0x1000, MemmapEntryType::Reserved),
+                (0x1000, 0x9000, MemmapEntryType::Usable),
+                (0x10000, 0x20000, MemmapEntryType::Usable),
+                (0x20000, 0x21000, MemmapEntryType::BootloaderReclaimable),
+                (0x21000, 0x25000, MemmapEntryType::KernelAndModules),
+            ];
+            let stats = MemoryStats::from_regions(
+                regions
+                    .into_iter()
+                    .map(|(start, end, typ)| MemmapItem { range: (start, end), typ }),
+            );
+            assert_eq!(stats.total, 0x1000 + 0x8000 + 0x10000 + 0x1000 + 0x4000);
+            assert_eq!(stats.usable, 0x18000);
+            assert_eq!(stats.reserved, 0x1000);
+            assert_eq!(stats.bootloader_reclaimable, 0x1000);
+            assert_eq!(stats.kernel, 0x4000);
+            assert_eq!(stats.framebuffer, 0);
+        }
+
+        fn mapper_totals() {
+            let mapper = unsafe { TreeMemoryMapper::manage((0, 0x10000)) };
+            let area = mapper.claim((0x1000, 0x3000)).unwrap();
+            let stats = MemoryStats::default().with_mapper(&mapper);
+            assert_eq!(stats.claimed, 0x2000);
+            assert_eq!(stats.unclaimed, 0xE000);
+            mapper.free(area);
+        }
+
+        fn size_units() {
+            use alloc::format;
+            assert_eq!(format!("{}", Size(512)), "512 B");
+            assert_eq!(format!("{}", Size(10 * 1024)), "10 KiB");
+            assert_eq!(format!("{}", Size(64 * 1024 * 1024)), "64 MiB");
+        }
+    }
+}
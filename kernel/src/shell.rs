@@ -0,0 +1,638 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Interactive debug shell on the console, started by `kmain` once booting is done.
+//!
+//! Every command is an entry in `COMMANDS`, it gets the words of the line after its name.
+//! Output goes to the console (see `console.rs`), so it does not fill up the kernel log.
+
+use crate::arch::{self, Arch, Cpu, Current};
+use crate::console::{self, Writer};
+use crate::driver::pci::Bar;
+use crate::driver::{self, Resource};
+use crate::driver::{block, partition};
+use crate::fs::{self, FileType, FsError};
+use crate::limine;
+use crate::memman::map::{self, AccessError, MemoryMapper, GLOBAL_MEMORY_MAPPER};
+use crate::memman::staticalloc::GLOBAL_STATIC_ALLOCATOR;
+use crate::memman::stats::{self, Size};
+use arrayvec::ArrayString;
+use core::fmt::Write;
+
+const PROMPT: &str = "rezos> ";
+/// Longest line accepted, further characters are ignored
+const LINE_CAPACITY: usize = 256;
+/// Most bytes shown by `peek`
+const PEEK_MAX: usize = 4096;
+
+/// Same as `log!()`, but writes to the console only
+macro_rules! out {
+    ($($arg:tt)*) => {{
+        // the console never fails
+        let _ = write!(Writer, $($arg)*);
+    }};
+}
+
+/// Result of a command, the error is printed after "error: "
+type CommandResult = Result<(), &'static str>;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&[&str]) -> CommandResult,
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "memmap",
+        usage: "",
+        help: "show the memory map from the bootloader",
+        run: memmap,
+    },
+    Command {
+        name: "claimed",
+        usage: "",
+        help: "show the regions claimed in the global memory map",
+        run: claimed,
+    },
+    Command {
+        name: "mem",
+        usage: "",
+        help: "show physical memory statistics",
+        run: mem,
+    },
+    Command {
+        name: "heap",
+        usage: "",
+        help: "show kernel heap statistics",
+        run: heap,
+    },
+    Command {
+        name: "log",
+        usage: "[BYTES]",
+        help: "show the end of the kernel log, 4096 bytes by default",
+        run: log,
+    },
+    Command {
+        name: "cpu",
+        usage: "",
+        help: "show information about the current CPU",
+        run: cpu,
+    },
+    Command {
+        name: "devices",
+        usage: "",
+        help: "show the device tree with the bound drivers",
+        run: devices,
+    },
+    Command {
+        name: "drivers",
+        usage: "",
+        help: "list the registered drivers & their dependencies",
+        run: drivers,
+    },
+    Command {
+        name: "pci",
+        usage: "",
+        help: "list the PCI functions with their BARs & bound drivers",
+        run: pci,
+    },
+    Command {
+        name: "disks",
+        usage: "",
+        help: "list the block devices",
+        run: disks,
+    },
+    Command {
+        name: "partitions",
+        usage: "",
+        help: "list the partitions of the disks",
+        run: partitions,
+    },
+    Command {
+        name: "sector",
+        usage: "DISK SECTOR",
+        help: "dump one sector of a block device",
+        run: sector,
+    },
+    Command {
+        name: "mount",
+        usage: "[DISK PATH]",
+        help: "mount the filesystem of a disk or partition, list the mounts without arguments",
+        run: mount,
+    },
+    Command {
+        name: "umount",
+        usage: "PATH",
+        help: "unmount the filesystem at a path",
+        run: umount,
+    },
+    Command {
+        name: "ls",
+        usage: "[PATH]",
+        help: "list a directory, / by default",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        usage: "PATH",
+        help: "print a file",
+        run: cat,
+    },
+    Command {
+        name: "write",
+        usage: "PATH [TEXT...]",
+        help: "replace the content of a file with the text & a newline, creating it if needed",
+        run: write,
+    },
+    Command {
+        name: "mkdir",
+        usage: "PATH",
+        help: "create a directory",
+        run: mkdir,
+    },
+    Command {
+        name: "rm",
+        usage: "PATH",
+        help: "remove a file or an empty directory",
+        run: rm,
+    },
+    Command {
+        name: "peek",
+        usage: "ADDRESS [LENGTH]",
+        help: "dump unclaimed physical memory, 64 bytes by default",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "ADDRESS VALUE [1|2|4|8]",
+        help: "write a value of the given size in bytes to unclaimed physical memory",
+        run: poke,
+    },
+    Command {
+        name: "fault",
+        usage: "panic|unmapped|breakpoint|undefined",
+        help: "trigger a kernel panic or a CPU exception",
+        run: fault,
+    },
+];
+
+/// Parses a decimal or `0x` prefixed hexadecimal number
+fn parse_number(text: &str) -> Result<usize, &'static str> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| "expected a number")
+}
+
+fn help(_: &[&str]) -> CommandResult {
+    for command in COMMANDS {
+        out!("{} {}\n    {}\n", command.name, command.usage, command.help);
+    }
+    Ok(())
+}
+
+fn memmap(_: &[&str]) -> CommandResult {
+    let memory_map = limine::memory_map().map_err(|_| "no memory map")?;
+    for region in memory_map {
+        let typ: &str = region.typ.into();
+        let (start, end) = region.range;
+        out!(
+            "0x{:X} - 0x{:X} {} ({})\n",
+            start,
+            end,
+            typ,
+            Size(end - start)
+        );
+    }
+    Ok(())
+}
+
+fn claimed(_: &[&str]) -> CommandResult {
+    let mapper = GLOBAL_MEMORY_MAPPER
+        .get()
+        .ok_or("the global memory map is not set up")?;
+    let (start, end) = mapper.dimensions();
+    out!("managed: 0x{:X} - 0x{:X}\n", start, end);
+    for (start, end) in mapper.iter() {
+        out!("0x{:X} - 0x{:X} ({})\n", start, end, Size(end - start));
+    }
+    Ok(())
+}
+
+fn mem(_: &[&str]) -> CommandResult {
+    out!("{}", stats::stats());
+    Ok(())
+}
+
+fn heap(_: &[&str]) -> CommandResult {
+    let allocator = &GLOBAL_STATIC_ALLOCATOR;
+    out!("capacity: {}\n", Size(allocator.capacity()));
+    out!("used: {}\n", Size(allocator.used()));
+    out!("live allocations: {}\n", allocator.allocations());
+    Ok(())
+}
+
+fn log(args: &[&str]) -> CommandResult {
+    let len = match args.first() {
+        Some(len) => parse_number(len)?,
+        None => 4096,
+    };
+    // the log is locked while writing, so nothing may be logged in between
+    crate::log::with_tail(len, console::write);
+    console::write("\n");
+    Ok(())
+}
+
+fn cpu(_: &[&str]) -> CommandResult {
+    out!("architecture: {:?}\n", <Current as Arch>::TYPE);
+    out!("id: 0x{:X}\n", Current::id());
+    out!("interrupts enabled: {}\n", Current::interrupts_enabled());
+    out!("page size: {}\n", Size(arch::PAGE_SIZE));
+    if let Ok(mode) = limine::paging_mode() {
+        out!("paging mode: {:?}\n", mode);
+    }
+    if let Ok(firmware) = limine::firmware_type() {
+        out!("firmware: {:?}\n", firmware);
+    }
+    Ok(())
+}
+
+fn pci(_: &[&str]) -> CommandResult {
+    let count = driver::with_devices(|devices| {
+        let functions = devices.iter().filter_map(|device| match &device.resource {
+            Resource::Pci(function) => Some((function, device.driver)),
+            _ => None,
+        });
+        let mut count = 0;
+        for (f, driver) in functions {
+            count += 1;
+            out!(
+                "{} 0x{:X}:0x{:X} class 0x{:X}.0x{:X}.0x{:X}",
+                f.address,
+                f.vendor,
+                f.device,
+                f.class,
+                f.subclass,
+                f.prog_if
+            );
+            match driver {
+                Some(driver) => out!(" driver {}\n", driver),
+                None => out!("\n"),
+            }
+            for (index, bar) in f.bars.iter().enumerate() {
+                match bar {
+                    Some(Bar::Io { port, size }) => {
+                        out!("    BAR{}: I/O 0x{:X} ({} B)\n", index, port, size)
+                    }
+                    Some(bar) => out!(
+                        "    BAR{}: memory 0x{:X} ({})\n",
+                        index,
+                        bar.address(),
+                        Size(bar.size() as usize)
+                    ),
+                    None => {}
+                }
+            }
+            if let Some(msi) = f.msi {
+                out!("    MSI: {} vectors\n", msi.vectors);
+            }
+            if let Some(msix) = f.msix {
+                out!("    MSI-X: {} vectors\n", msix.table_size);
+            }
+        }
+        count
+    });
+    out!("{} functions\n", count);
+    Ok(())
+}
+
+fn devices(_: &[&str]) -> CommandResult {
+    driver::with_devices(|devices| print_children(devices, None, 0));
+    Ok(())
+}
+
+/// Prints the devices below the device at index `parent` as a tree
+fn print_children(devices: &[driver::Device], parent: Option<usize>, depth: usize) {
+    for (index, device) in devices.iter().enumerate() {
+        if device.parent.map(|parent| parent.index()) != parent {
+            continue;
+        }
+        for _ in 0..depth {
+            out!("  ");
+        }
+        out!(
+            "{} [{}] {}\n",
+            device.name,
+            device.resource,
+            device.driver.unwrap_or("-")
+        );
+        print_children(devices, Some(index), depth + 1);
+    }
+}
+
+fn drivers(_: &[&str]) -> CommandResult {
+    driver::with_drivers(|drivers| {
+        for driver in drivers {
+            out!("{}", driver.name());
+            if !driver.dependencies().is_empty() {
+                out!(" (after");
+                for dependency in driver.dependencies() {
+                    out!(" {}", dependency);
+                }
+                out!(")");
+            }
+            out!("\n");
+        }
+    });
+    Ok(())
+}
+
+fn disks(_: &[&str]) -> CommandResult {
+    block::with_disks(|disks| {
+        for disk in disks {
+            let device = &disk.device;
+            let read_only = match device.read_only() {
+                true => ", read-only",
+                false => "",
+            };
+            out!(
+                "{}: {} sectors of {} B ({}){}\n",
+                disk.name,
+                device.sectors(),
+                device.sector_size(),
+                Size(device.sectors() as usize * device.sector_size()),
+                read_only
+            );
+        }
+    });
+    Ok(())
+}
+
+fn partitions(_: &[&str]) -> CommandResult {
+    partition::with_partitions(|partitions| {
+        for entry in partitions {
+            let partition = &entry.partition;
+            out!(
+                "{}: sectors {} - {} of {}, {}\n",
+                entry.name,
+                partition.first,
+                partition.first + partition.sectors - 1,
+                entry.disk,
+                partition.kind
+            );
+        }
+    });
+    Ok(())
+}
+
+fn sector(args: &[&str]) -> CommandResult {
+    let device = block::get(args.first().ok_or("missing disk")?).ok_or("no such disk")?;
+    let sector = parse_number(args.get(1).ok_or("missing sector")?)?;
+    let mut buffer = alloc::vec![0; device.sector_size()];
+    block::block_on(device.read(sector as u64, &mut buffer)).map_err(|_| "read failed")?;
+    dump(sector * device.sector_size(), &buffer);
+    Ok(())
+}
+
+/// Prints the filesystem error, the command then fails with a generic message
+fn fs_error(e: FsError) -> &'static str {
+    out!("{}\n", e);
+    "filesystem operation failed"
+}
+
+fn mount(args: &[&str]) -> CommandResult {
+    if let [device, path] = args {
+        let kind = fs::mount_device(path, device).map_err(fs_error)?;
+        out!("mounted {} ({}) at {}\n", device, kind, path);
+        return Ok(());
+    }
+    if !args.is_empty() {
+        return Err("expected a disk & a path");
+    }
+    fs::with_mounts(|mounts| {
+        for mount in mounts {
+            out!("{} on {} ({})\n", mount.device, mount.path, mount.kind);
+        }
+    });
+    Ok(())
+}
+
+fn umount(args: &[&str]) -> CommandResult {
+    fs::unmount(args.first().ok_or("missing path")?).map_err(fs_error)
+}
+
+fn ls(args: &[&str]) -> CommandResult {
+    let entries = fs::read_dir(args.first().unwrap_or(&"/")).map_err(fs_error)?;
+    for entry in entries {
+        match entry.kind {
+            FileType::Directory => out!("{}/\n", entry.name),
+            FileType::File => out!("{}\n", entry.name),
+        }
+    }
+    Ok(())
+}
+
+fn cat(args: &[&str]) -> CommandResult {
+    let content = fs::read_file(args.first().ok_or("missing path")?).map_err(fs_error)?;
+    // show invalid UTF-8 as replacement characters instead of failing
+    for chunk in content.utf8_chunks() {
+        out!("{}", chunk.valid());
+        if !chunk.invalid().is_empty() {
+            out!("\u{FFFD}");
+        }
+    }
+    Ok(())
+}
+
+fn write(args: &[&str]) -> CommandResult {
+    let (path, words) = args.split_first().ok_or("missing path")?;
+    let mut text = alloc::string::String::new();
+    for word in words {
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+    text.push('\n');
+    fs::write_file(path, text.as_bytes()).map_err(fs_error)
+}
+
+fn mkdir(args: &[&str]) -> CommandResult {
+    fs::create_dir(args.first().ok_or("missing path")?).map_err(fs_error)
+}
+
+fn rm(args: &[&str]) -> CommandResult {
+    fs::remove(args.first().ok_or("missing path")?).map_err(fs_error)
+}
+
+/// Prints `bytes` in lines of 16, the lines are labeled from `start` on
+fn dump(start: usize, bytes: &[u8]) {
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        out!("0x{:X}:", start + line * 16);
+        for byte in chunk {
+            // no padding in the format strings, see the warning on `log!()`
+            let pad = if *byte < 0x10 { " 0" } else { " " };
+            out!("{}{:X}", pad, byte);
+        }
+        out!("\n");
+    }
+}
+
+/// Claims `len` bytes at `address` for the duration of `f`. Accesses to unmapped pages fail with
+/// `AccessError::Unmapped`.
+fn with_area<R>(
+    address: usize,
+    len: usize,
+    f: impl FnOnce(&mut map::MapArea) -> R,
+) -> Result<R, &'static str> {
+    let end = address.checked_add(len).ok_or("region is out of range")?;
+    let mut area = map::claim_global((address, end))
+        .map_err(|_| "region is claimed by someone else or out of range")?;
+    let result = f(&mut area);
+    map::free_global(area);
+    Ok(result)
+}
+
+fn peek(args: &[&str]) -> CommandResult {
+    let address = parse_number(args.first().ok_or("missing address")?)?;
+    let len = match args.get(1) {
+        Some(len) => parse_number(len)?,
+        None => 64,
+    };
+    if len == 0 || len > PEEK_MAX {
+        return Err("length must be between 1 and 4096");
+    }
+    let mut bytes = alloc::vec![0; len];
+    with_area(address, len, |area| {
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            *byte = area.read::<u8>(offset)?;
+        }
+        Ok(())
+    })?
+    .map_err(|_: AccessError| "region is not mapped")?;
+    dump(address, &bytes);
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> CommandResult {
+    let address = parse_number(args.first().ok_or("missing address")?)?;
+    let value = parse_number(args.get(1).ok_or("missing value")?)?;
+    let size = match args.get(2) {
+        Some(size) => parse_number(size)?,
+        None => 1,
+    };
+    if !matches!(size, 1 | 2 | 4 | 8) {
+        return Err("the size must be 1, 2, 4 or 8 bytes");
+    }
+    if size < 8 && value >> (size * 8) != 0 {
+        return Err("the value does not fit into the size");
+    }
+    with_area(address, size, |area| match size {
+        1 => area.write(0, value as u8),
+        2 => area.write(0, value as u16),
+        4 => area.write(0, value as u32),
+        _ => area.write(0, value as u64),
+    })?
+    .map_err(|e| match e {
+        AccessError::Unmapped => "region is not mapped",
+        _ => "write failed",
+    })
+}
+
+fn fault(args: &[&str]) -> CommandResult {
+    match args.first().copied() {
+        Some("panic") => panic!("panic requested from the shell"),
+        // the top of the lower half is never mapped
+        Some("unmapped") => {
+            let value = unsafe { core::ptr::read_volatile(0x7FFF_FFFF_F000 as *const u64) };
+            out!("read 0x{:X}, the address is mapped\n", value);
+        }
+        #[cfg(target_arch = "x86_64")]
+        Some("breakpoint") => unsafe { core::arch::asm!("int3") },
+        #[cfg(target_arch = "aarch64")]
+        Some("breakpoint") => unsafe { core::arch::asm!("brk #0") },
+        #[cfg(target_arch = "x86_64")]
+        Some("undefined") => unsafe { core::arch::asm!("ud2") },
+        #[cfg(target_arch = "aarch64")]
+        Some("undefined") => unsafe { core::arch::asm!("udf #0") },
+        _ => return Err("expected panic, unmapped, breakpoint or undefined"),
+    }
+    Ok(())
+}
+
+/// Runs one line of input
+fn execute(line: &str) {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return;
+    };
+    let mut args = [""; 8];
+    let mut count = 0;
+    for word in words {
+        if count == args.len() {
+            return out!("error: too many arguments\n");
+        }
+        args[count] = word;
+        count += 1;
+    }
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => {
+            if let Err(e) = (command.run)(&args[..count]) {
+                out!("error: {}\n", e);
+            }
+        }
+        None => out!("unknown command {}, try help\n", name),
+    }
+}
+
+/// Waits for the next typed character. The serial port is polled, so this spins instead of
+/// halting until an interrupt.
+fn read_char() -> char {
+    loop {
+        if let Some(c) = console::read_char() {
+            return c;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Reads & runs commands forever
+pub fn run() -> ! {
+    out!("\nRezOS debug shell, type help for the commands\n");
+    let mut line = ArrayString::<LINE_CAPACITY>::new();
+    loop {
+        out!("{}", PROMPT);
+        line.clear();
+        loop {
+            match read_char() {
+                '\n' => break,
+                '\x08' => {
+                    if line.pop().is_some() {
+                        // step back, overwrite with a space & step back again
+                        console::write("\x08 \x08");
+                    }
+                }
+                c if c.is_control() => {}
+                c => {
+                    if line.try_push(c).is_ok() {
+                        out!("{}", c);
+                    }
+                }
+            }
+        }
+        out!("\n");
+        execute(&line);
+    }
+}
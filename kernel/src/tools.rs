@@ -19,13 +19,13 @@
 /// assert_eq!(Sum::Bar.into(), "Bar");
 /// ```
 #[macro_export]
-#[macro_use]
 macro_rules! enum_names {
     (
         pub enum $name:ident {
             $($variant:ident),*,
         }
     ) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
         pub enum $name {
             $($variant),*
         }
@@ -40,71 +40,151 @@ macro_rules! enum_names {
     };
 }
 
-// use in an impl block, will create functions to interact with ranges of the raw binary represenation of the type
-// Example usage:
-/*
-    impl u64 {
-        bitfield!(set_half, u32, 31, 0); // creates a set_half() function that takes an u32 and writes to bits 0..31 of the u64
-        bitfield!(set_flag, bool, 60); // creates a set_flag() function that takes a bool and writes to bit 60 of the u64
-    }
-*/
-/// Generates functions to mutate bitranges in the binary of a type.
-/// Use this inside an `impl` block.
+/// Defines a register-like type wrapping an unsigned integer (u8 - u128), with const getters &
+/// setters for named ranges of its bits and a `Debug` impl listing every field.
+///
+/// Each field is declared as `TYPE, GETTER, SETTER: HIGH, LOW;` for a range of bits or
+/// `TYPE, GETTER, SETTER: BIT;` for a single one, where TYPE is `bool` or an unsigned integer.
+/// `_` as the setter makes the field read-only. The ranges are checked at compile time, both
+/// against the backing integer & against the field type. Setters check that the payload fits into
+/// the range in debug builds, release builds cut off the bits that do not fit.
 ///
 /// # examples
 /// ```
-/// struct Token(u64);
-/// impl Token {
-///     bitfield!(set_foo, u16, 14, 0); // generates a set_foo function that takes an u16 and writes it into bits 0-14
-///     bitfield!(set_flag, get_flag, bool, 17); // generates a set_flag function that takes a boolean and writes to bit 17
-///     bitfield!(set_bar, get_bar, u32, 52, 20); // generates a set_flag and get_flag function that operate on bits 20-52
-///
-///     fn flip_flag(&mut self) {
-///         self.set_flag( !self.get_flag() )
+/// bitfield! {
+///     #[derive(Clone, Copy)]
+///     pub struct Token(u64) {
+///         pub u16, foo, set_foo: 14, 0; // reads & writes bits 0-14
+///         pub bool, flag, set_flag: 17; // reads & writes bit 17
+///         u32, bar, _: 52, 20; // only reads bits 20-52
 ///     }
-///     ...
 /// }
+///
+/// let mut token = Token::from_raw(0);
+/// token.set_flag(!token.flag());
+/// assert_eq!(token.raw(), 1 << 17);
 /// ```
 #[macro_export]
-#[macro_use]
 macro_rules! bitfield {
-    // bitrange, only setter
-    ($name:ident, $typ:ty, $h:literal, $l:literal) => {
-        const fn $name(&mut self, payload: $typ) {
-            *self = Self(bin_insert(self.0, payload as u64, $h, $l));
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident($raw:ident) {
+            $(
+                $(#[$field_meta:meta])*
+                $field_vis:vis $typ:ident, $get:ident, $set:tt: $h:literal $(, $l:literal)?;
+            )*
         }
-    };
+    ) => {
+        $(#[$meta])*
+        #[repr(transparent)]
+        $vis struct $name($raw);
+
+        #[allow(dead_code)]
+        impl $name {
+            /// Wraps a raw value, without any checks
+            pub const fn from_raw(raw: $raw) -> Self {
+                Self(raw)
+            }
 
-    // bitrange, setter & getter
-    ($set_name:ident, $get_name:ident, $typ:ty, $h:literal, $l:literal) => {
-        const fn $set_name(&mut self, payload: $typ) {
-            *self = Self(bin_insert(self.0, payload, $h, $l));
+            /// The raw value
+            pub const fn raw(&self) -> $raw {
+                self.0
+            }
+
+            $(
+                $crate::bitfield!(
+                    @field $raw, $(#[$field_meta])* $field_vis $typ, $get, $set,
+                    $h, $crate::bitfield!(@low $h $(, $l)?)
+                );
+            )*
         }
 
-        const fn $get_name(&mut self) -> $typ {
-            bin_extract(self.0, $h, $l).into()
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.debug_struct(stringify!($name))
+                    $(.field(stringify!($get), &self.$get()))*
+                    .finish()
+            }
         }
+
+        // the ranges must fit into both the backing integer & the field type
+        const _: () = {
+            $(
+                let low = $crate::bitfield!(@low $h $(, $l)?);
+                assert!(
+                    low <= $h && $h < <$raw>::BITS,
+                    concat!("bitfield ", stringify!($name), "::", stringify!($get), ": invalid range"),
+                );
+                assert!(
+                    $h - low < $crate::bitfield!(@bits $typ),
+                    concat!("bitfield ", stringify!($name), "::", stringify!($get), ": range is wider than ", stringify!($typ)),
+                );
+            )*
+        };
     };
 
-    // single-bit, only setter
-    ($name:ident, $typ:ty, $b:literal) => {
-        const fn $name(&mut self, payload: $typ) {
-            *self = Self(bin_insert(self.0, payload as u64, $b, $b));
-        }
+    (@low $h:literal) => { $h };
+    (@low $h:literal, $l:literal) => { $l };
+
+    (@bits bool) => { 1 };
+    (@bits $typ:ident) => { <$typ>::BITS };
+
+    (@mask $raw:ident, $h:expr, $l:expr) => {
+        <$raw>::MAX >> (<$raw>::BITS - 1 - ($h - $l))
     };
 
-    // single bit, setter & getter
-    ($set_name:ident, $get_name:ident, $typ:ty, $b:literal) => {
-        const fn $set_name(&mut self, payload: $typ) {
-            *self = Self(bin_insert(self.0, payload, $b, $b));
+    (@cast bool, $value:expr) => { $value != 0 };
+    (@cast $typ:ident, $value:expr) => { $value as $typ };
+
+    (@field $raw:ident, $(#[$meta:meta])* $vis:vis $typ:ident, $get:ident, _, $h:expr, $l:expr) => {
+        $(#[$meta])*
+        $vis const fn $get(&self) -> $typ {
+            $crate::bitfield!(@cast $typ, (self.0 >> $l) & $crate::bitfield!(@mask $raw, $h, $l))
         }
+    };
+
+    (@field $raw:ident, $(#[$meta:meta])* $vis:vis $typ:ident, $get:ident, $set:ident, $h:expr, $l:expr) => {
+        $crate::bitfield!(@field $raw, $(#[$meta])* $vis $typ, $get, _, $h, $l);
 
-        const fn $get_name(&self) -> $typ {
-            bin_extract(self.0, $b, $b).into()
+        $(#[$meta])*
+        $vis const fn $set(&mut self, payload: $typ) {
+            let mask = $crate::bitfield!(@mask $raw, $h, $l);
+            debug_assert!(
+                payload as u128 <= mask as u128,
+                concat!("payload of ", stringify!($set), " does not fit into bits ", stringify!($h), "-", stringify!($l)),
+            );
+            self.0 = (self.0 & !(mask << $l)) | ((payload as $raw & mask) << $l);
         }
     };
 }
 
+/// Declares tests that run inside of the kernel (see `ktest.rs`) as well as in the hosted build
+/// (see `hosted/`), where they use the standard test harness.
+///
+/// # examples
+/// ```
+/// unit_test! {
+///     fn addition() {
+///         assert_eq!(1 + 1, 2);
+///     }
+///
+///     fn subtraction() {
+///         assert_eq!(2 - 1, 1);
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! unit_test {
+    ($($(#[$meta:meta])* fn $name:ident() $body:block)*) => {
+        $(
+            #[cfg_attr(target_os = "none", test_case)]
+            #[cfg_attr(not(target_os = "none"), test)]
+            $(#[$meta])*
+            fn $name() $body
+        )*
+    };
+}
+
 /// reads bits from a binary value
 ///
 /// # examples
@@ -114,21 +194,32 @@ macro_rules! bitfield {
 
 pub const fn bin_extract(target: u64, higher: usize, lower: usize) -> u64 {
     // (x >> lower) -> cuts out the stuff we dont care about to the right
-    // ( (1 << (higher + 1 - lower)) - 1) -> creates a binary number full of ones with length (higher+1-lower), that then get used as a mask
+    // the mask is a binary number full of ones with length (higher+1-lower), that then get used
     // for bitwise and ('&') to remove all the stuff we dont care about to the left
-    (target >> lower) & ((1 << (higher + 1 - lower)) - 1)
+    (target >> lower) & range_mask(higher + 1 - lower)
+}
+
+/// creates a binary number full of ones with length `width`, works for the full 64 bits too
+const fn range_mask(width: usize) -> u64 {
+    if width >= 64 {
+        u64::MAX
+    } else {
+        (1 << width) - 1
+    }
 }
 
 /// inserts a binary sequence into another one
 ///
 /// # examples
 /// ```
-/// assert_eq!(bin_insert(0b0000000000000000, 0b00001111, 6, 3), 0b0000000000111100)
-/// assert_eq!(bin_insert(0b1110001010001100, 0b101, 16, 14), 0b1010001010001100)
+/// assert_eq!(bin_insert(0b0000000000000000, 0b00001111, 6, 3), 0b0000000001111000)
+/// assert_eq!(bin_insert(0b1110001010001100, 0b010, 15, 13), 0b0100001010001100)
 /// ```
+///
+/// Bits of the payload that do not fit into the range are cut off.
 pub const fn bin_insert(target: u64, payload: u64, higher: usize, lower: usize) -> u64 {
     // this mask enables all bits that have to be with a BitOr
-    let enable = (payload << lower) as u64;
+    let enable = (payload & range_mask(higher + 1 - lower)) << lower;
     // this mask disables all bits that must not be with a BitAnd
     // same as the enable mask, but full of ones on the left & the right to preserve the rest of
     // the data, except for our payload that can have some zeroes for disabling
@@ -142,3 +233,132 @@ pub const fn bin_insert(target: u64, payload: u64, higher: usize, lower: usize)
     // apply the masks
     (target | enable) & disable
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unit_test! {
+        fn extract_range() {
+            assert_eq!(bin_extract(0b11110011, 4, 1), 0b1001);
+        }
+
+        fn extract_single_bit() {
+            assert_eq!(bin_extract(0b100, 2, 2), 1);
+            assert_eq!(bin_extract(0b100, 1, 1), 0);
+        }
+
+        fn extract_whole() {
+            assert_eq!(bin_extract(u64::MAX, 63, 0), u64::MAX);
+        }
+
+        fn extract_top_bit() {
+            assert_eq!(bin_extract(1 << 63, 63, 63), 1);
+        }
+
+        fn insert_range() {
+            assert_eq!(bin_insert(0, 0b1111, 6, 3), 0b1111000);
+        }
+
+        fn insert_overwrites_range() {
+            assert_eq!(bin_insert(0b1111_1111, 0b01, 4, 3), 0b1110_1111);
+        }
+
+        fn insert_top_bit() {
+            assert_eq!(bin_insert(0, 1, 63, 63), 1 << 63);
+        }
+
+        fn insert_whole() {
+            assert_eq!(bin_insert(0x1234, u64::MAX, 63, 0), u64::MAX);
+            assert_eq!(bin_insert(u64::MAX, 0, 63, 0), 0);
+        }
+
+        fn insert_cuts_oversized_payload() {
+            assert_eq!(bin_insert(0, 0b111, 1, 0), 0b11);
+            assert_eq!(bin_insert(0b1000, 0b111, 2, 1), 0b1110);
+        }
+    }
+
+    bitfield! {
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        struct Register(u64) {
+            u16, low, set_low: 15, 0;
+            u8, middle, set_middle: 39, 32;
+            bool, flag, set_flag: 63;
+            u8, nibble, _: 3, 0;
+        }
+    }
+
+    bitfield! {
+        struct Wide(u128) {
+            u64, high, set_high: 127, 64;
+            bool, top, set_top: 127;
+        }
+    }
+
+    bitfield! {
+        struct Byte(u8) {
+            u8, upper, set_upper: 7, 4;
+            bool, first, set_first: 0;
+        }
+    }
+
+    unit_test! {
+        fn bitfield_setters() {
+            let mut reg = Register::from_raw(0);
+            reg.set_low(0xBEEF);
+            reg.set_middle(0xAB);
+            reg.set_flag(true);
+            assert_eq!(reg.raw(), 0x8000_00AB_0000_BEEF);
+            reg.set_flag(false);
+            reg.set_low(0);
+            assert_eq!(reg.raw(), 0x0000_00AB_0000_0000);
+        }
+
+        fn bitfield_getters() {
+            let reg = Register::from_raw(0x8000_00AB_0000_BEEF);
+            assert_eq!(reg.low(), 0xBEEF);
+            assert_eq!(reg.middle(), 0xAB);
+            assert!(reg.flag());
+            assert_eq!(reg.nibble(), 0xF);
+        }
+
+        fn bitfield_backing_types() {
+            let mut wide = Wide::from_raw(0);
+            wide.set_high(u64::MAX);
+            assert_eq!(wide.raw(), (u64::MAX as u128) << 64);
+            wide.set_top(false);
+            assert_eq!(wide.high(), u64::MAX >> 1);
+            let mut byte = Byte::from_raw(0);
+            byte.set_upper(0xA);
+            byte.set_first(true);
+            assert_eq!(byte.raw(), 0xA1);
+        }
+
+        fn bitfield_const() {
+            const REG: Register = {
+                let mut reg = Register::from_raw(0);
+                reg.set_middle(1);
+                reg
+            };
+            assert_eq!(REG.middle(), 1);
+        }
+
+        fn bitfield_debug() {
+            let reg = Register::from_raw(0x8000_0000_0000_0001);
+            assert_eq!(
+                alloc::format!("{:?}", reg),
+                "Register { low: 1, middle: 0, flag: true, nibble: 1 }"
+            );
+        }
+    }
+
+    // a panic would stop the kernel test run, so this only runs in the hosted build
+    #[cfg(all(not(target_os = "none"), debug_assertions))]
+    #[test]
+    #[should_panic]
+    fn bitfield_overflow() {
+        let mut byte = Byte::from_raw(0);
+        byte.set_upper(0x10);
+    }
+}
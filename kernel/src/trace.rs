@@ -0,0 +1,169 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Stack unwinding using frame pointers and symbolization with the kernel symbol table.
+//!
+//! The kernel is compiled with `-C force-frame-pointers=yes`, so every frame starts with the
+//! callers frame pointer followed by the return address. This layout is the same for x86_64
+//! (rbp) and AArch64 (x29). `kentry` zeroes the frame pointer, which terminates the chain.
+//!
+//! The symbol table is generated by `scripts/ksyms.py` from the linked kernel binary and linked
+//! into the `.ksyms` section in a second link pass.
+
+use crate::arch::{Cpu, Current, Paging};
+use crate::config::BACKTRACE_MAX_DEPTH;
+use crate::log;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{fmt, slice, str};
+
+/// One entry of the kernel symbol table, the layout must match `scripts/ksyms.py`
+#[repr(C)]
+struct KsymEntry {
+    addr: u64,
+    name_offset: u32,
+    name_len: u32,
+}
+
+// Linked from the generated ksyms object
+extern "C" {
+    static KSYMS_COUNT: u64;
+    static KSYMS_ENTRIES: [KsymEntry; 0];
+    static KSYMS_STRINGS: [u8; 0];
+}
+
+/// Returns the symbol table, sorted by address
+fn ksyms() -> &'static [KsymEntry] {
+    unsafe { slice::from_raw_parts(KSYMS_ENTRIES.as_ptr(), KSYMS_COUNT as usize) }
+}
+
+/// Resolves an address to the name of the function containing it and the offset into it
+pub fn symbolize(addr: usize) -> Option<(&'static str, usize)> {
+    let table = ksyms();
+    // index of the first symbol located after addr
+    let i = table.partition_point(|e| e.addr as usize <= addr);
+    if i == 0 {
+        return None;
+    }
+    let entry = &table[i - 1];
+    let name = unsafe {
+        let ptr = KSYMS_STRINGS.as_ptr().add(entry.name_offset as usize);
+        str::from_utf8(slice::from_raw_parts(ptr, entry.name_len as usize)).ok()?
+    };
+    Some((name, addr - entry.addr as usize))
+}
+
+/// A single frame on the call stack
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub frame_pointer: usize,
+    pub return_address: usize,
+}
+
+// no padding in the format strings, see the warning on `log!()`
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match symbolize(self.return_address) {
+            Some((name, offset)) => {
+                write!(f, "0x{:X} - {}+0x{:X}", self.return_address, name, offset)
+            }
+            None => write!(f, "0x{:X} - ???", self.return_address),
+        }
+    }
+}
+
+/// Iterates the call chain by following saved frame pointers
+pub struct Backtrace {
+    fp: usize,
+    depth: usize,
+}
+
+impl Backtrace {
+    /// Unwinds the stack of the caller
+    #[inline(always)]
+    pub fn new() -> Self {
+        Self::from_frame_pointer(Current::read_frame_pointer())
+    }
+
+    /// Unwinds from a saved frame pointer, e.g. the one pushed by an exception handler
+    pub fn from_frame_pointer(fp: usize) -> Self {
+        Self { fp, depth: 0 }
+    }
+}
+
+impl Default for Backtrace {
+    /// Same as `new()`
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // null or misaligned frame pointers mean the chain ended or is corrupted
+        if self.fp == 0 || self.fp % 8 != 0 || self.depth >= BACKTRACE_MAX_DEPTH {
+            return None;
+        }
+        // a corrupted frame pointer must not fault, e.g. inside of the panic handler
+        let end = self.fp.checked_add(2 * core::mem::size_of::<usize>() - 1)?;
+        if Current::translate(self.fp).is_none() || Current::translate(end).is_none() {
+            return None;
+        }
+        let frame = unsafe {
+            let record = self.fp as *const usize;
+            Frame {
+                frame_pointer: *record,
+                return_address: *record.add(1),
+            }
+        };
+        if frame.return_address == 0 {
+            return None;
+        }
+        // the stack grows down, so callers frames must be above ours, otherwise we would loop
+        self.fp = if frame.frame_pointer > self.fp {
+            frame.frame_pointer
+        } else {
+            0
+        };
+        self.depth += 1;
+        Some(frame)
+    }
+}
+
+/// Logs a symbolized backtrace of the caller
+#[inline(always)]
+pub fn print_backtrace() {
+    print_frames(Backtrace::new());
+}
+
+/// Program counter & frame pointer saved on entry of the exception that is about to panic
+static EXCEPTION_PC: AtomicUsize = AtomicUsize::new(0);
+static EXCEPTION_FP: AtomicUsize = AtomicUsize::new(0);
+
+/// Stashes the state saved on exception entry right before an exception handler panics, so the
+/// panic handler unwinds from the faulting code instead of from the exception handler. Nothing is
+/// logged here, the log lock may be held by the faulting code.
+pub fn set_exception(pc: usize, fp: usize) {
+    EXCEPTION_FP.store(fp, Ordering::SeqCst);
+    EXCEPTION_PC.store(pc, Ordering::SeqCst);
+}
+
+/// Takes the program counter & frame pointer stashed by `set_exception()`, if there are any
+pub fn take_exception() -> Option<(usize, usize)> {
+    match EXCEPTION_PC.swap(0, Ordering::SeqCst) {
+        0 => None,
+        pc => Some((pc, EXCEPTION_FP.load(Ordering::SeqCst))),
+    }
+}
+
+fn print_frames(trace: Backtrace) {
+    log!("Backtrace:\n");
+    for (i, frame) in trace.enumerate() {
+        log!("{}: {}\n", i, frame);
+    }
+}
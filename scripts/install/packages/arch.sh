@@ -6,4 +6,4 @@
 
 set -xe
 # see more in scripts/install/packages/packages.md
-pacman -Sy --noconfirm curl make nasm gcc qemu graphviz mtools xorriso automake llvm lld aarch64-linux-gnu-gcc git clang
+pacman -Sy --noconfirm curl make nasm gcc qemu graphviz mtools xorriso automake llvm lld aarch64-linux-gnu-gcc git clang e2fsprogs
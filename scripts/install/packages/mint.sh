@@ -7,4 +7,4 @@
 set -xe
 # see more in scripts/install/packages/packages.md
 apt update
-apt install -y curl make nasm gcc aqemu graphviz mtools xorriso automake llvm lld gcc-aarch64-linux-gnu git clang
+apt install -y curl make nasm gcc aqemu graphviz mtools xorriso automake llvm lld gcc-aarch64-linux-gnu git clang e2fsprogs
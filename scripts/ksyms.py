@@ -0,0 +1,57 @@
+#!/usr/bin/python3
+
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+# Generates the kernel symbol table (GNU assembler syntax) that gets linked into the final kernel binary.
+# usage: ksyms.py OUTPUT [KERNEL_ELF]
+# When KERNEL_ELF is omitted an empty table is generated, which is used for the first link pass.
+
+import sys, subprocess
+
+def text_symbols(elf: str):
+    # -n sorts by address, -C demangles rust symbols so they are readable in backtraces
+    out = subprocess.run(["nm", "-n", "-C", "--defined-only", elf], capture_output=True, text=True, check=True).stdout
+    symbols = []
+    for line in out.splitlines():
+        parts = line.split(" ", 2)
+        if len(parts) != 3 or parts[1] not in "tTwW":
+            continue
+        symbols.append((int(parts[0], 16), parts[2]))
+    return symbols
+
+def escape(name: str):
+    return name.replace("\\", "\\\\").replace("\"", "\\\"")
+
+def generate(symbols):
+    lines = [
+        "/* Generated by scripts/ksyms.py, do not edit */",
+        ".section .ksyms, \"a\"",
+        ".balign 8",
+        ".globl KSYMS_COUNT",
+        ".globl KSYMS_ENTRIES",
+        ".globl KSYMS_STRINGS",
+        "KSYMS_COUNT:",
+        f".quad {len(symbols)}",
+        "KSYMS_ENTRIES:",
+    ]
+    offset = 0
+    for addr, name in symbols:
+        size = len(name.encode())
+        # address, name offset, name length
+        lines.append(f".quad 0x{addr:x}")
+        lines.append(f".long {offset}, {size}")
+        offset += size
+    lines.append("KSYMS_STRINGS:")
+    for _, name in symbols:
+        lines.append(f".ascii \"{escape(name)}\"")
+    return "\n".join(lines) + "\n"
+
+if __name__ == "__main__":
+    if len(sys.argv) < 2:
+        print("usage: ksyms.py OUTPUT [KERNEL_ELF]")
+        sys.exit(1)
+    symbols = text_symbols(sys.argv[2]) if len(sys.argv) > 2 else []
+    with open(sys.argv[1], "w") as f:
+        f.write(generate(symbols))
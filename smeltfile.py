@@ -1,19 +1,38 @@
 #!/usr/bin/python3
-import sys, os
+import sys, os, json, subprocess
 sys.path.insert(1, 'smelt3/')
 import smelt3
 from smelt3 import task, use, File, file_tree, shell, create_setting, sett
 
 create_setting("KERNEL_BUILD_RELEASE", "")
+# UEFI firmware QEMU boots arm64 images with, from the qemu-efi-aarch64 package on Debian
+create_setting("AARCH64_UEFI_FIRMWARE", "/usr/share/qemu-efi-aarch64/QEMU_EFI.fd")
 
 ### Abstract
 
-def nasm(main: str, name: str, srcs: dict):
+def nasm(main: str, name: str, srcs: dict, include: str):
     for src in srcs.values():
         use(src)
-    shell(f"nasm -f elf64 {main} -o {name}")
+    shell(f"nasm -f elf64 -i {include} {main} -o {name}")
     return File(name)
 
+def link_rkernel(arch: str, kentry: File, kernel_obj: File, assembler: str, linker: str):
+    output = f"build/kernel.{arch}.bin"
+    linker_script = use(File(f"kernel/link/{arch}.ld"))
+    use(File("scripts/ksyms.py"))
+
+    # the symbol table can only be generated from an already linked kernel, so we link twice:
+    # first with an empty table, then with the table generated from the first binary.
+    # .ksyms sits after .text, so the addresses of functions do not change between the passes.
+    objs = f"{kentry} {kernel_obj}"
+    shell(f"python3 scripts/ksyms.py build/ksyms.{arch}.S")
+    shell(f"{assembler} build/ksyms.{arch}.S -o build/ksyms.{arch}.o")
+    shell(f"{linker} -T {linker_script} -o {output} {objs} build/ksyms.{arch}.o")
+    shell(f"python3 scripts/ksyms.py build/ksyms.{arch}.S {output}")
+    shell(f"{assembler} build/ksyms.{arch}.S -o build/ksyms.{arch}.o")
+    shell(f"{linker} -T {linker_script} -o {output} {objs} build/ksyms.{arch}.o")
+    return File(output)
+
 def compile_rkernel(arch: str):
     use(File("kernel/Cargo.lock"))
     use(File("kernel/Cargo.toml"))
@@ -30,30 +49,20 @@ def compile_rkernel(arch: str):
 
 @task("kentry")
 def x86_kentry():
+    # generated by config/config.sh
+    use(File("kernel/obj/x86_64/config.asm"))
     return nasm(
         main = "kernel/obj/x86_64/kentry/kentry.asm",
         name = "build/kentry.o",
-        srcs = file_tree("kernel/obj/x86_64/kentry/")
-    )
-
-@task()
-def x86_limfeats():
-    return nasm(
-        main = "kernel/obj/x86_64/limfeats/limine.asm",
-        name = "build/limfeats.o",
-        srcs = file_tree("kernel/obj/x86_64/limfeats/")
+        srcs = file_tree("kernel/obj/x86_64/kentry/"),
+        include = "kernel/obj/x86_64/"
     )
 
 @task("kernel")
 def x86_kernel():
-    output = "build/kernel.x86_64.bin"
-    linker_script = use(File("kernel/link/x86_64.ld"))
     kernel_obj = use(x86_kernel_object())
     kentry = use(x86_kentry())
-    limfeats = use(x86_limfeats())
-
-    shell(f"ld -T {linker_script} -o {output} {kentry} {limfeats} {kernel_obj}  ")
-    return File(output)
+    return link_rkernel("x86_64", kentry, kernel_obj, assembler="as", linker="ld")
 
 @task()
 def x86_kernel_object():
@@ -61,45 +70,195 @@ def x86_kernel_object():
 
 ### ARM tasks
 
+@task("kentry-arm")
+def arm_kentry():
+    # generated by config/config.sh
+    use(File("kernel/obj/aarch64/config.S"))
+    kentry = use(File("kernel/obj/aarch64/kentry.S"))
+    shell(f"aarch64-linux-gnu-as -I kernel/obj/aarch64/ {kentry} -o build/kentry.aarch64.o")
+    return File("build/kentry.aarch64.o")
+
+@task("kernel-arm")
+def arm_kernel():
+    kernel_obj = use(arm_kernel_object())
+    kentry = use(arm_kentry())
+    return link_rkernel("aarch64", kentry, kernel_obj, assembler="aarch64-linux-gnu-as", linker="aarch64-linux-gnu-ld")
+
 @task()
 def arm_kernel_object():
     return compile_rkernel(arch="aarch64")
 
+@task("iso-arm")
+def arm_isoroot():
+    kern = use(arm_kernel())
+    return make_iso(kern, "build/isoroot_aarch64", "build/image.aarch64.iso", arch="aarch64")
+
+@task("run-arm")
+def arm_run():
+    iso = use(arm_isoroot())
+    firmware = sett("AARCH64_UEFI_FIRMWARE")
+    # the virt machine has no IDE controller, so the image is attached as a SCSI CD-ROM
+    shell(f"""qemu-system-aarch64 -M virt -cpu cortex-a72 -m 512M -bios {firmware} \
+            -device virtio-scsi-pci -device scsi-cd,drive=cd -drive if=none,id=cd,format=raw,file={iso} \
+            -serial stdio -display none -no-reboot
+    """)
+
 ### General tasks
 
 @task("limine")
 def limine_bootloader():
     shell("cd limine && ./bootstrap")
-    shell("cd limine && ./configure --enable-bios --enable-bios-cd --enable-uefi-x86-64")
+    shell("cd limine && ./configure --enable-bios --enable-bios-cd --enable-uefi-x86-64 --enable-uefi-aarch64")
     shell("cd limine && make")
-    return [File("limine/bin/limine-bios-cd.bin"), File("limine/bin/BOOTX64.EFI"), File("limine/bin/limine-bios.sys")]
+    return [File("limine/bin/limine-bios-cd.bin"), File("limine/bin/BOOTX64.EFI"), File("limine/bin/limine-bios.sys"), File("limine/bin/BOOTAA64.EFI")]
 
-@task("iso")
-def isoroot():
+def make_iso(kernel: File, isoroot: str, output: str, arch: str = "x86_64"):
     boots = use(limine_bootloader())
-    kern = use(x86_kernel())
     limcfg = use(File("kernel/limine.conf"))
 
-    output = "build/image.iso"
-    shell("mkdir -p build/isoroot/EFI/BOOT/")
+    shell(f"mkdir -p {isoroot}/EFI/BOOT/")
+    shell(f"cp {kernel} {isoroot}/kernel.{arch}.bin")
+    # limine.conf boots kernel.x86_64.bin, the other architectures get their own copy
+    with open(str(limcfg)) as f:
+        config = f.read()
+    with open(f"{isoroot}/limine.conf", "w") as f:
+        f.write(config.replace("kernel.x86_64.bin", f"kernel.{arch}.bin"))
+
+    if arch == "aarch64":
+        # UEFI is the only way to boot arm64
+        shell(f"cp limine/bin/BOOTAA64.EFI {isoroot}/EFI/BOOT/")
+        shell(f"""xorriso -as mkisofs -R -r -J -hfsplus -apm-block-size 2048 \
+                --efi-boot /EFI/BOOT/BOOTAA64.EFI -efi-boot-part --efi-boot-image \
+                --protective-msdos-label ./{isoroot}/ -o {output}
+        """)
+        return File(output)
 
-    for b in [kern, limcfg, *boots]:
+    for b in boots:
         if (os.path.basename(str(b)) == "BOOTX64.EFI"):
-            shell(f"cp {b} build/isoroot/EFI/BOOT/")
-        else:
-            shell(f"cp {b} build/isoroot/")
+            shell(f"cp {b} {isoroot}/EFI/BOOT/")
+        elif (os.path.basename(str(b)) != "BOOTAA64.EFI"):
+            shell(f"cp {b} {isoroot}/")
 
     shell(f"""xorriso -as mkisofs -R -r -J -b limine-bios-cd.bin \
             -no-emul-boot -boot-load-size 4 -boot-info-table -hfsplus \
             -apm-block-size 2048 --efi-boot /EFI/BOOT/BOOTX64.EFI \
             -efi-boot-part --efi-boot-image --protective-msdos-label \
-            ./build/isoroot/ -o {output}
+            ./{isoroot}/ -o {output}
     """)
 
     shell(f"limine/bin/limine bios-install {output}")
 
     return File(output)
 
+@task("iso")
+def isoroot():
+    kern = use(x86_kernel())
+    return make_iso(kern, "build/isoroot", "build/image.iso")
+
+### Test tasks
+
+# exit codes of the isa-debug-exit device, see `QemuExitCode` in kernel/src/ktest.rs
+QEMU_EXIT_SUCCESS = (0x10 << 1) | 1
+
+@task()
+def ext2_test_image():
+    tree = "build/ext2-tree"
+    output = "build/ext2-test.img"
+    shell(f"rm -rf {tree} {output}")
+    os.makedirs(f"{tree}/dir")
+    os.makedirs(f"{tree}/many")
+    with open(f"{tree}/hello.txt", "w") as f:
+        f.write("Hello from ext2!\n")
+    # data behind the direct, single, double & triple indirect blocks, with holes in between
+    with open(f"{tree}/dir/sparse.bin", "wb") as f:
+        for offset, text in [(0, b"direct"), (20 << 10, b"single"), (300 << 10, b"double"), (70 << 20, b"triple")]:
+            f.seek(offset)
+            f.write(text)
+    # enough entries for more than one directory block
+    for i in range(40):
+        with open(f"{tree}/many/file-with-a-long-name-{i}", "w") as f:
+            f.write(f"{i}\n")
+    # 128 blocks of 1 KiB, the tests only copy the sectors they write onto the heap
+    shell(f"mke2fs -q -t ext2 -b 1024 -I 256 -N 128 -m 0 -d {tree} {output} 128")
+    return File(output)
+
+def configured(profile: str):
+    """Generates the config files from `profile`, returns a function that puts the previous
+    ones back"""
+    with open("config/list.json") as f:
+        outputs = json.load(f).values()
+    saved = {}
+    for path in outputs:
+        if os.path.exists(path):
+            with open(path) as f:
+                saved[path] = f.read()
+    shell(f"python3 config/genconfig.py {profile}")
+    def restore():
+        for path, text in saved.items():
+            with open(path, "w") as f:
+                f.write(text)
+    return restore
+
+@task()
+def x86_test_kernel():
+    # the tests need the larger heap & log of the test profile
+    restore = configured("test")
+    try:
+        return build_test_kernel()
+    finally:
+        restore()
+
+def build_test_kernel():
+    output = "build/kernel-test.x86_64.bin"
+    linker_script = use(File("kernel/link/x86_64.ld"))
+    kentry = use(x86_kentry())
+    # included by the ext2 tests, which are only built with `--cfg ext2_image`
+    use(ext2_test_image())
+    use(File("kernel/Cargo.toml"))
+    use(File(f"kernel/triple/x86_64.json"))
+    use(file_tree("kernel/src/"))
+    use(File("scripts/ksyms.py"))
+
+    # the test kernel is linked by cargo itself, test backtraces are not symbolized
+    shell("python3 scripts/ksyms.py build/ksyms-test.x86_64.S")
+    shell("as build/ksyms-test.x86_64.S -o build/ksyms-test.x86_64.o")
+    link_args = [f"-C link-arg={os.path.abspath(str(o))}" for o in [kentry, "build/ksyms-test.x86_64.o"]]
+    link_args.append(f"-C link-arg=-T{os.path.abspath(str(linker_script))}")
+
+    # `--profile test` builds the custom test harness (see `ktest.rs`) instead of the library
+    cargo = subprocess.run(
+        f"cargo rustc --lib --profile test --target triple/x86_64.json --message-format=json -- --cfg ext2_image {' '.join(link_args)}",
+        shell=True, cwd="kernel/", capture_output=True, text=True,
+    )
+    executable = None
+    for line in cargo.stdout.splitlines():
+        msg = json.loads(line)
+        if msg.get("reason") == "compiler-artifact" and msg.get("executable"):
+            executable = msg["executable"]
+        elif msg.get("reason") == "compiler-message":
+            print(msg["message"]["rendered"], end="")
+    if cargo.returncode != 0 or executable is None:
+        print(cargo.stderr)
+        raise Exception("Could not build the test kernel!")
+
+    shell(f"cp {executable} {output}")
+    return File(output)
+
+@task("test")
+def x86_test():
+    kern = use(x86_test_kernel())
+    iso = use(make_iso(kern, "build/isoroot_test", "build/image-test.iso"))
+
+    # test results are reported over serial, the kernel exits QEMU through isa-debug-exit
+    qemu = subprocess.run(
+        f"qemu-system-x86_64 -cdrom {iso} -serial stdio -display none -no-reboot "
+        "-device isa-debug-exit,iobase=0xf4,iosize=0x04",
+        shell=True,
+    )
+    if qemu.returncode != QEMU_EXIT_SUCCESS:
+        raise Exception(f"Kernel tests failed! (QEMU exit code {qemu.returncode})")
+    print("All kernel tests passed!")
+
 @task("docs")
 def doc_kernel():
     use(file_tree("kernel/src/"))